@@ -0,0 +1,539 @@
+use serde::Serialize;
+use worker::*;
+
+/// Default cap on downloaded content, larger files are uploaded to Gemini rather than inlined
+const DEFAULT_MAX_DOWNLOAD_BYTES: usize = 20 * 1024 * 1024;
+/// Default cap on chunks per document, keeps embedding calls and index growth bounded
+const DEFAULT_MAX_CHUNKS: usize = 50;
+/// Default cron trigger that sends the digests, every Monday at 9:00 UTC
+const DEFAULT_DIGEST_CRON: &str = "0 9 * * 1";
+/// Default window of `RATE_LIMIT`
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 60;
+/// Default similarity from which new content counts as a copy of a saved link
+const DEFAULT_DUPLICATE_THRESHOLD: f32 = 0.97;
+/// Default size of the chunks Gemini is asked for, in tokens
+const DEFAULT_CHUNK_TOKEN_SIZE: usize = 2000;
+/// Chunk sizes accepted from `CHUNK_TOKEN_SIZE`, others fall back to the default
+const CHUNK_TOKEN_SIZES: std::ops::RangeInclusive<usize> = 500..=8000;
+#[cfg(not(feature = "embedding-1024"))]
+pub const DEFAULT_EMBEDDING_MODEL: &str = "@cf/baai/bge-base-en-v1.5";
+#[cfg(feature = "embedding-1024")]
+pub const DEFAULT_EMBEDDING_MODEL: &str = "@cf/baai/bge-large-en-v1.5";
+
+/// Dimensions of the Workers AI embedding models whose size is known, `None` for others
+pub fn embedding_dimensions(model: &str) -> Option<usize> {
+    match model {
+        "@cf/baai/bge-small-en-v1.5" => Some(384),
+        "@cf/baai/bge-base-en-v1.5" | "@cf/google/embeddinggemma-300m" => Some(768),
+        "@cf/baai/bge-large-en-v1.5" | "@cf/baai/bge-m3" => Some(1024),
+        _ => None,
+    }
+}
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_MS: u64 = 500;
+pub const DEFAULT_NUM_TREES: usize = 4;
+pub const DEFAULT_MAX_LEAF_SIZE: usize = 20;
+const DEFAULT_EXACT_SEARCH_THRESHOLD: usize = 2000;
+const DEFAULT_GEMINI_MODEL: &str = "gemini-2.0-flash";
+
+/// What to do with links whose content Gemini can't summarize, e.g. audio, video or archives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedContent {
+    /// Save the link with a title and summary derived from its URL, type and size
+    #[default]
+    Metadata,
+    /// Refuse to save the link
+    Reject,
+}
+
+/// How code blocks are embedded when `EMBEDDING_PREPROCESS` is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeBlocks {
+    Keep,
+    /// Only the comments, which describe the code in words
+    #[default]
+    Comments,
+    Remove,
+}
+
+/// How the scores of a document's matching chunks combine into the document's score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreAggregation {
+    /// The best chunk alone
+    #[default]
+    Max,
+    /// Mean of the matching chunks
+    Mean,
+    /// Sum of the best `SCORE_TOP_K` chunks, favoring documents relevant throughout
+    SumTopK,
+}
+
+/// How search scores are shown in Telegram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoreFormat {
+    /// The cosine similarity, e.g. `0.73`
+    #[default]
+    Raw,
+    /// e.g. `73%`
+    Percent,
+    /// Zero to five stars, e.g. `★★★★☆`
+    Stars,
+}
+
+/// Effective settings of the deployment, the secrets and the `[vars]` in `wrangler.toml`
+/// with defaults filled in; secrets are skipped when serialized
+#[derive(Clone, Serialize)]
+pub struct Config {
+    #[serde(skip)]
+    pub bot_token: String,
+    #[serde(skip)]
+    pub gemini_api_key: String,
+    #[serde(skip)]
+    pub cf_account_id: String,
+    #[serde(skip)]
+    pub cf_api_token: String,
+    /// Checked against the header Telegram echoes on every update
+    #[serde(skip)]
+    pub webhook_secret: Option<String>,
+    /// Bearer token of the HTTP API, which is disabled when unset
+    #[serde(skip)]
+    pub api_token: Option<String>,
+    pub authorized_chat_ids: Vec<i64>,
+    /// Model that summarizes documents and answers the other prompts
+    pub gemini_model: String,
+    /// Gemini's own default when unset
+    pub gemini_temperature: Option<f32>,
+    /// Gemini's own default when unset
+    pub gemini_max_output_tokens: Option<u64>,
+    /// `OWNER_CHAT_ID`, falling back to the first authorized chat
+    pub owner_chat_id: Option<i64>,
+    pub max_download_bytes: usize,
+    /// Unlimited when unset
+    pub max_bytes_per_chat: Option<u64>,
+    pub max_chunks: usize,
+    /// Rough size of each chunk in tokens, smaller chunks make retrieval finer grained
+    pub chunk_token_size: usize,
+    /// Days the content of a link is kept in R2, forever when unset
+    pub content_retention_days: Option<u64>,
+    /// Metered requests a chat may make per `rate_limit_window_secs`, unlimited when unset
+    pub rate_limit: Option<u64>,
+    pub rate_limit_window_secs: u64,
+    /// Cron trigger that sends the digests, the other triggers only run the maintenance tasks
+    pub digest_cron: String,
+    /// Days between checks of whether a saved link still resolves, never checked when unset
+    pub dead_link_check_days: Option<u64>,
+    /// Message the owner of a link the check finds dead
+    pub report_dead_links: bool,
+    /// Similarity of a new link's first chunk to a saved link from which saving it asks first,
+    /// never asked when `DUPLICATE_THRESHOLD` is 0
+    pub duplicate_threshold: Option<f32>,
+    pub embedding_model: String,
+    pub multilingual_embedding_model: Option<String>,
+    /// Turn Markdown into plain prose before embedding chunks and queries
+    pub embedding_preprocess: bool,
+    pub embedding_code_blocks: CodeBlocks,
+    pub ai_retry_attempts: u32,
+    pub ai_retry_base_ms: u64,
+    pub vector_lite_num_trees: usize,
+    pub vector_lite_max_leaf_size: usize,
+    /// Store the vector_lite index as int8, about a quarter of the size
+    pub vector_lite_quantize: bool,
+    /// Forces exact or ANN search when set
+    pub search_exact: Option<bool>,
+    pub exact_search_threshold: usize,
+    pub score_aggregation: ScoreAggregation,
+    pub score_format: ScoreFormat,
+    /// Store content under `content/{category}/` so bucket lifecycle rules can target a category
+    pub categorized_bucket_paths: bool,
+    pub unsupported_content: UnsupportedContent,
+}
+
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Config {}",
+            serde_json::to_string(self).map_err(|_| std::fmt::Error)?
+        )
+    }
+}
+
+impl Config {
+    /// Read and validate the configuration, failing with every problem found at once
+    pub fn from_env(env: &Env) -> Result<Self> {
+        Self::parse(
+            |name| env.var(name).ok().map(|v| v.to_string()),
+            |name| env.secret(name).ok().map(|v| v.to_string()),
+        )
+    }
+
+    /// Parse the configuration from variable and secret lookups
+    /// Optional settings that don't parse fall back to their defaults, required ones are errors
+    fn parse(
+        var: impl Fn(&str) -> Option<String>,
+        secret: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let text = |name: &str| {
+            var(name)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let number = |name: &str| text(name).and_then(|v| v.parse::<u64>().ok());
+        let positive = |name: &str| number(name).filter(|v| *v > 0);
+        let flag = |name: &str| match text(name).map(|v| v.to_ascii_lowercase()).as_deref() {
+            Some("true") | Some("1") => Some(true),
+            Some("false") | Some("0") => Some(false),
+            _ => None,
+        };
+
+        let mut problems = vec![];
+        let mut required_secret = |name: &str| {
+            let value = secret(name)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty());
+            if value.is_none() {
+                problems.push(format!("secret {} is not set", name));
+            }
+            value.unwrap_or_default()
+        };
+        let bot_token = required_secret("BOT_TOKEN");
+        let gemini_api_key = required_secret("GEMINI_API_KEY");
+        let cf_account_id = required_secret("CF_ACCOUNT_ID");
+        let cf_api_token = required_secret("CF_API_TOKEN");
+        let optional_secret = |name: &str| secret(name).filter(|v| !v.trim().is_empty());
+
+        let authorized_chat_ids = text("AUTHORIZED_CHAT_IDS")
+            .map(|ids| parse_chat_ids(&ids))
+            .unwrap_or_default();
+        // Every chat is refused then, but updates are still answered, e.g. `/whoami`
+        if authorized_chat_ids.is_empty() {
+            console_error!("AUTHORIZED_CHAT_IDS has no chat ids, no chat is authorized");
+        }
+        let gemini_model = match text("GEMINI_MODEL") {
+            Some(model) => {
+                // Accept the `models/` prefix the API lists models with
+                let model = model.strip_prefix("models/").unwrap_or(&model).to_string();
+                if !is_model_name(&model) {
+                    problems.push(format!("GEMINI_MODEL {:?} is not a model name", model));
+                }
+                model
+            }
+            None => DEFAULT_GEMINI_MODEL.to_string(),
+        };
+        let owner_chat_id = match text("OWNER_CHAT_ID") {
+            Some(id) => match id.parse::<i64>() {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    problems.push(format!("OWNER_CHAT_ID {:?} is not a chat id", id));
+                    None
+                }
+            },
+            None => authorized_chat_ids.first().copied(),
+        };
+
+        if !problems.is_empty() {
+            return Err(Error::from(format!(
+                "Invalid configuration: {}",
+                problems.join("; ")
+            )));
+        }
+
+        Ok(Self {
+            bot_token,
+            gemini_api_key,
+            cf_account_id,
+            cf_api_token,
+            webhook_secret: optional_secret("WEBHOOK_SECRET"),
+            api_token: optional_secret("API_TOKEN"),
+            authorized_chat_ids,
+            owner_chat_id,
+            gemini_model,
+            gemini_temperature: text("GEMINI_TEMPERATURE")
+                .and_then(|v| v.parse::<f32>().ok())
+                .filter(|v| (0.0..=2.0).contains(v)),
+            gemini_max_output_tokens: positive("GEMINI_MAX_OUTPUT_TOKENS"),
+            max_download_bytes: number("MAX_DOWNLOAD_BYTES")
+                .map_or(DEFAULT_MAX_DOWNLOAD_BYTES, |v| v as usize),
+            max_bytes_per_chat: positive("MAX_BYTES_PER_CHAT"),
+            max_chunks: positive("MAX_CHUNKS").map_or(DEFAULT_MAX_CHUNKS, |v| v as usize),
+            chunk_token_size: number("CHUNK_TOKEN_SIZE")
+                .map(|v| v as usize)
+                .filter(|v| CHUNK_TOKEN_SIZES.contains(v))
+                .unwrap_or(DEFAULT_CHUNK_TOKEN_SIZE),
+            content_retention_days: positive("CONTENT_RETENTION_DAYS"),
+            rate_limit: positive("RATE_LIMIT"),
+            rate_limit_window_secs: positive("RATE_LIMIT_WINDOW_SECS")
+                .unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECS),
+            digest_cron: text("DIGEST_CRON").unwrap_or_else(|| DEFAULT_DIGEST_CRON.to_string()),
+            dead_link_check_days: positive("DEAD_LINK_CHECK_DAYS"),
+            report_dead_links: flag("REPORT_DEAD_LINKS").unwrap_or(false),
+            duplicate_threshold: match text("DUPLICATE_THRESHOLD")
+                .and_then(|v| v.parse::<f32>().ok())
+            {
+                Some(v) if v <= 0.0 => None,
+                Some(v) if v <= 1.0 => Some(v),
+                _ => Some(DEFAULT_DUPLICATE_THRESHOLD),
+            },
+            embedding_model: text("EMBEDDING_MODEL")
+                .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string()),
+            multilingual_embedding_model: text("MULTILINGUAL_EMBEDDING_MODEL"),
+            embedding_preprocess: flag("EMBEDDING_PREPROCESS").unwrap_or(false),
+            embedding_code_blocks: match text("EMBEDDING_CODE_BLOCKS")
+                .map(|v| v.to_ascii_lowercase())
+                .as_deref()
+            {
+                Some("keep") => CodeBlocks::Keep,
+                Some("remove") => CodeBlocks::Remove,
+                _ => CodeBlocks::Comments,
+            },
+            ai_retry_attempts: number("AI_RETRY_ATTEMPTS")
+                .map_or(DEFAULT_RETRY_ATTEMPTS, |v| v.clamp(1, 10) as u32),
+            ai_retry_base_ms: number("AI_RETRY_BASE_MS").unwrap_or(DEFAULT_RETRY_BASE_MS),
+            vector_lite_num_trees: positive("VECTOR_LITE_NUM_TREES")
+                .map_or(DEFAULT_NUM_TREES, |v| v as usize),
+            vector_lite_max_leaf_size: positive("VECTOR_LITE_MAX_LEAF_SIZE")
+                .map_or(DEFAULT_MAX_LEAF_SIZE, |v| v as usize),
+            vector_lite_quantize: flag("VECTOR_LITE_QUANTIZE").unwrap_or(false),
+            search_exact: flag("SEARCH_EXACT"),
+            exact_search_threshold: number("EXACT_SEARCH_THRESHOLD")
+                .map_or(DEFAULT_EXACT_SEARCH_THRESHOLD, |v| v as usize),
+            score_aggregation: match text("SCORE_AGGREGATION")
+                .map(|v| v.to_ascii_lowercase())
+                .as_deref()
+            {
+                Some("mean") => ScoreAggregation::Mean,
+                Some("sum_topk") => ScoreAggregation::SumTopK,
+                _ => ScoreAggregation::Max,
+            },
+            score_format: match text("SCORE_FORMAT")
+                .map(|v| v.to_ascii_lowercase())
+                .as_deref()
+            {
+                Some("percent") => ScoreFormat::Percent,
+                Some("stars") => ScoreFormat::Stars,
+                _ => ScoreFormat::Raw,
+            },
+            categorized_bucket_paths: flag("CATEGORIZED_BUCKET_PATHS").unwrap_or(false),
+            unsupported_content: match text("UNSUPPORTED_CONTENT")
+                .map(|v| v.to_ascii_lowercase())
+                .as_deref()
+            {
+                Some("reject") => UnsupportedContent::Reject,
+                _ => UnsupportedContent::Metadata,
+            },
+        })
+    }
+
+    /// Whether each secret is set, never their values
+    pub fn secrets(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("BOT_TOKEN", true),
+            ("GEMINI_API_KEY", true),
+            ("CF_ACCOUNT_ID", true),
+            ("CF_API_TOKEN", true),
+            ("WEBHOOK_SECRET", self.webhook_secret.is_some()),
+            ("API_TOKEN", self.api_token.is_some()),
+        ]
+    }
+
+    pub fn is_authorized(&self, chat_id: i64) -> bool {
+        self.authorized_chat_ids.contains(&chat_id)
+    }
+
+    pub fn is_owner(&self, chat_id: i64) -> bool {
+        self.owner_chat_id == Some(chat_id)
+    }
+
+    /// Whether a cron trigger is the one that sends digests, however its fields are spaced
+    pub fn is_digest_cron(&self, cron: &str) -> bool {
+        cron.split_whitespace()
+            .eq(self.digest_cron.split_whitespace())
+    }
+}
+
+/// Whether a Gemini model name is safe to put in a request path, e.g. `gemini-2.5-flash`
+fn is_model_name(model: &str) -> bool {
+    model.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && model
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+/// Parse a list of chat ids separated by commas, spaces or newlines, skipping entries that
+/// aren't numbers with a warning so one typo doesn't lock out everyone else; `#` starts a
+/// comment that runs to the end of the line, e.g. a member's name
+fn parse_chat_ids(ids: &str) -> Vec<i64> {
+    ids.lines()
+        .map(|line| line.split_once('#').map_or(line, |(ids, _)| ids))
+        .flat_map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| {
+            id.parse::<i64>()
+                .inspect_err(|_| {
+                    console_warn!("Skipping AUTHORIZED_CHAT_IDS entry {:?}, not a chat id", id)
+                })
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SECRETS: [&str; 4] = [
+        "BOT_TOKEN",
+        "GEMINI_API_KEY",
+        "CF_ACCOUNT_ID",
+        "CF_API_TOKEN",
+    ];
+
+    /// A configuration from `vars`, with every required secret set, for tests of other modules
+    pub(crate) fn parse(vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        Config::parse(
+            |name| vars.get(name).map(|v| v.to_string()),
+            |name| SECRETS.contains(&name).then(|| "secret".to_string()),
+        )
+    }
+
+    #[test]
+    fn parses_chat_ids_and_defaults() {
+        let config = parse(&[("AUTHORIZED_CHAT_IDS", "1, -2")]).unwrap();
+        assert_eq!(config.authorized_chat_ids, vec![1, -2]);
+        assert_eq!(config.owner_chat_id, Some(1));
+        assert_eq!(config.max_chunks, DEFAULT_MAX_CHUNKS);
+        assert_eq!(config.gemini_model, DEFAULT_GEMINI_MODEL);
+        assert!(config.is_authorized(-2));
+        assert!(!config.is_authorized(3));
+    }
+
+    #[test]
+    fn missing_chat_ids_authorize_no_one() {
+        let config = parse(&[]).unwrap();
+        assert!(config.authorized_chat_ids.is_empty());
+        assert_eq!(config.owner_chat_id, None);
+        assert!(!config.is_authorized(1));
+    }
+
+    #[test]
+    fn empty_chat_ids_authorize_no_one() {
+        for value in ["", "   ", "\n\t", ",", "# nobody yet"] {
+            let config = parse(&[("AUTHORIZED_CHAT_IDS", value)]).unwrap();
+            assert!(config.authorized_chat_ids.is_empty(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn missing_secrets_are_reported_together() {
+        let vars = HashMap::from([("AUTHORIZED_CHAT_IDS", "1")]);
+        let err = Config::parse(|name| vars.get(name).map(|v| v.to_string()), |_| None)
+            .unwrap_err()
+            .to_string();
+        for secret in SECRETS {
+            assert!(err.contains(secret), "{}", err);
+        }
+    }
+
+    #[test]
+    fn blank_secrets_are_missing() {
+        let vars = HashMap::from([("AUTHORIZED_CHAT_IDS", "1")]);
+        let err = Config::parse(
+            |name| vars.get(name).map(|v| v.to_string()),
+            |name| Some(if name == "BOT_TOKEN" { " " } else { "secret" }.to_string()),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("BOT_TOKEN"), "{}", err);
+        assert!(!err.contains("GEMINI_API_KEY"), "{}", err);
+    }
+
+    #[test]
+    fn parses_optional_settings() {
+        let config = parse(&[
+            ("AUTHORIZED_CHAT_IDS", "1 2"),
+            ("OWNER_CHAT_ID", "2"),
+            ("GEMINI_MODEL", "models/gemini-2.5-flash"),
+            ("MAX_CHUNKS", " 12 "),
+            ("RATE_LIMIT", "5"),
+            ("REPORT_DEAD_LINKS", "TRUE"),
+            ("SEARCH_EXACT", "0"),
+            ("DUPLICATE_THRESHOLD", "0"),
+            ("SCORE_AGGREGATION", "sum_topk"),
+        ])
+        .unwrap();
+        assert_eq!(config.owner_chat_id, Some(2));
+        assert!(config.is_owner(2) && !config.is_owner(1));
+        assert_eq!(config.gemini_model, "gemini-2.5-flash");
+        assert_eq!(config.max_chunks, 12);
+        assert_eq!(config.rate_limit, Some(5));
+        assert!(config.report_dead_links);
+        assert_eq!(config.search_exact, Some(false));
+        assert_eq!(config.duplicate_threshold, None);
+        assert_eq!(config.score_aggregation, ScoreAggregation::SumTopK);
+    }
+
+    #[test]
+    fn invalid_required_settings_are_errors() {
+        for (name, value) in [("OWNER_CHAT_ID", "alice"), ("GEMINI_MODEL", "../models")] {
+            let err = parse(&[("AUTHORIZED_CHAT_IDS", "1"), (name, value)])
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(name), "{}", err);
+        }
+    }
+
+    #[test]
+    fn invalid_optional_settings_fall_back() {
+        let config = parse(&[
+            ("AUTHORIZED_CHAT_IDS", "1"),
+            ("MAX_CHUNKS", "0"),
+            ("CHUNK_TOKEN_SIZE", "100"),
+            ("DUPLICATE_THRESHOLD", "2"),
+            ("SEARCH_EXACT", "maybe"),
+        ])
+        .unwrap();
+        assert_eq!(config.max_chunks, DEFAULT_MAX_CHUNKS);
+        assert_eq!(config.chunk_token_size, DEFAULT_CHUNK_TOKEN_SIZE);
+        assert_eq!(
+            config.duplicate_threshold,
+            Some(DEFAULT_DUPLICATE_THRESHOLD)
+        );
+        assert_eq!(config.search_exact, None);
+    }
+
+    #[test]
+    fn parses_chat_id_lists() {
+        assert_eq!(parse_chat_ids("1,2 3\n4"), vec![1, 2, 3, 4]);
+        assert_eq!(
+            parse_chat_ids("# family\n123 # alice\n-456, # group\n"),
+            vec![123, -456]
+        );
+        assert_eq!(parse_chat_ids("1, 2,,\n"), vec![1, 2]);
+        assert!(parse_chat_ids("  ").is_empty());
+    }
+
+    #[test]
+    fn only_the_digest_cron_sends_digests() {
+        let config = parse(&[]).unwrap();
+        assert!(config.is_digest_cron("0 9 * * 1"));
+        assert!(!config.is_digest_cron("0 */6 * * *"));
+
+        let config = parse(&[("DIGEST_CRON", "30 8 * * *")]).unwrap();
+        assert!(config.is_digest_cron("30  8 * * *"));
+        assert!(!config.is_digest_cron("0 9 * * 1"));
+    }
+
+    #[test]
+    fn bad_chat_ids_are_skipped() {
+        assert_eq!(parse_chat_ids("1, two, 3\n12a4 # typo\n-5"), vec![1, 3, -5]);
+        let config = parse(&[("AUTHORIZED_CHAT_IDS", "alice 42")]).unwrap();
+        assert_eq!(config.authorized_chat_ids, vec![42]);
+        assert_eq!(config.owner_chat_id, Some(42));
+    }
+}
@@ -1,24 +1,100 @@
+use crate::utils::{normalize_url, url_variants};
 use serde::{Deserialize, Serialize};
 use wasm_bindgen::JsValue;
 use worker::*;
 
 #[allow(unused)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocInfo {
     pub id: String,
     pub url: String,
     pub created_at: String,
     pub bucket_path: String,
+    /// Content type after sniffing, drives processing, the extension and the emoji
     pub content_type: String,
+    /// Content type the server sent, unset for links saved before it was recorded
+    #[serde(default)]
+    pub reported_content_type: Option<String>,
     pub size: usize,
     pub title: String,
     pub summary: String,
     pub chunk_count: usize,
+    /// Number of chunks before `MAX_CHUNKS` sampling, only set for capped documents
+    #[serde(default)]
+    pub original_chunk_count: Option<usize>,
+    /// ISO 639-1 code detected by Gemini
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Workers AI model the chunks were embedded with, unset for links saved before it was recorded
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    /// Set while the link still needs work, see `STATUS_PENDING_PROCESSING` and `STATUS_NEEDS_REEMBED`
+    #[serde(default)]
+    pub status: Option<String>,
+    /// Why the last processing attempt failed
+    #[serde(default)]
+    pub status_error: Option<String>,
+    /// Validators from the original fetch, so a re-crawl can skip unchanged pages
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// Chat that saved the link, unset for links saved before ownership was recorded
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    /// Author and publication date from the source, e.g. for threads on X
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub published_at: Option<String>,
+    /// When the content was deleted from R2 by the retention policy, the rest of the link is kept
+    #[serde(default)]
+    pub content_expired_at: Option<String>,
+    /// Comma-separated tags set with `/tag`
+    #[serde(default)]
+    pub tags: Option<String>,
+    /// When the scheduled dead link check last requested the URL
+    #[serde(default)]
+    pub last_checked: Option<String>,
+    /// Status of that request, unset when the server couldn't be reached
+    #[serde(default)]
+    pub http_status: Option<u16>,
 }
 
-/// Retrieves link statistics from the database
-/// Returns the total number of links and the details of the latest 10 links
-pub async fn get_link_stats(env: Env) -> Result<(u64, Vec<DocInfo>)> {
+impl DocInfo {
+    pub fn tag_list(&self) -> Vec<&str> {
+        self.tags
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+}
+
+/// Tags from a comma-separated list, trimmed, lowercased and without a leading `#` or duplicates
+pub fn parse_tags(input: &str) -> Vec<String> {
+    let mut tags: Vec<String> = vec![];
+    for tag in input.split(',') {
+        let tag = tag.trim().trim_start_matches('#').trim().to_lowercase();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Gemini failed, only the downloaded content is saved
+pub const STATUS_PENDING_PROCESSING: &str = "pending_processing";
+/// Embedding failed, the link has no vectors
+pub const STATUS_NEEDS_REEMBED: &str = "needs_reembed";
+
+/// Count all links and read one page of them, newest first
+pub async fn get_link_stats_paged(
+    env: Env,
+    offset: usize,
+    limit: usize,
+) -> Result<(u64, Vec<DocInfo>)> {
     let d1 = env.d1("SEEN_DB")?;
 
     let count_stmt = d1.prepare("SELECT COUNT(*) FROM links");
@@ -31,13 +107,270 @@ pub async fn get_link_stats(env: Env) -> Result<(u64, Vec<DocInfo>)> {
         0
     };
 
-    let links_stmt = d1.prepare("SELECT * FROM links ORDER BY created_at DESC LIMIT 10");
+    let links_stmt = d1
+        .prepare("SELECT * FROM links ORDER BY created_at DESC LIMIT ? OFFSET ?")
+        .bind(&[
+            JsValue::from_f64(limit as f64),
+            JsValue::from_f64(offset as f64),
+        ])?;
     let links_result = links_stmt.run().await?;
 
     let rows = links_result.results::<DocInfo>()?;
     Ok((count, rows))
 }
 
+/// Totals over all saved links, for `/stats`
+pub struct DetailedStats {
+    pub total_links: u64,
+    /// Bytes of content still in R2, expired content doesn't count
+    pub stored_bytes: u64,
+    /// Content type without parameters, links and stored bytes, most links first
+    pub by_content_type: Vec<(String, u64, u64)>,
+    pub added_last_week: u64,
+}
+
+pub async fn get_detailed_stats(env: &Env) -> Result<DetailedStats> {
+    #[derive(Deserialize)]
+    struct TypeRow {
+        content_type: String,
+        links: u64,
+        bytes: u64,
+    }
+    #[derive(Deserialize)]
+    struct CountRow {
+        links: u64,
+    }
+
+    let db = env.d1("SEEN_DB")?;
+    let results = db
+        .batch(vec![
+            db.prepare(
+                "SELECT content_type, COUNT(*) AS links, COALESCE(SUM(CASE WHEN content_expired_at IS NULL THEN size ELSE 0 END), 0) AS bytes FROM links GROUP BY content_type",
+            ),
+            db.prepare(
+                "SELECT COUNT(*) AS links FROM links WHERE created_at > datetime('now', '-7 days')",
+            ),
+        ])
+        .await?;
+    let [by_type, recent] = results.as_slice() else {
+        return Err(Error::from(
+            "Unexpected number of results for the link stats",
+        ));
+    };
+
+    // `text/html` and `text/html; charset=utf-8` are the same type
+    let mut by_content_type: Vec<(String, u64, u64)> = vec![];
+    for row in by_type.results::<TypeRow>()? {
+        let content_type = row
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        match by_content_type
+            .iter_mut()
+            .find(|(t, _, _)| *t == content_type)
+        {
+            Some((_, links, bytes)) => {
+                *links += row.links;
+                *bytes += row.bytes;
+            }
+            None => by_content_type.push((content_type, row.links, row.bytes)),
+        }
+    }
+    by_content_type.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    Ok(DetailedStats {
+        total_links: by_content_type.iter().map(|(_, links, _)| links).sum(),
+        stored_bytes: by_content_type.iter().map(|(_, _, bytes)| bytes).sum(),
+        by_content_type,
+        added_last_week: recent
+            .results::<CountRow>()?
+            .first()
+            .map_or(0, |row| row.links),
+    })
+}
+
+/// Total bytes of content stored for the links a chat saved
+pub async fn get_chat_usage(env: &Env, chat_id: i64) -> Result<u64> {
+    let db = env.d1("SEEN_DB")?;
+    let row = db
+        .prepare("SELECT COALESCE(SUM(size), 0) AS bytes FROM links WHERE chat_id = ? AND content_expired_at IS NULL")
+        .bind(&[JsValue::from_f64(chat_id as f64)])?
+        .first::<serde_json::Value>(None)
+        .await?;
+    Ok(row
+        .and_then(|row| row.get("bytes").and_then(|v| v.as_u64()))
+        .unwrap_or(0))
+}
+
+/// Replace the tags of a link, clearing them when `tags` is empty
+pub async fn set_link_tags(env: &Env, id: &str, tags: &[String]) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    let tags = Some(tags.join(",")).filter(|tags| !tags.is_empty());
+    db.prepare("UPDATE links SET tags = ? WHERE id = ?")
+        .bind(&[optional_str(tags.as_deref()), JsValue::from_str(id)])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Tags of the given links by id, skipping links without tags
+pub async fn get_link_tags(
+    env: &Env,
+    ids: &[String],
+) -> Result<std::collections::HashMap<String, String>> {
+    #[derive(Deserialize)]
+    struct TagRow {
+        id: String,
+        tags: String,
+    }
+
+    if ids.is_empty() {
+        return Ok(std::collections::HashMap::new());
+    }
+    let db = env.d1("SEEN_DB")?;
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let bindings = ids
+        .iter()
+        .map(|id| JsValue::from_str(id))
+        .collect::<Vec<_>>();
+    let rows = db
+        .prepare(format!(
+            "SELECT id, tags FROM links WHERE id IN ({}) AND tags IS NOT NULL",
+            placeholders
+        ))
+        .bind(&bindings)?
+        .all()
+        .await?
+        .results::<TagRow>()?;
+    Ok(rows.into_iter().map(|row| (row.id, row.tags)).collect())
+}
+
+/// Links not checked for the last `days`, the ones never checked first
+pub async fn get_links_to_check(env: &Env, days: u64, limit: usize) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare(
+        "SELECT * FROM links WHERE last_checked IS NULL OR last_checked < datetime('now', ?) ORDER BY last_checked IS NOT NULL, last_checked LIMIT ?",
+    )
+    .bind(&[
+        JsValue::from_str(&format!("-{} days", days)),
+        JsValue::from_f64(limit as f64),
+    ])?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
+/// Record the outcome of checking whether a link still resolves
+pub async fn set_link_check(env: &Env, id: &str, http_status: Option<u16>) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("UPDATE links SET last_checked = datetime('now'), http_status = ? WHERE id = ?")
+        .bind(&[
+            http_status.map_or(JsValue::NULL, |status| JsValue::from_f64(status as f64)),
+            JsValue::from_str(id),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Point a link at newly downloaded content, recording its size and validators
+pub async fn set_link_content(env: &Env, row: &DocInfo) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("UPDATE links SET bucket_path = ?, content_type = ?, reported_content_type = ?, size = ?, etag = ?, last_modified = ?, content_expired_at = NULL WHERE id = ?")
+        .bind(&[
+            JsValue::from_str(&row.bucket_path),
+            JsValue::from_str(&row.content_type),
+            optional_str(row.reported_content_type.as_deref()),
+            JsValue::from_f64(row.size as f64),
+            optional_str(row.etag.as_deref()),
+            optional_str(row.last_modified.as_deref()),
+            JsValue::from_str(&row.id),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Up to `limit` links picked at random
+pub async fn get_random_links(env: &Env, limit: usize) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("SELECT * FROM links ORDER BY RANDOM() LIMIT ?")
+        .bind(&[JsValue::from_f64(limit as f64)])?
+        .all()
+        .await?
+        .results::<DocInfo>()
+}
+
+/// The `created_at` of links saved `days` before `now_ms`, in the `YYYY-MM-DD HH:MM:SS` UTC form
+/// of SQLite's `datetime()`, so links saved earlier compare lower as strings
+fn expiry_cutoff(now_ms: f64, days: u64) -> String {
+    let seconds = (now_ms / 1000.0).floor() as i64 - days as i64 * 86400;
+    let (day, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // Days since 1970-01-01 to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = day + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Links saved more than `days` ago whose content is still stored, oldest first
+pub async fn get_links_to_expire(env: &Env, days: u64, limit: usize) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare(
+        "SELECT * FROM links WHERE content_expired_at IS NULL AND created_at < ? ORDER BY created_at LIMIT ?",
+    )
+    .bind(&[
+        JsValue::from_str(&expiry_cutoff(js_sys::Date::now(), days)),
+        JsValue::from_f64(limit as f64),
+    ])?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
+/// Record that the content of a link was deleted from R2
+pub async fn mark_content_expired(env: &Env, id: &str) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("UPDATE links SET content_expired_at = datetime('now') WHERE id = ?")
+        .bind(&[JsValue::from_str(id)])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Links a chat saved after `since` (a D1 `datetime`), oldest first, the last week when unset
+pub async fn get_links_saved_since(
+    env: &Env,
+    chat_id: i64,
+    since: Option<&str>,
+) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare(
+        "SELECT * FROM links WHERE chat_id = ? AND created_at > COALESCE(?, datetime('now', '-7 days')) ORDER BY created_at",
+    )
+    .bind(&[JsValue::from_f64(chat_id as f64), optional_str(since)])?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
 /// Retrieve a link by its ID from the database
 pub async fn get_link_by_id(env: &Env, id: &str) -> Result<Option<DocInfo>> {
     let db = env.d1("SEEN_DB")?;
@@ -55,6 +388,13 @@ pub async fn get_link_by_id(env: &Env, id: &str) -> Result<Option<DocInfo>> {
     }
 }
 
+/// Run a trivial query to check that the database is reachable
+pub async fn ping(env: &Env) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("SELECT 1").run().await?;
+    Ok(())
+}
+
 /// Save content to R2 bucket
 pub async fn save_to_bucket(env: &Env, bucket_path: &str, content: Vec<u8>) -> Result<()> {
     let bucket = env.bucket("SEEN_BUCKET")?;
@@ -77,53 +417,287 @@ pub async fn read_from_bucket(env: &Env, bucket_path: &str) -> Result<Vec<u8>> {
     Ok(bytes.to_vec())
 }
 
+/// Whether an object is stored at the path, without downloading it
+pub async fn exists_in_bucket(env: &Env, bucket_path: &str) -> Result<bool> {
+    let bucket = env.bucket("SEEN_BUCKET")?;
+    Ok(bucket.head(bucket_path).await?.is_some())
+}
+
+/// Restore the columns `save_link_to_db` doesn't take from the row, for links imported from a
+/// backup
+pub async fn restore_link_metadata(env: &Env, row: &DocInfo) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("UPDATE links SET created_at = ?, tags = ? WHERE id = ?")
+        .bind(&[
+            JsValue::from_str(&row.created_at),
+            optional_str(row.tags.as_deref()),
+            JsValue::from_str(&row.id),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
 /// Save link metadata and embeddings to database
-pub async fn save_link_to_db(env: &Env, row: &DocInfo, embeddings: &Vec<Vec<f32>>) -> Result<()> {
+/// `chunks` are the texts of the embedded chunks, in the same order
+pub async fn save_link_to_db(
+    env: &Env,
+    row: &DocInfo,
+    embeddings: &Vec<Vec<f32>>,
+    chunks: &[String],
+) -> Result<()> {
     let d1 = env.d1("SEEN_DB")?;
 
     let mut statements = vec![];
 
     let stmt = d1
-            .prepare("INSERT INTO links (id, url, created_at, bucket_path, content_type, size, title, summary, chunk_count) VALUES (?, ?, datetime('now'), ?, ?, ?, ?, ?, ?)")
+            .prepare("INSERT INTO links (id, url, normalized_url, created_at, bucket_path, content_type, size, title, summary, chunk_count, original_chunk_count, language, embedding_model, status, status_error, etag, last_modified, chat_id, reported_content_type, author, published_at) VALUES (?, ?, ?, datetime('now'), ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
             .bind(&[
                 JsValue::from_str(&row.id),
                 JsValue::from_str(&row.url),
+                JsValue::from_str(&normalize_url(&row.url)),
                 JsValue::from_str(&row.bucket_path),
                 JsValue::from_str(&row.content_type),
                 JsValue::from_f64(row.size as f64),
                 JsValue::from_str(&row.title),
                 JsValue::from_str(&row.summary),
                 JsValue::from_f64(row.chunk_count as f64),
+                optional_count(row.original_chunk_count),
+                optional_str(row.language.as_deref()),
+                optional_str(row.embedding_model.as_deref()),
+                optional_str(row.status.as_deref()),
+                optional_str(row.status_error.as_deref()),
+                optional_str(row.etag.as_deref()),
+                optional_str(row.last_modified.as_deref()),
+                row.chat_id.map_or(JsValue::NULL, |id| JsValue::from_f64(id as f64)),
+                JsValue::from_str(row.reported_content_type.as_deref().unwrap_or(&row.content_type)),
+                optional_str(row.author.as_deref()),
+                optional_str(row.published_at.as_deref()),
         ])?;
     statements.push(stmt);
 
-    // Insert embeddings
+    statements.extend(insert_embedding_statements(&d1, row, embeddings, chunks)?);
+    let _result = d1.batch(statements).await?;
+
+    Ok(())
+}
+
+fn optional_count(count: Option<usize>) -> JsValue {
+    count.map_or(JsValue::NULL, |c| JsValue::from_f64(c as f64))
+}
+
+fn optional_str(value: Option<&str>) -> JsValue {
+    value.map_or(JsValue::NULL, JsValue::from_str)
+}
+
+/// Build the statements inserting the embeddings of a link
+/// Chunks missing from `chunks`, or empty, are stored without their text
+fn insert_embedding_statements(
+    d1: &D1Database,
+    row: &DocInfo,
+    embeddings: &[Vec<f32>],
+    chunks: &[String],
+) -> Result<Vec<D1PreparedStatement>> {
+    let mut statements = Vec::with_capacity(embeddings.len());
     for (i, embedding) in embeddings.iter().enumerate() {
         let vec_id = format!("{}-{}", row.id, i);
 
-        let embed_stmt =
-            d1.prepare("INSERT INTO embeddings (vector_id, vector, link_id) VALUES (?, ?, ?)");
+        let embed_stmt = d1.prepare(
+            "INSERT INTO embeddings (vector_id, vector, link_id, model, chunk) VALUES (?, ?, ?, ?, ?)",
+        );
         let embed_stmt = embed_stmt.bind(&[
             JsValue::from_str(&vec_id),
-            JsValue::from(js_sys::Float32Array::from(embedding.as_slice().as_ref())),
+            JsValue::from(js_sys::Float32Array::from(embedding.as_slice())),
             JsValue::from_str(&row.id),
+            optional_str(row.embedding_model.as_deref()),
+            optional_str(chunks.get(i).map(String::as_str).filter(|c| !c.is_empty())),
         ])?;
 
         statements.push(embed_stmt);
     }
+    Ok(statements)
+}
+
+/// Replace the processed data and embeddings of an existing link
+pub async fn update_link_and_embeddings(
+    env: &Env,
+    row: &DocInfo,
+    embeddings: &[Vec<f32>],
+    chunks: &[String],
+) -> Result<()> {
+    let d1 = env.d1("SEEN_DB")?;
+
+    let mut statements = vec![
+        d1.prepare("DELETE FROM embeddings WHERE link_id = ?")
+            .bind(&[JsValue::from_str(&row.id)])?,
+        d1.prepare("UPDATE links SET bucket_path = ?, content_type = ?, title = ?, summary = ?, chunk_count = ?, original_chunk_count = ?, language = ?, embedding_model = ?, status = ?, status_error = ? WHERE id = ?")
+            .bind(&[
+                JsValue::from_str(&row.bucket_path),
+                JsValue::from_str(&row.content_type),
+                JsValue::from_str(&row.title),
+                JsValue::from_str(&row.summary),
+                JsValue::from_f64(row.chunk_count as f64),
+                optional_count(row.original_chunk_count),
+                optional_str(row.language.as_deref()),
+                optional_str(row.embedding_model.as_deref()),
+                optional_str(row.status.as_deref()),
+                optional_str(row.status_error.as_deref()),
+                JsValue::from_str(&row.id),
+            ])?,
+    ];
+    statements.extend(insert_embedding_statements(&d1, row, embeddings, chunks)?);
     let _result = d1.batch(statements).await?;
 
     Ok(())
 }
 
+/// Statements creating `links_fts`, the full-text index of link titles and summaries, and the
+/// triggers that keep it in sync with `links` whichever statement changes them
+const FTS_SCHEMA: &[&str] = &[
+    "CREATE VIRTUAL TABLE IF NOT EXISTS links_fts USING fts5(link_id UNINDEXED, title, summary)",
+    "CREATE TRIGGER IF NOT EXISTS links_fts_insert AFTER INSERT ON links BEGIN
+        INSERT INTO links_fts (link_id, title, summary) VALUES (new.id, new.title, new.summary);
+    END",
+    "CREATE TRIGGER IF NOT EXISTS links_fts_update AFTER UPDATE OF title, summary ON links BEGIN
+        DELETE FROM links_fts WHERE link_id = old.id;
+        INSERT INTO links_fts (link_id, title, summary) VALUES (new.id, new.title, new.summary);
+    END",
+    "CREATE TRIGGER IF NOT EXISTS links_fts_delete AFTER DELETE ON links BEGIN
+        DELETE FROM links_fts WHERE link_id = old.id;
+    END",
+];
+
+/// Create the full-text index and add the links it doesn't hold yet, returns how many were added
+async fn create_fts_index(db: &D1Database) -> Result<usize> {
+    for statement in FTS_SCHEMA {
+        db.prepare(*statement).run().await?;
+    }
+    let result = db
+        .prepare(
+            "INSERT INTO links_fts (link_id, title, summary) SELECT id, title, summary FROM links WHERE id NOT IN (SELECT link_id FROM links_fts)",
+        )
+        .run()
+        .await?;
+    Ok(result
+        .meta()?
+        .and_then(|meta| meta.changes)
+        .unwrap_or_default())
+}
+
+/// An FTS5 query matching any of the words, each quoted so characters like `"`, `*`, `:`, `-`
+/// or words like `NOT` are searched for rather than parsed as query syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+/// Ids of the links whose title or summary matches the words of the query, best first, with
+/// their bm25 scores, where lower is a better match
+pub async fn fts_search(env: &Env, query: &str, limit: usize) -> Result<Vec<(String, f64)>> {
+    #[derive(Deserialize)]
+    struct FtsRow {
+        link_id: String,
+        score: f64,
+    }
+
+    let query = fts_query(query);
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    let db = env.d1("SEEN_DB")?;
+    let rows = db
+        .prepare(
+            "SELECT link_id, bm25(links_fts) AS score FROM links_fts WHERE links_fts MATCH ? ORDER BY score LIMIT ?",
+        )
+        .bind(&[JsValue::from_str(&query), JsValue::from_f64(limit as f64)])?
+        .all()
+        .await?
+        .results::<FtsRow>()?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.link_id, row.score))
+        .collect())
+}
+
+/// Links tagged with `tag`, which is matched whole against the comma-separated tags
+pub async fn get_links_by_tag(env: &Env, tag: &str) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("SELECT * FROM links WHERE instr(',' || tags || ',', ?) > 0 ORDER BY created_at")
+        .bind(&[JsValue::from_str(&format!(",{},", tag))])?
+        .all()
+        .await?
+        .results::<DocInfo>()
+}
+
+/// Links by id, in no particular order, ids without a link are skipped
+pub async fn get_links_by_ids(env: &Env, ids: &[String]) -> Result<Vec<DocInfo>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let db = env.d1("SEEN_DB")?;
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let bindings = ids
+        .iter()
+        .map(|id| JsValue::from_str(id))
+        .collect::<Vec<_>>();
+    db.prepare(format!(
+        "SELECT * FROM links WHERE id IN ({})",
+        placeholders
+    ))
+    .bind(&bindings)?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
+/// Links whose title or summary contains any of the terms, case-insensitively for ASCII
+/// Unranked, the caller orders them by how well they match
+pub async fn find_links_containing(
+    env: &Env,
+    terms: &[String],
+    limit: usize,
+) -> Result<Vec<DocInfo>> {
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let db = env.d1("SEEN_DB")?;
+    let conditions =
+        vec!["title LIKE ? ESCAPE '\\' OR summary LIKE ? ESCAPE '\\'"; terms.len()].join(" OR ");
+    let mut bindings = vec![];
+    for term in terms {
+        let pattern = format!(
+            "%{}%",
+            term.replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        bindings.push(JsValue::from_str(&pattern));
+        bindings.push(JsValue::from_str(&pattern));
+    }
+    bindings.push(JsValue::from_f64(limit as f64));
+    db.prepare(format!("SELECT * FROM links WHERE {} LIMIT ?", conditions))
+        .bind(&bindings)?
+        .all()
+        .await?
+        .results::<DocInfo>()
+}
+
 /// Find a link by URL in the database
+/// Matches on the normalized URL, falling back to the raw URL for rows not yet migrated
 pub async fn find_link_by_url(env: &Env, url: &str) -> Result<DocInfo> {
     let db = env.d1("SEEN_DB")?;
 
     // Query the database
     let query_result = db
-        .prepare("SELECT * FROM links WHERE url = ? LIMIT 1")
-        .bind(&[url.into()])?
+        .prepare(
+            "SELECT * FROM links WHERE normalized_url = ? OR url = ? ORDER BY created_at LIMIT 1",
+        )
+        .bind(&[normalize_url(url).into(), url.into()])?
         .all()
         .await?;
 
@@ -136,10 +710,62 @@ pub async fn find_link_by_url(env: &Env, url: &str) -> Result<DocInfo> {
     }
 }
 
-/// Delete a link from the database by URL
-pub async fn delete_link_and_embedding_by_url(env: &Env, url: &str) -> Result<DocInfo> {
-    let link_info = find_link_by_url(env, url).await?;
+/// Links saved under an http/https or `www.` variant of `url`
+pub async fn find_links_by_url_variants(env: &Env, url: &str) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    let variants = url_variants(url);
+    let placeholders = vec!["?"; variants.len()].join(", ");
+    let bindings = variants
+        .iter()
+        .map(|v| JsValue::from_str(v))
+        .collect::<Vec<_>>();
+    db.prepare(format!(
+        "SELECT * FROM links WHERE normalized_url IN ({}) ORDER BY created_at",
+        placeholders
+    ))
+    .bind(&bindings)?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
+/// Links on the same site as `url`, for suggestions when no variant matches
+pub async fn find_links_on_same_site(env: &Env, url: &str) -> Result<Vec<DocInfo>> {
+    const LIMIT: f64 = 100.0;
 
+    let db = env.d1("SEEN_DB")?;
+    // Every variant only differs in scheme and host, so its origin is a prefix of the saved URL
+    let mut patterns = vec![];
+    for variant in url_variants(url) {
+        if let Ok(parsed) = Url::parse(&variant) {
+            let pattern = format!("{}/%", parsed.origin().ascii_serialization());
+            if !patterns.contains(&pattern) {
+                patterns.push(pattern);
+            }
+        }
+    }
+    if patterns.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let conditions = vec!["normalized_url LIKE ?"; patterns.len()].join(" OR ");
+    let mut bindings = patterns
+        .iter()
+        .map(|p| JsValue::from_str(p))
+        .collect::<Vec<_>>();
+    bindings.push(JsValue::from_f64(LIMIT));
+    db.prepare(format!(
+        "SELECT * FROM links WHERE {} ORDER BY created_at DESC LIMIT ?",
+        conditions
+    ))
+    .bind(&bindings)?
+    .all()
+    .await?
+    .results::<DocInfo>()
+}
+
+/// Delete a link and its embeddings from the database
+pub async fn delete_link_and_embedding(env: &Env, link_info: &DocInfo) -> Result<()> {
     let db = env.d1("SEEN_DB")?;
 
     let mut statements = vec![];
@@ -151,15 +777,15 @@ pub async fn delete_link_and_embedding_by_url(env: &Env, url: &str) -> Result<Do
         statements.push(delete_stmt);
     }
     statements.push(
-        db.prepare("DELETE FROM links WHERE url = ?")
-            .bind(&[url.into()])?,
+        db.prepare("DELETE FROM links WHERE id = ?")
+            .bind(&[link_info.id.as_str().into()])?,
     );
 
     let _delete_result = db.batch(statements).await?;
 
-    console_log!("Deleted link from database, URL: {}", url);
+    console_log!("Deleted link from database, URL: {}", link_info.url);
 
-    Ok(link_info)
+    Ok(())
 }
 
 /// Delete content from R2 bucket
@@ -177,3 +803,591 @@ pub async fn get_all_links(env: &Env) -> Result<Vec<DocInfo>> {
     let rows = links_result.results::<DocInfo>()?;
     Ok(rows)
 }
+
+#[derive(Deserialize)]
+struct EmbeddingRow {
+    vector_id: String,
+    vector: Vec<u8>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    chunk: Option<String>,
+}
+
+/// An embedding read back from the database
+pub struct StoredEmbedding {
+    pub vector_id: String,
+    pub model: Option<String>,
+    pub vector: Vec<f32>,
+    /// Text of the chunk, only read where needed and missing for links saved before it was stored
+    pub chunk: Option<String>,
+}
+
+/// Decode a vector stored as little-endian f32 bytes
+pub fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Embeddings stored under the vector ids of a document, `{document_id}-{chunk}`, in chunk order
+pub async fn get_embeddings_by_prefix(
+    env: &Env,
+    document_id: &str,
+) -> Result<Vec<(usize, StoredEmbedding)>> {
+    let d1 = env.d1("SEEN_DB")?;
+    let rows = d1
+        .prepare(
+            "SELECT vector_id, vector, model, chunk FROM embeddings WHERE link_id = ? OR vector_id LIKE ?",
+        )
+        .bind(&[
+            JsValue::from_str(document_id),
+            JsValue::from_str(&format!("{}-%", document_id)),
+        ])?
+        .all()
+        .await?
+        .results::<EmbeddingRow>()?;
+    let prefix = format!("{}-", document_id);
+    let mut embeddings = rows
+        .into_iter()
+        .filter_map(|row| {
+            let chunk = row.vector_id.strip_prefix(&prefix)?.parse::<usize>().ok()?;
+            Some((
+                chunk,
+                StoredEmbedding {
+                    vector: decode_vector(&row.vector),
+                    vector_id: row.vector_id,
+                    model: row.model,
+                    chunk: row.chunk,
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+    embeddings.sort_by_key(|(chunk, _)| *chunk);
+    Ok(embeddings)
+}
+
+/// Store the embeddings of a link under its id, removing the copies under `old_id`
+pub async fn move_embeddings(
+    env: &Env,
+    old_id: &str,
+    row: &DocInfo,
+    embeddings: &[Vec<f32>],
+    chunks: &[String],
+) -> Result<()> {
+    let d1 = env.d1("SEEN_DB")?;
+
+    let mut statements = vec![d1
+        .prepare("DELETE FROM embeddings WHERE link_id = ? OR link_id = ?")
+        .bind(&[JsValue::from_str(old_id), JsValue::from_str(&row.id)])?];
+    for i in 0..embeddings.len() {
+        statements.push(
+            d1.prepare("DELETE FROM embeddings WHERE vector_id = ?")
+                .bind(&[JsValue::from_str(&format!("{}-{}", old_id, i))])?,
+        );
+    }
+    statements.extend(insert_embedding_statements(&d1, row, embeddings, chunks)?);
+    let _result = d1.batch(statements).await?;
+
+    Ok(())
+}
+
+/// Texts of the given chunks by vector id, skipping chunks stored without their text
+pub async fn get_chunk_texts(
+    env: &Env,
+    vector_ids: &[String],
+) -> Result<std::collections::HashMap<String, String>> {
+    #[derive(Deserialize)]
+    struct ChunkRow {
+        vector_id: String,
+        chunk: String,
+    }
+
+    if vector_ids.is_empty() {
+        return Ok(std::collections::HashMap::new());
+    }
+    let d1 = env.d1("SEEN_DB")?;
+    let placeholders = vec!["?"; vector_ids.len()].join(", ");
+    let bindings = vector_ids
+        .iter()
+        .map(|id| JsValue::from_str(id))
+        .collect::<Vec<_>>();
+    let rows = d1
+        .prepare(format!(
+            "SELECT vector_id, chunk FROM embeddings WHERE vector_id IN ({}) AND chunk IS NOT NULL",
+            placeholders
+        ))
+        .bind(&bindings)?
+        .all()
+        .await?
+        .results::<ChunkRow>()?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.vector_id, row.chunk))
+        .collect())
+}
+
+/// Read every stored embedding, paging through the table to keep responses small
+pub async fn get_all_embeddings(env: &Env) -> Result<Vec<StoredEmbedding>> {
+    const PAGE_SIZE: usize = 500;
+
+    let d1 = env.d1("SEEN_DB")?;
+    let mut embeddings = vec![];
+    loop {
+        let rows = d1
+            .prepare("SELECT vector_id, vector, model FROM embeddings ORDER BY vector_id LIMIT ? OFFSET ?")
+            .bind(&[
+                JsValue::from_f64(PAGE_SIZE as f64),
+                JsValue::from_f64(embeddings.len() as f64),
+            ])?
+            .all()
+            .await?
+            .results::<EmbeddingRow>()?;
+        let page_len = rows.len();
+        embeddings.extend(rows.into_iter().map(|row| StoredEmbedding {
+            vector: decode_vector(&row.vector),
+            vector_id: row.vector_id,
+            model: row.model,
+            chunk: row.chunk,
+        }));
+        if page_len < PAGE_SIZE {
+            break;
+        }
+    }
+    Ok(embeddings)
+}
+
+/// Columns added to `links` after the initial schema, as (name, type)
+const LINK_COLUMNS: &[(&str, &str)] = &[
+    ("normalized_url", "TEXT"),
+    ("original_chunk_count", "INTEGER"),
+    ("language", "TEXT"),
+    ("embedding_model", "TEXT"),
+    ("status", "TEXT"),
+    ("status_error", "TEXT"),
+    ("etag", "TEXT"),
+    ("last_modified", "TEXT"),
+    ("chat_id", "INTEGER"),
+    ("reported_content_type", "TEXT"),
+    ("author", "TEXT"),
+    ("published_at", "TEXT"),
+    ("content_expired_at", "TEXT"),
+    ("tags", "TEXT"),
+    ("last_checked", "TEXT"),
+    ("http_status", "INTEGER"),
+];
+
+/// Columns added to `embeddings` after the initial schema
+const EMBEDDING_COLUMNS: &[(&str, &str)] = &[("model", "TEXT"), ("chunk", "TEXT")];
+
+/// Bring the database schema of an existing deployment up to date
+/// Safe to run repeatedly, every step is skipped when already applied
+pub async fn migrate(env: &Env) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+
+    db.prepare(
+        "CREATE TABLE IF NOT EXISTS embeddings (
+            vector_id TEXT PRIMARY KEY,
+            vector BLOB NOT NULL,
+            link_id TEXT NOT NULL,
+            model TEXT,
+            FOREIGN KEY (link_id) REFERENCES links(id)
+        )",
+    )
+    .run()
+    .await?;
+
+    db.prepare(
+        "CREATE TABLE IF NOT EXISTS chat_settings (
+            chat_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (chat_id, key)
+        )",
+    )
+    .run()
+    .await?;
+
+    db.prepare(
+        "CREATE TABLE IF NOT EXISTS feedback (
+            chat_id INTEGER NOT NULL,
+            query_key TEXT NOT NULL,
+            link_id TEXT NOT NULL,
+            verdict INTEGER NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (chat_id, query_key, link_id)
+        )",
+    )
+    .run()
+    .await?;
+
+    db.prepare(
+        "CREATE TABLE IF NOT EXISTS embedding_cache (
+            hash TEXT NOT NULL,
+            model TEXT NOT NULL,
+            vector BLOB NOT NULL,
+            PRIMARY KEY (hash, model)
+        )",
+    )
+    .run()
+    .await?;
+
+    db.prepare(
+        "CREATE TABLE IF NOT EXISTS rate_limit_events (
+            chat_id INTEGER NOT NULL,
+            at REAL NOT NULL
+        )",
+    )
+    .run()
+    .await?;
+    db.prepare(
+        "CREATE INDEX IF NOT EXISTS idx_rate_limit_events_chat_id ON rate_limit_events(chat_id, at)",
+    )
+    .run()
+    .await?;
+
+    add_missing_columns(&db, "links", LINK_COLUMNS).await?;
+    add_missing_columns(&db, "embeddings", EMBEDDING_COLUMNS).await?;
+    let indexed = create_fts_index(&db).await?;
+    if indexed > 0 {
+        console_log!("Added {} links to the keyword index", indexed);
+    }
+    db.prepare("CREATE INDEX IF NOT EXISTS idx_links_normalized_url ON links(normalized_url)")
+        .run()
+        .await?;
+    db.prepare("CREATE INDEX IF NOT EXISTS idx_links_chat_id ON links(chat_id)")
+        .run()
+        .await?;
+
+    // Older links only kept one content type, assume the server reported it
+    db.prepare(
+        "UPDATE links SET reported_content_type = content_type WHERE reported_content_type IS NULL",
+    )
+    .run()
+    .await?;
+
+    let backfilled = backfill_normalized_urls(&db).await?;
+    if backfilled > 0 {
+        console_log!("Updated normalized URLs of {} links", backfilled);
+    }
+    Ok(())
+}
+
+async fn add_missing_columns(db: &D1Database, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+    #[derive(Deserialize)]
+    struct ColumnInfo {
+        name: String,
+    }
+
+    let existing = db
+        .prepare(format!("PRAGMA table_info({})", table))
+        .all()
+        .await?
+        .results::<ColumnInfo>()?;
+    for (name, column_type) in columns {
+        if !existing.iter().any(|column| column.name == *name) {
+            console_log!("Adding column {}.{}", table, name);
+            db.prepare(format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, name, column_type
+            ))
+            .run()
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the normalized URL of links saved before the column existed, and bring the others
+/// up to date when `normalize_url` changed
+async fn backfill_normalized_urls(db: &D1Database) -> Result<usize> {
+    #[derive(Deserialize)]
+    struct UrlRow {
+        id: String,
+        url: String,
+        normalized_url: Option<String>,
+    }
+
+    let rows = db
+        .prepare("SELECT id, url, normalized_url FROM links")
+        .all()
+        .await?
+        .results::<UrlRow>()?;
+    let stale = rows
+        .iter()
+        .filter_map(|row| {
+            let normalized = normalize_url(&row.url);
+            (row.normalized_url.as_deref() != Some(normalized.as_str()))
+                .then_some((row.id.as_str(), normalized))
+        })
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let statements = stale
+        .iter()
+        .map(|(id, normalized)| {
+            db.prepare("UPDATE links SET normalized_url = ? WHERE id = ?")
+                .bind(&[normalized.as_str().into(), (*id).into()])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    db.batch(statements).await?;
+    Ok(stale.len())
+}
+
+/// Links flagged with a status, oldest first
+pub async fn get_flagged_links(env: &Env) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("SELECT * FROM links WHERE status IS NOT NULL ORDER BY created_at")
+        .all()
+        .await?
+        .results::<DocInfo>()
+}
+
+/// Record the outcome of a failed processing attempt
+pub async fn set_link_status(env: &Env, id: &str, status: &str, error: &str) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("UPDATE links SET status = ?, status_error = ? WHERE id = ?")
+        .bind(&[status.into(), error.into(), id.into()])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Read a per-chat setting, `None` when the chat never set it
+pub async fn get_chat_setting(env: &Env, chat_id: i64, key: &str) -> Result<Option<String>> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("SELECT value FROM chat_settings WHERE chat_id = ? AND key = ?")
+        .bind(&[JsValue::from_f64(chat_id as f64), key.into()])?
+        .first::<String>(Some("value"))
+        .await
+}
+
+/// Forget a per-chat setting, a no-op when the chat never set it
+pub async fn delete_chat_setting(env: &Env, chat_id: i64, key: &str) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("DELETE FROM chat_settings WHERE chat_id = ? AND key = ?")
+        .bind(&[JsValue::from_f64(chat_id as f64), key.into()])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Delete the settings of every chat whose key starts with `prefix` and whose JSON value has a
+/// `created_at` more than `hours` ago, or none at all; returns how many were deleted
+pub async fn delete_stale_chat_settings(env: &Env, prefix: &str, hours: u64) -> Result<usize> {
+    let db = env.d1("SEEN_DB")?;
+    let result = db
+        .prepare("DELETE FROM chat_settings WHERE instr(key, ?) = 1 AND (json_extract(value, '$.created_at') IS NULL OR julianday(json_extract(value, '$.created_at')) < julianday('now', ?))")
+        .bind(&[prefix.into(), JsValue::from_str(&format!("-{} hours", hours))])?
+        .run()
+        .await?;
+    Ok(result
+        .meta()?
+        .and_then(|meta| meta.changes)
+        .unwrap_or_default())
+}
+
+/// Store a per-chat setting, replacing any previous value
+pub async fn set_chat_setting(env: &Env, chat_id: i64, key: &str, value: &str) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("INSERT OR REPLACE INTO chat_settings (chat_id, key, value) VALUES (?, ?, ?)")
+        .bind(&[JsValue::from_f64(chat_id as f64), key.into(), value.into()])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CachedEmbeddingRow {
+    hash: String,
+    vector: Vec<u8>,
+}
+
+/// Embeddings of `model` cached under the SHA-256 of their chunk text, by hash
+pub async fn get_cached_embeddings(
+    env: &Env,
+    model: &str,
+    hashes: &[String],
+) -> Result<std::collections::HashMap<String, Vec<f32>>> {
+    // One parameter is the model, D1 binds at most 100
+    const BATCH: usize = 90;
+
+    let db = env.d1("SEEN_DB")?;
+    let mut embeddings = std::collections::HashMap::new();
+    for batch in hashes.chunks(BATCH) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let mut params = vec![JsValue::from_str(model)];
+        params.extend(batch.iter().map(|hash| JsValue::from_str(hash)));
+        let rows = db
+            .prepare(format!(
+                "SELECT hash, vector FROM embedding_cache WHERE model = ? AND hash IN ({})",
+                placeholders
+            ))
+            .bind(&params)?
+            .all()
+            .await?
+            .results::<CachedEmbeddingRow>()?;
+        embeddings.extend(
+            rows.into_iter()
+                .map(|row| (row.hash, decode_vector(&row.vector))),
+        );
+    }
+    Ok(embeddings)
+}
+
+/// Remember embeddings of `model` under the SHA-256 of their chunk text, keeping existing entries
+pub async fn cache_embeddings(env: &Env, model: &str, entries: &[(String, &[f32])]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let db = env.d1("SEEN_DB")?;
+    let mut statements = Vec::with_capacity(entries.len());
+    for (hash, vector) in entries {
+        statements.push(
+            db.prepare(
+                "INSERT OR IGNORE INTO embedding_cache (hash, model, vector) VALUES (?, ?, ?)",
+            )
+            .bind(&[
+                JsValue::from_str(hash),
+                JsValue::from_str(model),
+                JsValue::from(js_sys::Float32Array::from(*vector)),
+            ])?,
+        );
+    }
+    db.batch(statements).await?;
+    Ok(())
+}
+
+/// Count a request of a chat against `limit` requests per sliding window of `window_secs`
+/// Returns false, without counting the request, when the chat already used up the window
+pub async fn allow_request(env: &Env, chat_id: i64, limit: u64, window_secs: u64) -> Result<bool> {
+    let db = env.d1("SEEN_DB")?;
+    let now = js_sys::Date::now();
+    let chat_id = JsValue::from_f64(chat_id as f64);
+    let results = db
+        .batch(vec![
+            db.prepare("DELETE FROM rate_limit_events WHERE chat_id = ? AND at <= ?")
+                .bind(&[
+                    chat_id.clone(),
+                    JsValue::from_f64(now - window_secs as f64 * 1000.0),
+                ])?,
+            db.prepare("SELECT COUNT(*) AS requests FROM rate_limit_events WHERE chat_id = ?")
+                .bind(std::slice::from_ref(&chat_id))?,
+        ])
+        .await?;
+    let requests = match results.get(1) {
+        Some(result) => result
+            .results::<serde_json::Value>()?
+            .first()
+            .and_then(|row| row.get("requests").and_then(|v| v.as_u64()))
+            .unwrap_or(0),
+        None => 0,
+    };
+    if requests >= limit {
+        return Ok(false);
+    }
+    db.prepare("INSERT INTO rate_limit_events (chat_id, at) VALUES (?, ?)")
+        .bind(&[chat_id, JsValue::from_f64(now)])?
+        .run()
+        .await?;
+    Ok(true)
+}
+
+/// Record whether a search result was good (1) or bad (-1) for a query, replacing an earlier verdict
+pub async fn record_feedback(
+    env: &Env,
+    chat_id: i64,
+    query_key: &str,
+    link_id: &str,
+    verdict: i32,
+) -> Result<()> {
+    let db = env.d1("SEEN_DB")?;
+    db.prepare("INSERT OR REPLACE INTO feedback (chat_id, query_key, link_id, verdict, created_at) VALUES (?, ?, ?, ?, datetime('now'))")
+        .bind(&[
+            JsValue::from_f64(chat_id as f64),
+            query_key.into(),
+            link_id.into(),
+            JsValue::from_f64(verdict as f64),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct FeedbackRow {
+    link_id: String,
+    votes: i64,
+}
+
+/// Net verdicts per link for a query, over every chat
+pub async fn get_feedback_votes(
+    env: &Env,
+    query_key: &str,
+) -> Result<std::collections::HashMap<String, i64>> {
+    let db = env.d1("SEEN_DB")?;
+    let rows = db
+        .prepare("SELECT link_id, SUM(verdict) AS votes FROM feedback WHERE query_key = ? GROUP BY link_id")
+        .bind(&[query_key.into()])?
+        .all()
+        .await?
+        .results::<FeedbackRow>()?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.link_id, row.votes))
+        .collect())
+}
+
+/// Links sharing a normalized URL with an older link, i.e. the copies to merge away
+pub async fn find_duplicate_links(env: &Env) -> Result<Vec<DocInfo>> {
+    let db = env.d1("SEEN_DB")?;
+    let rows = db
+        .prepare(
+            "SELECT * FROM links AS l WHERE EXISTS (
+                SELECT 1 FROM links AS o
+                WHERE o.normalized_url = l.normalized_url
+                AND (o.created_at < l.created_at OR (o.created_at = l.created_at AND o.id < l.id))
+            )",
+        )
+        .all()
+        .await?
+        .results::<DocInfo>()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_cutoffs_count_back_whole_days() {
+        // 2024-03-01 12:34:56 UTC
+        let now = 1709296496000.0;
+        assert_eq!(expiry_cutoff(now, 0), "2024-03-01 12:34:56");
+        assert_eq!(expiry_cutoff(now + 999.0, 0), "2024-03-01 12:34:56");
+        assert_eq!(expiry_cutoff(now, 1), "2024-02-29 12:34:56");
+        assert_eq!(expiry_cutoff(now, 60), "2024-01-01 12:34:56");
+        assert_eq!(expiry_cutoff(now, 366), "2023-03-01 12:34:56");
+        // 2025-01-01 00:00:00 UTC
+        assert_eq!(expiry_cutoff(1735689600000.0, 1), "2024-12-31 00:00:00");
+        assert_eq!(expiry_cutoff(0.0, 1), "1969-12-31 00:00:00");
+    }
+
+    #[test]
+    fn links_saved_before_the_cutoff_expire() {
+        let cutoff = expiry_cutoff(1709296496000.0, 30);
+        assert_eq!(cutoff, "2024-01-31 12:34:56");
+        // `created_at < cutoff` as D1 compares the strings
+        for (created_at, expires) in [
+            ("2024-01-31 12:34:55", true),
+            ("2024-01-31 12:34:56", false),
+            ("2023-12-31 23:59:59", true),
+            ("2024-02-01 00:00:00", false),
+        ] {
+            assert_eq!(created_at < cutoff.as_str(), expires, "{}", created_at);
+        }
+    }
+}
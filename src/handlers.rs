@@ -1,158 +1,2373 @@
-use crate::d1::{self, DocInfo};
+use crate::config::{Config, ScoreAggregation, UnsupportedContent};
+use crate::d1::{self, DocInfo, STATUS_NEEDS_REEMBED, STATUS_PENDING_PROCESSING};
+use crate::metrics::{self, SearchTimings, Timer};
 use crate::models::Update;
-use crate::utils::{chunk_and_summary_link, fetch_content, get_extension_from_content_type};
-use crate::vector;
+use crate::utils::{
+    chunk_and_summary_link, compare_documents, correct_content_type, extract_title, fetch_content,
+    fetch_thread, fetch_youtube_transcript, format_size, get_extension_from_content_type,
+    is_summarizable, normalize_url, ping_gemini, sample_chunks, tweet_id, youtube_video_id,
+    CacheValidators, Comparison, FetchOutcome, FetchedContent, PageRange, ProcessedLinkData,
+    SummaryStyle,
+};
+use crate::vector::EmbeddingSpace;
+use crate::{vector, vector_index};
 use uuid::Uuid;
-use vector_lite::{ANNIndexOwned, Vector};
 use worker::*;
 
 /// Handle the webhook request from Telegram
-pub async fn handle_webhook(mut req: Request, env: Env) -> Result<Response> {
+/// A broken configuration is logged and the update acknowledged, so Telegram doesn't keep
+/// redelivering updates no one can be authorized for
+pub async fn handle_webhook(mut req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let config = match Config::from_env(&env) {
+        Ok(config) => config,
+        Err(e) => {
+            console_error!("Ignoring update: {}", e);
+            return Response::ok("OK");
+        }
+    };
+
+    // When a webhook secret is configured, Telegram echoes it back on every update
+    if let Some(secret) = &config.webhook_secret {
+        let header = req.headers().get("X-Telegram-Bot-Api-Secret-Token")?;
+        if header.as_deref() != Some(secret.as_str()) {
+            return Response::error("Unauthorized", 401);
+        }
+    }
+
     let update = req.json::<Update>().await?;
-    crate::telegram::process_update(env, update).await?;
+    crate::telegram::process_update(env, &config, ctx, update).await?;
     Response::ok("OK")
 }
 
-/// Process and store a link
-pub async fn insert_link(env: &Env, link: &str) -> Result<DocInfo> {
-    if let Ok(existing_link) = d1::find_link_by_url(env, link).await {
-        return Ok(existing_link);
+/// Check the `API_TOKEN` secret against a bearer token or a `token` query parameter
+/// Always fails when no token is configured
+fn is_api_authorized(req: &Request, config: &Config) -> Result<bool> {
+    let Some(expected) = &config.api_token else {
+        return Ok(false);
+    };
+
+    let bearer = req
+        .headers()
+        .get("Authorization")?
+        .and_then(|h| h.strip_prefix("Bearer ").map(|t| t.trim().to_string()));
+    let query = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "token")
+        .map(|(_, value)| value.into_owned());
+
+    Ok(bearer.or(query).is_some_and(|token| token == *expected))
+}
+
+/// Register this worker as the Telegram webhook, e.g. `GET /setup?base=https://your-bot.workers.dev`
+pub async fn handle_setup(req: Request, env: Env) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let Some(base) = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "base")
+        .map(|(_, value)| value.into_owned())
+    else {
+        return Response::error(
+            "Missing base parameter, e.g. /setup?base=https://your-bot.workers.dev",
+            400,
+        );
+    };
+    match Url::parse(&base) {
+        Ok(url) if url.scheme() == "https" => {}
+        _ => return Response::error("The base parameter must be an https URL", 400),
+    }
+
+    let webhook_url = format!("{}/webhook", base.trim_end_matches('/'));
+    console_log!("Registering webhook: {}", webhook_url);
+    let result = crate::telegram::set_webhook(
+        &config.bot_token,
+        &webhook_url,
+        config.webhook_secret.as_deref(),
+    )
+    .await?;
+    Response::from_json(&result)
+}
+
+/// Report Telegram's view of the webhook, useful to diagnose delivery issues
+pub async fn handle_setup_info(req: Request, env: Env) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let result = crate::telegram::get_webhook_info(&config.bot_token).await?;
+    Response::from_json(&result)
+}
+
+/// What `/content` serves instead of content the retention policy deleted, `None` while it's kept
+fn expired_content(link_info: &DocInfo) -> Option<String> {
+    let expired_at = link_info.content_expired_at.as_ref()?;
+    Some(format!(
+        "Original expired on {}, summary available.\n\n{}\n\n{}",
+        expired_at, link_info.title, link_info.summary
+    ))
+}
+
+/// Serve the stored content of a saved link, e.g. `GET /content?id=...`, the version seen when saving it
+pub async fn handle_content(req: Request, env: Env) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let Some(id) = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "id")
+        .map(|(_, value)| value.into_owned())
+    else {
+        return Response::error("Missing id parameter, e.g. /content?id=...", 400);
+    };
+    let Some(link_info) = d1::get_link_by_id(&env, &id).await? else {
+        return Response::error("Link not found", 404);
+    };
+    if let Some(body) = expired_content(&link_info) {
+        return Ok(Response::ok(body)?.with_status(410));
+    }
+    let content = match d1::read_from_bucket(&env, &link_info.bucket_path).await {
+        Ok(content) => content,
+        Err(e) => {
+            console_error!(
+                "Error reading content of {} at {}: {}",
+                id,
+                link_info.bucket_path,
+                e
+            );
+            return Response::error("Content not found", 404);
+        }
+    };
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", &link_info.content_type)?;
+    headers.set(
+        "Content-Disposition",
+        &format!(
+            "inline; filename=\"{}.{}\"",
+            link_info.id,
+            get_extension_from_content_type(&link_info.content_type)
+        ),
+    )?;
+    Ok(Response::from_bytes(content)?.with_headers(headers))
+}
+
+/// Serve an export made with `/export`, e.g. `GET /export?key=exports/...`
+pub async fn handle_export(req: Request, env: Env) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let Some(key) = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "key")
+        .map(|(_, value)| value.into_owned())
+    else {
+        return Response::error("Missing key parameter, e.g. /export?key=exports/...", 400);
+    };
+    // Only exports are served, not the content or the vector index next to them
+    if !key.starts_with(EXPORT_PREFIX) || key.contains("..") {
+        return Response::error("Not an export", 400);
+    }
+    let content = match d1::read_from_bucket(&env, &key).await {
+        Ok(content) => content,
+        Err(_) => return Response::error("Export not found", 404),
+    };
+    let format = if key.ends_with(ExportFormat::Ndjson.extension()) {
+        ExportFormat::Ndjson
+    } else {
+        ExportFormat::Json
+    };
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", format.content_type())?;
+    headers.set(
+        "Content-Disposition",
+        &format!(
+            "attachment; filename=\"{}\"",
+            key.trim_start_matches(EXPORT_PREFIX)
+        ),
+    )?;
+    Ok(Response::from_bytes(content)?.with_headers(headers))
+}
+
+/// Summarize a URL without saving it, e.g. `GET /summarize?url=https://...`
+/// Goes through the same download limits and processing as saving a link, but nothing is
+/// written to R2, D1 or the vector index
+pub async fn handle_summarize(req: Request, env: Env) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let Some(link) = req
+        .url()?
+        .query_pairs()
+        .find(|(key, _)| key == "url")
+        .map(|(_, value)| value.into_owned())
+    else {
+        return Response::error(
+            "Missing url parameter, e.g. /summarize?url=https://...",
+            400,
+        );
+    };
+    match Url::parse(&link) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => return Response::error("The url parameter must be an http or https URL", 400),
+    }
+
+    let link_info = match preview_link(&env, &config, &link, SummaryStyle::default()).await {
+        Ok(link_info) => link_info,
+        Err(e) => {
+            console_error!("Error summarizing {}: {}", link, e);
+            return Response::error(format!("Error summarizing {}: {}", link, e), 502);
+        }
+    };
+    Response::from_json(&serde_json::json!({
+        "url": link_info.url,
+        "title": link_info.title,
+        "summary": link_info.summary,
+        "content_type": link_info.content_type,
+        "size": link_info.size,
+        "language": link_info.language,
+        "author": link_info.author,
+        "published_at": link_info.published_at,
+    }))
+}
+
+/// Results the JSON search API ranks at most, pages end within them
+const MAX_API_RESULTS: usize = 50;
+const DEFAULT_API_LIMIT: usize = 10;
+
+/// Search saved links as JSON, e.g. `GET /search?q=rust type:pdf&offset=10&limit=10`
+/// `q` takes the same filters as the Telegram command, `total` counts the matches among the
+/// top `MAX_API_RESULTS` and `next` is the offset of the following page, null on the last one
+pub async fn handle_search(req: Request, env: Env, ctx: &Context) -> Result<Response> {
+    let config = Config::from_env(&env)?;
+    if !is_api_authorized(&req, &config)? {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let url = req.url()?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    // `top_k` is another name for `limit`, for clients that think of it as a vector search
+    let limit_param = if param("limit").is_none() && param("top_k").is_some() {
+        "top_k"
+    } else {
+        "limit"
+    };
+    let (offset, limit) = match page_bounds(
+        param("offset").as_deref(),
+        param(limit_param).as_deref(),
+        limit_param,
+    ) {
+        Ok(bounds) => bounds,
+        Err(e) => return Response::error(e, 400),
+    };
+    let (query, filters) = match SearchFilters::parse(&param("q").unwrap_or_default()) {
+        Ok(parsed) => parsed,
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+    if query.is_empty() {
+        return Response::error("Missing q parameter, e.g. /search?q=rust", 400);
+    }
+
+    let response = search_links(
+        env.clone(),
+        &config,
+        &query,
+        &filters,
+        false,
+        MAX_API_RESULTS,
+    )
+    .await?;
+    remove_orphan_vectors(ctx, &env, &config, response.orphans);
+    let total = response.results.len();
+    let filtered_out = response.filtered_out;
+    let mut passages = response.passages;
+    let (results, next) = page(response.results, offset, limit);
+    let results = results
+        .into_iter()
+        .map(|(link_info, score)| {
+            serde_json::json!({
+                "passage": passages
+                    .remove(&link_info.id)
+                    .and_then(|passages| passages.into_iter().next())
+                    .map(|passage| passage.text),
+                "id": link_info.id,
+                "url": link_info.url,
+                "title": link_info.title,
+                "summary": link_info.summary,
+                "content_type": link_info.content_type,
+                "created_at": link_info.created_at,
+                "score": score,
+            })
+        })
+        .collect::<Vec<_>>();
+    Response::from_json(&serde_json::json!({
+        "query": query,
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "next": next,
+        "filtered_out": filtered_out,
+        "results": results,
+    }))
+}
+
+/// Offset and limit of a `/search` page from its parameters
+/// The limit is clamped so the page ends within the top `MAX_API_RESULTS`
+fn page_bounds(
+    offset: Option<&str>,
+    limit: Option<&str>,
+    limit_param: &str,
+) -> std::result::Result<(usize, usize), String> {
+    let number = |value: Option<&str>, name: &str, default: usize| match value {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| format!("The {} parameter must be a number", name)),
+        None => Ok(default),
+    };
+    let offset = number(offset, "offset", 0)?;
+    let limit = number(limit, limit_param, DEFAULT_API_LIMIT)?;
+    if limit == 0 {
+        return Err(format!("{} must be positive", limit_param));
+    }
+    Ok((offset, limit.min(MAX_API_RESULTS.saturating_sub(offset))))
+}
+
+/// One page of ranked results and the offset of the next page, `None` on the last one
+fn page<T>(results: Vec<T>, offset: usize, limit: usize) -> (Vec<T>, Option<usize>) {
+    let total = results.len();
+    let page = results.into_iter().skip(offset).take(limit).collect();
+    (page, Some(offset + limit).filter(|next| *next < total))
+}
+
+/// Run a dependency check, logging the error but only reporting whether it passed
+async fn check_dependency(
+    name: &str,
+    check: impl std::future::Future<Output = Result<()>>,
+) -> serde_json::Value {
+    let (result, latency_ms) = metrics::timed(check).await;
+    if let Err(e) = &result {
+        console_error!("Health check {} failed: {}", name, e);
+    }
+    serde_json::json!({ "ok": result.is_ok(), "latency_ms": latency_ms })
+}
+
+/// Report whether D1 and R2 are reachable, e.g. `GET /healthz`, add `?llm=1` to also ping Gemini
+/// Unauthenticated, so it only reports booleans and latencies; returns 503 when D1 or R2 is down
+pub async fn handle_healthz(req: Request, env: Env) -> Result<Response> {
+    let check_llm = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "llm" && (value == "1" || value == "true"));
+
+    let (d1, r2) = futures_util::join!(
+        check_dependency("d1", d1::ping(&env)),
+        check_dependency("r2", async {
+            env.bucket("SEEN_BUCKET")?
+                .head(vector::VECTOR_LITE_KEY)
+                .await
+                .map(|_| ())
+        }),
+    );
+    let ok = d1["ok"] == true && r2["ok"] == true;
+
+    let mut body = serde_json::json!({ "ok": ok, "d1": d1, "r2": r2 });
+    if check_llm {
+        body["gemini"] = check_dependency("gemini", async {
+            ping_gemini(&Config::from_env(&env)?).await
+        })
+        .await;
+    }
+    Ok(Response::from_json(&body)?.with_status(if ok { 200 } else { 503 }))
+}
+
+/// Fetch a link, returning the content along with its content type corrected by sniffing
+async fn download(config: &Config, link: &str) -> Result<(FetchedContent, String)> {
+    download_if_changed(config, link, &CacheValidators::default())
+        .await?
+        .ok_or_else(|| Error::from("Server answered an unconditional request with 304"))
+}
+
+/// Like `download`, sending `validators` so a page unchanged since they were recorded is `None`
+/// Threads and transcripts carry no validators and are always fetched
+async fn download_if_changed(
+    config: &Config,
+    link: &str,
+    validators: &CacheValidators,
+) -> Result<Option<(FetchedContent, String)>> {
+    // Posts on X are served as a JavaScript shell, so read them from the embed endpoint
+    if let Some(id) = tweet_id(link) {
+        match fetch_thread(link, &id).await {
+            Ok(fetched) => {
+                let content_type = fetched.content_type.clone();
+                return Ok(Some((fetched, content_type)));
+            }
+            Err(e) => console_error!(
+                "Error fetching {} as a thread, fetching the page: {}",
+                link,
+                e
+            ),
+        }
+    }
+    // Videos are saved by what is said in them, the page itself is mostly scripts
+    if let Some(id) = youtube_video_id(link) {
+        match fetch_youtube_transcript(link, &id, config.max_download_bytes).await {
+            Ok(fetched) => {
+                let content_type = fetched.content_type.clone();
+                return Ok(Some((fetched, content_type)));
+            }
+            Err(e) => console_error!(
+                "Error fetching the transcript of {}, fetching the page: {}",
+                link,
+                e
+            ),
+        }
+    }
+    console_log!("Fetching content from link: {}", link);
+    let mut fetched = match fetch_content(link, config.max_download_bytes, validators).await? {
+        FetchOutcome::Fetched(fetched) => fetched,
+        FetchOutcome::Unchanged => return Ok(None),
+    };
+    let content_type = correct_content_type(&fetched.content, &fetched.content_type);
+    if content_type != fetched.content_type {
+        console_log!(
+            "Corrected content type of {} from {} to {}",
+            link,
+            fetched.content_type,
+            content_type
+        );
+    }
+    // The charset comes from the server's content type even when the type itself was corrected
+    if content_type.starts_with("text/html") && fetched.title.is_none() {
+        fetched.title = extract_title(&fetched.content, &fetched.content_type);
+    }
+    Ok(Some((fetched, content_type)))
+}
+
+/// Fetch and process a link like `insert_link` would, without embedding or storing anything
+/// The returned link has no id and isn't saved
+pub async fn preview_link(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    style: SummaryStyle,
+) -> Result<DocInfo> {
+    let (fetched, content_type) = download(config, link).await?;
+    let processed_data = process_content(
+        env,
+        config,
+        link,
+        &fetched.content,
+        &content_type,
+        style,
+        None,
+    )
+    .await?;
+    Ok(preview_info(
+        config,
+        link,
+        fetched,
+        content_type,
+        processed_data,
+    ))
+}
+
+/// What saving a processed link would store, with nothing that ties it to a stored copy: no id,
+/// bucket path, creation time or chat
+fn preview_info(
+    config: &Config,
+    link: &str,
+    fetched: FetchedContent,
+    content_type: String,
+    processed_data: ProcessedLinkData,
+) -> DocInfo {
+    let (processed_data, original_chunk_count) = cap_chunks(config, link, processed_data);
+
+    DocInfo {
+        id: String::new(),
+        url: link.to_string(),
+        created_at: String::new(),
+        bucket_path: String::new(),
+        content_type,
+        reported_content_type: Some(fetched.content_type),
+        size: fetched.content.len(),
+        title: fetched.title.unwrap_or(processed_data.title),
+        summary: processed_data.summary,
+        chunk_count: processed_data.chunks.len(),
+        original_chunk_count,
+        language: Some(processed_data.language).filter(|l| !l.is_empty()),
+        embedding_model: None,
+        status: None,
+        status_error: None,
+        etag: fetched.validators.etag,
+        last_modified: fetched.validators.last_modified,
+        chat_id: None,
+        author: fetched.author,
+        published_at: fetched.published_at,
+        content_expired_at: None,
+        tags: None,
+        last_checked: None,
+        http_status: None,
+    }
+}
+
+/// Reject content that would push the chat over `MAX_BYTES_PER_CHAT`
+async fn check_quota(env: &Env, config: &Config, chat_id: i64, incoming: usize) -> Result<()> {
+    let Some(max) = config.max_bytes_per_chat else {
+        return Ok(());
+    };
+    let used = d1::get_chat_usage(env, chat_id).await?;
+    if exceeds_quota(used, incoming as u64, max) {
+        return Err(Error::from(format!(
+            "Storage quota exceeded ({} of {} used, this link is {}), delete some links",
+            format_size(used as usize),
+            format_size(max as usize),
+            format_size(incoming)
+        )));
+    }
+    Ok(())
+}
+
+/// Filling the quota exactly is allowed, going past it is not
+fn exceeds_quota(used: u64, incoming: u64, max: u64) -> bool {
+    used.saturating_add(incoming) > max
+}
+
+/// Process and store a link, counting it against the quota of `chat_id` when given
+/// When processing fails after the download, the content is kept and the link flagged for `/repair`
+/// `pages` only processes that range of a PDF, `/repair` and `/retype` process all of it again
+pub async fn insert_link(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    style: SummaryStyle,
+    chat_id: Option<i64>,
+    pages: Option<PageRange>,
+    check_duplicates: bool,
+) -> Result<InsertOutcome> {
+    if let Ok(existing_link) = d1::find_link_by_url(env, link).await {
+        if existing_link.status.is_some() {
+            // Sending a flagged link again retries it
+            let content_type = existing_link.content_type.clone();
+            return reprocess_link(env, config, existing_link, &content_type, style)
+                .await
+                .map(InsertOutcome::saved);
+        }
+        return Ok(InsertOutcome::saved(existing_link));
+    }
+    let timer = Timer::start();
+    let link_id = Uuid::new_v4().to_string();
+    let current_time = js_sys::Date::new_0().to_iso_string().as_string().unwrap();
+
+    // Download content first
+    let (fetched, content_type) = download(config, link).await?;
+    let content = fetched.content;
+    let reported_content_type = fetched.content_type;
+    reject_unsupported(config, &content_type)?;
+    let bucket_path = get_bucket_path(config, &content_type, &link_id);
+    let content_size = content.len();
+    if let Some(chat_id) = chat_id {
+        check_quota(env, config, chat_id, content_size).await?;
+    }
+
+    let mut row = DocInfo {
+        id: link_id.clone(),
+        url: link.to_string(),
+        created_at: current_time.clone(),
+        bucket_path: bucket_path.clone(),
+        content_type: content_type.clone(),
+        reported_content_type: Some(reported_content_type),
+        size: content_size,
+        title: fetched.title.clone().unwrap_or_else(|| link.to_string()),
+        summary: String::new(),
+        chunk_count: 0,
+        original_chunk_count: None,
+        language: None,
+        embedding_model: None,
+        status: None,
+        status_error: None,
+        etag: fetched.validators.etag,
+        last_modified: fetched.validators.last_modified,
+        chat_id,
+        author: fetched.author,
+        published_at: fetched.published_at,
+        content_expired_at: None,
+        tags: None,
+        last_checked: None,
+        http_status: None,
+    };
+
+    // Process the content with Gemini API, loading the vector index meanwhile so the
+    // update at the end doesn't wait on R2 after a cold start
+    console_log!("Processing content with Gemini API from: {}", link);
+    // The warm-up is only an optimization, a failure leaves the update to load the index itself
+    let processing = process_content(env, config, link, &content, &content_type, style, pages);
+    let ((processed_data, gemini_ms), (warmed, warm_ms)) = futures_util::join!(
+        metrics::timed(processing),
+        metrics::timed(vector_index::warm(env, EmbeddingSpace::Primary)),
+    );
+    match warmed {
+        Ok(index_len) => console_log!(
+            "Vector index with {} vectors ready in {:.0} ms",
+            index_len,
+            warm_ms
+        ),
+        Err(e) => console_error!("Failed to warm up the vector index: {}", e),
+    }
+    let processed_data = match processed_data {
+        Ok(processed_data) => processed_data,
+        Err(e) => {
+            return save_unprocessed(env, row, content, STATUS_PENDING_PROCESSING, e)
+                .await
+                .map(InsertOutcome::saved)
+        }
+    };
+    console_log!(
+        "Processed data in {:.0} ms: {:?}",
+        gemini_ms,
+        processed_data
+    );
+    let (processed_data, original_chunk_count) = cap_chunks(config, link, processed_data);
+    let space = EmbeddingSpace::for_language(config, &processed_data.language);
+    let model = space.model(config);
+
+    // A title from the source, e.g. of a video, beats the one Gemini makes up
+    if fetched.title.is_none() {
+        row.title = processed_data.title.clone();
+    }
+    row.summary = processed_data.summary.clone();
+    row.language = Some(processed_data.language.clone()).filter(|l| !l.is_empty());
+
+    let embeddings = match embed_chunks(env, config, &model, &processed_data.chunks).await {
+        Ok(embeddings) => embeddings,
+        Err(e) => {
+            return save_unprocessed(env, row, content, STATUS_NEEDS_REEMBED, e)
+                .await
+                .map(InsertOutcome::saved)
+        }
+    };
+    // Checked before anything is stored, so declining leaves no trace
+    if let (true, Some(threshold), Some(first)) = (
+        check_duplicates,
+        config.duplicate_threshold,
+        embeddings.first(),
+    ) {
+        match find_near_duplicate(env, config, space, first, threshold).await {
+            Ok(Some((existing, score))) => {
+                console_log!(
+                    "{} looks like a copy of {} (score {:.3}), not saving it",
+                    link,
+                    existing.url,
+                    score
+                );
+                return Ok(InsertOutcome::NearDuplicate {
+                    existing: Box::new(existing),
+                    score,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => console_error!("Error looking for copies of {}: {}", link, e),
+        }
+    }
+    row.chunk_count = processed_data.chunks.len();
+    row.original_chunk_count = original_chunk_count;
+    row.embedding_model = Some(model);
+
+    // The bucket, D1 and the vector index share no transaction, so when a step fails the earlier
+    // ones are undone rather than leaving an object without a row or a row without vectors
+    d1::save_to_bucket(env, &bucket_path, content).await?;
+    if let Err(e) = d1::save_link_to_db(env, &row, &embeddings, &processed_data.chunks).await {
+        rollback_insert(env, &row, false).await;
+        return Err(e);
+    }
+    if let Err(e) = vector_index::update(
+        env,
+        space,
+        vec![],
+        vector::chunk_vectors(&link_id, &embeddings),
+    )
+    .await
+    {
+        rollback_insert(env, &row, true).await;
+        return Err(e);
+    }
+
+    metrics::record(env, "insert", timer.elapsed_ms(), row.chunk_count as f64);
+    Ok(InsertOutcome::saved(row))
+}
+
+/// How `insert_link` ended
+pub enum InsertOutcome {
+    /// The link is saved, or was already
+    Saved(Box<DocInfo>),
+    /// Nothing was stored, the content closely matches a saved link, see `DUPLICATE_THRESHOLD`
+    NearDuplicate { existing: Box<DocInfo>, score: f32 },
+}
+
+impl InsertOutcome {
+    fn saved(link_info: DocInfo) -> Self {
+        Self::Saved(Box::new(link_info))
+    }
+}
+
+/// The saved link with the chunk closest to the first chunk of new content, when at least
+/// `threshold` similar, e.g. the same article saved from a mirror site
+async fn find_near_duplicate(
+    env: &Env,
+    config: &Config,
+    space: EmbeddingSpace,
+    first_chunk: &[f32],
+    threshold: f32,
+) -> Result<Option<(DocInfo, f32)>> {
+    let matches = vector_index::search(env, config, space, first_chunk.to_vec(), 1).await?;
+    let Some((vector_id, score)) = matches.results.into_iter().next() else {
+        return Ok(None);
+    };
+    let score = vector::similarity("cosine", score);
+    let Some((document_id, _)) = vector_id.rsplit_once('-') else {
+        return Ok(None);
+    };
+    if score < threshold {
+        return Ok(None);
+    }
+    Ok(d1::get_link_by_id(env, document_id)
+        .await?
+        .map(|link_info| (link_info, score)))
+}
+
+/// Undo the steps of a failed insert that succeeded, best effort: a failing undo is logged and
+/// the caller reports the original error
+/// Vectors an index update applied before failing are dropped by searches that run into them
+async fn rollback_insert(env: &Env, row: &DocInfo, saved_to_db: bool) {
+    if saved_to_db {
+        console_log!("Rolling back the insert of {}: deleting its row", row.url);
+        if let Err(e) = d1::delete_link_and_embedding(env, row).await {
+            console_error!("Error deleting the row of {}: {}", row.url, e);
+        }
+    }
+    console_log!(
+        "Rolling back the insert of {}: deleting {} from the bucket",
+        row.url,
+        row.bucket_path
+    );
+    if let Err(e) = d1::delete_from_bucket(env, &row.bucket_path).await {
+        console_error!("Error deleting {}: {}", row.bucket_path, e);
+    }
+}
+
+/// Save a link without vectors, flagged with `status` so `/queue` lists it and `/repair` retries it
+async fn save_unprocessed(
+    env: &Env,
+    mut row: DocInfo,
+    content: Vec<u8>,
+    status: &str,
+    error: Error,
+) -> Result<DocInfo> {
+    console_error!(
+        "Processing {} failed, saving it as {}: {}",
+        row.url,
+        status,
+        error
+    );
+    row.status = Some(status.to_string());
+    row.status_error = Some(error.to_string());
+    d1::save_to_bucket(env, &row.bucket_path, content).await?;
+    d1::save_link_to_db(env, &row, &vec![], &[]).await?;
+    Ok(row)
+}
+
+/// Summarize content with Gemini, or describe content it can't summarize by its URL, type and size
+/// The single chunk of such a link holds its title and URL, so it's still found by search
+async fn process_content(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    content: &[u8],
+    content_type: &str,
+    style: SummaryStyle,
+    pages: Option<PageRange>,
+) -> Result<ProcessedLinkData> {
+    if is_summarizable(content_type) {
+        return chunk_and_summary_link(env, config, content, content_type, style, pages).await;
+    }
+    reject_unsupported(config, content_type)?;
+    console_log!(
+        "Not summarizing {} of type {}, saving its metadata only",
+        link,
+        content_type
+    );
+    Ok(describe_unsupported(link, content_type, content.len()))
+}
+
+/// Title, summary and chunk of a link Gemini can't summarize, from its URL, type and size
+fn describe_unsupported(link: &str, content_type: &str, size: usize) -> ProcessedLinkData {
+    let title = Url::parse(link)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()
+                .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+                .or(url.host_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| link.to_string());
+    let kind = match content_type.split('/').next().unwrap_or("") {
+        "audio" => "Audio",
+        "video" => "Video",
+        "font" => "Font",
+        _ => "Binary",
+    };
+    ProcessedLinkData {
+        summary: format!(
+            "{} file ({}), {}, not summarized",
+            kind,
+            content_type,
+            format_size(size)
+        ),
+        chunks: vec![format!("{}\n{}", title, link)],
+        title,
+        language: String::new(),
+    }
+}
+
+/// Fail for content Gemini can't summarize when `UNSUPPORTED_CONTENT` is `reject`
+fn reject_unsupported(config: &Config, content_type: &str) -> Result<()> {
+    if config.unsupported_content == UnsupportedContent::Reject && !is_summarizable(content_type) {
+        return Err(Error::from(format!(
+            "Content of type {} can't be summarized",
+            content_type
+        )));
+    }
+    Ok(())
+}
+
+/// Sample the chunks down to `MAX_CHUNKS`, returning the original count when some were dropped
+fn cap_chunks(
+    config: &Config,
+    link: &str,
+    mut processed_data: ProcessedLinkData,
+) -> (ProcessedLinkData, Option<usize>) {
+    let max_chunks = config.max_chunks;
+    let total = processed_data.chunks.len();
+    if total <= max_chunks {
+        return (processed_data, None);
+    }
+    console_log!("Sampling {} of {} chunks from {}", max_chunks, total, link);
+    processed_data.chunks = sample_chunks(processed_data.chunks, max_chunks);
+    (processed_data, Some(total))
+}
+
+/// Generate one embedding per chunk, reusing the cached embeddings of chunk texts seen before
+/// The cache is an optimization, when D1 fails every chunk is embedded
+async fn embed_chunks(
+    env: &Env,
+    config: &Config,
+    model: &str,
+    chunks: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let hashes = chunks
+        .iter()
+        .map(|chunk| crate::utils::sha256_hex(chunk.as_bytes()))
+        .collect::<Vec<_>>();
+    let mut cached = d1::get_cached_embeddings(env, model, &hashes)
+        .await
+        .unwrap_or_else(|e| {
+            console_warn!("Failed to read the embedding cache: {}", e);
+            std::collections::HashMap::new()
+        });
+
+    let mut missing_hashes = vec![];
+    let mut missing_chunks = vec![];
+    for (hash, chunk) in hashes.iter().zip(chunks) {
+        if !cached.contains_key(hash) && !missing_hashes.contains(hash) {
+            missing_hashes.push(hash.clone());
+            missing_chunks.push(chunk.clone());
+        }
+    }
+    if !missing_chunks.is_empty() {
+        console_log!(
+            "Embedding {} of {} chunks, the rest are cached",
+            missing_chunks.len(),
+            chunks.len()
+        );
+        let embeddings = vector::generate_embeddings_batch(config, model, &missing_chunks).await?;
+        let entries = missing_hashes
+            .iter()
+            .cloned()
+            .zip(embeddings.iter().map(Vec::as_slice))
+            .collect::<Vec<_>>();
+        if let Err(e) = d1::cache_embeddings(env, model, &entries).await {
+            console_warn!("Failed to cache embeddings: {}", e);
+        }
+        cached.extend(missing_hashes.into_iter().zip(embeddings));
+    }
+
+    hashes
+        .iter()
+        .map(|hash| {
+            cached
+                .get(hash)
+                .cloned()
+                .ok_or_else(|| Error::from("Missing embedding for a chunk"))
+        })
+        .collect()
+}
+
+/// Change the content type of a saved link and reprocess its stored content
+/// Returns the previous and the updated link info
+pub async fn retype_link(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    content_type: &str,
+    style: SummaryStyle,
+) -> Result<(DocInfo, DocInfo)> {
+    let old = d1::find_link_by_url(env, link).await?;
+    console_log!(
+        "Reprocessing {} as {} (was {})",
+        link,
+        content_type,
+        old.content_type
+    );
+    let row = reprocess_link(env, config, old.clone(), content_type, style).await?;
+    Ok((old, row))
+}
+
+/// Process the stored content of a saved link again, e.g. after the prompt improved
+/// Keeps the link id and bucket object, returns the previous and the updated link info
+pub async fn reprocess_saved_link(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    style: SummaryStyle,
+) -> Result<(DocInfo, DocInfo)> {
+    let old = d1::find_link_by_url(env, link).await?;
+    console_log!("Reprocessing {} from its stored content", link);
+    let content_type = old.content_type.clone();
+    let row = reprocess_link(env, config, old.clone(), &content_type, style).await?;
+    Ok((old, row))
+}
+
+/// Chunks retrieved to answer a question, before grouping them by document
+const ASK_TOP_K: usize = 20;
+/// Chunks scoring below this are unrelated to the question, when none is above it `/ask` declines
+const MIN_ASK_SCORE: f32 = 0.6;
+/// Documents an answer draws on at most
+const MAX_ASK_SOURCES: usize = 5;
+/// Characters of chunk text sent to Gemini at most, across all sources
+const MAX_ASK_CONTEXT_CHARS: usize = 20_000;
+
+/// Answer to a question from saved links, citing `sources` by their position from 1 as `[n]`
+pub struct Answer {
+    pub text: String,
+    pub sources: Vec<DocInfo>,
+}
+
+/// The documents to answer from, best first, each with its chunks relevant to the question
+/// Only chunks scoring `MIN_ASK_SCORE` count, so unrelated questions find no documents
+fn ask_documents(mut matches: Vec<(String, f32)>) -> Vec<(String, Vec<String>)> {
+    matches.retain(|(_, score)| *score >= MIN_ASK_SCORE);
+    matches.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut documents: Vec<(String, Vec<String>)> = vec![];
+    for (vector_id, _) in matches {
+        let Some((document_id, _)) = vector_id.rsplit_once('-') else {
+            continue;
+        };
+        match documents.iter().position(|(id, _)| id == document_id) {
+            Some(i) => documents[i].1.push(vector_id),
+            None if documents.len() < MAX_ASK_SOURCES => {
+                documents.push((document_id.to_string(), vec![vector_id]))
+            }
+            None => {}
+        }
+    }
+    documents
+}
+
+/// Answer a question from the saved chunks that match it best, grouped by document
+/// Returns `None` when no saved link is relevant enough to answer from
+pub async fn ask_question(env: &Env, config: &Config, question: &str) -> Result<Option<Answer>> {
+    let mut timings = SearchTimings::default();
+    let matches = vector::query_vectors_with_scores_vector_lite(
+        env,
+        config,
+        question,
+        ASK_TOP_K,
+        &mut timings,
+    )
+    .await?;
+    console_log!("Chunks matching question {:?}: {:?}", question, matches);
+    let documents = ask_documents(matches);
+    let vector_ids = documents
+        .iter()
+        .flat_map(|(_, vector_ids)| vector_ids.iter().cloned())
+        .collect::<Vec<_>>();
+    let texts = d1::get_chunk_texts(env, &vector_ids).await?;
+
+    let mut sources = vec![];
+    let mut budget = MAX_ASK_CONTEXT_CHARS;
+    for (document_id, vector_ids) in documents {
+        if budget == 0 {
+            break;
+        }
+        let Some(link_info) = d1::get_link_by_id(env, &document_id).await? else {
+            continue;
+        };
+        // Links saved before chunk texts were stored can only offer their summary
+        let mut text = vector_ids
+            .iter()
+            .filter_map(|id| texts.get(id).map(String::as_str))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        if text.is_empty() {
+            text = link_info.summary.clone();
+        }
+        let text = text.chars().take(budget).collect::<String>();
+        budget -= text.chars().count();
+        sources.push((link_info, text));
+    }
+    if sources.is_empty() {
+        return Ok(None);
+    }
+
+    let prompt_sources = sources
+        .iter()
+        .map(|(link_info, text)| (link_info.title.as_str(), text.as_str()))
+        .collect::<Vec<_>>();
+    let text = crate::utils::answer_question(env, config, question, &prompt_sources).await?;
+    Ok(Some(Answer {
+        text,
+        sources: sources
+            .into_iter()
+            .map(|(link_info, _)| link_info)
+            .collect(),
+    }))
+}
+
+/// Combined size of the stored contents sent to Gemini by a comparison, well below its request limit
+const MAX_COMPARE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Compare two saved links by their stored content
+/// When both don't fit in one request, the larger side and then both are replaced by their summaries
+pub async fn compare_links(
+    env: &Env,
+    config: &Config,
+    first: &DocInfo,
+    second: &DocInfo,
+) -> Result<Comparison> {
+    // Expired content is gone, only the summary is left to compare
+    let size = |link_info: &DocInfo| match link_info.content_expired_at {
+        Some(_) => 0,
+        None => link_info.size,
+    };
+    let fits = |size: usize| size <= MAX_COMPARE_BYTES;
+    let (summarize_first, summarize_second) = if fits(size(first) + size(second)) {
+        (false, false)
+    } else if size(first) >= size(second) {
+        (true, !fits(size(second)))
+    } else {
+        (!fits(size(first)), true)
+    };
+    let summarize_first = summarize_first || first.content_expired_at.is_some();
+    let summarize_second = summarize_second || second.content_expired_at.is_some();
+    let summary = |link_info: &DocInfo| {
+        format!("# {}\n\n{}", link_info.title, link_info.summary).into_bytes()
+    };
+    let first_content = if summarize_first {
+        summary(first)
+    } else {
+        d1::read_from_bucket(env, &first.bucket_path).await?
+    };
+    let second_content = if summarize_second {
+        summary(second)
+    } else {
+        d1::read_from_bucket(env, &second.bucket_path).await?
+    };
+    let content_type = |link_info: &DocInfo, summarized: bool| {
+        if summarized {
+            "text/markdown".to_string()
+        } else {
+            link_info.content_type.clone()
+        }
+    };
+    compare_documents(
+        env,
+        config,
+        (&content_type(first, summarize_first), &first_content),
+        (&content_type(second, summarize_second), &second_content),
+    )
+    .await
+}
+
+/// Retry every link flagged by a failed insert, returning each link with the outcome
+pub async fn repair_links(
+    env: &Env,
+    config: &Config,
+    style: SummaryStyle,
+) -> Result<Vec<(DocInfo, Result<DocInfo>)>> {
+    let mut outcomes = vec![];
+    for link_info in d1::get_flagged_links(env).await? {
+        let content_type = link_info.content_type.clone();
+        let outcome = reprocess_link(env, config, link_info.clone(), &content_type, style).await;
+        if let Err(e) = &outcome {
+            console_error!("Error repairing {}: {}", link_info.url, e);
+            let status = link_info
+                .status
+                .as_deref()
+                .unwrap_or(STATUS_PENDING_PROCESSING);
+            d1::set_link_status(env, &link_info.id, status, &e.to_string()).await?;
+        }
+        outcomes.push((link_info, outcome));
+    }
+    Ok(outcomes)
+}
+
+/// Download a saved link again and reprocess it when it changed, returning the previous and the
+/// updated link info
+/// The stored `ETag` and `Last-Modified` are sent along, when the server answers 304 nothing is
+/// reprocessed and `None` is returned
+pub async fn recrawl_link(
+    env: &Env,
+    config: &Config,
+    link: &str,
+    style: SummaryStyle,
+) -> Result<Option<(DocInfo, DocInfo)>> {
+    let old = d1::find_link_by_url(env, link).await?;
+    let validators = CacheValidators {
+        etag: old.etag.clone(),
+        last_modified: old.last_modified.clone(),
+    };
+    let Some((fetched, content_type)) = download_if_changed(config, link, &validators).await?
+    else {
+        console_log!(
+            "{} is unchanged since it was fetched, skipping reprocessing",
+            link
+        );
+        return Ok(None);
+    };
+    reject_unsupported(config, &content_type)?;
+    let row = DocInfo {
+        bucket_path: get_bucket_path(config, &content_type, &old.id),
+        content_type: content_type.clone(),
+        reported_content_type: Some(fetched.content_type),
+        size: fetched.content.len(),
+        etag: fetched.validators.etag,
+        last_modified: fetched.validators.last_modified,
+        content_expired_at: None,
+        ..old.clone()
+    };
+    d1::save_to_bucket(env, &row.bucket_path, fetched.content).await?;
+    d1::set_link_content(env, &row).await?;
+    if row.bucket_path != old.bucket_path && old.content_expired_at.is_none() {
+        d1::delete_from_bucket(env, &old.bucket_path).await?;
+    }
+    console_log!("{} changed since it was fetched, reprocessing", link);
+    let row = reprocess_link(env, config, row, &content_type, style).await?;
+    Ok(Some((old, row)))
+}
+
+/// Run Gemini and the embeddings again on the stored content of a link, clearing its status
+async fn reprocess_link(
+    env: &Env,
+    config: &Config,
+    old: DocInfo,
+    content_type: &str,
+    style: SummaryStyle,
+) -> Result<DocInfo> {
+    if let Some(expired_at) = &old.content_expired_at {
+        return Err(Error::from(format!(
+            "The content of {} was deleted on {} by the retention policy, delete the link and save it again",
+            old.url, expired_at
+        )));
+    }
+    let content = d1::read_from_bucket(env, &old.bucket_path).await?;
+    let processed_data =
+        process_content(env, config, &old.url, &content, content_type, style, None).await?;
+    let (processed_data, original_chunk_count) = cap_chunks(config, &old.url, processed_data);
+    let space = EmbeddingSpace::for_language(config, &processed_data.language);
+    let model = space.model(config);
+    let embeddings = embed_chunks(env, config, &model, &processed_data.chunks).await?;
+
+    let row = DocInfo {
+        id: old.id.clone(),
+        url: old.url.clone(),
+        created_at: old.created_at.clone(),
+        bucket_path: get_bucket_path(config, content_type, &old.id),
+        content_type: content_type.to_string(),
+        reported_content_type: old.reported_content_type.clone(),
+        size: old.size,
+        title: processed_data.title,
+        summary: processed_data.summary,
+        chunk_count: processed_data.chunks.len(),
+        original_chunk_count,
+        language: Some(processed_data.language).filter(|l| !l.is_empty()),
+        embedding_model: Some(model),
+        status: None,
+        status_error: None,
+        etag: old.etag.clone(),
+        last_modified: old.last_modified.clone(),
+        chat_id: old.chat_id,
+        author: old.author.clone(),
+        published_at: old.published_at.clone(),
+        content_expired_at: None,
+        tags: old.tags.clone(),
+        last_checked: old.last_checked.clone(),
+        http_status: old.http_status,
+    };
+
+    // The path follows the content type and bucket layout, so the object may need to move
+    if row.bucket_path != old.bucket_path {
+        d1::save_to_bucket(env, &row.bucket_path, content).await?;
+    }
+    d1::update_link_and_embeddings(env, &row, &embeddings, &processed_data.chunks).await?;
+    let old_ids = vector::chunk_vector_ids(&old.id, old.chunk_count);
+    let new_vectors = vector::chunk_vectors(&row.id, &embeddings);
+    let old_space = link_space(config, &old);
+    if old_space == space {
+        vector_index::update(env, space, old_ids, new_vectors).await?;
+    } else {
+        vector_index::update(env, old_space, old_ids, vec![]).await?;
+        vector_index::update(env, space, vec![], new_vectors).await?;
+    }
+    if row.bucket_path != old.bucket_path {
+        d1::delete_from_bucket(env, &old.bucket_path).await?;
+    }
+
+    Ok(row)
+}
+
+/// Move the vectors keyed under an old document id to the saved link now holding that document,
+/// in D1 and the vector index; vectors missing from D1 are fetched from Vectorize
+/// Returns the number of vectors moved
+pub async fn move_vectors(env: &Env, config: &Config, old_id: &str, new_id: &str) -> Result<usize> {
+    if old_id == new_id {
+        return Err(Error::from("The old and new ids are the same"));
+    }
+    if d1::get_link_by_id(env, old_id).await?.is_some() {
+        return Err(Error::from(format!(
+            "Link {} still exists, its vectors are not orphaned",
+            old_id
+        )));
+    }
+    let Some(link_info) = d1::get_link_by_id(env, new_id).await? else {
+        return Err(Error::from(format!("No link with id {}", new_id)));
+    };
+    let existing = d1::get_embeddings_by_prefix(env, new_id).await?;
+    if !existing.is_empty() {
+        return Err(Error::from(format!(
+            "Link {} already has {} stored vectors",
+            new_id,
+            existing.len()
+        )));
+    }
+    if link_info.chunk_count == 0 {
+        return Err(Error::from(format!("Link {} has no chunks", new_id)));
+    }
+    let stored = d1::get_embeddings_by_prefix(env, old_id).await?;
+    let chunks = stored
+        .iter()
+        .map(|(_, embedding)| embedding.chunk.clone().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let embeddings = if stored.is_empty() {
+        console_log!(
+            "No vectors stored under {}, fetching them from Vectorize",
+            old_id
+        );
+        let ids = vector::chunk_vector_ids(old_id, link_info.chunk_count);
+        let ids = ids.iter().map(String::as_str).collect::<Vec<_>>();
+        vector::get_vector_by_id(config, &ids)
+            .await?
+            .iter()
+            .map(|vector| vector.as_slice().to_vec())
+            .collect::<Vec<_>>()
+    } else {
+        let chunks = stored.iter().map(|(chunk, _)| *chunk);
+        if !chunks.eq(0..link_info.chunk_count) {
+            return Err(Error::from(format!(
+                "{} has {} vectors stored, which don't match the {} chunks of link {}",
+                old_id,
+                stored.len(),
+                link_info.chunk_count,
+                new_id
+            )));
+        }
+        stored
+            .into_iter()
+            .map(|(_, embedding)| embedding.vector)
+            .collect()
+    };
+    if embeddings.len() != link_info.chunk_count {
+        return Err(Error::from(format!(
+            "Found {} vectors under {}, expected {}",
+            embeddings.len(),
+            old_id,
+            link_info.chunk_count
+        )));
+    }
+
+    d1::move_embeddings(env, old_id, &link_info, &embeddings, &chunks).await?;
+    let old_ids = vector::chunk_vector_ids(old_id, embeddings.len());
+    let new_vectors = vector::chunk_vectors(new_id, &embeddings);
+    for (old, (new, _)) in old_ids.iter().zip(&new_vectors) {
+        console_log!("Moved vector {} to {}", old, new);
+    }
+    vector_index::update(env, link_space(config, &link_info), old_ids, new_vectors).await?;
+
+    Ok(embeddings.len())
+}
+
+/// Links whose content is deleted per scheduled run, the rest waits for the next one
+const EXPIRE_BATCH: usize = 100;
+
+/// Delete the content of links older than `CONTENT_RETENTION_DAYS` from R2, keeping their
+/// summary and vectors so they stay searchable; returns the number of links expired
+pub async fn expire_content(env: &Env, config: &Config) -> Result<usize> {
+    let Some(days) = config.content_retention_days else {
+        return Ok(0);
+    };
+    let links = d1::get_links_to_expire(env, days, EXPIRE_BATCH).await?;
+    for link_info in &links {
+        d1::delete_from_bucket(env, &link_info.bucket_path).await?;
+        d1::mark_content_expired(env, &link_info.id).await?;
+        console_log!(
+            "Expired content of {} saved {}",
+            link_info.url,
+            link_info.created_at
+        );
+    }
+    Ok(links.len())
+}
+
+/// Links checked per scheduled run at most, the rest waits for the next one
+const LINK_CHECK_BATCH: usize = 60;
+/// Requests in flight at once, Workers allow six open connections per invocation
+const LINK_CHECK_CONCURRENCY: usize = 6;
+/// Time a scheduled run spends checking links before leaving the rest for the next run
+const LINK_CHECK_BUDGET_MS: f64 = 15_000.0;
+
+/// Whether a status means the page is gone, rather than e.g. a temporary server error
+pub fn is_dead_status(status: u16) -> bool {
+    matches!(status, 404 | 410)
+}
+
+/// Check whether saved links not checked for `DEAD_LINK_CHECK_DAYS` still resolve, recording
+/// the status on each link; returns the links found dead that weren't at their previous check
+pub async fn check_links(env: &Env, config: &Config) -> Result<Vec<DocInfo>> {
+    let Some(days) = config.dead_link_check_days else {
+        return Ok(vec![]);
+    };
+    let timer = Timer::start();
+    let links = d1::get_links_to_check(env, days, LINK_CHECK_BATCH).await?;
+    let mut checked = 0;
+    let mut dead = vec![];
+    for batch in links.chunks(LINK_CHECK_CONCURRENCY) {
+        if timer.elapsed_ms() > LINK_CHECK_BUDGET_MS {
+            break;
+        }
+        let statuses = futures_util::future::join_all(batch.iter().map(|link_info| {
+            let validators = CacheValidators {
+                etag: link_info.etag.clone(),
+                last_modified: link_info.last_modified.clone(),
+            };
+            async move { link_status(&link_info.url, &validators).await }
+        }))
+        .await;
+        for (link_info, status) in batch.iter().zip(statuses) {
+            let status = status
+                .inspect_err(|e| console_error!("Error checking {}: {}", link_info.url, e))
+                .ok();
+            d1::set_link_check(env, &link_info.id, status).await?;
+            checked += 1;
+            let was_dead = link_info.http_status.is_some_and(is_dead_status);
+            if status.is_some_and(is_dead_status) && !was_dead {
+                dead.push(DocInfo {
+                    http_status: status,
+                    ..link_info.clone()
+                });
+            }
+        }
+    }
+    console_log!(
+        "Checked {} of {} links in {:.0} ms, {} newly dead",
+        checked,
+        links.len(),
+        timer.elapsed_ms(),
+        dead.len()
+    );
+    Ok(dead)
+}
+
+/// Status of a link after redirects, with a HEAD request or a GET for servers that refuse HEAD
+/// The stored validators are sent along, so an unchanged page answers 304
+/// The body of the GET is never read
+async fn link_status(url: &str, validators: &CacheValidators) -> Result<u16> {
+    let request = |method| -> Result<Request> {
+        let mut headers = Headers::new();
+        for (name, value) in validators.request_headers() {
+            headers.set(name, value)?;
+        }
+        Request::new_with_init(
+            url,
+            RequestInit::new().with_method(method).with_headers(headers),
+        )
+    };
+    let status = Fetch::Request(request(Method::Head)?)
+        .send()
+        .await?
+        .status_code();
+    if status != 405 && status != 501 {
+        return Ok(status);
+    }
+    Ok(Fetch::Request(request(Method::Get)?)
+        .send()
+        .await?
+        .status_code())
+}
+
+/// Bucket folder of the exports of all saved links
+const EXPORT_PREFIX: &str = "exports/";
+/// Links read from D1 at once while exporting
+const EXPORT_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    /// A JSON array of links
+    Json,
+    /// One JSON link per line, which tools can read without loading the whole file
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// Where an export was saved and what it holds
+pub struct Export {
+    pub key: String,
+    pub links: usize,
+    pub size: usize,
+}
+
+/// Save every link, without its content or vectors, to `exports/<timestamp>.<format>` in R2
+/// Links are read and serialized a page at a time, so only the output is held in full
+pub async fn export_links(env: &Env, format: ExportFormat) -> Result<Export> {
+    let mut output = vec![];
+    if let ExportFormat::Json = format {
+        output.extend_from_slice(b"[");
+    }
+    let mut links = 0;
+    loop {
+        let (_, page) = d1::get_link_stats_paged(env.clone(), links, EXPORT_PAGE_SIZE).await?;
+        for link_info in &page {
+            match format {
+                ExportFormat::Json if links > 0 => output.extend_from_slice(b",\n"),
+                ExportFormat::Json => output.extend_from_slice(b"\n"),
+                ExportFormat::Ndjson => {}
+            }
+            serde_json::to_writer(&mut output, link_info)?;
+            if let ExportFormat::Ndjson = format {
+                output.extend_from_slice(b"\n");
+            }
+            links += 1;
+        }
+        if page.len() < EXPORT_PAGE_SIZE {
+            break;
+        }
+    }
+    if let ExportFormat::Json = format {
+        output.extend_from_slice(b"\n]\n");
+    }
+
+    let timestamp = js_sys::Date::new_0()
+        .to_iso_string()
+        .as_string()
+        .unwrap_or_default()
+        .replace(':', "-");
+    let key = format!("{}{}.{}", EXPORT_PREFIX, timestamp, format.extension());
+    let size = output.len();
+    d1::save_to_bucket(env, &key, output).await?;
+    console_log!("Exported {} links to {} ({} bytes)", links, key, size);
+    Ok(Export { key, links, size })
+}
+
+/// Time an import spends processing imported links, the rest wait in the queue for `/repair`
+const IMPORT_BUDGET_MS: f64 = 20_000.0;
+
+/// Outcome of importing a backup
+#[derive(Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Imported links processed and embedded right away, the others are queued for `/repair`
+    pub processed: usize,
+    /// Links whose URL is already saved
+    pub skipped: usize,
+    /// URL, or line for an unreadable entry, and the error
+    pub failed: Vec<(String, String)>,
+}
+
+/// Restore the links of an `/export` file from R2, keeping their id, chat, date and tags
+/// Content still in the bucket is reused, otherwise the URL is downloaded again. Links are
+/// processed and embedded until `IMPORT_BUDGET_MS` runs out, one failing doesn't stop the rest
+pub async fn import_links(
+    env: &Env,
+    config: &Config,
+    bucket_path: &str,
+    style: SummaryStyle,
+) -> Result<ImportReport> {
+    let timer = Timer::start();
+    let bytes = d1::read_from_bucket(env, bucket_path).await?;
+    let mut report = ImportReport::default();
+
+    for (line, entry) in parse_export(&bytes)?.into_iter().enumerate() {
+        let link_info = match entry {
+            Ok(link_info) => link_info,
+            Err(e) => {
+                report
+                    .failed
+                    .push((format!("entry {}", line + 1), e.to_string()));
+                continue;
+            }
+        };
+        if d1::find_link_by_url(env, &link_info.url).await.is_ok()
+            || d1::get_link_by_id(env, &link_info.id).await?.is_some()
+        {
+            report.skipped += 1;
+            continue;
+        }
+        let url = link_info.url.clone();
+        match import_link(env, config, link_info).await {
+            Ok(row) => {
+                report.imported += 1;
+                if timer.elapsed_ms() > IMPORT_BUDGET_MS {
+                    continue;
+                }
+                let content_type = row.content_type.clone();
+                let id = row.id.clone();
+                match reprocess_link(env, config, row, &content_type, style).await {
+                    Ok(_) => report.processed += 1,
+                    Err(e) => {
+                        console_error!("Error processing imported {}: {}", url, e);
+                        d1::set_link_status(env, &id, STATUS_PENDING_PROCESSING, &e.to_string())
+                            .await?;
+                    }
+                }
+            }
+            Err(e) => {
+                console_error!("Error importing {}: {}", url, e);
+                report.failed.push((url, e.to_string()));
+            }
+        }
+    }
+    console_log!(
+        "Imported {} links from {}, {} processed, {} skipped, {} failed",
+        report.imported,
+        bucket_path,
+        report.processed,
+        report.skipped,
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// The links of an export, a JSON array or one link per line, each parsed on its own so a
+/// damaged line only loses that link
+fn parse_export(bytes: &[u8]) -> Result<Vec<std::result::Result<DocInfo, serde_json::Error>>> {
+    let text = std::str::from_utf8(bytes).map_err(|e| Error::from(e.to_string()))?;
+    if text.trim_start().starts_with('[') {
+        let entries = serde_json::from_str::<Vec<serde_json::Value>>(text)?;
+        return Ok(entries.into_iter().map(serde_json::from_value).collect());
+    }
+    Ok(text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect())
+}
+
+/// Save an exported link, unprocessed and queued, with its content in the bucket
+async fn import_link(env: &Env, config: &Config, link_info: DocInfo) -> Result<DocInfo> {
+    if link_info.url.is_empty() || link_info.id.is_empty() {
+        return Err(Error::from("Missing url or id"));
+    }
+    let mut row = DocInfo {
+        chunk_count: 0,
+        original_chunk_count: None,
+        embedding_model: None,
+        status: Some(STATUS_PENDING_PROCESSING.to_string()),
+        status_error: Some("Imported, not processed yet".to_string()),
+        content_expired_at: None,
+        ..link_info
+    };
+    if !d1::exists_in_bucket(env, &row.bucket_path).await? {
+        let (fetched, content_type) = download(config, &row.url).await?;
+        row.content_type = content_type;
+        row.size = fetched.content.len();
+        row.bucket_path = get_bucket_path(config, &row.content_type, &row.id);
+        d1::save_to_bucket(env, &row.bucket_path, fetched.content).await?;
+    }
+    d1::save_link_to_db(env, &row, &vec![], &[]).await?;
+    d1::restore_link_metadata(env, &row).await?;
+    Ok(row)
+}
+
+/// The embedding space holding a link's vectors
+fn link_space(config: &Config, link_info: &DocInfo) -> EmbeddingSpace {
+    EmbeddingSpace::for_model(config, link_info.embedding_model.as_deref())
+}
+
+/// Prepare metadata for storage
+/// `content/{id}.{ext}`, or `content/{category}/{id}.{ext}` with `CATEGORIZED_BUCKET_PATHS`
+/// Links keep the exact path in D1, so objects saved under the other layout still resolve
+fn get_bucket_path(config: &Config, content_type: &str, link_id: &str) -> String {
+    let extension = get_extension_from_content_type(content_type);
+    if config.categorized_bucket_paths {
+        format!(
+            "content/{}/{}.{}",
+            content_category(content_type),
+            link_id,
+            extension
+        )
+    } else {
+        format!("content/{}.{}", link_id, extension)
+    }
+}
+
+/// Coarse bucket folder of a content type
+fn content_category(content_type: &str) -> &'static str {
+    match content_type.split(';').next().unwrap_or("").trim() {
+        "text/html" => "html",
+        "application/pdf" => "pdf",
+        t if t.starts_with("image/") => "image",
+        t if t.starts_with("text/") => "text",
+        "application/json" | "application/javascript" | "application/xml" => "text",
+        _ => "other",
     }
+}
 
-    let link_id = Uuid::new_v4().to_string();
-    let current_time = js_sys::Date::new_0().to_iso_string().as_string().unwrap();
-
-    // Download content first
-    console_log!("Fetching content from link: {}", link);
-    let (content, content_type) = fetch_content(link).await?;
-    let bucket_path = get_bucket_path(&content_type, &link_id);
-    let content_size = content.len();
+/// Constraints applied to search results after the vector step
+/// Dates are `YYYY-MM-DD`, `after` is inclusive and `before` is exclusive
+#[derive(Debug, Default, Clone)]
+pub struct SearchFilters {
+    pub after: Option<String>,
+    pub before: Option<String>,
+    /// A category like `pdf` or `image`, or a content type like `text/markdown`
+    pub content_type: Option<String>,
+    /// `#tag` tokens, links need every one of them
+    pub tags: Vec<String>,
+}
 
-    // Process the content with Gemini API
-    console_log!("Processing content with Gemini API from: {}", link);
-    let processed_data = chunk_and_summary_link(env, &content, &content_type).await?;
-    console_log!("Processed data: {:?}", processed_data);
+impl SearchFilters {
+    /// Split `before:`, `after:`, `type:` and `#tag` tokens off a query, returning the text to embed
+    /// and the filters
+    pub fn parse(query: &str) -> Result<(String, Self)> {
+        let mut filters = Self::default();
+        let mut words = vec![];
+        for token in query.split_whitespace() {
+            if let Some(date) = token.strip_prefix("after:") {
+                filters.after = Some(parse_date(date)?);
+            } else if let Some(date) = token.strip_prefix("before:") {
+                filters.before = Some(parse_date(date)?);
+            } else if let Some(content_type) = token.strip_prefix("type:") {
+                if content_type.is_empty() {
+                    return Err(Error::from(
+                        "Missing type in type:, e.g. type:pdf or type:text/markdown",
+                    ));
+                }
+                filters.content_type = Some(content_type.to_ascii_lowercase());
+            } else if token.len() > 1 && token.starts_with('#') {
+                for tag in d1::parse_tags(token) {
+                    if !filters.tags.contains(&tag) {
+                        filters.tags.push(tag);
+                    }
+                }
+            } else {
+                words.push(token);
+            }
+        }
+        Ok((words.join(" "), filters))
+    }
 
-    let row = DocInfo {
-        id: link_id.clone(),
-        url: link.to_string(),
-        created_at: current_time.clone(),
-        bucket_path: bucket_path.clone(),
-        content_type: content_type.clone(),
-        size: content_size,
-        title: processed_data.title.clone(),
-        summary: processed_data.summary.clone(),
-        chunk_count: processed_data.chunks.len(),
-    };
+    pub fn is_empty(&self) -> bool {
+        self.after.is_none()
+            && self.before.is_none()
+            && self.content_type.is_none()
+            && self.tags.is_empty()
+    }
 
-    let mut embeddings = Vec::with_capacity(processed_data.chunks.len());
-    for chunk_text in processed_data.chunks.iter() {
-        let embedding = vector::generate_embeddings(env, chunk_text).await?;
-        embeddings.push(embedding);
+    fn matches_tags(&self, tags: &[&str]) -> bool {
+        self.tags
+            .iter()
+            .all(|wanted| tags.contains(&wanted.as_str()))
     }
 
-    let mut vector_lite = vector::get_vector_lite(env).await?;
+    /// Check a link against every filter
+    pub fn matches(&self, link: &DocInfo) -> bool {
+        // `created_at` is either `YYYY-MM-DD HH:MM:SS` or ISO 8601, both start with the date
+        let date = link.created_at.get(..10).unwrap_or(&link.created_at);
+        self.after.as_deref().is_none_or(|after| date >= after)
+            && self.before.as_deref().is_none_or(|before| date < before)
+            && self.content_type.as_deref().is_none_or(|wanted| {
+                let content_type = link.content_type.to_ascii_lowercase();
+                let base = content_type.split(';').next().unwrap_or("").trim();
+                if wanted.contains('/') {
+                    base == wanted
+                } else {
+                    content_category(base) == wanted
+                }
+            })
+            && self.matches_tags(&link.tag_list())
+    }
+}
 
-    for (i, embedding) in embeddings.iter().enumerate() {
-        let vector_id = format!("{}-{}", link_id, i);
-        vector_lite.insert(Vector::try_from(embedding.clone()).unwrap(), vector_id);
+/// Validate a `YYYY-MM-DD` date, which then compares correctly as a string
+fn parse_date(date: &str) -> Result<String> {
+    let parts: Vec<&str> = date.split('-').collect();
+    let valid = matches!(
+        parts.as_slice(),
+        [year, month, day]
+            if year.len() == 4
+                && month.len() == 2
+                && day.len() == 2
+                && year.chars().chain(month.chars()).chain(day.chars()).all(|c| c.is_ascii_digit())
+                && (1..=12).contains(&month.parse::<u32>().unwrap_or(0))
+                && (1..=31).contains(&day.parse::<u32>().unwrap_or(0))
+    );
+    if valid {
+        Ok(date.to_string())
+    } else {
+        Err(Error::from(format!(
+            "Invalid date '{}', expected YYYY-MM-DD",
+            date
+        )))
     }
+}
 
-    // TODO: how to make sure these steps are atomic?
-    d1::save_to_bucket(env, &bucket_path, content.clone()).await?;
-    d1::save_link_to_db(env, &row, &embeddings).await?;
-    vector::save_vector_lite(env, &vector_lite).await?;
+/// Results of a search, best match first
+pub struct SearchResponse {
+    pub results: Vec<(DocInfo, f32)>,
+    /// Documents the vectors matched but the filters left out, to tell apart no match from
+    /// everything filtered
+    pub filtered_out: usize,
+    /// Matching chunks of each result, best first, by link id, for links saved with their chunks
+    pub passages: std::collections::HashMap<String, Vec<Passage>>,
+    pub timings: SearchTimings,
+    /// Vectors of matched documents whose link is gone, for the caller to hand to
+    /// `remove_orphan_vectors` once the results are out
+    pub orphans: Vec<String>,
+}
 
-    Ok(row)
+/// A chunk of a search result that matched the query
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub text: String,
+    pub score: f32,
 }
 
-/// Prepare metadata for storage
-fn get_bucket_path(content_type: &str, link_id: &str) -> String {
-    let extension = get_extension_from_content_type(content_type);
-    format!("content/{}.{}", link_id, extension)
+/// Matching chunks kept per search result, the rest of a document's matches only count
+/// towards its score
+const MAX_PASSAGES: usize = 3;
+
+/// Saved links closest to a saved link, searching the index with the embedding of its first chunk
+/// Returns at most `max_results` links other than the link itself, best match first
+pub async fn similar_links(
+    env: &Env,
+    config: &Config,
+    link_info: &DocInfo,
+    max_results: usize,
+) -> Result<Vec<(DocInfo, f32)>> {
+    let Some((_, first_chunk)) = d1::get_embeddings_by_prefix(env, &link_info.id)
+        .await?
+        .into_iter()
+        .next()
+    else {
+        return Err(Error::from(format!(
+            "{} has no stored embeddings, send /repair to embed it",
+            link_info.url
+        )));
+    };
+
+    // Every chunk of the link itself matches too, so look further down the ranking
+    let top_k = (max_results + link_info.chunk_count) * 4;
+    let space = link_space(config, link_info);
+    let matches = vector_index::search(env, config, space, first_chunk.vector, top_k).await?;
+
+    // Matches come best first, so the first chunk of each document carries its score
+    let mut sorted_docs: Vec<(String, f32)> = vec![];
+    for (vector_id, score) in matches.results {
+        let Some((document_id, _)) = vector_id.rsplit_once('-') else {
+            continue;
+        };
+        if document_id != link_info.id && !sorted_docs.iter().any(|(id, _)| id == document_id) {
+            sorted_docs.push((document_id.to_string(), vector::similarity("cosine", score)));
+        }
+    }
+    sorted_docs.truncate(max_results);
+
+    let ids = sorted_docs
+        .iter()
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+    let mut links = d1::get_links_by_ids(env, &ids)
+        .await?
+        .into_iter()
+        .map(|link| (link.id.clone(), link))
+        .collect::<std::collections::HashMap<_, _>>();
+    Ok(sorted_docs
+        .into_iter()
+        .filter_map(|(id, score)| links.remove(&id).map(|link| (link, score)))
+        .collect())
 }
 
 /// Search links using vector similarity
-/// Returns a list of links and their chunks
+/// Returns at most `max_results` links, best match first
 pub async fn search_links(
     env: Env,
+    config: &Config,
     query: &str,
+    filters: &SearchFilters,
     search_from_cf: bool,
-) -> Result<Vec<(DocInfo, f32)>> {
-    console_log!("Searching for: {}", query);
+    max_results: usize,
+) -> Result<SearchResponse> {
+    console_log!("Searching for: {} {:?}", query, filters);
+    let timer = Timer::start();
+    let mut timings = SearchTimings::default();
+
+    // Filters drop documents after the vector step, so look further down the ranking
+    let (top_k, max_docs) = if filters.is_empty() {
+        (max_results * 4, max_results)
+    } else {
+        (max_results * 20, max_results * 4)
+    };
 
     // Query the vector database to get vector IDs and scores
     let mut vector_results = if search_from_cf {
-        vector::query_vectors_with_scores(&env, query, 20).await?
+        vector::query_vectors_with_scores(config, query, top_k, &mut timings).await?
     } else {
-        vector::query_vectors_with_scores_vector_lite(&env, query, 20).await?
+        vector::query_vectors_with_scores_vector_lite(&env, config, query, top_k, &mut timings)
+            .await?
     };
 
     if vector_results.is_empty() {
-        return Ok(vec![]);
+        return Ok(SearchResponse {
+            results: vec![],
+            filtered_out: 0,
+            passages: Default::default(),
+            timings,
+            orphans: vec![],
+        });
     }
 
     vector_results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
 
     console_log!("Vector results: {:?}", vector_results);
 
-    let mut sorted_docs = vec![];
-    let mut doc_tracker = std::collections::HashSet::new();
-    for (vector_id, score) in vector_results {
-        let parts = vector_id.split("-").collect::<Vec<_>>();
-        let document_id = parts[0..parts.len() - 1].join("-");
-        if !doc_tracker.contains(&document_id) {
-            doc_tracker.insert(document_id.clone());
-            sorted_docs.push((document_id, score));
-        }
-        if sorted_docs.len() >= 5 {
-            break;
-        }
+    let DocumentMatches {
+        ranked: mut sorted_docs,
+        vectors: mut doc_vectors,
+        scores: doc_scores,
+    } = rank_documents(config.score_aggregation, vector_results);
+    apply_feedback(&env, query, &mut sorted_docs).await;
+
+    // Tags are checked on the ids alone, so documents without them aren't fetched at all
+    let mut filtered_out = 0;
+    if !filters.tags.is_empty() {
+        let ids = sorted_docs
+            .iter()
+            .map(|(document_id, _)| document_id.clone())
+            .collect::<Vec<_>>();
+        let tags = d1::get_link_tags(&env, &ids).await?;
+        let candidates = sorted_docs.len();
+        sorted_docs.retain(|(document_id, _)| {
+            tags.get(document_id)
+                .is_some_and(|tags| filters.matches_tags(&tags.split(',').collect::<Vec<_>>()))
+        });
+        filtered_out = candidates - sorted_docs.len();
     }
+    sorted_docs.truncate(max_docs);
 
     // Create a vector of futures for parallel execution
-    let link_futures = sorted_docs.iter().take(5).map(|(doc_id, score)| {
+    let link_futures = sorted_docs.iter().map(|(doc_id, score)| {
         let env_clone = env.clone();
         let doc_id_clone = doc_id.clone();
         async move {
-            match d1::get_link_by_id(&env_clone, &doc_id_clone).await {
-                Ok(Some(link_info)) => Ok((link_info, *score)),
-                Ok(None) => {
-                    console_log!("Link not found, id: {}", doc_id_clone);
-                    Err(Error::from(format!("Link {} not found", doc_id_clone)))
-                }
-                Err(e) => {
-                    console_log!("Error fetching link {}: {:?}", doc_id_clone, e);
-                    Err(e)
+            let result = d1::get_link_by_id(&env_clone, &doc_id_clone).await;
+            (
+                doc_id_clone,
+                result.map(|link| link.map(|link| (link, *score))),
+            )
+        }
+    });
+
+    let (results, fetch_links_ms) =
+        metrics::timed(futures_util::future::join_all(link_futures)).await;
+    timings.fetch_links_ms = fetch_links_ms;
+    let (found, orphans) = split_orphans(results, &mut doc_vectors);
+    // Only the vector_lite indexes are cleaned up, orphans matched through Vectorize stay
+    let orphans = if search_from_cf { vec![] } else { orphans };
+    let found_count = found.len();
+    let matching = found
+        .into_iter()
+        .filter(|(link_info, _)| filters.matches(link_info))
+        .collect::<Vec<_>>();
+    filtered_out += found_count - matching.len();
+    let return_val: Vec<(DocInfo, f32)> = matching.into_iter().take(max_results).collect();
+    // Chunks come best first, so the first vectors of each result are its best passages
+    let best_vectors = return_val
+        .iter()
+        .flat_map(|(link_info, _)| {
+            let vector_ids = doc_vectors.get(&link_info.id).into_iter().flatten();
+            let scores = doc_scores.get(&link_info.id).into_iter().flatten();
+            vector_ids
+                .zip(scores)
+                .take(MAX_PASSAGES)
+                .map(|(vector_id, score)| (link_info.id.clone(), vector_id.clone(), *score))
+        })
+        .collect::<Vec<_>>();
+    let vector_ids = best_vectors
+        .iter()
+        .map(|(_, vector_id, _)| vector_id.clone())
+        .collect::<Vec<_>>();
+    let passages = match d1::get_chunk_texts(&env, &vector_ids).await {
+        Ok(mut chunks) => {
+            let mut passages: std::collections::HashMap<String, Vec<Passage>> = Default::default();
+            for (id, vector_id, score) in best_vectors {
+                if let Some(text) = chunks.remove(&vector_id) {
+                    passages
+                        .entry(id)
+                        .or_default()
+                        .push(Passage { text, score });
                 }
             }
+            passages
         }
-    });
+        Err(e) => {
+            console_error!("Error fetching the matching passages: {}", e);
+            Default::default()
+        }
+    };
+
+    timings.total_ms = timer.elapsed_ms();
+    console_log!("Search timings: {:?}", timings);
+    metrics::record(&env, "search", timings.total_ms, return_val.len() as f64);
+    Ok(SearchResponse {
+        results: return_val,
+        filtered_out,
+        passages,
+        timings,
+        orphans,
+    })
+}
+
+/// Split the links fetched for the matched documents into the ones found and the vectors of the
+/// ones that are gone
+/// Vectors can outlive their link, those are skipped rather than failing the whole search; links
+/// that failed to load may still exist, so their vectors are kept
+fn split_orphans<T>(
+    results: Vec<(String, Result<Option<T>>)>,
+    doc_vectors: &mut std::collections::HashMap<String, Vec<String>>,
+) -> (Vec<T>, Vec<String>) {
+    let mut found = vec![];
+    let mut orphans = vec![];
+    for (doc_id, result) in results {
+        match result {
+            Ok(Some(link)) => found.push(link),
+            Ok(None) => {
+                console_error!("Link not found, id: {}", doc_id);
+                orphans.extend(doc_vectors.remove(&doc_id).unwrap_or_default());
+            }
+            Err(e) => console_error!("Error fetching link {}: {:?}", doc_id, e),
+        }
+    }
+    (found, orphans)
+}
+
+/// Dampens the weight of the top ranks in reciprocal rank fusion, 60 as in the original paper
+const RRF_K: f32 = 60.0;
+/// Query words matched by the keyword side of hybrid search at most
+const MAX_KEYWORD_TERMS: usize = 8;
+/// Links containing a query word that are read from D1 to rank, at most
+const KEYWORD_CANDIDATES: usize = 200;
+/// Full-text matches read from D1, at most, which binds no more than 100 parameters per query
+const FTS_CANDIDATES: usize = 50;
+
+/// Search like `search_links`, also matching the words of the query in titles and summaries,
+/// which finds exact phrases and rare identifiers like error codes that embeddings blur
+/// The two rankings are merged with reciprocal rank fusion, scores are the fused score scaled
+/// so a link ranked first by both is 1
+pub async fn hybrid_search_links(
+    env: Env,
+    config: &Config,
+    query: &str,
+    filters: &SearchFilters,
+    max_results: usize,
+) -> Result<SearchResponse> {
+    let timer = Timer::start();
+    let candidates = max_results * 4;
+    let (vector, keyword) = futures_util::join!(
+        search_links(env.clone(), config, query, filters, false, candidates),
+        keyword_search(&env, query, filters, candidates)
+    );
+    let mut response = vector?;
+    let (keyword_links, keyword_filtered_out) = keyword?;
+
+    let vector_ranking = response
+        .results
+        .iter()
+        .map(|(link_info, _)| link_info.id.clone())
+        .collect::<Vec<_>>();
+    let keyword_ranking = keyword_links
+        .iter()
+        .map(|link_info| link_info.id.clone())
+        .collect::<Vec<_>>();
+    let fused = reciprocal_rank_fusion(&[vector_ranking, keyword_ranking]);
+    let best = 2.0 / (RRF_K + 1.0);
+
+    let mut links = response
+        .results
+        .drain(..)
+        .map(|(link_info, _)| link_info)
+        .chain(keyword_links)
+        .map(|link_info| (link_info.id.clone(), link_info))
+        .collect::<std::collections::HashMap<_, _>>();
+    response.results = fused
+        .into_iter()
+        .filter_map(|(id, score)| Some((links.remove(&id)?, score / best)))
+        .take(max_results)
+        .collect();
+    // Links filtered out on both sides are counted twice, it only tells whether filters matter
+    response.filtered_out += keyword_filtered_out;
+    response.timings.total_ms = timer.elapsed_ms();
+    Ok(response)
+}
 
-    let results = futures_util::future::join_all(link_futures).await;
-    let return_val: Vec<(DocInfo, f32)> = results
+/// Links whose title or summary contain words of the query, best first, and how many of them
+/// the filters left out
+/// Ranked by bm25 in the full-text index, or with `like_search` until `/upgrade` created it
+async fn keyword_search(
+    env: &Env,
+    query: &str,
+    filters: &SearchFilters,
+    max_results: usize,
+) -> Result<(Vec<DocInfo>, usize)> {
+    let ranked = match d1::fts_search(env, query, FTS_CANDIDATES).await {
+        Ok(matches) => {
+            let ids = matches.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+            let mut links = d1::get_links_by_ids(env, &ids)
+                .await?
+                .into_iter()
+                .map(|link_info| (link_info.id.clone(), link_info))
+                .collect::<std::collections::HashMap<_, _>>();
+            ids.iter().filter_map(|id| links.remove(id)).collect()
+        }
+        Err(e) => {
+            console_error!(
+                "Keyword index unavailable, send /upgrade to create it, matching with LIKE: {}",
+                e
+            );
+            like_search(env, query).await?
+        }
+    };
+    let found = ranked.len();
+    let matching = ranked
         .into_iter()
-        .collect::<Result<Vec<(DocInfo, f32)>>>()?;
+        .filter(|link_info| filters.matches(link_info))
+        .collect::<Vec<_>>();
+    let filtered_out = found - matching.len();
+    Ok((
+        matching.into_iter().take(max_results).collect(),
+        filtered_out,
+    ))
+}
+
+/// Links whose title or summary contain words of the query, best first
+/// A link ranks by the words it contains, those in the title count twice, and the whole query
+/// appearing verbatim counts most
+async fn like_search(env: &Env, query: &str) -> Result<Vec<DocInfo>> {
+    let query = query.to_lowercase();
+    let mut terms: Vec<String> = vec![];
+    for word in query.split_whitespace() {
+        if word.chars().count() > 1 && !terms.iter().any(|term| term == word) {
+            terms.push(word.to_string());
+        }
+    }
+    terms.truncate(MAX_KEYWORD_TERMS);
 
-    Ok(return_val)
+    let links = d1::find_links_containing(env, &terms, KEYWORD_CANDIDATES).await?;
+    let mut ranked = links
+        .into_iter()
+        .map(|link_info| {
+            let title = link_info.title.to_lowercase();
+            let summary = link_info.summary.to_lowercase();
+            let mut score = terms
+                .iter()
+                .map(|term| 2 * title.contains(term) as usize + summary.contains(term) as usize)
+                .sum::<usize>();
+            if terms.len() > 1 && (title.contains(&query) || summary.contains(&query)) {
+                score += 3 * terms.len();
+            }
+            (link_info, score)
+        })
+        .filter(|(_, score)| *score > 0)
+        .collect::<Vec<_>>();
+    ranked.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+    Ok(ranked.into_iter().map(|(link_info, _)| link_info).collect())
 }
 
-/// Delete a link and all associated data
-pub async fn delete_link(env: &Env, link: &str) -> Result<DocInfo> {
-    console_log!("Deleting link: {}", link);
+/// Merge rankings of ids, best first, into one ranked by the sum of `1 / (RRF_K + rank)`
+/// over the rankings each id appears in, with the fused scores
+pub fn reciprocal_rank_fusion(rankings: &[Vec<String>]) -> Vec<(String, f32)> {
+    let mut order: Vec<String> = vec![];
+    let mut scores: std::collections::HashMap<&str, f32> = std::collections::HashMap::new();
+    for ranking in rankings {
+        for (rank, id) in ranking.iter().enumerate() {
+            let score = scores.entry(id).or_insert_with(|| {
+                order.push(id.clone());
+                0.0
+            });
+            *score += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused = order
+        .into_iter()
+        .map(|id| {
+            let score = scores[id.as_str()];
+            (id, score)
+        })
+        .collect::<Vec<_>>();
+    // Stable, so ties keep the order of the first ranking that has them
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    fused
+}
+
+/// Chunk matches of a search collapsed into documents
+struct DocumentMatches {
+    /// Documents ranked by `document_score`, best first
+    ranked: Vec<(String, f32)>,
+    /// Matching vector ids of each document, best first, to clean up if the document is gone
+    vectors: std::collections::HashMap<String, Vec<String>>,
+    /// Scores of those vectors
+    scores: std::collections::HashMap<String, Vec<f32>>,
+}
+
+/// Collapse chunk matches, given best first, into documents
+fn rank_documents(
+    aggregation: ScoreAggregation,
+    vector_results: Vec<(String, f32)>,
+) -> DocumentMatches {
+    let mut doc_order = vec![];
+    let mut doc_vectors: std::collections::HashMap<String, Vec<String>> =
+        std::collections::HashMap::new();
+    let mut doc_scores: std::collections::HashMap<String, Vec<f32>> =
+        std::collections::HashMap::new();
+    for (vector_id, score) in vector_results {
+        let parts = vector_id.split("-").collect::<Vec<_>>();
+        let document_id = parts[0..parts.len() - 1].join("-");
+        let vector_ids = doc_vectors.entry(document_id.clone()).or_default();
+        if vector_ids.is_empty() {
+            doc_order.push(document_id.clone());
+        }
+        vector_ids.push(vector_id);
+        doc_scores.entry(document_id).or_default().push(score);
+    }
+    let mut sorted_docs = doc_order
+        .into_iter()
+        .map(|document_id| {
+            let score = document_score(aggregation, &doc_scores[&document_id]);
+            (document_id, score)
+        })
+        .collect::<Vec<_>>();
+    sorted_docs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    DocumentMatches {
+        ranked: sorted_docs,
+        vectors: doc_vectors,
+        scores: doc_scores,
+    }
+}
+
+/// Chunks summed at most by `ScoreAggregation::SumTopK`
+const SCORE_TOP_K: usize = 3;
+
+/// Combine the scores of a document's matching chunks, given best first
+/// Only chunks among the vector search's top results are known, so a document with many relevant
+/// chunks can still be judged by a few of them
+fn document_score(aggregation: ScoreAggregation, scores: &[f32]) -> f32 {
+    match aggregation {
+        ScoreAggregation::Max => scores.first().copied().unwrap_or(0.0),
+        ScoreAggregation::Mean => scores.iter().sum::<f32>() / scores.len().max(1) as f32,
+        ScoreAggregation::SumTopK => scores.iter().take(SCORE_TOP_K).sum(),
+    }
+}
+
+/// Drop vectors whose link no longer exists from the vector_lite indexes, so drift between D1
+/// and the indexes heals as searches run into it rather than waiting for `/reindex`
+/// Only the matched chunks are known, the rest go once a later search matches them
+/// Runs through `wait_until` after the response is sent, failures are only logged
+pub fn remove_orphan_vectors(ctx: &Context, env: &Env, config: &Config, vector_ids: Vec<String>) {
+    if vector_ids.is_empty() {
+        return;
+    }
+    let env = env.clone();
+    let config = config.clone();
+    ctx.wait_until(async move { remove_vectors_from_indexes(&env, &config, vector_ids).await });
+}
+
+async fn remove_vectors_from_indexes(env: &Env, config: &Config, vector_ids: Vec<String>) {
+    console_log!(
+        "Removing {} orphaned vectors: {:?}",
+        vector_ids.len(),
+        vector_ids
+    );
+    // The link is gone along with its embedding model, so try every index; missing ids are no-ops
+    for space in EmbeddingSpace::active(config) {
+        if let Err(e) = vector_index::update(env, space, vector_ids.clone(), vec![]).await {
+            console_error!(
+                "Error removing orphaned vectors from the {} index: {}",
+                space.name(),
+                e
+            );
+        }
+    }
+}
+
+/// Score added per net good verdict a link got for the same query
+const FEEDBACK_BOOST_PER_VOTE: f32 = 0.02;
+/// Net verdicts counted at most, so feedback nudges the ranking rather than deciding it
+const MAX_FEEDBACK_VOTES: i64 = 3;
+
+/// Key of a query in the feedback table, the same for queries differing in case or spacing
+pub fn query_key(query: &str) -> String {
+    let normalized = query
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ");
+    // FNV-1a, stable across deployments unlike the std hasher
+    let hash = normalized.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Re-rank documents by the verdicts given on earlier results of the same query
+/// Failing to read feedback, e.g. before `/upgrade` created the table, leaves the ranking as is
+async fn apply_feedback(env: &Env, query: &str, docs: &mut [(String, f32)]) {
+    let votes = match d1::get_feedback_votes(env, &query_key(query)).await {
+        Ok(votes) => votes,
+        Err(e) => {
+            console_error!("Error reading search feedback: {}", e);
+            return;
+        }
+    };
+    boost_by_feedback(docs, &votes);
+}
+
+/// Nudge each document's score by its net verdicts for the query, capped, and sort again
+fn boost_by_feedback(docs: &mut [(String, f32)], votes: &std::collections::HashMap<String, i64>) {
+    if votes.is_empty() {
+        return;
+    }
+    for (document_id, score) in docs.iter_mut() {
+        if let Some(votes) = votes.get(document_id) {
+            let votes = (*votes).clamp(-MAX_FEEDBACK_VOTES, MAX_FEEDBACK_VOTES);
+            *score += votes as f32 * FEEDBACK_BOOST_PER_VOTE;
+        }
+    }
+    docs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+}
+
+/// How a URL typed by a user resolved to saved links
+pub enum LinkMatch {
+    Found(Box<DocInfo>),
+    /// Several links could be meant, best guess first
+    Candidates(Vec<DocInfo>),
+    NotFound,
+}
+
+/// Find the saved link a user meant: the exact or normalized URL first, then http/https and
+/// `www.` variants, then the links on the same site sharing the longest URL prefix
+pub async fn resolve_link(env: &Env, url: &str) -> Result<LinkMatch> {
+    const MAX_CANDIDATES: usize = 5;
 
-    let link_info = d1::delete_link_and_embedding_by_url(env, link).await?;
+    if let Ok(link_info) = d1::find_link_by_url(env, url).await {
+        return Ok(LinkMatch::Found(Box::new(link_info)));
+    }
 
-    let mut vector_lite = vector::get_vector_lite(env).await?;
-    for i in 0..link_info.chunk_count {
-        let vector_id = format!("{}-{}", link_info.id, i);
-        vector_lite.delete_by_id(&vector_id);
+    let mut variants = d1::find_links_by_url_variants(env, url).await?;
+    match variants.len() {
+        0 => {}
+        1 => return Ok(LinkMatch::Found(Box::new(variants.remove(0)))),
+        _ => {
+            variants.truncate(MAX_CANDIDATES);
+            return Ok(LinkMatch::Candidates(variants));
+        }
     }
 
-    d1::delete_from_bucket(env, &link_info.bucket_path).await?;
+    // Compare without scheme and `www.` so only the path decides the ranking
+    let strip = |u: &str| {
+        let u = u.split_once("://").map_or(u, |(_, rest)| rest);
+        u.strip_prefix("www.").unwrap_or(u).to_string()
+    };
+    let target = strip(&normalize_url(url));
+    let mut candidates = d1::find_links_on_same_site(env, url)
+        .await?
+        .into_iter()
+        .map(|link_info| {
+            let saved = strip(&normalize_url(&link_info.url));
+            let common = target
+                .chars()
+                .zip(saved.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            (common, link_info)
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|(common, _)| std::cmp::Reverse(*common));
+    let candidates = candidates
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|(_, link_info)| link_info)
+        .collect::<Vec<_>>();
+
+    if candidates.is_empty() {
+        Ok(LinkMatch::NotFound)
+    } else {
+        Ok(LinkMatch::Candidates(candidates))
+    }
+}
 
-    vector::delete_vectors_by_prefix(env, &link_info.id, link_info.chunk_count).await?;
-    vector::save_vector_lite(env, &vector_lite).await?;
+/// Delete a link and all associated data
+pub async fn delete_link(env: &Env, config: &Config, link: &str) -> Result<DocInfo> {
+    console_log!("Deleting link: {}", link);
+    let link_info = d1::find_link_by_url(env, link).await?;
+    delete_in_order(|step| delete_step(env, config, &link_info, step)).await?;
 
     console_log!(
         "Successfully deleted link and all associated data: {}",
@@ -161,3 +2376,666 @@ pub async fn delete_link(env: &Env, link: &str) -> Result<DocInfo> {
 
     Ok(link_info)
 }
+
+/// Delete several links, updating each vector index once for the whole batch
+/// Links that fail to delete are logged and skipped, the deleted ones are returned
+pub async fn delete_links(env: &Env, config: &Config, links: Vec<DocInfo>) -> Result<Vec<DocInfo>> {
+    let mut cleaned = Vec::with_capacity(links.len());
+    let mut vector_ids: std::collections::HashMap<EmbeddingSpace, Vec<String>> =
+        std::collections::HashMap::new();
+    for link_info in links {
+        match delete_link_content(env, config, &link_info).await {
+            Ok(()) => {
+                vector_ids
+                    .entry(link_space(config, &link_info))
+                    .or_default()
+                    .extend(vector::chunk_vector_ids(
+                        &link_info.id,
+                        link_info.chunk_count,
+                    ));
+                cleaned.push(link_info);
+            }
+            Err(e) => console_error!("Error deleting link {}: {}", link_info.url, e),
+        }
+    }
+
+    for (space, ids) in vector_ids {
+        vector_index::update(env, space, ids, vec![]).await?;
+    }
+
+    let mut deleted = Vec::with_capacity(cleaned.len());
+    for link_info in cleaned {
+        match d1::delete_link_and_embedding(env, &link_info).await {
+            Ok(()) => deleted.push(link_info),
+            Err(e) => console_error!("Error deleting link {}: {}", link_info.url, e),
+        }
+    }
+    console_log!("Deleted {} links", deleted.len());
+
+    Ok(deleted)
+}
+
+/// Delete links saved again under a variant of an existing URL, keeping the oldest copy
+pub async fn merge_duplicate_links(env: &Env, config: &Config) -> Result<Vec<DocInfo>> {
+    let duplicates = d1::find_duplicate_links(env).await?;
+    if duplicates.is_empty() {
+        return Ok(vec![]);
+    }
+    console_log!("Merging {} duplicate links", duplicates.len());
+    delete_links(env, config, duplicates).await
+}
+
+/// The places a link's data is deleted from, in the order of `DELETION_STEPS`
+///
+/// Each step succeeds when there is nothing left to delete, and the row stays until everything
+/// else is gone, so if the worker dies halfway, deleting the link again finishes the cleanup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeletionStep {
+    /// The downloaded content in R2
+    Content,
+    Vectorize,
+    /// The vector_lite index of the link's embedding space
+    VectorIndex,
+    /// The link's embeddings and row in D1
+    Row,
+}
+
+const DELETION_STEPS: [DeletionStep; 4] = [
+    DeletionStep::Content,
+    DeletionStep::Vectorize,
+    DeletionStep::VectorIndex,
+    DeletionStep::Row,
+];
+
+/// Run every deletion step in order, stopping at the first that fails
+async fn delete_in_order<F>(mut delete: impl FnMut(DeletionStep) -> F) -> Result<()>
+where
+    F: std::future::Future<Output = Result<()>>,
+{
+    for step in DELETION_STEPS {
+        delete(step).await?;
+    }
+    Ok(())
+}
+
+/// Delete one kind of a link's data, see `DeletionStep`
+async fn delete_step(
+    env: &Env,
+    config: &Config,
+    link_info: &DocInfo,
+    step: DeletionStep,
+) -> Result<()> {
+    match step {
+        DeletionStep::Content => d1::delete_from_bucket(env, &link_info.bucket_path).await,
+        DeletionStep::Vectorize => {
+            vector::delete_vectors_by_prefix(config, &link_info.id, link_info.chunk_count).await
+        }
+        DeletionStep::VectorIndex => vector_index::update(
+            env,
+            link_space(config, link_info),
+            vector::chunk_vector_ids(&link_info.id, link_info.chunk_count),
+            vec![],
+        )
+        .await
+        .map(|_| ()),
+        DeletionStep::Row => d1::delete_link_and_embedding(env, link_info).await,
+    }
+}
+
+/// Delete the content and Vectorize vectors of a link, the first steps of deleting it
+async fn delete_link_content(env: &Env, config: &Config, link_info: &DocInfo) -> Result<()> {
+    delete_step(env, config, link_info, DeletionStep::Content).await?;
+    delete_step(env, config, link_info, DeletionStep::Vectorize).await
+}
+
+/// Delete a link by its id, running the same cleanup as `delete_link`
+pub async fn delete_link_by_id(env: &Env, config: &Config, id: &str) -> Result<DocInfo> {
+    let Some(link_info) = d1::get_link_by_id(env, id).await? else {
+        return Err(Error::from(format!("No link with id {}", id)));
+    };
+    delete_link(env, config, &link_info.url).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_search_filters() {
+        let (query, filters) =
+            SearchFilters::parse("rust after:2024-01-01 async type:PDF before:2024-06-30").unwrap();
+        assert_eq!(query, "rust async");
+        assert_eq!(filters.after.as_deref(), Some("2024-01-01"));
+        assert_eq!(filters.before.as_deref(), Some("2024-06-30"));
+        assert_eq!(filters.content_type.as_deref(), Some("pdf"));
+        assert!(!filters.is_empty());
+
+        let (query, filters) = SearchFilters::parse("  plain   words ").unwrap();
+        assert_eq!(query, "plain words");
+        assert!(filters.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_search_filters() {
+        for query in [
+            "after:2024-1-01",
+            "before:2024-13-01",
+            "after:yesterday",
+            "rust type:",
+        ] {
+            assert!(SearchFilters::parse(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn quota_allows_filling_up_exactly() {
+        assert!(!exceeds_quota(0, 100, 100));
+        assert!(!exceeds_quota(60, 40, 100));
+        assert!(exceeds_quota(60, 41, 100));
+        assert!(exceeds_quota(101, 0, 100));
+        // Sizes near the limit of u64 saturate rather than wrap around
+        assert!(exceeds_quota(u64::MAX, u64::MAX, 100));
+    }
+
+    #[test]
+    fn query_keys_ignore_case_and_spacing() {
+        assert_eq!(
+            query_key("Rust  async\tTraits "),
+            query_key("rust async traits")
+        );
+        assert_ne!(query_key("rust async"), query_key("rust sync"));
+        // FNV-1a of the empty string, stored keys must not change across releases
+        assert_eq!(query_key(""), "cbf29ce484222325");
+        assert_eq!(query_key("a"), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn orphaned_vectors_are_queued_for_removal() {
+        let mut doc_vectors = std::collections::HashMap::from([
+            ("a".to_string(), vec!["a-0".to_string(), "a-1".to_string()]),
+            ("b".to_string(), vec!["b-0".to_string()]),
+            ("c".to_string(), vec!["c-0".to_string()]),
+        ]);
+        let results = vec![
+            ("a".to_string(), Ok(None)),
+            ("b".to_string(), Ok(Some(0.9))),
+            ("c".to_string(), Err(Error::from("D1 unavailable"))),
+        ];
+        let (found, orphans) = split_orphans(results, &mut doc_vectors);
+        assert_eq!(found, vec![0.9]);
+        // A link that failed to load may still exist, so only the missing one is cleaned up
+        assert_eq!(orphans, vec!["a-0", "a-1"]);
+    }
+
+    #[test]
+    fn feedback_lets_a_boosted_doc_overtake_its_neighbour() {
+        let ranking =
+            |docs: &[(String, f32)]| docs.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        let mut docs = vec![
+            ("a".to_string(), 0.80),
+            ("b".to_string(), 0.79),
+            ("c".to_string(), 0.70),
+        ];
+        boost_by_feedback(&mut docs, &Default::default());
+        assert_eq!(ranking(&docs), ["a", "b", "c"]);
+
+        let votes = std::collections::HashMap::from([("b".to_string(), 1), ("a".to_string(), -1)]);
+        boost_by_feedback(&mut docs, &votes);
+        assert_eq!(ranking(&docs), ["b", "a", "c"]);
+        assert!((docs[0].1 - (0.79 + FEEDBACK_BOOST_PER_VOTE)).abs() < 1e-6);
+
+        // Votes are capped, a pile of them can't lift a weak match over a much better one
+        let mut docs = vec![("a".to_string(), 0.90), ("c".to_string(), 0.70)];
+        let votes = std::collections::HashMap::from([("c".to_string(), 100)]);
+        boost_by_feedback(&mut docs, &votes);
+        assert_eq!(ranking(&docs), ["a", "c"]);
+        let max_boost = MAX_FEEDBACK_VOTES as f32 * FEEDBACK_BOOST_PER_VOTE;
+        assert!((docs[1].1 - (0.70 + max_boost)).abs() < 1e-6);
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn fusion_ranks_docs_found_by_both_searches_first() {
+        let fused = reciprocal_rank_fusion(&[ids(&["a", "b", "c"]), ids(&["d", "e", "c"])]);
+        let order = fused.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>();
+        // Last in both lists still beats the top of either one
+        assert_eq!(order, ["c", "a", "d", "b", "e"]);
+        assert_eq!(fused[0].1, 2.0 / (RRF_K + 3.0));
+    }
+
+    #[test]
+    fn fusion_ties_keep_the_first_ranking_order() {
+        let fused = reciprocal_rank_fusion(&[ids(&["a", "b"]), ids(&["b", "a"])]);
+        assert_eq!(fused[0].1, fused[1].1);
+        assert_eq!(fused[0].0, "a");
+        let fused = reciprocal_rank_fusion(&[ids(&["x"]), ids(&["y"])]);
+        assert_eq!(
+            fused.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            ["x", "y"]
+        );
+    }
+
+    #[test]
+    fn fusion_of_empty_rankings() {
+        assert!(reciprocal_rank_fusion(&[]).is_empty());
+        assert!(reciprocal_rank_fusion(&[vec![], vec![]]).is_empty());
+        let fused = reciprocal_rank_fusion(&[vec![], ids(&["a", "b"])]);
+        assert_eq!(
+            fused.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(fused[0].1, 1.0 / (RRF_K + 1.0));
+    }
+
+    #[test]
+    fn aggregations_rank_a_crafted_candidate_set() {
+        let mut chunks = [
+            ("lucky-0", 0.9),
+            ("wide-3", 0.85),
+            ("thorough-1", 0.8),
+            ("thorough-4", 0.75),
+            ("thorough-0", 0.7),
+            ("wide-0", 0.2),
+            ("wide-1", 0.1),
+            ("wide-2", 0.1),
+        ]
+        .map(|(id, score)| (id.to_string(), score))
+        .to_vec();
+        chunks.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let rank = |aggregation| {
+            let matches = rank_documents(aggregation, chunks.clone());
+            matches
+                .ranked
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        // One lucky chunk wins on its own, several good ones win once they add up
+        assert_eq!(rank(ScoreAggregation::Max), ["lucky", "wide", "thorough"]);
+        assert_eq!(rank(ScoreAggregation::Mean), ["lucky", "thorough", "wide"]);
+        assert_eq!(
+            rank(ScoreAggregation::SumTopK),
+            ["thorough", "wide", "lucky"]
+        );
+
+        let matches = rank_documents(ScoreAggregation::Max, chunks);
+        assert_eq!(matches.scores["thorough"], [0.8, 0.75, 0.7]);
+        assert_eq!(
+            matches.vectors["thorough"],
+            ["thorough-1", "thorough-4", "thorough-0"]
+        );
+    }
+
+    #[test]
+    fn document_scores_by_aggregation() {
+        let scores = [0.8, 0.6, 0.4, 0.2];
+        assert_eq!(document_score(ScoreAggregation::Max, &scores), 0.8);
+        assert!((document_score(ScoreAggregation::Mean, &scores) - 0.5).abs() < 1e-6);
+        // Only the best `SCORE_TOP_K` chunks count
+        assert!((document_score(ScoreAggregation::SumTopK, &scores) - 1.8).abs() < 1e-6);
+        for aggregation in [
+            ScoreAggregation::Max,
+            ScoreAggregation::Mean,
+            ScoreAggregation::SumTopK,
+        ] {
+            assert_eq!(document_score(aggregation, &[]), 0.0);
+        }
+    }
+
+    #[test]
+    fn search_pages_are_bounded() {
+        assert_eq!(page_bounds(None, None, "limit"), Ok((0, DEFAULT_API_LIMIT)));
+        assert_eq!(page_bounds(Some("20"), Some("5"), "limit"), Ok((20, 5)));
+        // Pages are clamped to end within the ranked results
+        assert_eq!(page_bounds(Some("45"), Some("10"), "limit"), Ok((45, 5)));
+        assert_eq!(
+            page_bounds(None, Some("500"), "top_k"),
+            Ok((0, MAX_API_RESULTS))
+        );
+        assert_eq!(page_bounds(Some("60"), None, "limit"), Ok((60, 0)));
+        assert!(page_bounds(None, Some("0"), "limit").is_err());
+        assert!(page_bounds(Some("-1"), None, "limit").is_err());
+        assert!(page_bounds(None, Some("ten"), "top_k")
+            .unwrap_err()
+            .contains("top_k"));
+    }
+
+    #[test]
+    fn search_pages_point_to_the_next_one() {
+        let results = (0..25).collect::<Vec<_>>();
+        assert_eq!(page(results.clone(), 0, 10), ((0..10).collect(), Some(10)));
+        assert_eq!(
+            page(results.clone(), 10, 10),
+            ((10..20).collect(), Some(20))
+        );
+        // The last page has no next cursor, even when it's full
+        assert_eq!(page(results.clone(), 20, 10), ((20..25).collect(), None));
+        assert_eq!(page(results.clone(), 15, 10), ((15..25).collect(), None));
+        // Past the end is an empty page rather than an error
+        assert_eq!(page(results.clone(), 30, 10), (vec![], None));
+        assert_eq!(page(Vec::<i32>::new(), 0, 10), (vec![], None));
+    }
+
+    fn doc(id: &str, created_at: &str, content_type: &str, tags: &str) -> DocInfo {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "url": format!("https://example.com/{}", id),
+            "created_at": created_at,
+            "bucket_path": format!("content/{}", id),
+            "content_type": content_type,
+            "size": 1024,
+            "title": id,
+            "summary": "",
+            "chunk_count": 1,
+            "tags": tags,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn search_filters_combine() {
+        let docs = [
+            doc("old-pdf", "2023-12-31 23:59:59", "application/pdf", "work"),
+            doc("pdf", "2024-01-01 00:00:00", "application/pdf", "work,rust"),
+            doc(
+                "untagged-pdf",
+                "2024-03-01T08:00:00Z",
+                "application/pdf",
+                "",
+            ),
+            doc(
+                "page",
+                "2024-03-01 08:00:00",
+                "text/html; charset=utf-8",
+                "work",
+            ),
+            doc("late-pdf", "2024-06-30 00:00:00", "application/pdf", "work"),
+        ];
+        let matching = |q: &str| {
+            let (query, filters) = SearchFilters::parse(q).unwrap();
+            assert_eq!(query, "rust");
+            docs.iter()
+                .filter(|doc| filters.matches(doc))
+                .map(|doc| doc.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            matching("rust type:pdf after:2024-01-01 before:2024-06-30"),
+            ["pdf", "untagged-pdf"]
+        );
+        assert_eq!(
+            matching("rust type:pdf after:2024-01-01 before:2024-06-30 #work"),
+            ["pdf"]
+        );
+        assert_eq!(matching("#work rust type:text/html"), ["page"]);
+        assert_eq!(matching("rust #work #rust"), ["pdf"]);
+        assert!(matching("rust after:2024-07-01 before:2024-01-01").is_empty());
+    }
+
+    #[test]
+    fn bucket_paths_by_content_type() {
+        let flat = crate::config::tests::parse(&[]).unwrap();
+        let categorized =
+            crate::config::tests::parse(&[("CATEGORIZED_BUCKET_PATHS", "true")]).unwrap();
+        for (content_type, flat_path, categorized_path) in [
+            (
+                "text/html; charset=utf-8",
+                "content/id.html",
+                "content/html/id.html",
+            ),
+            ("application/pdf", "content/id.pdf", "content/pdf/id.pdf"),
+            ("image/png", "content/id.png", "content/image/id.png"),
+            ("image/webp", "content/id.bin", "content/image/id.bin"),
+            ("text/plain", "content/id.txt", "content/text/id.txt"),
+            (
+                "application/json",
+                "content/id.json",
+                "content/text/id.json",
+            ),
+            ("application/zip", "content/id.bin", "content/other/id.bin"),
+        ] {
+            assert_eq!(get_bucket_path(&flat, content_type, "id"), flat_path);
+            assert_eq!(
+                get_bucket_path(&categorized, content_type, "id"),
+                categorized_path
+            );
+        }
+    }
+
+    #[test]
+    fn content_categories() {
+        assert_eq!(content_category("text/html"), "html");
+        assert_eq!(content_category("text/html ; charset=utf-8"), "html");
+        assert_eq!(content_category("application/pdf"), "pdf");
+        assert_eq!(content_category("image/svg+xml"), "image");
+        assert_eq!(content_category("text/markdown"), "text");
+        assert_eq!(content_category("application/xml"), "text");
+        assert_eq!(content_category("application/octet-stream"), "other");
+        assert_eq!(content_category(""), "other");
+    }
+
+    /// What is left of a link, deleted like the real stores do: deleting nothing succeeds
+    #[derive(Debug, PartialEq)]
+    struct LinkRemains {
+        steps: Vec<DeletionStep>,
+    }
+
+    impl LinkRemains {
+        fn new() -> Self {
+            Self {
+                steps: DELETION_STEPS.to_vec(),
+            }
+        }
+
+        /// Delete a step's data, then fail if the worker is set to die right after it
+        fn delete(&mut self, step: DeletionStep, die_after: Option<DeletionStep>) -> Result<()> {
+            self.steps.retain(|left| *left != step);
+            match die_after {
+                Some(dying) if dying == step => Err(Error::from("worker died")),
+                _ => Ok(()),
+            }
+        }
+
+        fn run(&mut self, die_after: Option<DeletionStep>) -> Result<()> {
+            use futures_util::FutureExt;
+            delete_in_order(|step| std::future::ready(self.delete(step, die_after)))
+                .now_or_never()
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn deleting_again_finishes_a_partial_deletion() {
+        for dying in DELETION_STEPS {
+            let mut link = LinkRemains::new();
+            assert!(link.run(Some(dying)).is_err());
+            // Everything up to the failure is gone and the rest is left for the re-run
+            let done = DELETION_STEPS
+                .iter()
+                .position(|step| *step == dying)
+                .unwrap()
+                + 1;
+            assert_eq!(link.steps, DELETION_STEPS[done..], "died after {:?}", dying);
+            if dying != DeletionStep::Row {
+                assert!(link.steps.contains(&DeletionStep::Row));
+            }
+            assert!(link.run(None).is_ok(), "died after {:?}", dying);
+            assert!(link.steps.is_empty());
+        }
+    }
+
+    #[test]
+    fn deleting_a_deleted_link_succeeds() {
+        let mut link = LinkRemains::new();
+        assert!(link.run(Some(DeletionStep::Row)).is_err());
+        assert!(link.steps.is_empty());
+        // Failing after the D1 delete leaves nothing behind, running the steps again is a no-op
+        assert!(link.run(None).is_ok());
+        assert!(link.run(None).is_ok());
+        assert!(link.steps.is_empty());
+    }
+
+    #[test]
+    fn a_missing_link_leaves_the_other_results() {
+        let ids = ["a", "b", "c", "d", "e"];
+        let mut doc_vectors = ids
+            .iter()
+            .map(|id| (id.to_string(), vec![format!("{}-0", id)]))
+            .collect();
+        let results = ids
+            .iter()
+            .map(|id| {
+                let link = (*id != "c").then(|| id.to_string());
+                (id.to_string(), Ok(link))
+            })
+            .collect();
+        let (found, orphans) = split_orphans(results, &mut doc_vectors);
+        // The four links left keep their ranking
+        assert_eq!(found, ["a", "b", "d", "e"]);
+        assert_eq!(orphans, ["c-0"]);
+    }
+
+    #[test]
+    fn previews_carry_nothing_of_a_stored_link() {
+        let config = crate::config::tests::parse(&[("MAX_CHUNKS", "2")]).unwrap();
+        let fetched = FetchedContent {
+            content: b"<title>Post</title>".to_vec(),
+            content_type: "text/html; charset=utf-8".to_string(),
+            validators: CacheValidators {
+                etag: Some("\"v1\"".to_string()),
+                last_modified: None,
+            },
+            title: None,
+            author: Some("Ada".to_string()),
+            published_at: None,
+        };
+        let processed = ProcessedLinkData {
+            title: "A post".to_string(),
+            summary: "About it".to_string(),
+            chunks: vec!["one".into(), "two".into(), "three".into()],
+            language: "en".to_string(),
+        };
+        let preview = preview_info(
+            &config,
+            "https://a.com/post",
+            fetched,
+            "text/html".to_string(),
+            processed,
+        );
+        // Nothing that would exist only once the link is written
+        assert!(preview.id.is_empty());
+        assert!(preview.bucket_path.is_empty());
+        assert!(preview.created_at.is_empty());
+        assert_eq!(preview.chat_id, None);
+        assert_eq!(preview.embedding_model, None);
+        // What saving would store
+        assert_eq!(preview.title, "A post");
+        assert_eq!(preview.size, 19);
+        assert_eq!(preview.chunk_count, 2);
+        assert_eq!(preview.original_chunk_count, Some(3));
+        assert_eq!(preview.language.as_deref(), Some("en"));
+        assert_eq!(preview.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(preview.author.as_deref(), Some("Ada"));
+    }
+
+    #[test]
+    fn unsupported_content_is_described_by_type() {
+        for (link, content_type, size, title, summary) in [
+            (
+                "https://a.com/talks/keynote.mp3",
+                "audio/mpeg",
+                3 * 1024 * 1024,
+                "keynote.mp3",
+                "Audio file (audio/mpeg), 3.0 MB, not summarized",
+            ),
+            (
+                "https://a.com/demo.mp4/",
+                "video/mp4",
+                2048,
+                "demo.mp4",
+                "Video file (video/mp4), 2.0 KB, not summarized",
+            ),
+            (
+                "https://a.com/Inter.woff2",
+                "font/woff2",
+                512,
+                "Inter.woff2",
+                "Font file (font/woff2), 512 bytes, not summarized",
+            ),
+            (
+                "https://a.com",
+                "application/zip",
+                10,
+                "a.com",
+                "Binary file (application/zip), 10 bytes, not summarized",
+            ),
+        ] {
+            assert!(!is_summarizable(content_type), "{}", content_type);
+            let described = describe_unsupported(link, content_type, size);
+            assert_eq!(described.title, title);
+            assert_eq!(described.summary, summary);
+            // Found by search through its title and URL
+            assert_eq!(described.chunks, [format!("{}\n{}", title, link)]);
+        }
+    }
+
+    #[test]
+    fn unsupported_content_can_be_rejected() {
+        let metadata = crate::config::tests::parse(&[]).unwrap();
+        let reject = crate::config::tests::parse(&[("UNSUPPORTED_CONTENT", "reject")]).unwrap();
+        for content_type in ["audio/ogg", "video/webm", "application/octet-stream"] {
+            assert!(reject_unsupported(&metadata, content_type).is_ok());
+            assert!(reject_unsupported(&reject, content_type).is_err());
+        }
+        for content_type in ["text/html", "application/pdf", "image/png"] {
+            assert!(is_summarizable(content_type), "{}", content_type);
+            assert!(reject_unsupported(&reject, content_type).is_ok());
+        }
+    }
+
+    #[test]
+    fn expired_content_serves_the_summary() {
+        let mut link_info = doc("kept", "2024-01-01 00:00:00", "application/pdf", "");
+        link_info.summary = "What the paper found".to_string();
+        assert_eq!(expired_content(&link_info), None);
+        link_info.content_expired_at = Some("2024-04-01 03:00:00".to_string());
+        assert_eq!(
+            expired_content(&link_info).as_deref(),
+            Some("Original expired on 2024-04-01 03:00:00, summary available.\n\nkept\n\nWhat the paper found")
+        );
+    }
+
+    #[test]
+    fn questions_are_answered_from_relevant_documents() {
+        let matches = [
+            ("b-2", 0.7),
+            ("a-0", 0.9),
+            ("b-0", 0.8),
+            ("c-1", 0.5),
+            ("a-3", 0.65),
+        ]
+        .map(|(id, score)| (id.to_string(), score))
+        .to_vec();
+        assert_eq!(
+            ask_documents(matches),
+            [
+                ("a".to_string(), vec!["a-0".to_string(), "a-3".to_string()]),
+                ("b".to_string(), vec!["b-0".to_string(), "b-2".to_string()]),
+            ]
+        );
+        // Nothing relevant enough, so there's nothing to answer from
+        let unrelated = vec![("a-0".to_string(), 0.59), ("b-0".to_string(), 0.2)];
+        assert!(ask_documents(unrelated).is_empty());
+        // Chunks of documents past the limit are left out, those of the kept ones still count
+        let many = (0..MAX_ASK_SOURCES + 2)
+            .map(|i| (format!("doc{}-0", i), 0.9 - i as f32 * 0.01))
+            .chain([("doc0-1".to_string(), 0.61)])
+            .collect();
+        let documents = ask_documents(many);
+        assert_eq!(documents.len(), MAX_ASK_SOURCES);
+        assert_eq!(documents[0].1, ["doc0-0", "doc0-1"]);
+    }
+}
@@ -1,14 +1,17 @@
 use worker::*;
+mod config;
 mod d1;
 mod handlers;
+mod metrics;
 mod models;
 mod telegram;
 mod utils;
 mod vector;
+mod vector_index;
 
 // Use the console_error_panic_hook for panic handling
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
     // Get request URL
@@ -17,7 +20,49 @@ async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
 
     match path {
         "/" => Response::ok("Telegram Bot is running!"),
-        "/webhook" => handlers::handle_webhook(req, env).await,
+        "/webhook" => handlers::handle_webhook(req, env, &ctx).await,
+        "/setup" => handlers::handle_setup(req, env).await,
+        "/setup/info" => handlers::handle_setup_info(req, env).await,
+        "/healthz" => handlers::handle_healthz(req, env).await,
+        "/search" => handlers::handle_search(req, env, &ctx).await,
+        "/content" => handlers::handle_content(req, env).await,
+        "/summarize" => handlers::handle_summarize(req, env).await,
+        "/export" => handlers::handle_export(req, env).await,
         _ => Response::error("Not Found", 404),
     }
 }
+
+/// Runs on the cron triggers in `wrangler.toml`, sending the digests of the chats that opted in
+/// on the `DIGEST_CRON` trigger, and deleting content past `CONTENT_RETENTION_DAYS` and checking
+/// for dead links on every trigger
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    console_log!("Running scheduled tasks for cron {}", event.cron());
+    let config = match config::Config::from_env(&env) {
+        Ok(config) => config,
+        Err(e) => {
+            console_error!("Skipping scheduled tasks: {}", e);
+            return;
+        }
+    };
+    if config.is_digest_cron(&event.cron()) {
+        if let Err(e) = telegram::send_digests(env.clone(), &config).await {
+            console_error!("Error sending digests: {}", e);
+        }
+    }
+    match handlers::expire_content(&env, &config).await {
+        Ok(0) => {}
+        Ok(count) => console_log!("Expired the content of {} links", count),
+        Err(e) => console_error!("Error expiring content: {}", e),
+    }
+    match handlers::check_links(&env, &config).await {
+        Ok(dead) => {
+            if let Err(e) = telegram::report_dead_links(&config, &dead).await {
+                console_error!("Error reporting dead links: {}", e);
+            }
+        }
+        Err(e) => console_error!("Error checking links: {}", e),
+    }
+}
@@ -0,0 +1,174 @@
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use worker::*;
+
+/// Optional Analytics Engine binding, metrics are only logged when it is absent
+const METRICS_BINDING: &str = "SEEN_METRICS";
+const BUFFER_SIZE: usize = 200;
+
+/// A single recorded operation
+struct Metric {
+    name: &'static str,
+    duration_ms: f64,
+    value: f64,
+}
+
+thread_local! {
+    // Recent metrics of this isolate, isolates are short-lived so this is only a rough picture
+    static RECENT: RefCell<VecDeque<Metric>> = RefCell::new(VecDeque::with_capacity(BUFFER_SIZE));
+}
+
+/// Measures wall time between I/O operations
+pub struct Timer {
+    start: f64,
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        Self {
+            start: js_sys::Date::now(),
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> f64 {
+        js_sys::Date::now() - self.start
+    }
+}
+
+/// Await a future and return its output together with the elapsed time
+pub async fn timed<F: Future>(future: F) -> (F::Output, f64) {
+    let timer = Timer::start();
+    let output = future.await;
+    (output, timer.elapsed_ms())
+}
+
+/// Wall time of each search stage
+/// The clock only advances across I/O in Workers, so CPU-bound stages may report 0
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SearchTimings {
+    pub embed_ms: f64,
+    pub load_index_ms: f64,
+    pub search_ms: f64,
+    pub fetch_links_ms: f64,
+    pub total_ms: f64,
+}
+
+impl SearchTimings {
+    /// Add the round trip of searching one index, of which `load_index_ms` went to loading it
+    pub fn add_index_search(&mut self, round_trip_ms: f64, load_index_ms: f64) {
+        self.load_index_ms += load_index_ms;
+        self.search_ms += (round_trip_ms - load_index_ms).max(0.0);
+    }
+
+    /// The stages on one line, as `/search --timing` shows them
+    pub fn breakdown(&self) -> String {
+        format!(
+            "embed {:.0} ms, load index {:.0} ms, search {:.0} ms, fetch links {:.0} ms, total {:.0} ms",
+            self.embed_ms, self.load_index_ms, self.search_ms, self.fetch_links_ms, self.total_ms
+        )
+    }
+}
+
+/// Record an operation's latency and an associated value (e.g. chunk or token count)
+pub fn record(env: &Env, name: &'static str, duration_ms: f64, value: f64) {
+    console_log!(
+        "{}",
+        json!({ "metric": name, "duration_ms": duration_ms, "value": value })
+    );
+
+    RECENT.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        if recent.len() == BUFFER_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(Metric {
+            name,
+            duration_ms,
+            value,
+        });
+    });
+
+    if let Ok(dataset) = env.analytics_engine(METRICS_BINDING) {
+        let data_point = AnalyticsEngineDataPointBuilder::new()
+            .indexes([name])
+            .add_blob(name)
+            .add_double(duration_ms)
+            .add_double(value)
+            .build();
+        if let Err(e) = dataset.write_data_point(&data_point) {
+            console_error!("Failed to write metric {}: {}", name, e);
+        }
+    }
+}
+
+/// Aggregated view of the recent metrics of one operation
+pub struct MetricSummary {
+    pub name: &'static str,
+    pub count: usize,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    pub avg_value: f64,
+}
+
+/// Summarize the rolling buffer per operation name
+pub fn summary() -> Vec<MetricSummary> {
+    RECENT.with(|recent| {
+        let mut grouped: BTreeMap<&'static str, Vec<(f64, f64)>> = BTreeMap::new();
+        for metric in recent.borrow().iter() {
+            grouped
+                .entry(metric.name)
+                .or_default()
+                .push((metric.duration_ms, metric.value));
+        }
+
+        grouped
+            .into_iter()
+            .map(|(name, samples)| {
+                let count = samples.len();
+                MetricSummary {
+                    name,
+                    count,
+                    avg_ms: samples.iter().map(|s| s.0).sum::<f64>() / count as f64,
+                    max_ms: samples.iter().map(|s| s.0).fold(0.0, f64::max),
+                    avg_value: samples.iter().map(|s| s.1).sum::<f64>() / count as f64,
+                }
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_searches_split_loading_from_searching() {
+        let mut timings = SearchTimings::default();
+        // A cold object loads the index, a warm one only searches
+        timings.add_index_search(120.0, 100.0);
+        timings.add_index_search(15.0, 0.0);
+        assert_eq!(timings.load_index_ms, 100.0);
+        assert_eq!(timings.search_ms, 35.0);
+        // Clocks that only move across I/O can't make the search negative
+        timings.add_index_search(0.0, 5.0);
+        assert_eq!(timings.load_index_ms, 105.0);
+        assert_eq!(timings.search_ms, 35.0);
+    }
+
+    #[test]
+    fn breakdown_shows_every_stage() {
+        let timings = SearchTimings {
+            embed_ms: 40.4,
+            load_index_ms: 100.0,
+            search_ms: 12.6,
+            fetch_links_ms: 30.0,
+            total_ms: 183.0,
+        };
+        assert_eq!(
+            timings.breakdown(),
+            "embed 40 ms, load index 100 ms, search 13 ms, fetch links 30 ms, total 183 ms"
+        );
+    }
+}
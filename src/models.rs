@@ -6,6 +6,31 @@ pub struct Update {
     pub update_id: i64,
     #[serde(default)]
     pub message: Option<Message>,
+    #[serde(default)]
+    pub inline_query: Option<InlineQuery>,
+    #[serde(default)]
+    pub callback_query: Option<CallbackQuery>,
+    /// Update types the bot doesn't handle yet, e.g. `my_chat_member`, kept so they can be logged
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Update {
+    /// Name of the update type Telegram sent, the single field besides `update_id`
+    pub fn kind(&self) -> &str {
+        if self.message.is_some() {
+            "message"
+        } else if self.inline_query.is_some() {
+            "inline_query"
+        } else if self.callback_query.is_some() {
+            "callback_query"
+        } else {
+            self.other
+                .keys()
+                .next()
+                .map_or("unknown", |key| key.as_str())
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -16,6 +41,109 @@ pub struct Message {
     pub text: Option<String>,
     #[serde(default)]
     pub from: Option<User>,
+    #[serde(default)]
+    pub entities: Vec<MessageEntity>,
+    /// Text of a photo or document, which comes instead of `text`
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub caption_entities: Vec<MessageEntity>,
+}
+
+/// Formatting of a span of the message text, e.g. a link
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageEntity {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Offset and length in UTF-16 code units
+    pub offset: usize,
+    pub length: usize,
+    /// Target of a `text_link`, whose text can be anything
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+impl Message {
+    /// The text of the message, or the caption of a photo or document
+    pub fn text_or_caption(&self) -> Option<&str> {
+        self.text.as_deref().or(self.caption.as_deref())
+    }
+
+    /// URLs Telegram detected in the text or caption, and the targets of formatted links, in order
+    /// Falls back to scanning for http(s) URLs, e.g. in forwarded messages that lost their entities
+    pub fn urls(&self) -> Vec<String> {
+        let entities = if self.text.is_some() {
+            &self.entities
+        } else {
+            &self.caption_entities
+        };
+        let text = self
+            .text_or_caption()
+            .unwrap_or("")
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        let mut urls = vec![];
+        for entity in entities {
+            let url = match entity.kind.as_str() {
+                "text_link" => entity.url.clone(),
+                "url" => text
+                    .get(entity.offset..entity.offset + entity.length)
+                    .map(String::from_utf16_lossy),
+                _ => None,
+            };
+            if let Some(url) = url.filter(|url| !urls.contains(url)) {
+                urls.push(url);
+            }
+        }
+        if urls.is_empty() {
+            urls = scan_urls(self.text_or_caption().unwrap_or(""));
+        }
+        urls
+    }
+}
+
+/// http(s) URLs in free text, without the punctuation that usually follows a URL in a sentence
+fn scan_urls(text: &str) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for word in text.split_whitespace() {
+        let Some(start) = word.find("https://").or_else(|| word.find("http://")) else {
+            continue;
+        };
+        let mut url = &word[start..];
+        // A closing parenthesis belongs to the URL when it has a matching one, as on Wikipedia
+        while let Some(last) = url.chars().last() {
+            let trailing = match last {
+                ')' => url.matches(')').count() > url.matches('(').count(),
+                '.' | ',' | ';' | ':' | '!' | '?' | ']' | '"' | '\'' | '>' => true,
+                _ => false,
+            };
+            if !trailing {
+                break;
+            }
+            url = &url[..url.len() - last.len_utf8()];
+        }
+        if url.len() > "https://".len() && !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InlineQuery {
+    pub id: String,
+    pub from: User,
+    pub query: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    #[serde(default)]
+    pub message: Option<Message>,
+    #[serde(default)]
+    pub data: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -90,9 +218,27 @@ pub struct VectorQueryResult {
 #[derive(Deserialize)]
 pub struct VectorMatch {
     pub id: String,
+    /// Raw score in the metric the index was created with, see `vector::similarity`
     pub score: f32,
 }
 
+#[derive(Deserialize)]
+pub struct VectorIndexResponse {
+    pub result: VectorIndexInfo,
+    pub success: bool,
+}
+
+#[derive(Deserialize)]
+pub struct VectorIndexInfo {
+    pub config: VectorIndexConfig,
+}
+
+#[derive(Deserialize)]
+pub struct VectorIndexConfig {
+    /// `cosine`, `euclidean` or `dot-product`, fixed when the index is created
+    pub metric: String,
+}
+
 #[derive(Deserialize)]
 pub struct VectorGetResponse {
     pub result: Vec<VectorGetResult>,
@@ -103,3 +249,78 @@ pub struct VectorGetResponse {
 pub struct VectorGetResult {
     pub values: Vec<f32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str, entities: serde_json::Value) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "chat": { "id": 1 },
+            "text": text,
+            "entities": entities,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn urls_of_text_links_come_from_the_entity() {
+        // The text says nothing of the URL, only the entity has it
+        let message = message(
+            "Read this article",
+            serde_json::json!([
+                { "type": "bold", "offset": 0, "length": 4 },
+                { "type": "text_link", "offset": 5, "length": 12, "url": "https://a.com/post" },
+            ]),
+        );
+        assert_eq!(message.urls(), ["https://a.com/post"]);
+    }
+
+    #[test]
+    fn url_entities_are_cut_from_the_text_in_utf16() {
+        // 🎉 is two UTF-16 code units, so the offsets are past it in UTF-16 rather than bytes
+        let message = message(
+            "🎉 see https://a.com/x and https://b.com/y",
+            serde_json::json!([
+                { "type": "url", "offset": 7, "length": 15 },
+                { "type": "text_link", "offset": 23, "length": 3, "url": "https://c.com/" },
+                { "type": "url", "offset": 27, "length": 15 },
+                { "type": "url", "offset": 7, "length": 15 },
+            ]),
+        );
+        assert_eq!(
+            message.urls(),
+            ["https://a.com/x", "https://c.com/", "https://b.com/y"]
+        );
+    }
+
+    #[test]
+    fn urls_are_scanned_without_entities() {
+        let message = message(
+            "Forwarded: (see https://en.wikipedia.org/wiki/Rust_(language)), https://a.com.",
+            serde_json::json!([]),
+        );
+        assert_eq!(
+            message.urls(),
+            [
+                "https://en.wikipedia.org/wiki/Rust_(language)",
+                "https://a.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn captions_use_their_own_entities() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "chat": { "id": 1 },
+            "caption": "Slides",
+            "caption_entities": [
+                { "type": "text_link", "offset": 0, "length": 6, "url": "https://a.com/slides.pdf" },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(message.urls(), ["https://a.com/slides.pdf"]);
+    }
+}
@@ -1,103 +1,409 @@
 use crate::{
-    d1::{read_from_bucket, save_to_bucket, DocInfo},
-    models::Update,
+    config::{Config, ScoreFormat},
+    d1::DocInfo,
+    handlers::{ExportFormat, InsertOutcome, LinkMatch, SearchFilters},
+    models::{CallbackQuery, InlineQuery, Message, Update},
+    utils::{PageRange, SummaryStyle},
     vector,
 };
 use serde_json::json;
-use vector_lite::ANNIndexOwned;
 use wasm_bindgen::JsValue;
 use worker::*;
 
 // Telegram API constants
-const BOT_TOKEN: &str = "BOT_TOKEN";
 const TELEGRAM_API_BASE: &str = "https://api.telegram.org/bot";
+/// Links shown per search, in a chat and inline
+const SEARCH_RESULTS: usize = 5;
+/// Longest excerpt of the matching passage shown under a search result, in graphemes
+const SEARCH_PASSAGE_LEN: usize = 240;
+/// Shorter for `--deep`, which shows several passages per result and must fit one message
+const DEEP_PASSAGE_LEN: usize = 120;
 
-fn get_authorized_chat_ids(env: Env) -> Vec<i64> {
-    let authorized_chat_ids_str = env.var("AUTHORIZED_CHAT_IDS").unwrap();
+/// Lowercase the command of a message and drop the `@bot_username` groups add to it, so
+/// `/Search@SeenBot rust` dispatches like `/search rust`; the arguments are kept as sent
+/// In groups Telegram only delivers commands that mention this bot, unless privacy mode is off
+fn normalize_command(text: &str) -> String {
+    if !text.starts_with('/') {
+        return text.to_string();
+    }
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let (command, rest) = text.split_at(end);
+    let command = command
+        .split_once('@')
+        .map_or(command, |(command, _)| command);
+    format!("{}{}", command.to_lowercase(), rest)
+}
+
+/// Commands that call Gemini or Workers AI, counted against `RATE_LIMIT`
+/// `/similar` and `/get` make no paid calls, they search with a stored embedding and read the bucket
+const METERED_COMMANDS: &[&str] = &[
+    "/search",
+    "/insert",
+    "/ask",
+    "/preview",
+    "/compare",
+    "/summary",
+    "/retype",
+    "/repair",
+    "/reprocess",
+    "/refresh",
+];
+
+/// Whether a message costs Gemini or Workers AI calls, text that isn't a command saves its URLs
+/// or is searched for
+fn is_metered(text: &str) -> bool {
+    let command = text.split_whitespace().next().unwrap_or("");
+    !text.starts_with('/') || METERED_COMMANDS.contains(&command)
+}
 
-    authorized_chat_ids_str
-        .to_string()
-        .split(',')
-        .filter_map(|id_str| id_str.trim().parse::<i64>().ok())
-        .collect()
+/// Count a metered request against `RATE_LIMIT`, false when the chat is over it
+/// Inline queries and the buttons that process a link again count too, they cost the same calls
+async fn within_rate_limit(env: &Env, config: &Config, chat_id: i64) -> bool {
+    let Some(limit) = config.rate_limit else {
+        return true;
+    };
+    match crate::d1::allow_request(env, chat_id, limit, config.rate_limit_window_secs).await {
+        Ok(allowed) => allowed,
+        // A broken limiter shouldn't lock everyone out
+        Err(e) => {
+            console_error!("Error checking the rate limit of {}: {}", chat_id, e);
+            true
+        }
+    }
 }
 
-fn check_id(env: Env, id: i64) -> bool {
-    get_authorized_chat_ids(env).contains(&id)
+fn slow_down_message(config: &Config) -> String {
+    format!(
+        "🐢 Slow down, at most {} searches or saved links per {} seconds. Try again in a moment",
+        config.rate_limit.unwrap_or_default(),
+        config.rate_limit_window_secs
+    )
 }
 
 /// Processes an update from Telegram webhook
-pub async fn process_update(env: Env, update: Update) -> Result<()> {
-    let token = env.secret(BOT_TOKEN)?.to_string();
+pub async fn process_update(
+    env: Env,
+    config: &Config,
+    ctx: &Context,
+    update: Update,
+) -> Result<()> {
+    let token = &config.bot_token;
+
+    if let Some(inline_query) = &update.inline_query {
+        return answer_inline_query(env, config, ctx, inline_query).await;
+    }
+    if let Some(callback_query) = &update.callback_query {
+        return handle_callback_query(env, config, callback_query).await;
+    }
 
     let Some(message) = &update.message else {
+        console_debug!(
+            "Ignoring unhandled update {} of type {}",
+            update.update_id,
+            update.kind()
+        );
         return Ok(());
     };
 
     let chat_id = message.chat.id;
-    let Some(text) = &message.text else {
+    let Some(text) = message.text_or_caption() else {
+        console_debug!(
+            "Ignoring update {}, message from chat_id {} has no text",
+            update.update_id,
+            chat_id
+        );
         return Ok(());
     };
 
     console_log!("Received message: {} from chat_id: {}", text, chat_id);
 
-    if !check_id(env.clone(), chat_id) {
+    // Answered before the authorization check, it's how a new user learns what to send the owner
+    if normalize_command(text) == "/whoami" {
+        return send_message(token, chat_id, &whoami(config, message)).await;
+    }
+
+    if !config.is_authorized(chat_id) {
         let message = format!(
             "Sorry, you are not authorized to use this bot. Send this message to bot owner to get access:<pre>{:#?}</pre>",
             update.message.as_ref().unwrap()
         );
-        send_message(&token, chat_id, message.as_str()).await?;
+        send_message(token, chat_id, message.as_str()).await?;
         return Ok(());
     }
 
     // Chat is authorized, process commands
+    let urls = message.urls();
+    let text = &normalize_command(text);
+    if is_metered(text) && !within_rate_limit(&env, config, chat_id).await {
+        send_message(token, chat_id, &slow_down_message(config)).await?;
+        return Ok(());
+    }
     let response = match text.as_str() {
         "/start" => "Hello! I'm Seen, your knowledge assistant!".to_string(),
         "/help" => html_escape::encode_text(
             "Available commands:
 /start - Start the bot
 /help - Show this help message
-/list - Show link statistics
-/search <query> - Search through saved links
-/delete <url> - Delete a saved link
+/whoami - Show your chat id and whether you may use the bot
+/list [page] - Show your saved links and storage usage, 10 links per page
+/stats - Show totals, links per content type and the vector index size
+/random [n] - Show a random saved link, or n of them, to rediscover old saves
+/similar <url> - Find saved links similar to a saved link
+/search <query> - Search through saved links, add --timing to show where the time went
+    or --explain to show the raw similarity scores, or --deep to show every matching passage
+    or after:YYYY-MM-DD / before:YYYY-MM-DD to filter by save date, type:pdf to filter by type,
+    #tag to only search links with that tag
+/search hybrid <query> - Also match the words of the query in titles and summaries, for exact
+    phrases and identifiers like error codes
+👍/👎 under a search result - Rank it higher or lower the next time you search the same query
+/insert <url> [pages=1-20] - Save a link, only processing those pages of a PDF
+/delete <url|#tag> [...] - Delete saved links, or every link with a tag, after confirming
+/delete_id <id> - Delete a saved link by its id
 /delete_vector <id> - Delete a vector by id
-/upgrade - Upgrade vector index
+/move_vector <old_id> <new_id> - Move the vectors of a regenerated document id to its link (owner only)
+/retype <url> <type> - Fix a link's content type and reprocess it (owner only)
+/reprocess <url> - Summarize and chunk a saved link again from its stored content (owner only)
+/refresh <url> - Download a saved link again and reprocess it if the page changed (owner only)
+/preview <url> - Show what saving a link would store, without saving it
+/compare <url1> <url2> - Compare two saved links
+/summary <url> - Show the stored summary of a saved link
+/get <url> - Send the saved copy of a link as a file, e.g. when the original is gone
+/tag <url> <tag1,tag2> - Set the tags of a saved link, shown by {tags} in /template
+/ask <question> - Answer a question from your saved links, citing them
+/debug <url> - Show how a link was stored (owner only)
+/config - Show the effective settings, secrets redacted (owner only)
+/broadcast <text> - Send a message to every authorized chat (owner only)
+/export [json|ndjson] - Save all links as JSON to the bucket for a backup (owner only)
+/import <path> - Restore the links of an export that aren't saved (owner only)
+/upgrade - Migrate the database and vector index, merging duplicate links
+/index_info - Show vector index statistics
+/perf - Show recent operation timings
+/style [preset] - Show or set how summaries of your links are written
+/template [format|reset] - Show or set how /search and /list show each link
+/digest [on|off] - Show or set whether you get a regular digest of your new links
+/queue - List links whose processing failed
+/repair - Retry processing the links in /queue
+/reindex - Rebuild the vector index from stored embeddings
+/check_index - Compare the vector index with the stored embeddings
 Or simply send a URL to save it, or any text to search for it.",
         )
         .to_string(),
-        "/list" => list_links(env).await,
-        "/upgrade" => match upgrade_vector_index(env).await {
-            Ok((total_ids, migrated)) => format!(
-                "Vector index upgraded. Total IDs: {}, Migrated: {}",
-                total_ids, migrated
-            ),
-            Err(e) => format!("Error upgrading vector index: {}", e),
+        "/list" => list_links(env, config, chat_id, 1).await,
+        "/stats" => show_stats(env, config).await,
+        "/random" => random_links(env, token, chat_id, 1).await,
+        "/upgrade" => upgrade(env, config).await,
+        "/index_info" => index_info(env, config).await,
+        "/check_index" => check_index(env, config).await,
+        "/style" => show_summary_style(env, chat_id).await,
+        "/template" => show_result_template(env, chat_id).await,
+        "/digest" => show_digest(env, chat_id).await,
+        "/digest on" | "/digest off" => set_digest(env, chat_id, text.ends_with("on")).await,
+        "/queue" => show_queue(env).await,
+        "/repair" => repair_links(env, config, chat_id).await,
+        "/perf" => format_perf(),
+        "/config" => {
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can view the configuration".to_string()
+            } else {
+                show_config(config)
+            }
+        }
+        "/export" | "/export json" | "/export ndjson" => {
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can export links".to_string()
+            } else if text.ends_with("ndjson") {
+                export_links(env, ExportFormat::Ndjson).await
+            } else {
+                export_links(env, ExportFormat::Json).await
+            }
+        }
+        "/reindex" => match vector::rebuild_vector_lite(&env, config).await {
+            Ok(count) => format!("Vector index rebuilt with {} vectors", count),
+            Err(e) => format!("Error rebuilding vector index: {}", e),
         },
+        _ if text.starts_with("/delete_id ") => {
+            let id = text[11..].trim();
+            if id.is_empty() {
+                "Please provide a link id to delete, e.g., '/delete_id 123'".to_string()
+            } else {
+                delete_link_by_id(env, config, id).await
+            }
+        }
         _ if text.starts_with("/delete_vector ") => {
             let id = &text[15..].trim();
             if id.is_empty() {
                 "Please provide a vector id to delete, e.g., '/delete_vector 123'".to_string()
             } else {
-                delete_vector(env, id).await
+                delete_vector(env, config, id).await
+            }
+        }
+        _ if text.starts_with("/move_vector ") => {
+            let ids = text[13..].split_whitespace().collect::<Vec<_>>();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can move vectors".to_string()
+            } else if let [old_id, new_id] = ids.as_slice() {
+                move_vector(env, config, old_id, new_id).await
+            } else {
+                "Please provide the old and the new document id, e.g., '/move_vector 123 456'"
+                    .to_string()
+            }
+        }
+        _ if text.starts_with("/insert ") => match text[8..].split_whitespace().collect::<Vec<_>>()[..] {
+            [url] => insert_link(env, config, token, chat_id, url, None).await,
+            [url, pages] if pages.starts_with("pages=") => match PageRange::parse(&pages[6..]) {
+                Ok(pages) => insert_link(env, config, token, chat_id, url, Some(pages)).await,
+                Err(e) => e.to_string(),
+            },
+            _ => "Please provide a URL to insert, e.g., '/insert https://example.com', add pages=1-20 to only process those pages of a PDF".to_string(),
+        },
+        _ if text.starts_with("/import ") => {
+            let bucket_path = text[8..].trim();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can import links".to_string()
+            } else if bucket_path.is_empty() {
+                "Please provide the path of an export, e.g., '/import exports/2025-01-01T00-00-00.000Z.json'".to_string()
+            } else {
+                import_links(env, config, chat_id, bucket_path).await
+            }
+        }
+        _ if text.starts_with("/retype ") => {
+            let args = text[8..].split_whitespace().collect::<Vec<_>>();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can retype links".to_string()
+            } else if args.len() != 2 || !args[1].contains('/') {
+                "Please provide a URL and a content type, e.g., '/retype https://example.com/paper application/pdf'".to_string()
+            } else {
+                retype_link(env, config, chat_id, args[0], args[1]).await
+            }
+        }
+        _ if text.starts_with("/reprocess ") => {
+            let url = text[11..].trim();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can reprocess links".to_string()
+            } else if url.is_empty() {
+                "Please provide a URL to reprocess, e.g., '/reprocess https://example.com'".to_string()
+            } else {
+                reprocess_link(env, config, chat_id, url).await
+            }
+        }
+        _ if text.starts_with("/refresh ") => {
+            let url = text[9..].trim();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can refresh links".to_string()
+            } else if url.is_empty() {
+                "Please provide a URL to refresh, e.g., '/refresh https://example.com'".to_string()
+            } else {
+                refresh_link(env, config, chat_id, url).await
+            }
+        }
+        _ if text.starts_with("/preview ") => {
+            let url = text[9..].trim();
+            if url.is_empty() {
+                "Please provide a URL to preview, e.g., '/preview https://example.com'".to_string()
+            } else {
+                preview_link(env, config, chat_id, url).await
+            }
+        }
+        _ if text.starts_with("/ask ") => {
+            let question = text[5..].trim();
+            if question.is_empty() {
+                "Please provide a question, e.g., '/ask how does rust handle async?'".to_string()
+            } else {
+                ask_question(env, config, question).await
+            }
+        }
+        _ if text.starts_with("/list ") => match text[6..].trim().parse::<usize>() {
+            Ok(page) => list_links(env, config, chat_id, page).await,
+            Err(_) => "Please provide a page number, e.g., '/list 2'".to_string(),
+        },
+        _ if text.starts_with("/similar ") => {
+            let url = text[9..].trim();
+            if url.is_empty() {
+                "Please provide a saved URL, e.g., '/similar https://example.com'".to_string()
+            } else {
+                similar_links(env, config, chat_id, url).await
+            }
+        }
+        _ if text.starts_with("/random ") => match text[8..].trim().parse::<usize>() {
+            Ok(count) if (1..=MAX_RANDOM_LINKS).contains(&count) => {
+                random_links(env, token, chat_id, count).await
+            }
+            _ => format!(
+                "Please provide a number of links from 1 to {}, e.g., '/random 3'",
+                MAX_RANDOM_LINKS
+            ),
+        },
+        _ if text.starts_with("/get ") => {
+            let url = text[5..].trim();
+            if url.is_empty() {
+                "Please provide a saved URL, e.g., '/get https://example.com'".to_string()
+            } else {
+                send_saved_copy(env, token, chat_id, url).await
             }
         }
-        _ if text.starts_with("/insert ") => {
-            let url = &text[8..].trim();
+        _ if text.starts_with("/summary ") => {
+            let url = text[9..].trim();
             if url.is_empty() {
-                "Please provide a URL to insert, e.g., '/insert https://example.com'".to_string()
+                "Please provide a saved URL, e.g., '/summary https://example.com'".to_string()
             } else {
-                insert_link(env, url).await
+                show_summary(env, config, ctx, url).await
             }
         }
+        _ if text.starts_with("/tag ") => match text[5..].trim().split_once(char::is_whitespace) {
+            Some((url, tags)) => tag_link(env, url, tags).await,
+            None => "Please provide a saved URL and tags, e.g., '/tag https://example.com rust,async'"
+                .to_string(),
+        },
+        _ if text.starts_with("/compare ") => {
+            let urls = text[9..].split_whitespace().collect::<Vec<_>>();
+            match urls.as_slice() {
+                [first, second] => compare_links(env, config, first, second).await,
+                _ => "Please provide two saved URLs to compare, e.g., '/compare https://example.com/a https://example.com/b'".to_string(),
+            }
+        }
+        _ if text.starts_with("/debug ") => {
+            let url = text[7..].trim();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can debug links".to_string()
+            } else if url.is_empty() {
+                "Please provide a URL to debug, e.g., '/debug https://example.com'".to_string()
+            } else {
+                debug_link(env, url).await
+            }
+        }
+        _ if text.starts_with("/template ") => set_result_template(env, chat_id, &text[10..]).await,
+        _ if text.starts_with("/style ") => set_summary_style(env, chat_id, &text[7..]).await,
+        _ if text.starts_with("/broadcast ") => {
+            let message = text[11..].trim();
+            if !config.is_owner(chat_id) {
+                "Only the bot owner can broadcast".to_string()
+            } else if message.is_empty() {
+                "Please provide a message to broadcast, e.g., '/broadcast Maintenance tonight'"
+                    .to_string()
+            } else {
+                broadcast(config, message).await
+            }
+        }
+        _ if !text.starts_with('/') && !urls.is_empty() => {
+            insert_links(env, config, token, chat_id, &urls).await
+        }
         _ if text.starts_with("http://") || text.starts_with("https://") => {
-            insert_link(env, text).await
+            insert_link(env, config, token, chat_id, text, None).await
         }
         _ if text.starts_with("/search cf ") => {
             let query = &text[11..];
             if query.trim().is_empty() {
                 "Please provide a search query, e.g., '/search cf cloudflare'".to_string()
             } else {
-                search_query(env, query, true).await
+                search_query(env, config, ctx, token, chat_id, query, SearchBackend::Vectorize).await
+            }
+        }
+        _ if text.starts_with("/search hybrid ") => {
+            let query = &text[15..];
+            if query.trim().is_empty() {
+                "Please provide a search query, e.g., '/search hybrid E0502'".to_string()
+            } else {
+                search_query(env, config, ctx, token, chat_id, query, SearchBackend::Hybrid).await
             }
         }
         _ if text.starts_with("/search ") => {
@@ -105,57 +411,208 @@ Or simply send a URL to save it, or any text to search for it.",
             if query.trim().is_empty() {
                 "Please provide a search query, e.g., '/search cloudflare'".to_string()
             } else {
-                search_query(env, query, false).await
+                search_query(env, config, ctx, token, chat_id, query, SearchBackend::VectorLite).await
             }
         }
         _ if text.starts_with("/delete ") => {
-            let url = &text[8..].trim();
-            if url.is_empty() {
-                "Please provide a URL to delete, e.g., '/delete https://example.com'".to_string()
-            } else {
-                delete_link(env, url).await
+            let targets = text[8..].split_whitespace().collect::<Vec<_>>();
+            match targets.as_slice() {
+                [] => "Please provide a URL or #tag to delete, e.g., '/delete https://example.com'"
+                    .to_string(),
+                [url] if !url.starts_with('#') => delete_link(env, token, chat_id, url).await,
+                _ => confirm_delete_links(env, token, chat_id, &targets).await,
             }
         }
-        _ => search_query(env, text, false).await,
+        _ => search_query(env, config, ctx, token, chat_id, text, SearchBackend::VectorLite).await,
     };
 
-    // Send the response back to the user
-    send_message(&token, chat_id, response.as_str()).await?;
+    // Send the response back to the user, unless the command already replied itself
+    if !response.is_empty() {
+        send_message(token, chat_id, response.as_str()).await?;
+    }
 
     Ok(())
 }
 
-pub async fn delete_vector(env: Env, id: &str) -> String {
-    vector::delete_vectors_by_prefix(&env, id, 10)
-        .await
-        .unwrap();
-    let mut vector_lite = vector::get_vector_lite(&env).await.unwrap();
-    for i in 0..10 {
-        vector_lite.delete_by_id(&format!("{}-{}", id, i));
+pub async fn delete_vector(env: Env, config: &Config, id: &str) -> String {
+    if let Err(e) = vector::delete_vectors_by_prefix(config, id, 10).await {
+        console_error!("Error deleting vectors of {}: {}", id, e);
+        return format!("Error deleting vectors: {}", e);
+    }
+    for space in vector::EmbeddingSpace::active(config) {
+        let ids = vector::chunk_vector_ids(id, 10);
+        if let Err(e) = crate::vector_index::update(&env, space, ids, vec![]).await {
+            console_error!("Error deleting vectors of {}: {}", id, e);
+            return format!("Error deleting vectors: {}", e);
+        }
     }
-    vector::save_vector_lite(&env, &vector_lite).await.unwrap();
     "Vector deleted".to_string()
 }
 
-pub async fn upgrade_vector_index(env: Env) -> Result<(usize, usize)> {
-    let mut index = match read_from_bucket(&env, "vector_lite.bin").await {
-        Ok(existing) => vector_lite::VectorLite::<768>::from_bytes(&existing),
-        Err(_e) => vector_lite::VectorLite::<768>::new(4, 20),
+async fn move_vector(env: Env, config: &Config, old_id: &str, new_id: &str) -> String {
+    console_log!("Moving vectors of {} to {}", old_id, new_id);
+    match crate::handlers::move_vectors(&env, config, old_id, new_id).await {
+        Ok(count) => format!(
+            "Moved {} vectors from <code>{}</code> to <code>{}</code>",
+            count,
+            html_escape::encode_text(old_id),
+            html_escape::encode_text(new_id)
+        ),
+        Err(e) => {
+            console_error!("Error moving vectors of {} to {}: {}", old_id, new_id, e);
+            format!(
+                "Error moving vectors: {}",
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+fn format_perf() -> String {
+    let summary = crate::metrics::summary();
+    if summary.is_empty() {
+        return "No operations recorded yet on this worker instance".to_string();
+    }
+
+    let mut ret = "⏱️ Recent operations on this worker instance\n\n".to_string();
+    for metric in summary {
+        ret.push_str(&format!(
+            "<b>{}</b>: {} calls, avg {:.0} ms, max {:.0} ms, avg value {:.1}\n",
+            metric.name, metric.count, metric.avg_ms, metric.max_ms, metric.avg_value
+        ));
+    }
+    ret
+}
+
+async fn check_index(env: Env, config: &Config) -> String {
+    const MAX_IDS: usize = 10;
+
+    let report = match vector::check_consistency(&env, config).await {
+        Ok(report) => report,
+        Err(e) => {
+            console_error!("Error checking vector index: {}", e);
+            return format!("Error checking vector index: {}", e);
+        }
+    };
+    let format_ids = |ids: &[String]| {
+        let mut ret = ids
+            .iter()
+            .take(MAX_IDS)
+            .map(|id| format!("<code>{}</code>", html_escape::encode_text(id)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if ids.len() > MAX_IDS {
+            ret.push_str(&format!(" and {} more", ids.len() - MAX_IDS));
+        }
+        ret
+    };
+
+    let mut ret = String::new();
+    let mut consistent = true;
+    for space in report {
+        ret.push_str(&format!(
+            "<b>{} index:</b> {} stored, {} indexed\n",
+            space.space.name(),
+            space.stored,
+            space.indexed
+        ));
+        if !space.missing.is_empty() {
+            ret.push_str(&format!(
+                "Missing from the index: {}\n",
+                format_ids(&space.missing)
+            ));
+        }
+        if !space.stale.is_empty() {
+            ret.push_str(&format!("No longer stored: {}\n", format_ids(&space.stale)));
+        }
+        consistent &=
+            space.stored == space.indexed && space.missing.is_empty() && space.stale.is_empty();
+    }
+    if consistent {
+        ret.push_str("✅ The index matches the stored embeddings");
+    } else {
+        ret.push_str("⚠️ Run /reindex to rebuild the index from the stored embeddings");
+    }
+    ret
+}
+
+async fn index_info(env: Env, config: &Config) -> String {
+    let spaces = vector::EmbeddingSpace::active(config);
+    let mut ret = String::new();
+    for space in spaces.iter() {
+        if spaces.len() > 1 {
+            ret.push_str(&format!(
+                "<b>{} index</b> ({})\n",
+                space.name(),
+                html_escape::encode_text(&space.model(config))
+            ));
+        }
+        match vector::get_index_info(&env, config, *space).await {
+            Ok(info) => {
+                ret.push_str(&format!(
+                    "<b>Vectors:</b> {}\n\
+                    <b>Trees:</b> {}\n\
+                    <b>Max leaf size:</b> {}\n\
+                    <b>Quantized:</b> {}\n\
+                    <b>Size:</b> {}\n",
+                    info.vector_count,
+                    info.params.num_trees,
+                    info.params.max_leaf_size,
+                    if info.params.quantized { "int8" } else { "no" },
+                    crate::utils::format_size(info.size_bytes),
+                ));
+                if info.params != info.configured_params {
+                    ret.push_str(&format!(
+                        "\n⚠️ Configured parameters differ (trees: {}, max leaf size: {}, quantized: {}), run /reindex to apply them\n",
+                        info.configured_params.num_trees,
+                        info.configured_params.max_leaf_size,
+                        info.configured_params.quantized
+                    ));
+                }
+            }
+            Err(e) => {
+                console_error!("Error reading {} index info: {}", space.name(), e);
+                ret.push_str(&format!("Error reading index info: {}\n", e));
+            }
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Migrate the database and vector index, then merge links saved under URL variants
+async fn upgrade(env: Env, config: &Config) -> String {
+    let mut ret = match upgrade_vector_index(env.clone(), config).await {
+        Ok((total_ids, migrated)) => format!(
+            "Vector index upgraded. Total IDs: {}, Migrated: {}",
+            total_ids, migrated
+        ),
+        Err(e) => return format!("Error upgrading vector index: {}", e),
     };
+    match crate::handlers::merge_duplicate_links(&env, config).await {
+        Ok(merged) if merged.is_empty() => {}
+        Ok(merged) => {
+            ret.push_str(&format!("\nMerged {} duplicate links:", merged.len()));
+            for link in merged {
+                ret.push_str(&format!("\n{}", html_escape::encode_text(&link.url)));
+            }
+        }
+        Err(e) => {
+            console_error!("Error merging duplicate links: {}", e);
+            ret.push_str(&format!("\nError merging duplicate links: {}", e));
+        }
+    }
+    ret
+}
+
+/// Copy vectors saved before the vector_lite index from Vectorize into D1 and the index, a
+/// batch per run; the index takes them through its Durable Object like any other update
+pub async fn upgrade_vector_index(env: Env, config: &Config) -> Result<(usize, usize)> {
+    let indexed = crate::vector_index::warm(&env, vector::EmbeddingSpace::Primary).await?;
 
-    // Create embedding table if it doesn't exist
+    // Create the embedding table and any new columns if they don't exist
+    crate::d1::migrate(&env).await?;
     let db = env.d1("SEEN_DB")?;
-    let create_table_stmt = db.prepare(
-        "
-        CREATE TABLE IF NOT EXISTS embeddings (
-            vector_id TEXT PRIMARY KEY,
-            vector BLOB NOT NULL,
-            link_id TEXT NOT NULL,
-            FOREIGN KEY (link_id) REFERENCES links(id)
-        )
-    ",
-    );
-    create_table_stmt.run().await?;
 
     let links = crate::d1::get_all_links(&env).await?;
 
@@ -169,13 +626,14 @@ pub async fn upgrade_vector_index(env: Env) -> Result<(usize, usize)> {
     }
 
     let total_ids = ids.len();
-    let mut migrated = index.len();
+    let mut migrated = indexed;
+    let mut inserts = vec![];
 
-    let new_ids = ids.iter().skip(index.len()).collect::<Vec<_>>();
+    let new_ids = ids.iter().skip(indexed).collect::<Vec<_>>();
     // Get vectors in batches of 20
     for chunk in new_ids.chunks(20).take(15) {
         let chunk_as_str: Vec<&str> = chunk.iter().map(|s| s.as_str()).collect();
-        let chunk_vectors = vector::get_vector_by_id(&env, &chunk_as_str).await?;
+        let chunk_vectors = vector::get_vector_by_id(config, &chunk_as_str).await?;
         migrated += chunk.len();
 
         for (id, vector) in chunk.into_iter().zip(chunk_vectors) {
@@ -200,29 +658,54 @@ pub async fn upgrade_vector_index(env: Env) -> Result<(usize, usize)> {
 
             stmt.run().await?;
 
-            index.insert(vector, id.to_string());
+            inserts.push((id.to_string(), vector.as_slice().to_vec()));
         }
     }
 
-    let bytes = index.to_bytes();
-    save_to_bucket(&env, "vector_lite.bin", bytes).await?;
+    if !inserts.is_empty() {
+        crate::vector_index::update(&env, vector::EmbeddingSpace::Primary, vec![], inserts).await?;
+    }
 
     Ok((total_ids, migrated))
 }
 
-/// Sends a message to a Telegram chat
-pub async fn send_message(token: &str, chat_id: i64, text: &str) -> Result<()> {
-    // Create the API URL for sending messages
-    let url = format!("{}{}/sendMessage", TELEGRAM_API_BASE, token);
+/// The chat id and username of a message's sender, and whether the bot serves that chat
+fn whoami(config: &Config, message: &Message) -> String {
+    let chat_id = message.chat.id;
+    let username = message
+        .from
+        .as_ref()
+        .and_then(|user| user.username.as_deref())
+        .or(message.chat.username.as_deref());
+    let status = if config.is_owner(chat_id) {
+        "✅ Authorized, and the owner of this bot"
+    } else if config.is_authorized(chat_id) {
+        "✅ Authorized"
+    } else {
+        "❌ Not authorized, ask the owner to add this chat id to AUTHORIZED_CHAT_IDS"
+    };
+    format!(
+        "<b>Chat id:</b> <code>{}</code>\n\
+        <b>Username:</b> {}\n\
+        {}",
+        chat_id,
+        username.map_or("none".to_string(), |username| format!(
+            "@{}",
+            html_escape::encode_text(username)
+        )),
+        status
+    )
+}
 
-    // Create request JSON
-    let body = json!({
-        "chat_id": chat_id,
-        "text": text,
-        "parse_mode": "HTML",
-    });
+/// Calls a Telegram Bot API method with a JSON body
+/// Returns the status code and the parsed response
+async fn call_api(
+    token: &str,
+    method: &str,
+    body: &serde_json::Value,
+) -> Result<(u16, serde_json::Value)> {
+    let url = format!("{}{}/{}", TELEGRAM_API_BASE, token, method);
 
-    // Send the request
     let mut headers = Headers::new();
     headers.set("Content-Type", "application/json")?;
 
@@ -232,14 +715,55 @@ pub async fn send_message(token: &str, chat_id: i64, text: &str) -> Result<()> {
         .with_body(Some(wasm_bindgen::JsValue::from_str(&body.to_string())));
 
     let request = Request::new_with_init(&url, &init)?;
-    let response = Fetch::Request(request).send().await?;
+    let mut response = Fetch::Request(request).send().await?;
+    let status = response.status_code();
+    let result = response
+        .json::<serde_json::Value>()
+        .await
+        .unwrap_or(serde_json::Value::Null);
+
+    Ok((status, result))
+}
+
+/// Sends a message to a Telegram chat
+pub async fn send_message(token: &str, chat_id: i64, text: &str) -> Result<()> {
+    send_message_with_buttons(token, chat_id, text, &[]).await
+}
+
+/// Sends a message with rows of inline keyboard buttons, each given as (label, callback data)
+/// Callback data is limited to 64 bytes by Telegram
+async fn send_message_with_buttons(
+    token: &str,
+    chat_id: i64,
+    text: &str,
+    buttons: &[Vec<(String, String)>],
+) -> Result<()> {
+    let mut body = json!({
+        "chat_id": chat_id,
+        "text": text,
+        "parse_mode": "HTML",
+    });
+    if !buttons.is_empty() {
+        let keyboard = buttons
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|(label, data)| json!({ "text": label, "callback_data": data }))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        body["reply_markup"] = json!({ "inline_keyboard": keyboard });
+    }
+
+    let (status, result) = call_api(token, "sendMessage", &body).await?;
 
     // Check status code
-    if response.status_code() != 200 {
+    if status != 200 {
         console_error!(
-            "Failed to send message: Status {}, message: {}",
-            response.status_code(),
-            body.to_string()
+            "Failed to send message: Status {}, message: {}, response: {}",
+            status,
+            body.to_string(),
+            result
         );
         return Err(Error::from("Failed to send message"));
     }
@@ -247,116 +771,2342 @@ pub async fn send_message(token: &str, chat_id: i64, text: &str) -> Result<()> {
     Ok(())
 }
 
-async fn insert_link(env: Env, url: &str) -> String {
-    match crate::handlers::insert_link(&env, url).await {
-        Ok(link_info) => {
-            format!(
-                "✅ Document saved!\n\
-                {}",
-                link_info.format_telegram_message()
-            )
-        }
-        Err(e) => {
-            console_error!("Error handling link: {}, error: {}", url, e);
+/// Largest file a bot can send, Telegram rejects bigger uploads
+const MAX_DOCUMENT_BYTES: usize = 50 * 1024 * 1024;
+/// Characters of a document caption at most, counted after entities are parsed
+const MAX_CAPTION_LEN: usize = 1024;
+
+/// Send a file to a chat as a document, with an HTML caption
+/// The file is uploaded as multipart/form-data, at most `MAX_DOCUMENT_BYTES`
+async fn send_document(
+    token: &str,
+    chat_id: i64,
+    file_name: &str,
+    content_type: &str,
+    content: &[u8],
+    caption: &str,
+) -> Result<()> {
+    let url = format!("{}{}/sendDocument", TELEGRAM_API_BASE, token);
+    let boundary = format!("seen-{}", uuid::Uuid::new_v4().simple());
+
+    let mut body = Vec::with_capacity(content.len() + 1024);
+    for (name, value) in [
+        ("chat_id", chat_id.to_string()),
+        ("caption", caption.to_string()),
+        ("parse_mode", "HTML".to_string()),
+    ] {
+        body.extend_from_slice(
             format!(
-                "Error handling link: {}, error: {}",
-                url,
-                html_escape::encode_text(&e.to_string())
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
             )
-        }
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"{}\"\r\n\
+            Content-Type: {}\r\n\r\n",
+            boundary, file_name, content_type
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(content);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let mut headers = Headers::new();
+    headers.set(
+        "Content-Type",
+        &format!("multipart/form-data; boundary={}", boundary),
+    )?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+    let mut response = Fetch::Request(Request::new_with_init(&url, &init)?)
+        .send()
+        .await?;
+    if response.status_code() != 200 {
+        let status = response.status_code();
+        let result = response.text().await.unwrap_or_default();
+        console_error!(
+            "Failed to send document: Status {}, response: {}",
+            status,
+            result
+        );
+        return Err(Error::from(format!("Failed to send document: {}", result)));
     }
+    Ok(())
 }
 
-async fn list_links(env: Env) -> String {
-    match crate::d1::get_link_stats(env).await {
-        Ok((count, rows)) => {
-            let mut ret = format!("Total links saved: <b>{}</b>\n\n", count);
-            for (i, row) in rows.iter().enumerate() {
-                ret.push_str(&format!(
-                    "<b>{}.</b> {} <a href=\"{}\">{}</a>\n\n",
-                    i + 1,
-                    format_type_emoji(&row.content_type),
-                    row.url,
-                    html_escape::encode_text(&row.title),
-                ));
-            }
-            ret
-        }
-        Err(e) => {
-            console_error!("Error listing links: {}", e);
-            format!("Error listing links: {}", e)
-        }
+/// Replace the text of a message sent by the bot, dropping its buttons
+async fn edit_message_text(token: &str, chat_id: i64, message_id: i64, text: &str) -> Result<()> {
+    let body = json!({
+        "chat_id": chat_id,
+        "message_id": message_id,
+        "text": text,
+        "parse_mode": "HTML",
+    });
+    let (status, result) = call_api(token, "editMessageText", &body).await?;
+    if status != 200 {
+        console_error!(
+            "Failed to edit message: Status {}, response: {}",
+            status,
+            result
+        );
+        return Err(Error::from("Failed to edit message"));
     }
+    Ok(())
 }
 
-async fn search_query(env: Env, query: &str, search_from_cf: bool) -> String {
-    let result = crate::handlers::search_links(env, query, search_from_cf).await;
-    match result {
-        Ok(response) => {
-            let mut ret = format!("🔍 Search results for '{}'\n\n", query);
-            for (i, (link_info, score)) in response.into_iter().enumerate() {
-                ret.push_str(&format!(
-                    "<b>{}.</b> {} <a href=\"{}\">{}</a> ({:.2})\n\n",
-                    i + 1,
-                    crate::telegram::format_type_emoji(&link_info.content_type),
-                    link_info.url,
-                    html_escape::encode_text(&link_info.title),
-                    score,
-                ));
+/// Callback data prefix of the buttons picking a link to delete
+/// Followed by a link id, or by `batch:` and the key of links held for a batch deletion
+const CALLBACK_DELETE: &str = "delete";
+const CALLBACK_DELETE_BATCH: &str = "batch";
+/// Callback data of the button dismissing a deletion
+const CALLBACK_CANCEL: &str = "cancel";
+/// `feedback:{+|-}:{query key}:{link id}`, a verdict on a search result, 64 bytes with a UUID
+const CALLBACK_FEEDBACK: &str = "feedback";
+/// Callback data prefixes answering whether to save a likely copy, followed by its key
+const CALLBACK_SAVE_COPY: &str = "save";
+const CALLBACK_SKIP_COPY: &str = "skip";
+
+/// Handle a press on an inline keyboard button, the result replaces the message with the buttons
+async fn handle_callback_query(
+    env: Env,
+    config: &Config,
+    callback_query: &CallbackQuery,
+) -> Result<()> {
+    let token = &config.bot_token;
+    let answer = |text: Option<String>| async move {
+        let mut body = json!({ "callback_query_id": callback_query.id });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        call_api(token, "answerCallbackQuery", &body)
+            .await
+            .map(|_| ())
+    };
+
+    let (Some(data), Some(message)) = (&callback_query.data, &callback_query.message) else {
+        return answer(None).await;
+    };
+    let chat_id = message.chat.id;
+    if !config.is_authorized(chat_id) {
+        return answer(None).await;
+    }
+    console_log!("Received callback: {} from chat_id: {}", data, chat_id);
+
+    // Feedback keeps the search results, the answer shows as a short notice instead
+    if let Some(feedback) = data
+        .strip_prefix(CALLBACK_FEEDBACK)
+        .and_then(|rest| rest.strip_prefix(':'))
+    {
+        let notice = record_feedback(&env, chat_id, feedback).await;
+        return answer(Some(notice)).await;
+    }
+    // Saving a held copy processes the link, with the same Gemini and Workers AI calls as sending it
+    let saves_copy = data
+        .split_once(':')
+        .is_some_and(|(action, _)| action == CALLBACK_SAVE_COPY);
+    if saves_copy && !within_rate_limit(&env, config, chat_id).await {
+        return answer(Some(slow_down_message(config))).await;
+    }
+    // Answer right away so the client stops showing a spinner on the button
+    answer(None).await?;
+
+    let response = match data.split_once(':') {
+        Some((CALLBACK_DELETE, id)) => match id.split_once(':') {
+            Some((CALLBACK_DELETE_BATCH, key)) => {
+                delete_held_links(env, config, chat_id, key).await
             }
-            ret
+            _ => delete_link_by_id(env, config, id).await,
+        },
+        Some((CALLBACK_SAVE_COPY, key)) => resolve_held_copy(env, config, chat_id, key, true).await,
+        Some((CALLBACK_SKIP_COPY, key)) => {
+            resolve_held_copy(env, config, chat_id, key, false).await
         }
-        Err(e) => {
-            console_error!("Error searching links: {}", e);
-            format!("Error searching links: {}", e)
+        None if data == CALLBACK_CANCEL => "Cancelled, nothing was deleted".to_string(),
+        _ => {
+            console_log!("Unknown callback data: {}", data);
+            return Ok(());
         }
-    }
+    };
+    edit_message_text(token, chat_id, message.message_id, &response).await
 }
 
-async fn delete_link(env: Env, url: &str) -> String {
-    match crate::handlers::delete_link(&env, url).await {
-        Ok(link_info) => {
-            format!(
-                "✅ Successfully deleted:\n\
-                <b>URL:</b> {}\n\
-                <b>Title:</b> {}\n\
-                <b>Type:</b> {} {}\n",
-                link_info.url,
-                html_escape::encode_text(&link_info.title),
-                format_type_emoji(&link_info.content_type),
-                link_info.content_type
-            )
+/// Send a message to every authorized chat, paced below Telegram's limit of about 30 messages per second
+/// A chat that fails (e.g. blocked the bot) is logged and skipped
+async fn broadcast(config: &Config, message: &str) -> String {
+    const SEND_INTERVAL: std::time::Duration = std::time::Duration::from_millis(40);
+
+    let text = format!("📢 {}", html_escape::encode_text(message));
+    let chat_ids = &config.authorized_chat_ids;
+    let mut failed = vec![];
+    for (i, chat_id) in chat_ids.iter().enumerate() {
+        if i > 0 {
+            Delay::from(SEND_INTERVAL).await;
         }
-        Err(e) => {
-            console_error!("Error deleting link: {}", e);
-            format!("Error deleting link: {}", e)
+        if let Err(e) = send_message(&config.bot_token, *chat_id, &text).await {
+            console_error!("Error broadcasting to {}: {}", chat_id, e);
+            failed.push(chat_id.to_string());
         }
     }
-}
 
-/// Helper function to determine file type emoji based on content type
-pub fn format_type_emoji(content_type: &str) -> &'static str {
-    match content_type.split(';').next().unwrap_or("") {
-        "text/html" => "🌐",
-        "application/pdf" => "📄",
-        t if t.starts_with("image/") => "🖼️",
-        "text/plain" => "📝",
-        _ => "📁",
+    let mut ret = format!(
+        "📢 Broadcast sent to {} of {} chats",
+        chat_ids.len() - failed.len(),
+        chat_ids.len()
+    );
+    if !failed.is_empty() {
+        ret.push_str(&format!("\nFailed: {}", failed.join(", ")));
     }
+    ret
 }
 
-impl DocInfo {
-    fn format_telegram_message(&self) -> String {
-        format!(
-            "{}<a href=\"{}\">{}</a>\n\
-            <b>Size:</b> {} ({} chunks)\n\
-            <b>Summary:</b>\n{}\n",
+/// Message an inline result posts to the chat, a link to the saved page by its title
+fn inline_message_text(link_info: &DocInfo) -> String {
+    format!(
+        "{} <a href=\"{}\">{}</a>",
+        format_type_emoji(&link_info.content_type),
+        html_escape::encode_double_quoted_attribute(&link_info.url),
+        html_escape::encode_text(&link_info.title)
+    )
+}
+
+/// Answer an inline query (`@bot query` from any chat) with the best matching links
+/// Unauthorized users get an empty answer so their client stops waiting
+async fn answer_inline_query(
+    env: Env,
+    config: &Config,
+    ctx: &Context,
+    inline_query: &InlineQuery,
+) -> Result<()> {
+    // Telegram accepts at most 50 results per answer
+    const MAX_INLINE_RESULTS: usize = 50;
+    const INLINE_CACHE_SECONDS: u32 = 60;
+
+    let query = inline_query.query.trim();
+    console_log!(
+        "Received inline query: {} from user: {}",
+        query,
+        inline_query.from.id
+    );
+
+    let mut results = vec![];
+    // Over the rate limit the answer is empty, like for a query matching nothing
+    if config.is_authorized(inline_query.from.id)
+        && !query.is_empty()
+        && within_rate_limit(&env, config, inline_query.from.id).await
+    {
+        match SearchFilters::parse(query) {
+            Ok((query, filters)) if !query.is_empty() => {
+                match crate::handlers::search_links(
+                    env.clone(),
+                    config,
+                    &query,
+                    &filters,
+                    false,
+                    SEARCH_RESULTS,
+                )
+                .await
+                {
+                    Ok(response) => {
+                        crate::handlers::remove_orphan_vectors(ctx, &env, config, response.orphans);
+                        results = response
+                            .results
+                            .into_iter()
+                            .take(MAX_INLINE_RESULTS)
+                            .map(|(link_info, score)| {
+                                json!({
+                                    "type": "article",
+                                    "id": link_info.id,
+                                    "title": link_info.title,
+                                    "description": format!(
+                                        "({}) {}",
+                                        format_score(config.score_format, score, false),
+                                        crate::utils::snippet(&link_info.summary, &query, 200)
+                                    ),
+                                    "url": link_info.url,
+                                    "input_message_content": {
+                                        "message_text": inline_message_text(&link_info),
+                                        "parse_mode": "HTML",
+                                    },
+                                })
+                            })
+                            .collect();
+                    }
+                    Err(e) => console_error!("Error searching links for inline query: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => console_log!("Invalid inline query filters: {}", e),
+        }
+    }
+
+    let body = json!({
+        "inline_query_id": inline_query.id,
+        "results": results,
+        "cache_time": INLINE_CACHE_SECONDS,
+        "is_personal": true,
+    });
+    let (status, result) = call_api(&config.bot_token, "answerInlineQuery", &body).await?;
+    if status != 200 {
+        console_error!(
+            "Failed to answer inline query: Status {}, response: {}",
+            status,
+            result
+        );
+        return Err(Error::from("Failed to answer inline query"));
+    }
+    Ok(())
+}
+
+/// Registers `webhook_url` with Telegram, optionally with a secret token echoed back on every update
+pub async fn set_webhook(
+    token: &str,
+    webhook_url: &str,
+    secret_token: Option<&str>,
+) -> Result<serde_json::Value> {
+    let mut body = json!({ "url": webhook_url });
+    if let Some(secret_token) = secret_token {
+        body["secret_token"] = json!(secret_token);
+    }
+    let (_status, result) = call_api(token, "setWebhook", &body).await?;
+    Ok(result)
+}
+
+/// Reports the current webhook status, including pending updates and the last delivery error
+pub async fn get_webhook_info(token: &str) -> Result<serde_json::Value> {
+    let (_status, result) = call_api(token, "getWebhookInfo", &json!({})).await?;
+    Ok(result)
+}
+
+const SUMMARY_STYLE_KEY: &str = "summary_style";
+
+/// The summary style chosen by a chat, the default when unset or unreadable
+async fn summary_style(env: &Env, chat_id: i64) -> SummaryStyle {
+    match crate::d1::get_chat_setting(env, chat_id, SUMMARY_STYLE_KEY).await {
+        Ok(name) => name
+            .and_then(|name| SummaryStyle::parse(&name))
+            .unwrap_or_default(),
+        Err(e) => {
+            console_error!("Error reading summary style of {}: {}", chat_id, e);
+            SummaryStyle::default()
+        }
+    }
+}
+
+/// Show a search score, a cosine similarity, as `format` asks; `explain` adds the raw value
+/// Negative similarities show as 0% and zero stars, scores above 1 from summing chunks as the top
+fn format_score(format: ScoreFormat, score: f32, explain: bool) -> String {
+    let fraction = score.clamp(0.0, 1.0);
+    let shown = match format {
+        ScoreFormat::Raw if explain => return format!("{:.4}", score),
+        ScoreFormat::Raw => return format!("{:.2}", score),
+        ScoreFormat::Percent => format!("{:.0}%", fraction * 100.0),
+        ScoreFormat::Stars => {
+            let stars = (fraction * 5.0).round() as usize;
+            format!("{}{}", "★".repeat(stars), "☆".repeat(5 - stars))
+        }
+    };
+    if explain {
+        format!("{}, {:.4}", shown, score)
+    } else {
+        shown
+    }
+}
+
+/// Chat setting holding the custom layout of each `/search` and `/list` result
+const RESULT_TEMPLATE_KEY: &str = "result_template";
+const SEARCH_RESULT_TEMPLATE: &str = "{emoji} {link} ({score})";
+const LIST_RESULT_TEMPLATE: &str = "{emoji} {link}";
+const TEMPLATE_PLACEHOLDERS: &[&str] = &[
+    "title", "url", "link", "score", "summary", "date", "emoji", "tags",
+];
+
+enum TemplatePart {
+    Text(String),
+    Placeholder(&'static str),
+}
+
+/// Layout of a result line, literal text with `{name}` placeholders
+/// Both the text and the substituted values are HTML-escaped, `{link}` is the only markup
+struct ResultTemplate(Vec<TemplatePart>);
+
+impl ResultTemplate {
+    fn parse(template: &str) -> std::result::Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if rest[..start].contains('}') {
+                return Err("'}' without a matching '{'".to_string());
+            }
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let Some(len) = rest[start..].find('}') else {
+                return Err("'{' without a matching '}'".to_string());
+            };
+            let name = &rest[start + 1..start + len];
+            let Some(placeholder) = TEMPLATE_PLACEHOLDERS.iter().find(|p| **p == name) else {
+                return Err(format!("unknown placeholder {{{}}}", name));
+            };
+            parts.push(TemplatePart::Placeholder(placeholder));
+            rest = &rest[start + len + 1..];
+        }
+        if rest.contains('}') {
+            return Err("'}' without a matching '{'".to_string());
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, TemplatePart::Placeholder(_)))
+        {
+            return Err("the template has no placeholders".to_string());
+        }
+        Ok(Self(parts))
+    }
+
+    /// `score` is already formatted, see `format_score`
+    fn render(&self, link_info: &DocInfo, score: Option<&str>) -> String {
+        let mut ret = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Text(text) => ret.push_str(&html_escape::encode_text(text)),
+                TemplatePart::Placeholder(name) => ret.push_str(&match *name {
+                    "title" => html_escape::encode_text(&link_info.title).to_string(),
+                    "url" => html_escape::encode_text(&link_info.url).to_string(),
+                    "link" => format!(
+                        "<a href=\"{}\">{}</a>",
+                        html_escape::encode_double_quoted_attribute(&link_info.url),
+                        html_escape::encode_text(&link_info.title)
+                    ),
+                    "score" => score
+                        .map(|s| html_escape::encode_text(s).to_string())
+                        .unwrap_or_default(),
+                    "summary" => html_escape::encode_text(&link_info.summary).to_string(),
+                    "date" => html_escape::encode_text(
+                        link_info
+                            .created_at
+                            .get(..10)
+                            .unwrap_or(&link_info.created_at),
+                    )
+                    .to_string(),
+                    "emoji" => format_type_emoji(&link_info.content_type).to_string(),
+                    "tags" => {
+                        html_escape::encode_text(&format_tags(&link_info.tag_list())).to_string()
+                    }
+                    _ => String::new(),
+                }),
+            }
+        }
+        ret
+    }
+}
+
+/// The chat's result template, or `default` when none is set or the saved one no longer parses
+async fn result_template(env: &Env, chat_id: i64, default: &str) -> ResultTemplate {
+    let saved = match crate::d1::get_chat_setting(env, chat_id, RESULT_TEMPLATE_KEY).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            console_error!("Error reading result template of {}: {}", chat_id, e);
+            None
+        }
+    };
+    saved_template_or(chat_id, saved.as_deref(), default)
+}
+
+fn saved_template_or(chat_id: i64, saved: Option<&str>, default: &str) -> ResultTemplate {
+    saved
+        .filter(|template| !template.is_empty())
+        .and_then(|template| {
+            ResultTemplate::parse(template)
+                .inspect_err(|e| console_error!("Ignoring result template of {}: {}", chat_id, e))
+                .ok()
+        })
+        .unwrap_or_else(|| ResultTemplate::parse(default).expect("default templates are valid"))
+}
+
+fn template_placeholder_names() -> String {
+    TEMPLATE_PLACEHOLDERS
+        .iter()
+        .map(|name| format!("{{{}}}", name))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+async fn show_result_template(env: Env, chat_id: i64) -> String {
+    let current = match crate::d1::get_chat_setting(&env, chat_id, RESULT_TEMPLATE_KEY).await {
+        Ok(Some(template)) if !template.is_empty() => {
+            html_escape::encode_text(&template).to_string()
+        }
+        Ok(_) => "default".to_string(),
+        Err(e) => return format!("Error reading result template: {}", e),
+    };
+    format!(
+        "Result template: <code>{}</code>\nPlaceholders: {}\n\
+        Change it with '/template {{date}} {{link}}', or go back with '/template reset'",
+        current,
+        template_placeholder_names()
+    )
+}
+
+async fn set_result_template(env: Env, chat_id: i64, template: &str) -> String {
+    let template = template.trim();
+    // The default templates differ between /search and /list, so reset stores an empty value
+    let value = if template == "reset" {
+        ""
+    } else {
+        if let Err(e) = ResultTemplate::parse(template) {
+            return format!(
+                "Invalid template: {}\nPlaceholders: {}",
+                html_escape::encode_text(&e),
+                template_placeholder_names()
+            );
+        }
+        template
+    };
+    match crate::d1::set_chat_setting(&env, chat_id, RESULT_TEMPLATE_KEY, value).await {
+        Ok(()) if value.is_empty() => "✅ Results use the default layout again".to_string(),
+        Ok(()) => "✅ Results will use the new layout".to_string(),
+        Err(e) => {
+            console_error!("Error saving result template: {}", e);
+            format!("Error saving result template: {}", e)
+        }
+    }
+}
+
+/// Chat setting opting a chat in to digests, "on" when enabled
+const DIGEST_KEY: &str = "digest";
+/// Chat setting holding the `created_at` of the newest link in the last digest
+const LAST_DIGEST_KEY: &str = "last_digest";
+/// Links listed by name in a digest, the rest are only counted
+const DIGEST_MAX_LINKS: usize = 20;
+
+async fn digest_enabled(env: &Env, chat_id: i64) -> Result<bool> {
+    Ok(crate::d1::get_chat_setting(env, chat_id, DIGEST_KEY)
+        .await?
+        .is_some_and(|value| value == "on"))
+}
+
+async fn show_digest(env: Env, chat_id: i64) -> String {
+    match digest_enabled(&env, chat_id).await {
+        Ok(true) => "Digest: <b>on</b>\nTurn it off with '/digest off'".to_string(),
+        Ok(false) => "Digest: <b>off</b>\nTurn it on with '/digest on'".to_string(),
+        Err(e) => format!("Error reading digest setting: {}", e),
+    }
+}
+
+async fn set_digest(env: Env, chat_id: i64, enabled: bool) -> String {
+    let value = if enabled { "on" } else { "off" };
+    match crate::d1::set_chat_setting(&env, chat_id, DIGEST_KEY, value).await {
+        Ok(()) if enabled => {
+            "✅ You'll get a digest of the links you saved since the last one".to_string()
+        }
+        Ok(()) => "✅ Digest turned off".to_string(),
+        Err(e) => {
+            console_error!("Error saving digest setting: {}", e);
+            format!("Error saving digest setting: {}", e)
+        }
+    }
+}
+
+/// Send every opted-in authorized chat the links it saved since its last digest
+/// Chats with nothing new are skipped, a failing chat is logged and doesn't stop the others
+pub async fn send_digests(env: Env, config: &Config) -> Result<()> {
+    for chat_id in &config.authorized_chat_ids {
+        if let Err(e) = send_digest(&env, config, *chat_id).await {
+            console_error!("Error sending digest to {}: {}", chat_id, e);
+        }
+    }
+    Ok(())
+}
+
+async fn send_digest(env: &Env, config: &Config, chat_id: i64) -> Result<()> {
+    if !digest_enabled(env, chat_id).await? {
+        return Ok(());
+    }
+    let since = crate::d1::get_chat_setting(env, chat_id, LAST_DIGEST_KEY).await?;
+    let links = crate::d1::get_links_saved_since(env, chat_id, since.as_deref()).await?;
+    let Some(newest) = links.last() else {
+        return Ok(());
+    };
+
+    let mut text = format!(
+        "📬 <b>Your digest:</b> {} new links since {}\n\n",
+        links.len(),
+        since
+            .as_deref()
+            .map_or("last week", |since| since.get(..10).unwrap_or(since))
+    );
+    for link_info in links.iter().rev().take(DIGEST_MAX_LINKS) {
+        text.push_str(&format!(
+            "{} <a href=\"{}\">{}</a>\n",
+            format_type_emoji(&link_info.content_type),
+            html_escape::encode_double_quoted_attribute(&link_info.url),
+            html_escape::encode_text(&link_info.title)
+        ));
+    }
+    if links.len() > DIGEST_MAX_LINKS {
+        text.push_str(&format!("…and {} more\n", links.len() - DIGEST_MAX_LINKS));
+    }
+
+    send_message(&config.bot_token, chat_id, &text).await?;
+    crate::d1::set_chat_setting(env, chat_id, LAST_DIGEST_KEY, &newest.created_at).await
+}
+
+/// Tell the chats that saved them about links the scheduled check found dead, with
+/// `REPORT_DEAD_LINKS`; links without an owner go to every authorized chat
+pub async fn report_dead_links(config: &Config, links: &[DocInfo]) -> Result<()> {
+    if !config.report_dead_links || links.is_empty() {
+        return Ok(());
+    }
+    let mut by_chat: std::collections::BTreeMap<i64, Vec<&DocInfo>> = Default::default();
+    for link_info in links {
+        match link_info.chat_id {
+            Some(chat_id) => by_chat.entry(chat_id).or_default().push(link_info),
+            None => {
+                for chat_id in &config.authorized_chat_ids {
+                    by_chat.entry(*chat_id).or_default().push(link_info);
+                }
+            }
+        }
+    }
+
+    for (chat_id, links) in by_chat {
+        let mut text = format!("🪦 {} saved links no longer resolve:\n\n", links.len());
+        for link_info in links {
+            text.push_str(&format!(
+                "{} <a href=\"{}\">{}</a> ({})\n",
+                format_type_emoji(&link_info.content_type),
+                html_escape::encode_double_quoted_attribute(&link_info.url),
+                html_escape::encode_text(&link_info.title),
+                link_info.http_status.unwrap_or_default()
+            ));
+        }
+        text.push_str(
+            "\nTheir saved content and summaries are kept, /delete them if no longer needed",
+        );
+        if let Err(e) = send_message(&config.bot_token, chat_id, &text).await {
+            console_error!("Error reporting dead links to {}: {}", chat_id, e);
+        }
+    }
+    Ok(())
+}
+
+fn summary_style_names() -> String {
+    SummaryStyle::ALL
+        .iter()
+        .map(|style| style.name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+async fn show_summary_style(env: Env, chat_id: i64) -> String {
+    format!(
+        "Summary style: <b>{}</b>\nAvailable styles: {}\nChange it with '/style bullets'",
+        summary_style(&env, chat_id).await.name(),
+        summary_style_names()
+    )
+}
+
+async fn set_summary_style(env: Env, chat_id: i64, name: &str) -> String {
+    let Some(style) = SummaryStyle::parse(name) else {
+        return format!(
+            "Unknown style '{}', available styles: {}",
+            html_escape::encode_text(name.trim()),
+            summary_style_names()
+        );
+    };
+    match crate::d1::set_chat_setting(&env, chat_id, SUMMARY_STYLE_KEY, style.name()).await {
+        Ok(()) => format!(
+            "✅ New links will be summarized in the <b>{}</b> style",
+            style.name()
+        ),
+        Err(e) => {
+            console_error!("Error saving summary style: {}", e);
+            format!("Error saving summary style: {}", e)
+        }
+    }
+}
+
+async fn show_queue(env: Env) -> String {
+    let links = match crate::d1::get_flagged_links(&env).await {
+        Ok(links) => links,
+        Err(e) => {
+            console_error!("Error reading queue: {}", e);
+            return format!("Error reading queue: {}", e);
+        }
+    };
+    if links.is_empty() {
+        return "✅ Nothing is waiting for processing".to_string();
+    }
+
+    let mut counts = std::collections::BTreeMap::new();
+    for link_info in links.iter() {
+        *counts
+            .entry(link_info.status.as_deref().unwrap_or_default())
+            .or_insert(0) += 1;
+    }
+    let mut ret = counts
+        .iter()
+        .map(|(status, count)| format!("<b>{}:</b> {}", status, count))
+        .collect::<Vec<_>>()
+        .join("\n");
+    ret.push_str("\n\n");
+    for link_info in links.iter().take(20) {
+        ret.push_str(&format!(
+            "{} <a href=\"{}\">{}</a>\n{}\n\n",
+            format_type_emoji(&link_info.content_type),
+            html_escape::encode_double_quoted_attribute(&link_info.url),
+            html_escape::encode_text(&link_info.title),
+            html_escape::encode_text(link_info.status_error.as_deref().unwrap_or_default())
+        ));
+    }
+    if links.len() > 20 {
+        ret.push_str(&format!("...and {} more\n", links.len() - 20));
+    }
+    ret.push_str("Send /repair to retry them");
+    ret
+}
+
+async fn repair_links(env: Env, config: &Config, chat_id: i64) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::repair_links(&env, config, style).await {
+        Ok(outcomes) if outcomes.is_empty() => "✅ Nothing to repair".to_string(),
+        Ok(outcomes) => {
+            let repaired = outcomes.iter().filter(|(_, o)| o.is_ok()).count();
+            let mut ret = format!("🔧 Repaired {} of {} links\n", repaired, outcomes.len());
+            for (link_info, outcome) in outcomes {
+                if let Err(e) = outcome {
+                    ret.push_str(&format!(
+                        "\n❌ {}: {}",
+                        html_escape::encode_text(&link_info.url),
+                        html_escape::encode_text(&e.to_string())
+                    ));
+                }
+            }
+            ret
+        }
+        Err(e) => {
+            console_error!("Error repairing links: {}", e);
+            format!("Error repairing links: {}", e)
+        }
+    }
+}
+
+/// Chat setting prefix of a link held back as a likely copy, followed by the key in its buttons
+const PENDING_SAVE_KEY: &str = "pending_save";
+/// Links held back as copies or for deletion are dropped after this long, their buttons then
+/// answer as if already pressed
+const PENDING_MAX_AGE_HOURS: u64 = 24;
+
+/// Forget the links held back as copies or for deletion whose buttons were never pressed
+/// Runs whenever a link is held or a button pressed, failures are only logged
+async fn drop_stale_pending(env: &Env) {
+    for prefix in [PENDING_SAVE_KEY, PENDING_DELETE_KEY] {
+        let prefix = format!("{}:", prefix);
+        match crate::d1::delete_stale_chat_settings(env, &prefix, PENDING_MAX_AGE_HOURS).await {
+            Ok(0) => {}
+            Ok(count) => console_log!("Dropped {} stale {} settings", count, prefix),
+            Err(e) => console_error!("Error dropping stale {} settings: {}", prefix, e),
+        }
+    }
+}
+
+/// When a held link was stored, for `drop_stale_pending`
+fn pending_created_at() -> String {
+    js_sys::Date::new_0()
+        .to_iso_string()
+        .as_string()
+        .unwrap_or_default()
+}
+
+async fn insert_link(
+    env: Env,
+    config: &Config,
+    token: &str,
+    chat_id: i64,
+    url: &str,
+    pages: Option<PageRange>,
+) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::insert_link(&env, config, url, style, Some(chat_id), pages, true).await {
+        Ok(InsertOutcome::NearDuplicate { existing, score }) => {
+            ask_save_copy(&env, token, chat_id, url, pages, &existing, score).await
+        }
+        Ok(InsertOutcome::Saved(link_info)) => format_inserted(url, pages, Ok(*link_info)),
+        Err(e) => format_inserted(url, pages, Err(e)),
+    }
+}
+
+/// Ask whether to save a link that looks like a copy of a saved one, keeping the link in the
+/// chat's settings until a button is pressed since callback data only holds 64 bytes
+async fn ask_save_copy(
+    env: &Env,
+    token: &str,
+    chat_id: i64,
+    url: &str,
+    pages: Option<PageRange>,
+    existing: &DocInfo,
+    score: f32,
+) -> String {
+    drop_stale_pending(env).await;
+    let key = crate::utils::sha256_hex(url.as_bytes())[..16].to_string();
+    let pending = json!({
+        "url": url,
+        "pages": pages.map(|pages| pages.to_string()),
+        "created_at": pending_created_at(),
+    });
+    let setting = format!("{}:{}", PENDING_SAVE_KEY, key);
+    if let Err(e) = crate::d1::set_chat_setting(env, chat_id, &setting, &pending.to_string()).await
+    {
+        console_error!("Error holding back {}: {}", url, e);
+        return format!("Error saving link: {}, error: {}", url, e);
+    }
+    let text = format!(
+        "🤔 {} looks like a copy of a saved link ({:.0}% similar):\n\
+        <a href=\"{}\">{}</a>, saved {}\n\n\
+        Save it anyway?",
+        html_escape::encode_text(url),
+        score * 100.0,
+        html_escape::encode_double_quoted_attribute(&existing.url),
+        html_escape::encode_text(&existing.title),
+        html_escape::encode_text(&existing.created_at)
+    );
+    let buttons = vec![vec![
+        (
+            "💾 Save anyway".to_string(),
+            format!("{}:{}", CALLBACK_SAVE_COPY, key),
+        ),
+        (
+            "✖️ Don't save".to_string(),
+            format!("{}:{}", CALLBACK_SKIP_COPY, key),
+        ),
+    ]];
+    match send_message_with_buttons(token, chat_id, &text, &buttons).await {
+        Ok(()) => String::new(),
+        Err(e) => format!("Error asking about a likely copy: {}", e),
+    }
+}
+
+/// Save or drop a link held back as a likely copy, as the button pressed says
+async fn resolve_held_copy(
+    env: Env,
+    config: &Config,
+    chat_id: i64,
+    key: &str,
+    save: bool,
+) -> String {
+    drop_stale_pending(&env).await;
+    let setting = format!("{}:{}", PENDING_SAVE_KEY, key);
+    let pending = match crate::d1::get_chat_setting(&env, chat_id, &setting).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            return "This link was already saved or dropped, or the question expired".to_string()
+        }
+        Err(e) => {
+            console_error!("Error reading held back link: {}", e);
+            return format!("Error reading held back link: {}", e);
+        }
+    };
+    if let Err(e) = crate::d1::delete_chat_setting(&env, chat_id, &setting).await {
+        console_error!("Error forgetting held back link: {}", e);
+    }
+    let pending = serde_json::from_str::<serde_json::Value>(&pending).unwrap_or_default();
+    let Some(url) = pending["url"].as_str() else {
+        return "The held back link is unreadable, send it again".to_string();
+    };
+    if !save {
+        return format!("Not saved: {}", html_escape::encode_text(url));
+    }
+    let pages = pending["pages"]
+        .as_str()
+        .and_then(|pages| PageRange::parse(pages).ok());
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::insert_link(&env, config, url, style, Some(chat_id), pages, false).await
+    {
+        Ok(InsertOutcome::Saved(link_info)) => format_inserted(url, pages, Ok(*link_info)),
+        Ok(InsertOutcome::NearDuplicate { .. }) => {
+            format!("Not saved: {}", html_escape::encode_text(url))
+        }
+        Err(e) => format_inserted(url, pages, Err(e)),
+    }
+}
+
+/// Reply to saving a link: its details, or why it wasn't saved or processed
+fn format_inserted(url: &str, pages: Option<PageRange>, result: Result<DocInfo>) -> String {
+    let warning = match (&result, pages) {
+        (Ok(link_info), Some(pages)) if !link_info.content_type.starts_with("application/pdf") => {
+            format!(
+                "⚠️ pages={} only applies to PDFs, the whole {} was processed\n",
+                pages, link_info.content_type
+            )
+        }
+        _ => String::new(),
+    };
+    let response = match result {
+        // The content is saved, but without embeddings search can't find it
+        Ok(link_info) if link_info.status.as_deref() == Some(crate::d1::STATUS_NEEDS_REEMBED) => {
+            format!(
+                "⚠️ Saved {} but not indexed, embeddings failed: {}\n\
+                It won't show up in search until /repair succeeds",
+                html_escape::encode_text(url),
+                html_escape::encode_text(
+                    link_info.status_error.as_deref().unwrap_or("unknown error")
+                )
+            )
+        }
+        Ok(link_info) if link_info.status.is_some() => format!(
+            "⚠️ Saved {}, but processing failed: {}\nSend /repair to retry",
+            html_escape::encode_text(url),
+            html_escape::encode_text(link_info.status_error.as_deref().unwrap_or("unknown error"))
+        ),
+        Ok(link_info) => {
+            format!(
+                "✅ Document saved!\n\
+                {}",
+                link_info.format_telegram_message()
+            )
+        }
+        Err(e) => {
+            console_error!("Error handling link: {}, error: {}", url, e);
+            format!(
+                "Error handling link: {}, error: {}",
+                url,
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    };
+    warning + &response
+}
+
+/// Save every link of a message, e.g. one shared from another app with the URL behind formatted text
+async fn insert_links(
+    env: Env,
+    config: &Config,
+    token: &str,
+    chat_id: i64,
+    urls: &[String],
+) -> String {
+    let mut responses = vec![];
+    for url in urls {
+        // Telegram also marks bare domains as urls, which fetch can't handle without a scheme
+        let url = if url.starts_with("http://") || url.starts_with("https://") {
+            url.clone()
+        } else {
+            format!("https://{}", url)
+        };
+        responses.push(insert_link(env.clone(), config, token, chat_id, &url, None).await);
+    }
+    // Links held back as copies were already asked about in their own message
+    responses.retain(|response| !response.is_empty());
+    responses.join("\n\n")
+}
+
+/// A previewed link, labeled so it isn't mistaken for a saved one
+fn preview_message(link_info: &DocInfo) -> String {
+    format!(
+        "👀 <b>Preview, not saved</b>\n\
+        {}\n\
+        Send the link to save it",
+        link_info.format_telegram_message()
+    )
+}
+
+async fn preview_link(env: Env, config: &Config, chat_id: i64, url: &str) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::preview_link(&env, config, url, style).await {
+        Ok(link_info) => preview_message(&link_info),
+        Err(e) => {
+            console_error!("Error previewing link: {}, error: {}", url, e);
+            format!(
+                "Error previewing link: {}, error: {}",
+                url,
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+async fn retype_link(
+    env: Env,
+    config: &Config,
+    chat_id: i64,
+    url: &str,
+    content_type: &str,
+) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::retype_link(&env, config, url, content_type, style).await {
+        Ok((old, link_info)) => {
+            format!(
+                "✅ Changed type from {} to {} and reprocessed ({} → {} chunks)\n\
+                {}",
+                html_escape::encode_text(&old.content_type),
+                html_escape::encode_text(&link_info.content_type),
+                old.chunk_count,
+                link_info.chunk_count,
+                link_info.format_telegram_message()
+            )
+        }
+        Err(e) => {
+            console_error!("Error retyping link: {}, error: {}", url, e);
+            format!(
+                "Error retyping link: {}, error: {}",
+                url,
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+async fn reprocess_link(env: Env, config: &Config, chat_id: i64, url: &str) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::reprocess_saved_link(&env, config, url, style).await {
+        Ok((old, link_info)) => format!(
+            "✅ Reprocessed ({} → {} chunks)\n\
+            {}",
+            old.chunk_count,
+            link_info.chunk_count,
+            link_info.format_telegram_message()
+        ),
+        Err(e) => {
+            console_error!("Error reprocessing link: {}, error: {}", url, e);
+            format!(
+                "Error reprocessing link: {}, error: {}",
+                url,
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+async fn refresh_link(env: Env, config: &Config, chat_id: i64, url: &str) -> String {
+    let style = summary_style(&env, chat_id).await;
+    match crate::handlers::recrawl_link(&env, config, url, style).await {
+        Ok(None) => "✅ Unchanged since it was saved, nothing was reprocessed".to_string(),
+        Ok(Some((old, link_info))) => format!(
+            "✅ Refreshed ({} → {} chunks)\n\
+            {}",
+            old.chunk_count,
+            link_info.chunk_count,
+            link_info.format_telegram_message()
+        ),
+        Err(e) => {
+            console_error!("Error refreshing link: {}, error: {}", url, e);
+            format!(
+                "Error refreshing link: {}, error: {}",
+                url,
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+/// Links shown per `/list` page
+const LIST_PAGE_SIZE: usize = 10;
+
+/// Show the link count, the chat's storage usage and a page of the newest links, counting from 1
+/// Export every link to R2 and explain how to download the file
+async fn export_links(env: Env, format: ExportFormat) -> String {
+    match crate::handlers::export_links(&env, format).await {
+        Ok(export) => format!(
+            "📦 Exported {} links ({}) to <code>{}</code> in the bucket.\n\n\
+            Download it with <code>GET /export?key={}</code> and your API_TOKEN, \
+            or <code>npx wrangler r2 object get seen/{} --remote</code>",
+            export.links,
+            crate::utils::format_size(export.size),
+            html_escape::encode_text(&export.key),
+            html_escape::encode_text(&export.key),
+            html_escape::encode_text(&export.key)
+        ),
+        Err(e) => {
+            console_error!("Error exporting links: {}", e);
+            format!("Error exporting links: {}", e)
+        }
+    }
+}
+
+/// Restore links from an export and report what happened to them
+async fn import_links(env: Env, config: &Config, chat_id: i64, bucket_path: &str) -> String {
+    let style = summary_style(&env, chat_id).await;
+    let report = match crate::handlers::import_links(&env, config, bucket_path, style).await {
+        Ok(report) => report,
+        Err(e) => {
+            console_error!("Error importing {}: {}", bucket_path, e);
+            return format!(
+                "Error importing {}: {}",
+                html_escape::encode_text(bucket_path),
+                e
+            );
+        }
+    };
+    let mut ret = format!(
+        "📥 Imported {} links, skipped {} already saved, {} failed\n",
+        report.imported,
+        report.skipped,
+        report.failed.len()
+    );
+    if report.processed < report.imported {
+        ret.push_str(&format!(
+            "{} of them are processed, send /repair to process the rest\n",
+            report.processed
+        ));
+    }
+    // Each failure is a line, keep the message within Telegram's length limit
+    for (url, error) in report.failed.iter().take(20) {
+        ret.push_str(&format!(
+            "\n❌ {}: {}",
+            html_escape::encode_text(&url),
+            html_escape::encode_text(&error)
+        ));
+    }
+    ret
+}
+
+/// Most links `/random` shows at once
+const MAX_RANDOM_LINKS: usize = 5;
+
+/// Resurface saved links picked at random, a message each since summaries can be long
+async fn random_links(env: Env, token: &str, chat_id: i64, count: usize) -> String {
+    match crate::d1::get_random_links(&env, count).await {
+        Ok(links) if links.is_empty() => "No saved links yet, send a URL to save one".to_string(),
+        Ok(links) => {
+            for link_info in links {
+                let text = format!("🎲 {}", link_info.format_telegram_message());
+                if let Err(e) = send_message(token, chat_id, &text).await {
+                    return format!("Error sending a random link: {}", e);
+                }
+            }
+            String::new()
+        }
+        Err(e) => {
+            console_error!("Error picking random links: {}", e);
+            format!("Error picking random links: {}", e)
+        }
+    }
+}
+
+/// Totals over all saved links, per content type, and the size of each vector index
+async fn show_stats(env: Env, config: &Config) -> String {
+    let stats = match crate::d1::get_detailed_stats(&env).await {
+        Ok(stats) => stats,
+        Err(e) => {
+            console_error!("Error reading link stats: {}", e);
+            return format!("Error reading link stats: {}", e);
+        }
+    };
+    let mut ret = format!(
+        "📊 <b>Links:</b> {}\n\
+        <b>Stored content:</b> {}\n\
+        <b>Added in the last 7 days:</b> {}\n\n\
+        <b>By content type</b>\n",
+        stats.total_links,
+        crate::utils::format_size(stats.stored_bytes as usize),
+        stats.added_last_week
+    );
+    for (content_type, links, bytes) in &stats.by_content_type {
+        ret.push_str(&format!(
+            "{} {}: {} ({})\n",
+            format_type_emoji(content_type),
+            html_escape::encode_text(content_type),
+            links,
+            crate::utils::format_size(*bytes as usize)
+        ));
+    }
+
+    ret.push('\n');
+    for space in vector::EmbeddingSpace::active(config) {
+        let size = match vector::get_vector_lite_size(&env, space).await {
+            Ok(Some(size)) => crate::utils::format_size(size as usize),
+            Ok(None) => "not built yet".to_string(),
+            Err(e) => {
+                console_error!(
+                    "Error reading the size of the {} index: {}",
+                    space.name(),
+                    e
+                );
+                "unknown".to_string()
+            }
+        };
+        ret.push_str(&format!("<b>{} index:</b> {}\n", space.name(), size));
+    }
+    ret
+}
+
+async fn list_links(env: Env, config: &Config, chat_id: i64, page: usize) -> String {
+    if page == 0 {
+        return "Pages start at 1, e.g., '/list 1'".to_string();
+    }
+    let usage = match crate::d1::get_chat_usage(&env, chat_id).await {
+        Ok(used) => match config.max_bytes_per_chat {
+            Some(max) => format!(
+                "Your storage: <b>{}</b> of {}\n",
+                crate::utils::format_size(used as usize),
+                crate::utils::format_size(max as usize)
+            ),
+            None => format!(
+                "Your storage: <b>{}</b>\n",
+                crate::utils::format_size(used as usize)
+            ),
+        },
+        Err(e) => {
+            console_error!("Error reading storage usage of {}: {}", chat_id, e);
+            String::new()
+        }
+    };
+    let template = result_template(&env, chat_id, LIST_RESULT_TEMPLATE).await;
+    let offset = (page - 1).saturating_mul(LIST_PAGE_SIZE);
+    match crate::d1::get_link_stats_paged(env, offset, LIST_PAGE_SIZE).await {
+        Ok((count, rows)) => {
+            let pages = (count as usize).div_ceil(LIST_PAGE_SIZE).max(1);
+            if rows.is_empty() && page > 1 {
+                return format!(
+                    "There is no page {}, the last page is {}, e.g., '/list {}'",
+                    page, pages, pages
+                );
+            }
+            let mut ret = format!("Total links saved: <b>{}</b>\n{}\n", count, usage);
+            for (i, row) in rows.iter().enumerate() {
+                ret.push_str(&format!(
+                    "<b>{}.</b> {}\n\n",
+                    offset + i + 1,
+                    template.render(row, None)
+                ));
+            }
+            if page < pages {
+                ret.push_str(&format!(
+                    "Page {}/{} — send /list {} for more",
+                    page,
+                    pages,
+                    page + 1
+                ));
+            } else if pages > 1 {
+                ret.push_str(&format!("Page {}/{}", page, pages));
+            }
+            ret
+        }
+        Err(e) => {
+            console_error!("Error listing links: {}", e);
+            format!("Error listing links: {}", e)
+        }
+    }
+}
+
+/// Remove a whitespace-separated flag from the query, returning whether it was present
+fn take_flag(query: &str, flag: &str) -> (String, bool) {
+    let mut found = false;
+    let rest = query
+        .split_whitespace()
+        .filter(|token| {
+            let is_flag = *token == flag;
+            found |= is_flag;
+            !is_flag
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    (rest, found)
+}
+
+/// Store a verdict from a feedback button, returning the notice shown to the user
+async fn record_feedback(env: &Env, chat_id: i64, feedback: &str) -> String {
+    let Some((verdict, query_key, link_id)) = parse_feedback(feedback) else {
+        console_log!("Unknown feedback data: {}", feedback);
+        return "Unknown feedback".to_string();
+    };
+    match crate::d1::record_feedback(env, chat_id, query_key, link_id, verdict).await {
+        Ok(()) => {
+            console_log!(
+                "Feedback {} on {} for query {} from chat_id: {}",
+                verdict,
+                link_id,
+                query_key,
+                chat_id
+            );
+            "Thanks, this will shape future results".to_string()
+        }
+        Err(e) => {
+            console_error!("Error recording feedback: {}", e);
+            format!("Error recording feedback: {}", e)
+        }
+    }
+}
+
+/// Callback data of a rating button, `verdict` is `+` or `-`
+fn feedback_data(verdict: &str, query_key: &str, link_id: &str) -> String {
+    format!(
+        "{}:{}:{}:{}",
+        CALLBACK_FEEDBACK, verdict, query_key, link_id
+    )
+}
+
+/// The verdict, query key and link id of a rating, without the `feedback:` prefix
+fn parse_feedback(feedback: &str) -> Option<(i32, &str, &str)> {
+    let (verdict, rest) = feedback.split_once(':')?;
+    let (query_key, link_id) = rest.split_once(':')?;
+    let verdict = match verdict {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    Some((verdict, query_key, link_id))
+}
+
+/// Where a search looks for links
+#[derive(Clone, Copy, PartialEq)]
+enum SearchBackend {
+    VectorLite,
+    /// `/search cf`
+    Vectorize,
+    /// `/search hybrid`, vector_lite merged with keyword matches
+    Hybrid,
+}
+
+/// Search and reply with the results, each with buttons to rate it for the query
+async fn search_query(
+    env: Env,
+    config: &Config,
+    ctx: &Context,
+    token: &str,
+    chat_id: i64,
+    query: &str,
+    backend: SearchBackend,
+) -> String {
+    let (query, show_timing) = take_flag(query, "--timing");
+    let (query, explain) = take_flag(&query, "--explain");
+    let (query, deep) = take_flag(&query, "--deep");
+    let score_format = config.score_format;
+    let (query, filters) = match SearchFilters::parse(&query) {
+        Ok(parsed) => parsed,
+        Err(e) => return e.to_string(),
+    };
+    if query.is_empty() {
+        return "Please provide a search query, e.g., '/search rust after:2024-01-01'".to_string();
+    }
+    let template = result_template(&env, chat_id, SEARCH_RESULT_TEMPLATE).await;
+    let result = match backend {
+        SearchBackend::Hybrid => {
+            crate::handlers::hybrid_search_links(
+                env.clone(),
+                config,
+                &query,
+                &filters,
+                SEARCH_RESULTS,
+            )
+            .await
+        }
+        _ => {
+            let search_from_cf = backend == SearchBackend::Vectorize;
+            crate::handlers::search_links(
+                env.clone(),
+                config,
+                &query,
+                &filters,
+                search_from_cf,
+                SEARCH_RESULTS,
+            )
+            .await
+        }
+    };
+    if let Ok(response) = &result {
+        let orphans = response.orphans.clone();
+        crate::handlers::remove_orphan_vectors(ctx, &env, config, orphans);
+    }
+    match result {
+        Ok(response) if response.results.is_empty() && response.filtered_out > 0 => format!(
+            "🔍 {} links matched '{}', but none of them passed the filters",
+            response.filtered_out,
+            html_escape::encode_text(&query)
+        ),
+        Ok(response) if response.results.is_empty() => format!(
+            "🔍 No saved links match '{}'",
+            html_escape::encode_text(&query)
+        ),
+        Ok(response) => {
+            let mut ret = format!(
+                "🔍 Search results for '{}'\n\n",
+                html_escape::encode_text(&query)
+            );
+            for (i, (link_info, score)) in response.results.iter().enumerate() {
+                ret.push_str(&format!(
+                    "<b>{}.</b> {}\n",
+                    i + 1,
+                    template.render(
+                        link_info,
+                        Some(&format_score(score_format, *score, explain))
+                    )
+                ));
+                // The passages that matched say more than the summary of a long document, the best
+                // one by default and each with its own score with --deep
+                let passages = response.passages.get(&link_info.id);
+                for passage in
+                    passages
+                        .into_iter()
+                        .flatten()
+                        .take(if deep { usize::MAX } else { 1 })
+                {
+                    let excerpt = html_escape::encode_text(&crate::utils::snippet(
+                        &passage.text,
+                        &query,
+                        if deep {
+                            DEEP_PASSAGE_LEN
+                        } else {
+                            SEARCH_PASSAGE_LEN
+                        },
+                    ))
+                    .to_string();
+                    ret.push_str(&if deep {
+                        format!(
+                            "<blockquote>{}\n<i>{}</i></blockquote>\n",
+                            excerpt,
+                            format_score(score_format, passage.score, explain)
+                        )
+                    } else {
+                        format!("<blockquote>{}</blockquote>\n", excerpt)
+                    });
+                }
+                ret.push('\n');
+            }
+            if show_timing {
+                ret.push_str(&format!("⏱️ {}", response.timings.breakdown()));
+            }
+            let query_key = crate::handlers::query_key(&query);
+            let buttons = response
+                .results
+                .iter()
+                .enumerate()
+                .map(|(i, (link_info, _))| {
+                    ["👍", "👎"]
+                        .into_iter()
+                        .zip(["+", "-"])
+                        .map(|(label, verdict)| {
+                            (
+                                format!("{} {}", label, i + 1),
+                                feedback_data(verdict, &query_key, &link_info.id),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            match send_message_with_buttons(token, chat_id, &ret, &buttons).await {
+                Ok(()) => String::new(),
+                Err(e) => format!("Error sending search results: {}", e),
+            }
+        }
+        Err(e) => {
+            console_error!("Error searching links: {}", e);
+            format!("Error searching links: {}", e)
+        }
+    }
+}
+
+/// List the saved links closest to a saved link, scored like search results
+async fn similar_links(env: Env, config: &Config, chat_id: i64, url: &str) -> String {
+    let link_info = match find_saved_link(&env, url).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+    let score_format = config.score_format;
+    let template = result_template(&env, chat_id, SEARCH_RESULT_TEMPLATE).await;
+    match crate::handlers::similar_links(&env, config, &link_info, SEARCH_RESULTS).await {
+        Ok(results) if results.is_empty() => format!(
+            "No other saved links are similar to {}",
+            html_escape::encode_text(&link_info.title)
+        ),
+        Ok(results) => {
+            let mut ret = format!(
+                "🔗 Links similar to {}\n\n",
+                html_escape::encode_text(&link_info.title)
+            );
+            for (i, (link_info, score)) in results.iter().enumerate() {
+                ret.push_str(&format!(
+                    "<b>{}.</b> {}\n\n",
+                    i + 1,
+                    template.render(link_info, Some(&format_score(score_format, *score, false)))
+                ));
+            }
+            ret
+        }
+        Err(e) => {
+            console_error!("Error finding links similar to {}: {}", url, e);
+            format!(
+                "Error finding similar links: {}",
+                html_escape::encode_text(&e.to_string())
+            )
+        }
+    }
+}
+
+/// Render the effective configuration, secrets only show whether they are set
+fn show_config(config: &Config) -> String {
+    let settings = serde_json::to_value(config).unwrap_or_default();
+    let mut ret = "<b>Settings</b>\n<pre>".to_string();
+    if let Some(settings) = settings.as_object() {
+        for (name, value) in settings {
+            ret.push_str(&format!(
+                "{} = {}\n",
+                name,
+                html_escape::encode_text(&value.to_string())
+            ));
+        }
+    }
+    ret.push_str("</pre>\n<b>Secrets</b>\n");
+    for (name, set) in config.secrets() {
+        ret.push_str(&format!("{} {}\n", if set { "✅" } else { "❌" }, name));
+    }
+    ret
+}
+
+/// Find the saved link a URL resolves to, or the reply explaining why there is none
+async fn find_saved_link(env: &Env, url: &str) -> std::result::Result<DocInfo, String> {
+    saved_link_or_reply(url, crate::handlers::resolve_link(env, url).await)
+}
+
+/// The link a lookup found, or the reply listing the candidates or saying it wasn't found
+fn saved_link_or_reply(
+    url: &str,
+    found: Result<LinkMatch>,
+) -> std::result::Result<DocInfo, String> {
+    match found {
+        Ok(LinkMatch::Found(link_info)) => Ok(*link_info),
+        Ok(LinkMatch::Candidates(candidates)) => {
+            let mut ret = format!(
+                "No exact match for {}, did you mean:\n",
+                html_escape::encode_text(url)
+            );
+            for link_info in candidates {
+                ret.push_str(&format!("\n{}", html_escape::encode_text(&link_info.url)));
+            }
+            Err(ret)
+        }
+        Ok(LinkMatch::NotFound) => {
+            Err(format!("Link not found: {}", html_escape::encode_text(url)))
+        }
+        Err(e) => {
+            console_error!("Error looking up link: {}", e);
+            Err(format!("Error looking up link: {}", e))
+        }
+    }
+}
+
+/// Answer a question from saved links, each citation linking to its source
+async fn ask_question(env: Env, config: &Config, question: &str) -> String {
+    let answer = match crate::handlers::ask_question(&env, config, question).await {
+        Ok(Some(answer)) => answer,
+        Ok(None) => {
+            return "None of your saved links look relevant enough to answer that".to_string()
+        }
+        Err(e) => {
+            console_error!("Error answering {:?}: {}", question, e);
+            return format!(
+                "Error answering the question: {}",
+                html_escape::encode_text(&e.to_string())
+            );
+        }
+    };
+    let (text, cited) = link_citations(&answer.text, &answer.sources);
+    // An answer citing nothing, e.g. saying the sources don't cover it, still shows what was read
+    let listed = if cited.is_empty() {
+        (1..=answer.sources.len()).collect()
+    } else {
+        cited
+    };
+    let mut ret = format!("💡 {}\n\n<b>Sources</b>\n", text);
+    for n in listed {
+        let link_info = &answer.sources[n - 1];
+        ret.push_str(&format!(
+            "[{}] <a href=\"{}\">{}</a>\n",
+            n,
+            html_escape::encode_double_quoted_attribute(&link_info.url),
+            html_escape::encode_text(&link_info.title)
+        ));
+    }
+    ret
+}
+
+/// Escape an answer and turn its `[n]` citations into links to the sources
+/// Returns the numbers of the cited sources in order, bracketed numbers that match no source are
+/// left as text
+fn link_citations(answer: &str, sources: &[DocInfo]) -> (String, Vec<usize>) {
+    let escaped = html_escape::encode_text(answer);
+    let mut ret = String::new();
+    let mut cited = vec![];
+    let mut rest: &str = &escaped;
+    while let Some(start) = rest.find('[') {
+        ret.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let citation = rest[1..]
+            .find(']')
+            .and_then(|end| Some((rest[1..end + 1].parse::<usize>().ok()?, end + 2)))
+            .filter(|(n, _)| (1..=sources.len()).contains(n));
+        match citation {
+            Some((n, len)) => {
+                ret.push_str(&format!(
+                    "<a href=\"{}\">[{}]</a>",
+                    html_escape::encode_double_quoted_attribute(&sources[n - 1].url),
+                    n
+                ));
+                if !cited.contains(&n) {
+                    cited.push(n);
+                }
+                rest = &rest[len..];
+            }
+            None => {
+                ret.push('[');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    cited.sort_unstable();
+    (ret, cited)
+}
+
+/// Show the stored title, date and summary of a saved link
+/// A URL that isn't saved is searched for, in case it names a saved page differently
+async fn show_summary(env: Env, config: &Config, ctx: &Context, url: &str) -> String {
+    let link_info = match crate::handlers::resolve_link(&env, url).await {
+        Ok(LinkMatch::NotFound) => {
+            let mut ret = format!("Link not found: {}", html_escape::encode_text(url));
+            match crate::handlers::search_links(
+                env.clone(),
+                config,
+                url,
+                &SearchFilters::default(),
+                false,
+                1,
+            )
+            .await
+            {
+                Ok(response) => {
+                    crate::handlers::remove_orphan_vectors(ctx, &env, config, response.orphans);
+                    if let Some((closest, _)) = response.results.first() {
+                        ret.push_str(&format!(
+                            "\n\nClosest saved link: <a href=\"{}\">{}</a>\n{}",
+                            html_escape::encode_double_quoted_attribute(&closest.url),
+                            html_escape::encode_text(&closest.title),
+                            html_escape::encode_text(&closest.url)
+                        ));
+                    }
+                }
+                Err(e) => console_error!("Error searching for {}: {}", url, e),
+            }
+            return ret;
+        }
+        found => match saved_link_or_reply(url, found) {
+            Ok(link_info) => link_info,
+            Err(reply) => return reply,
+        },
+    };
+    format!(
+        "{} <a href=\"{}\">{}</a>\n\
+        <b>Saved:</b> {}\n\
+        <b>Summary:</b>\n{}",
+        format_type_emoji(&link_info.content_type),
+        html_escape::encode_double_quoted_attribute(&link_info.url),
+        html_escape::encode_text(&link_info.title),
+        html_escape::encode_text(&link_info.created_at),
+        html_escape::encode_text(&link_info.summary)
+    )
+}
+
+/// Send the stored content of a saved link as a file named after its title
+async fn send_saved_copy(env: Env, token: &str, chat_id: i64, url: &str) -> String {
+    let link_info = match find_saved_link(&env, url).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+    if let Some(expired_at) = &link_info.content_expired_at {
+        return format!(
+            "The saved copy of {} was deleted on {} by the retention policy",
+            html_escape::encode_text(&link_info.url),
+            html_escape::encode_text(expired_at)
+        );
+    }
+    // The stored size is checked first so an oversized copy isn't read at all
+    if link_info.size > MAX_DOCUMENT_BYTES {
+        return format!(
+            "⚠️ The saved copy is {}, but Telegram only sends files up to {}. \
+            Get it from the bucket with <code>npx wrangler r2 object get seen/{} --remote</code>",
+            crate::utils::format_size(link_info.size),
+            crate::utils::format_size(MAX_DOCUMENT_BYTES),
+            html_escape::encode_text(&link_info.bucket_path)
+        );
+    }
+    let content = match crate::d1::read_from_bucket(&env, &link_info.bucket_path).await {
+        Ok(content) => content,
+        Err(e) => {
+            console_error!("Error reading {}: {}", link_info.bucket_path, e);
+            return format!("Error reading the saved copy: {}", e);
+        }
+    };
+
+    // The extension of the bucket object also covers types the content type has none for
+    let extension = link_info
+        .bucket_path
+        .rsplit_once('.')
+        .map_or("bin", |(_, extension)| extension);
+    let name = link_info
+        .title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(80)
+        .collect::<String>();
+    let name = name.trim_matches('_');
+    let file_name = format!(
+        "{}.{}",
+        if name.is_empty() { &link_info.id } else { name },
+        extension
+    );
+    let title = link_info.title.chars().take(200).collect::<String>();
+    let caption = format!(
+        "{} {}\n{}",
+        format_type_emoji(&link_info.content_type),
+        html_escape::encode_text(&title),
+        html_escape::encode_text(&link_info.url)
+    );
+    let caption = if caption.chars().count() > MAX_CAPTION_LEN {
+        html_escape::encode_text(&title).to_string()
+    } else {
+        caption
+    };
+    let content_type = Some(
+        link_info
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim(),
+    )
+    .filter(|content_type| !content_type.is_empty())
+    .unwrap_or("application/octet-stream");
+    match send_document(token, chat_id, &file_name, content_type, &content, &caption).await {
+        Ok(()) => String::new(),
+        Err(e) => format!("Error sending the saved copy: {}", e),
+    }
+}
+
+/// Replace the tags of a saved link
+async fn tag_link(env: Env, url: &str, tags: &str) -> String {
+    let link_info = match find_saved_link(&env, url).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+    let tags = crate::d1::parse_tags(tags);
+    if tags.is_empty() {
+        return "Please provide at least one tag, e.g., 'rust,async'".to_string();
+    }
+    match crate::d1::set_link_tags(&env, &link_info.id, &tags).await {
+        Ok(()) => format!(
+            "🏷 Tagged <a href=\"{}\">{}</a> with {}",
+            html_escape::encode_double_quoted_attribute(&link_info.url),
+            html_escape::encode_text(&link_info.title),
+            html_escape::encode_text(&format_tags(&tags))
+        ),
+        Err(e) => {
+            console_error!("Error tagging {}: {}", link_info.url, e);
+            format!("Error tagging link: {}", e)
+        }
+    }
+}
+
+/// Tags as hashtags, e.g. `#rust #async`
+fn format_tags<S: AsRef<str>>(tags: &[S]) -> String {
+    tags.iter()
+        .map(|tag| format!("#{}", tag.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Contrast two saved links: what they share, where they differ and which to trust
+async fn compare_links(env: Env, config: &Config, first: &str, second: &str) -> String {
+    let first = match find_saved_link(&env, first).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+    let second = match find_saved_link(&env, second).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+    if first.id == second.id {
+        return "Both URLs are the same saved link".to_string();
+    }
+
+    let comparison = match crate::handlers::compare_links(&env, config, &first, &second).await {
+        Ok(comparison) => comparison,
+        Err(e) => {
+            console_error!("Error comparing {} and {}: {}", first.url, second.url, e);
+            return format!(
+                "Error comparing links: {}",
+                html_escape::encode_text(&e.to_string())
+            );
+        }
+    };
+    let title = |link_info: &DocInfo| {
+        format!(
+            "<a href=\"{}\">{}</a>",
+            html_escape::encode_double_quoted_attribute(&link_info.url),
+            html_escape::encode_text(&link_info.title)
+        )
+    };
+    let points = |points: &[String]| {
+        if points.is_empty() {
+            "• -\n".to_string()
+        } else {
+            points
+                .iter()
+                .map(|point| format!("• {}\n", html_escape::encode_text(point)))
+                .collect::<String>()
+        }
+    };
+    format!(
+        "⚖️ <b>1.</b> {}\n<b>2.</b> {}\n\n\
+        <b>Shared</b>\n{}\n\
+        <b>Differences</b>\n{}\n\
+        <b>Assessment</b>\n{}",
+        title(&first),
+        title(&second),
+        points(&comparison.shared),
+        points(&comparison.differences),
+        html_escape::encode_text(&comparison.assessment)
+    )
+}
+
+/// Show the stored metadata of a link, e.g. to spot sites serving the wrong content type
+async fn debug_link(env: Env, url: &str) -> String {
+    let link_info = match find_saved_link(&env, url).await {
+        Ok(link_info) => link_info,
+        Err(reply) => return reply,
+    };
+
+    let reported = link_info
+        .reported_content_type
+        .as_deref()
+        .unwrap_or(&link_info.content_type);
+    let content_type = if reported == link_info.content_type {
+        html_escape::encode_text(&link_info.content_type).to_string()
+    } else {
+        format!(
+            "served as {}, detected as {}",
+            html_escape::encode_text(reported),
+            html_escape::encode_text(&link_info.content_type)
+        )
+    };
+    let optional = |value: &Option<String>| {
+        html_escape::encode_text(value.as_deref().unwrap_or("-")).to_string()
+    };
+    format!(
+        "<b>ID:</b> <code>{}</code>\n\
+        <b>URL:</b> {}\n\
+        <b>Saved:</b> {}\n\
+        <b>Type:</b> {} {}\n\
+        <b>Object:</b> <code>{}</code> ({}){}\n\
+        <b>Chunks:</b> {} (original {})\n\
+        <b>Author:</b> {} {}\n\
+        <b>Language:</b> {}\n\
+        <b>Embedding model:</b> {}\n\
+        <b>Status:</b> {} {}\n\
+        <b>ETag:</b> {}\n\
+        <b>Last-Modified:</b> {}\n\
+        <b>Last checked:</b> {} {}\n",
+        link_info.id,
+        html_escape::encode_text(&link_info.url),
+        link_info.created_at,
+        format_type_emoji(&link_info.content_type),
+        content_type,
+        html_escape::encode_text(&link_info.bucket_path),
+        crate::utils::format_size(link_info.size),
+        link_info
+            .content_expired_at
+            .as_ref()
+            .map_or(String::new(), |date| format!(", expired {}", date)),
+        link_info.chunk_count,
+        link_info
+            .original_chunk_count
+            .map_or("-".to_string(), |count| count.to_string()),
+        optional(&link_info.author),
+        optional(&link_info.published_at),
+        optional(&link_info.language),
+        optional(&link_info.embedding_model),
+        optional(&link_info.status),
+        optional(&link_info.status_error),
+        optional(&link_info.etag),
+        optional(&link_info.last_modified),
+        optional(&link_info.last_checked),
+        link_info
+            .http_status
+            .map_or(String::new(), |status| format!("(HTTP {})", status)),
+    )
+}
+
+/// Ask to confirm deleting the link a URL resolves to, or which one when several saved links
+/// could be meant, the link is only deleted once a button is pressed
+async fn delete_link(env: Env, token: &str, chat_id: i64, url: &str) -> String {
+    let candidates = match crate::handlers::resolve_link(&env, url).await {
+        Ok(LinkMatch::Found(link_info)) => {
+            let text = format!(
+                "Delete this link? This can't be undone.\n\n\
+                <b>Title:</b> {}\n\
+                <b>URL:</b> {}",
+                html_escape::encode_text(&link_info.title),
+                html_escape::encode_text(&link_info.url)
+            );
+            let buttons = [vec![
+                (
+                    "🗑 Confirm".to_string(),
+                    format!("{}:{}", CALLBACK_DELETE, link_info.id),
+                ),
+                ("Cancel".to_string(), CALLBACK_CANCEL.to_string()),
+            ]];
+            return match send_message_with_buttons(token, chat_id, &text, &buttons).await {
+                Ok(()) => String::new(),
+                Err(e) => format!("Error asking to confirm the deletion: {}", e),
+            };
+        }
+        Ok(LinkMatch::Candidates(candidates)) => candidates,
+        Ok(LinkMatch::NotFound) => {
+            return format!("Link not found: {}", html_escape::encode_text(url))
+        }
+        Err(e) => return format_delete_result(Err(e)),
+    };
+
+    let buttons = candidates
+        .iter()
+        .map(|link_info| {
+            let title = crate::utils::snippet(&link_info.title, "", 60);
+            vec![(
+                format!("🗑 {}", title),
+                format!("{}:{}", CALLBACK_DELETE, link_info.id),
+            )]
+        })
+        .collect::<Vec<_>>();
+    let mut text = format!(
+        "No exact match for {}, which one should be deleted?\n",
+        html_escape::encode_text(url)
+    );
+    for (i, link_info) in candidates.iter().enumerate() {
+        text.push_str(&format!(
+            "\n{}. {}",
+            i + 1,
+            html_escape::encode_text(&link_info.url)
+        ));
+    }
+    match send_message_with_buttons(token, chat_id, &text, &buttons).await {
+        Ok(()) => String::new(),
+        Err(e) => format!("Error asking which link to delete: {}", e),
+    }
+}
+
+async fn delete_link_by_id(env: Env, config: &Config, id: &str) -> String {
+    format_delete_result(crate::handlers::delete_link_by_id(&env, config, id).await)
+}
+
+/// Chat setting prefix holding the ids of links waiting for a batch deletion to be confirmed
+const PENDING_DELETE_KEY: &str = "pending_delete";
+/// Links listed by title when asking to confirm a batch deletion, the rest are counted
+const DELETE_PREVIEW_LINKS: usize = 20;
+
+/// Ask to confirm deleting several links at once, given as URLs or `#tag`s that take every link
+/// with the tag; the links are held under a key and only deleted once the button is pressed
+async fn confirm_delete_links(env: Env, token: &str, chat_id: i64, targets: &[&str]) -> String {
+    let mut links: Vec<DocInfo> = vec![];
+    let mut missing = vec![];
+    for target in targets {
+        let found = if target.starts_with('#') {
+            let mut tagged = vec![];
+            for tag in crate::d1::parse_tags(target) {
+                match crate::d1::get_links_by_tag(&env, &tag).await {
+                    Ok(links) => tagged.extend(links),
+                    Err(e) => {
+                        console_error!("Error finding links tagged {}: {}", tag, e);
+                        return format!("Error finding links tagged {}: {}", tag, e);
+                    }
+                }
+            }
+            tagged
+        } else {
+            crate::d1::find_link_by_url(&env, target)
+                .await
+                .map(|link_info| vec![link_info])
+                .unwrap_or_default()
+        };
+        if found.is_empty() {
+            missing.push(*target);
+        }
+        for link_info in found {
+            if !links.iter().any(|link| link.id == link_info.id) {
+                links.push(link_info);
+            }
+        }
+    }
+    let not_found = if missing.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "
+Not found: {}",
+            html_escape::encode_text(&missing.join(", "))
+        )
+    };
+    if links.is_empty() {
+        return format!("Nothing to delete{}", not_found);
+    }
+
+    drop_stale_pending(&env).await;
+    let ids = links.iter().map(|link| link.id.clone()).collect::<Vec<_>>();
+    let key = crate::utils::sha256_hex(ids.join(",").as_bytes())[..16].to_string();
+    let setting = format!("{}:{}", PENDING_DELETE_KEY, key);
+    let pending = json!({ "ids": ids, "created_at": pending_created_at() });
+    if let Err(e) = crate::d1::set_chat_setting(&env, chat_id, &setting, &pending.to_string()).await
+    {
+        console_error!("Error holding links for deletion: {}", e);
+        return format!("Error deleting links: {}", e);
+    }
+
+    let mut text = format!(
+        "Delete these {} links? This can't be undone.\n\n",
+        links.len()
+    );
+    for link_info in links.iter().take(DELETE_PREVIEW_LINKS) {
+        text.push_str(&format!(
+            "{} {}\n",
+            format_type_emoji(&link_info.content_type),
+            html_escape::encode_text(&link_info.title)
+        ));
+    }
+    if links.len() > DELETE_PREVIEW_LINKS {
+        text.push_str(&format!(
+            "…and {} more\n",
+            links.len() - DELETE_PREVIEW_LINKS
+        ));
+    }
+    text.push_str(&not_found);
+    let buttons = [vec![
+        (
+            format!("🗑 Delete {}", links.len()),
+            format!("{}:{}:{}", CALLBACK_DELETE, CALLBACK_DELETE_BATCH, key),
+        ),
+        ("Cancel".to_string(), CALLBACK_CANCEL.to_string()),
+    ]];
+    match send_message_with_buttons(token, chat_id, &text, &buttons).await {
+        Ok(()) => String::new(),
+        Err(e) => format!("Error asking to confirm the deletion: {}", e),
+    }
+}
+
+/// Delete the links held by `confirm_delete_links` once the deletion is confirmed
+async fn delete_held_links(env: Env, config: &Config, chat_id: i64, key: &str) -> String {
+    drop_stale_pending(&env).await;
+    let setting = format!("{}:{}", PENDING_DELETE_KEY, key);
+    let pending = match crate::d1::get_chat_setting(&env, chat_id, &setting).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            return "These links were already deleted, or the confirmation expired".to_string()
+        }
+        Err(e) => {
+            console_error!("Error reading links held for deletion: {}", e);
+            return format!("Error deleting links: {}", e);
+        }
+    };
+    if let Err(e) = crate::d1::delete_chat_setting(&env, chat_id, &setting).await {
+        console_error!("Error forgetting links held for deletion: {}", e);
+    }
+    let ids = serde_json::from_str::<serde_json::Value>(&pending)
+        .ok()
+        .and_then(|pending| serde_json::from_value::<Vec<String>>(pending["ids"].clone()).ok())
+        .unwrap_or_default();
+    // D1 takes at most 100 bound parameters per query
+    let mut links = vec![];
+    for ids in ids.chunks(100) {
+        match crate::d1::get_links_by_ids(&env, ids).await {
+            Ok(found) => links.extend(found),
+            Err(e) => {
+                console_error!("Error reading links held for deletion: {}", e);
+                return format!("Error deleting links: {}", e);
+            }
+        }
+    }
+
+    match crate::handlers::delete_links(&env, config, links).await {
+        Ok(deleted) => {
+            let mut ret = format!("✅ Deleted {} links\n", deleted.len());
+            for link_info in deleted {
+                ret.push_str(&format!(
+                    "{} {}\n",
+                    format_type_emoji(&link_info.content_type),
+                    html_escape::encode_text(&link_info.title)
+                ));
+            }
+            ret
+        }
+        Err(e) => {
+            console_error!("Error deleting links: {}", e);
+            format!("Error deleting links: {}\n", e)
+        }
+    }
+}
+
+fn format_delete_result(result: Result<DocInfo>) -> String {
+    match result {
+        Ok(link_info) => {
+            format!(
+                "✅ Successfully deleted:\n\
+                <b>URL:</b> {}\n\
+                <b>Title:</b> {}\n\
+                <b>Type:</b> {} {}\n",
+                link_info.url,
+                html_escape::encode_text(&link_info.title),
+                format_type_emoji(&link_info.content_type),
+                link_info.content_type
+            )
+        }
+        Err(e) => {
+            console_error!("Error deleting link: {}", e);
+            format!("Error deleting link: {}", e)
+        }
+    }
+}
+
+/// Helper function to determine file type emoji based on content type
+pub fn format_type_emoji(content_type: &str) -> &'static str {
+    match content_type.split(';').next().unwrap_or("") {
+        "text/html" => "🌐",
+        "application/pdf" => "📄",
+        t if t.starts_with("image/") => "🖼️",
+        "text/plain" => "📝",
+        _ => "📁",
+    }
+}
+
+impl DocInfo {
+    fn format_telegram_message(&self) -> String {
+        let chunks = match self.original_chunk_count {
+            Some(original) => format!(
+                "{} of {} chunks, sampled evenly ⚠️",
+                self.chunk_count, original
+            ),
+            None => format!("{} chunks", self.chunk_count),
+        };
+        let tags = self.tag_list();
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<b>Tags:</b> {}\n",
+                html_escape::encode_text(&format_tags(&tags))
+            )
+        };
+        format!(
+            "{}<a href=\"{}\">{}</a>\n\
+            <b>Size:</b> {} ({})\n\
+            {}\
+            <b>Summary:</b>\n{}\n",
             format_type_emoji(&self.content_type),
-            self.url,
+            html_escape::encode_double_quoted_attribute(&self.url),
             html_escape::encode_text(&self.title),
             crate::utils::format_size(self.size),
-            self.chunk_count,
+            chunks,
+            tags,
             html_escape::encode_text(&self.summary)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_commands() {
+        assert_eq!(normalize_command("/Search@SeenBot Rust"), "/search Rust");
+        assert_eq!(normalize_command("/LIST"), "/list");
+        assert_eq!(normalize_command("/list@SeenBot"), "/list");
+        assert_eq!(
+            normalize_command("/tag https://a.com/@user x"),
+            "/tag https://a.com/@user x"
+        );
+        assert_eq!(normalize_command("Hello @bot"), "Hello @bot");
+    }
+
+    #[test]
+    fn meters_commands_that_cost_api_calls() {
+        assert!(is_metered("/search rust"));
+        assert!(is_metered("https://example.com"));
+        assert!(is_metered("rust async"));
+        assert!(!is_metered("/list"));
+        assert!(!is_metered("/searching"));
+    }
+
+    fn message(chat_id: i64, username: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "chat": { "id": chat_id },
+            "from": { "id": chat_id, "username": username },
+            "text": "/whoami",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn whoami_answers_without_authorized_chats() {
+        // A new owner runs /whoami before AUTHORIZED_CHAT_IDS is set, to learn the id to set
+        let config = crate::config::tests::parse(&[]).unwrap();
+        let reply = whoami(&config, &message(42, "a<b"));
+        assert!(reply.contains("<code>42</code>"), "{}", reply);
+        assert!(reply.contains("@a&lt;b"), "{}", reply);
+        assert!(reply.contains("Not authorized"), "{}", reply);
+
+        let config = crate::config::tests::parse(&[("AUTHORIZED_CHAT_IDS", "7 42")]).unwrap();
+        assert!(whoami(&config, &message(42, "alice")).ends_with("✅ Authorized"));
+        assert!(whoami(&config, &message(7, "bob")).contains("the owner"));
+    }
+
+    fn link(url: &str, title: &str, summary: &str) -> DocInfo {
+        serde_json::from_value(json!({
+            "id": "id",
+            "url": url,
+            "created_at": "2024-05-01 10:00:00",
+            "bucket_path": "content/id.html",
+            "content_type": "text/html",
+            "size": 1024,
+            "title": title,
+            "summary": summary,
+            "chunk_count": 2,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn links_escape_quotes_in_urls() {
+        let link_info = link("https://a.com/?q=\"x\"&y=<1>", "A & B", "");
+        let text = inline_message_text(&link_info);
+        assert!(
+            text.contains("href=\"https://a.com/?q=&quot;x&quot;&amp;y=&lt;1&gt;\""),
+            "{}",
+            text
+        );
+        assert!(text.contains(">A &amp; B</a>"), "{}", text);
+        let message = link_info.format_telegram_message();
+        assert!(message.contains("q=&quot;x&quot;"), "{}", message);
+    }
+
+    #[test]
+    fn templates_substitute_and_escape_placeholders() {
+        let link_info = link("https://a.com/?a=1&b=\"2\"", "<b>&\"", "<b>&\" summary");
+        let template =
+            ResultTemplate::parse("<i>{title}</i> | {url} | {summary} | {link}").unwrap();
+        assert_eq!(
+            template.render(&link_info, None),
+            "&lt;i&gt;&lt;b&gt;&amp;\"&lt;/i&gt; | https://a.com/?a=1&amp;b=\"2\" | \
+            &lt;b&gt;&amp;\" summary | \
+            <a href=\"https://a.com/?a=1&amp;b=&quot;2&quot;\">&lt;b&gt;&amp;\"</a>"
+        );
+        let template = ResultTemplate::parse("{date} {score}{tags}").unwrap();
+        assert_eq!(
+            template.render(&link_info, Some("<90%>")),
+            "2024-05-01 &lt;90%&gt;"
+        );
+    }
+
+    #[test]
+    fn invalid_templates_fall_back_to_the_default() {
+        for template in ["{title", "title}", "{nope}", "no placeholders"] {
+            assert!(ResultTemplate::parse(template).is_err(), "{}", template);
+        }
+        let link_info = link("https://a.com", "A", "");
+        let default = ResultTemplate::parse(LIST_RESULT_TEMPLATE).unwrap();
+        for saved in [None, Some(""), Some("{nope}")] {
+            let template = saved_template_or(1, saved, LIST_RESULT_TEMPLATE);
+            assert_eq!(
+                template.render(&link_info, None),
+                default.render(&link_info, None)
+            );
+        }
+        let template = saved_template_or(1, Some("{title}!"), LIST_RESULT_TEMPLATE);
+        assert_eq!(template.render(&link_info, None), "A!");
+    }
+
+    #[test]
+    fn rating_buttons_round_trip() {
+        let query_key = crate::handlers::query_key("Rust  Async");
+        let link_id = "0b7e2d6c-3f1a-4c59-9d2e-6a8f1b2c3d4e";
+        let data = feedback_data("+", &query_key, link_id);
+        // Telegram drops buttons whose callback data is over 64 bytes
+        assert!(data.len() <= 64, "{}", data);
+        let rating = data
+            .strip_prefix(CALLBACK_FEEDBACK)
+            .unwrap()
+            .strip_prefix(':')
+            .unwrap();
+        assert_eq!(
+            parse_feedback(rating),
+            Some((1, query_key.as_str(), link_id))
+        );
+        assert_eq!(parse_feedback("-:key:id"), Some((-1, "key", "id")));
+        assert_eq!(parse_feedback("?:key:id"), None);
+        assert_eq!(parse_feedback("+:key"), None);
+    }
+
+    #[test]
+    fn scores_map_to_percent_and_stars_at_the_boundaries() {
+        use ScoreFormat::{Percent, Raw, Stars};
+        // Negative cosine is as unrelated as it gets, not a negative percentage
+        assert_eq!(format_score(Percent, -0.3, false), "0%");
+        assert_eq!(format_score(Percent, 0.0, false), "0%");
+        assert_eq!(format_score(Percent, 0.734, false), "73%");
+        assert_eq!(format_score(Percent, 1.0, false), "100%");
+        assert_eq!(format_score(Stars, -0.3, false), "☆☆☆☆☆");
+        assert_eq!(format_score(Stars, 0.0, false), "☆☆☆☆☆");
+        assert_eq!(format_score(Stars, 0.5, false), "★★★☆☆");
+        assert_eq!(format_score(Stars, 1.0, false), "★★★★★");
+        assert_eq!(format_score(Raw, -0.3, false), "-0.30");
+        assert_eq!(format_score(Raw, 1.0, false), "1.00");
+    }
+
+    #[test]
+    fn explained_scores_keep_the_raw_value() {
+        use ScoreFormat::{Percent, Raw, Stars};
+        assert_eq!(format_score(Percent, -0.25, true), "0%, -0.2500");
+        assert_eq!(format_score(Stars, 1.0, true), "★★★★★, 1.0000");
+        assert_eq!(format_score(Raw, 0.73129, true), "0.7313");
+    }
+
+    #[test]
+    fn previews_are_labeled_as_not_saved() {
+        let text = preview_message(&link("https://a.com/post", "A post", "About it"));
+        assert!(text.starts_with("👀 <b>Preview, not saved</b>\n"));
+        assert!(text.contains("A post"));
+        assert!(text.ends_with("Send the link to save it"));
+    }
+
+    #[test]
+    fn citations_link_to_their_sources() {
+        let sources = [
+            link("https://a.com/?x=\"1\"", "A", ""),
+            link("https://b.com/", "B", ""),
+        ];
+        let (text, cited) = link_citations(
+            "It's fast [2][1]. Also <safe> [2], see [3] and [x].",
+            &sources,
+        );
+        assert_eq!(
+            text,
+            "It's fast <a href=\"https://b.com/\">[2]</a><a href=\"https://a.com/?x=&quot;1&quot;\">[1]</a>. \
+             Also &lt;safe&gt; <a href=\"https://b.com/\">[2]</a>, see [3] and [x]."
+        );
+        // Each cited source once, in source order
+        assert_eq!(cited, [1, 2]);
+        let (text, cited) = link_citations("The sources don't say.", &sources);
+        assert_eq!(text, "The sources don't say.");
+        assert!(cited.is_empty());
+    }
+}
@@ -1,13 +1,34 @@
+use crate::config::{CodeBlocks, Config};
 use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 use worker::*;
 
 /// Structured data returned from Gemini API for link processing
-#[derive(Debug, Deserialize, Serialize)]
+/// Fields may be missing from a truncated response and are filled in by follow-ups
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ProcessedLinkData {
+    #[serde(default)]
     pub title: String,
+    #[serde(default)]
     pub summary: String,
+    #[serde(default)]
     pub chunks: Vec<String>,
+    #[serde(default)]
+    pub language: String,
+}
+
+/// Structured comparison of two documents from Gemini
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Comparison {
+    #[serde(default)]
+    pub shared: Vec<String>,
+    #[serde(default)]
+    pub differences: Vec<String>,
+    /// Which document is more recent or authoritative, and why
+    #[serde(default)]
+    pub assessment: String,
 }
 
 /// Helper function to format file sizes
@@ -40,18 +61,453 @@ pub fn get_extension_from_content_type(content_type: &str) -> &'static str {
     }
 }
 
+/// Detect a content type from well-known magic bytes
+fn sniff_magic_bytes(content: &[u8]) -> Option<&'static str> {
+    if content.starts_with(b"%PDF") {
+        return Some("application/pdf");
+    }
+    if content.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if content.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+
+    // HTML may be preceded by a BOM or whitespace
+    let text = content.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(content);
+    let start = text
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(text.len());
+    let head = &text[start..];
+    let starts_with = |prefix: &[u8]| {
+        head.len() >= prefix.len() && head[..prefix.len()].eq_ignore_ascii_case(prefix)
+    };
+    if starts_with(b"<!doctype html") || starts_with(b"<html") {
+        return Some("text/html");
+    }
+    None
+}
+
+/// Correct content types that are obviously wrong given the content itself
+/// Binary signatures always win; HTML only overrides generic types like `application/octet-stream`
+pub fn correct_content_type(content: &[u8], reported: &str) -> String {
+    let Some(sniffed) = sniff_magic_bytes(content) else {
+        return reported.to_string();
+    };
+
+    let reported_base = reported.split(';').next().unwrap_or("").trim();
+    if reported_base.eq_ignore_ascii_case(sniffed) {
+        return reported.to_string();
+    }
+
+    let is_generic = matches!(
+        reported_base.to_ascii_lowercase().as_str(),
+        "" | "application/octet-stream"
+            | "binary/octet-stream"
+            | "application/binary"
+            | "application/x-download"
+            | "application/force-download"
+            | "text/plain"
+    );
+    if sniffed != "text/html" || is_generic {
+        sniffed.to_string()
+    } else {
+        reported.to_string()
+    }
+}
+
+/// Text encodings HTML is decoded from, others are read as UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// Also what browsers use for pages labeled ISO-8859-1, Latin-1 or ASCII
+    Windows1252,
+}
+
+impl Charset {
+    /// The encoding a `charset` label names, by the WHATWG encoding standard's labels
+    fn from_label(label: &str) -> Option<Self> {
+        match label.trim().to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Some(Self::Utf8),
+            "utf-16" | "utf-16le" | "unicode" => Some(Self::Utf16Le),
+            "utf-16be" | "unicodefffe" => Some(Self::Utf16Be),
+            "windows-1252" | "cp1252" | "x-cp1252" | "iso-8859-1" | "iso8859-1" | "iso_8859-1"
+            | "latin1" | "l1" | "us-ascii" | "ascii" => Some(Self::Windows1252),
+            _ => None,
+        }
+    }
+}
+
+/// Characters of windows-1252 bytes 0x80 to 0x9F, where it differs from Latin-1
+/// The five undefined bytes map to the control characters of the same value, as browsers do
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// Bytes of an HTML document searched for a `<meta>` charset declaration, as browsers do
+const META_CHARSET_SCAN_BYTES: usize = 1024;
+
+/// Charset of an HTML document and the length of its byte order mark
+/// A byte order mark wins over the `charset` of the content type, which wins over a `<meta>`
+/// declaration; without any the document is read as UTF-8
+fn html_charset(content: &[u8], content_type: &str) -> (Charset, usize) {
+    if content.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return (Charset::Utf8, 3);
+    }
+    if content.starts_with(&[0xFF, 0xFE]) {
+        return (Charset::Utf16Le, 2);
+    }
+    if content.starts_with(&[0xFE, 0xFF]) {
+        return (Charset::Utf16Be, 2);
+    }
+    let declared = content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, value)| Charset::from_label(value.trim().trim_matches('"')));
+    if let Some(charset) = declared {
+        return (charset, 0);
+    }
+
+    // A document that can declare its charset in ASCII isn't UTF-16, whatever the declaration says
+    let head = String::from_utf8_lossy(&content[..content.len().min(META_CHARSET_SCAN_BYTES)])
+        .to_ascii_lowercase();
+    let charset = head
+        .split("<meta")
+        .skip(1)
+        .filter_map(|tag| {
+            let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+            let value = &tag[tag.find("charset")? + "charset".len()..];
+            let value = value.trim_start().strip_prefix('=')?.trim_start();
+            let value = value.trim_start_matches(['"', '\'']);
+            let end = value
+                .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | ';' | '/' | '>'))
+                .unwrap_or(value.len());
+            Charset::from_label(&value[..end])
+        })
+        .next()
+        .map(|charset| match charset {
+            Charset::Utf16Le | Charset::Utf16Be => Charset::Utf8,
+            charset => charset,
+        });
+    (charset.unwrap_or(Charset::Utf8), 0)
+}
+
+/// Text of an HTML document decoded from its charset, see `html_charset`
+/// Invalid sequences become U+FFFD rather than failing
+pub fn decode_html(content: &[u8], content_type: &str) -> String {
+    let (charset, bom) = html_charset(content, content_type);
+    let content = &content[bom..];
+    match charset {
+        Charset::Utf8 => String::from_utf8_lossy(content).into_owned(),
+        Charset::Utf16Le | Charset::Utf16Be => {
+            let units = content.chunks_exact(2).map(|pair| {
+                if charset == Charset::Utf16Le {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                }
+            });
+            char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect()
+        }
+        Charset::Windows1252 => content
+            .iter()
+            .map(|&b| match b {
+                0x80..=0x9F => WINDOWS_1252_HIGH[(b - 0x80) as usize],
+                _ => b as char,
+            })
+            .collect(),
+    }
+}
+
+/// The `<title>` of an HTML document in any charset `decode_html` reads, with entities decoded
+/// and whitespace collapsed; `None` when it has no title or an empty one
+pub fn extract_title(content: &[u8], content_type: &str) -> Option<String> {
+    let text = decode_html(content, content_type);
+    // ASCII lowercasing keeps byte offsets, so positions found in it index the original
+    let lower = text.to_ascii_lowercase();
+    let mut from = 0;
+    let start = loop {
+        let tag = from + lower[from..].find("<title")?;
+        let after = tag + "<title".len();
+        // `<titles>` or `<title-bar>` aren't the title element
+        match lower[after..].chars().next() {
+            Some('>') | Some('/') => break after,
+            Some(c) if c.is_ascii_whitespace() => break after,
+            _ => from = after,
+        }
+    };
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start
+        + lower[start..]
+            .find("</title")
+            .unwrap_or(lower.len() - start);
+    let title = html_escape::decode_html_entities(&text[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(title).filter(|title| !title.is_empty())
+}
+
+/// Query parameters that only record where a link was shared from
+fn is_tracking_param(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("utm_") || name == "fbclid" || name == "gclid"
+}
+
+/// Normalize a URL so that variants of the same page share one dedup key
+/// Lowercases the host, strips default ports, the trailing slash, the fragment and tracking
+/// parameters, and sorts the remaining parameters
+/// Strings that don't parse as URLs are only trimmed
+pub fn normalize_url(link: &str) -> String {
+    let link = link.trim();
+    // Parsing already lowercases the scheme and host and drops default ports
+    let Ok(mut url) = Url::parse(link) else {
+        return link.to_string();
+    };
+
+    url.set_fragment(None);
+    let mut params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| !is_tracking_param(name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    params.sort();
+    if params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    // The root path always serializes as "/", so only deeper paths lose the slash
+    let path = url.path().trim_end_matches('/').to_string();
+    if !path.is_empty() {
+        url.set_path(&path);
+    }
+
+    url.to_string()
+}
+
+/// Look up the Gemini model, checking the API key and connectivity without spending tokens
+pub async fn ping_gemini(config: &Config) -> Result<()> {
+    let api_url = format!(
+        "https://generativelanguage.googleapis.com/v1beta/models/{}?key={}",
+        config.gemini_model, config.gemini_api_key
+    );
+    let response = Fetch::Url(Url::parse(&api_url)?).send().await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Gemini API returned status {}",
+            response.status_code()
+        )));
+    }
+    Ok(())
+}
+
+/// The http/https and `www.` variants of a normalized URL, which usually point to the same page
+pub fn url_variants(link: &str) -> Vec<String> {
+    let normalized = normalize_url(link);
+    let Ok(url) = Url::parse(&normalized) else {
+        return vec![normalized];
+    };
+    let Some(host) = url.host_str() else {
+        return vec![normalized];
+    };
+    let bare_host = host.strip_prefix("www.").unwrap_or(host).to_string();
+
+    let mut variants = vec![normalized.clone()];
+    for scheme in ["https", "http"] {
+        for host in [bare_host.clone(), format!("www.{}", bare_host)] {
+            let mut variant = url.clone();
+            if variant.set_scheme(scheme).is_ok() && variant.set_host(Some(&host)).is_ok() {
+                let variant = variant.to_string();
+                if !variants.contains(&variant) {
+                    variants.push(variant);
+                }
+            }
+        }
+    }
+    variants
+}
+
+/// Above this combined size content is uploaded through the Gemini Files API instead of inlined,
+/// base64 makes inline data a third larger and a request may be at most 20 MB
+const INLINE_DATA_MAX_BYTES: usize = 10 * 1024 * 1024;
+/// How often to check whether an uploaded file is ready, one second apart
+const FILE_PROCESSING_POLLS: usize = 60;
+
+/// Content sent along with a Gemini prompt
+enum Attachment<'a> {
+    /// Base64-encoded into the request
+    Inline { mime_type: &'a str, data: &'a [u8] },
+    /// Uploaded through the Files API, expires after 48 hours
+    File { mime_type: String, uri: String },
+}
+
+impl Attachment<'_> {
+    fn part(&self) -> serde_json::Value {
+        match self {
+            Attachment::Inline { mime_type, data } => serde_json::json!({
+                "inline_data": {
+                    "mime_type": mime_type,
+                    "data": STANDARD.encode(data)
+                }
+            }),
+            Attachment::File { mime_type, uri } => serde_json::json!({
+                "file_data": {
+                    "mime_type": mime_type,
+                    "file_uri": uri
+                }
+            }),
+        }
+    }
+}
+
+/// Whether contents sent together are too large to inline, counted before base64
+fn needs_upload(contents: &[(&str, &[u8])]) -> bool {
+    contents.iter().map(|(_, data)| data.len()).sum::<usize>() > INLINE_DATA_MAX_BYTES
+}
+
+/// Prepare content for Gemini requests, uploading it when it's too large to inline
+/// The result can be reused across requests, e.g. follow-ups of a truncated response
+async fn attach<'a>(
+    config: &Config,
+    contents: &[(&'a str, &'a [u8])],
+) -> Result<Vec<Attachment<'a>>> {
+    let upload = needs_upload(contents);
+    let mut attachments = vec![];
+    for (mime_type, data) in contents {
+        if !upload {
+            attachments.push(Attachment::Inline { mime_type, data });
+        } else {
+            attachments.push(Attachment::File {
+                mime_type: mime_type.to_string(),
+                uri: upload_file(config, mime_type, data).await?,
+            });
+        }
+    }
+    Ok(attachments)
+}
+
+/// Upload content with the resumable protocol of the Gemini Files API, returning its URI once ready
+async fn upload_file(config: &Config, mime_type: &str, data: &[u8]) -> Result<String> {
+    let api_key = &config.gemini_api_key;
+    console_log!(
+        "Uploading {} of {} to the Gemini Files API",
+        format_size(data.len()),
+        mime_type
+    );
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("X-Goog-Upload-Protocol", "resumable")?;
+    headers.set("X-Goog-Upload-Command", "start")?;
+    headers.set(
+        "X-Goog-Upload-Header-Content-Length",
+        &data.len().to_string(),
+    )?;
+    headers.set("X-Goog-Upload-Header-Content-Type", mime_type)?;
+    let mut req_init = RequestInit::new();
+    req_init
+        .with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(wasm_bindgen::JsValue::from_str(
+            &serde_json::json!({"file": {"display_name": "seen"}}).to_string(),
+        )));
+    let api_url = format!(
+        "https://generativelanguage.googleapis.com/upload/v1beta/files?key={}",
+        api_key
+    );
+    let mut response = Fetch::Request(Request::new_with_init(&api_url, &req_init)?)
+        .send()
+        .await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Gemini file upload failed to start: Status {}, Error: {}",
+            response.status_code(),
+            response.text().await?
+        )));
+    }
+    let upload_url = response
+        .headers()
+        .get("X-Goog-Upload-URL")?
+        .ok_or_else(|| Error::from("Gemini file upload returned no upload URL"))?;
+
+    let mut headers = Headers::new();
+    headers.set("X-Goog-Upload-Offset", "0")?;
+    headers.set("X-Goog-Upload-Command", "upload, finalize")?;
+    let mut req_init = RequestInit::new();
+    req_init
+        .with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(js_sys::Uint8Array::from(data).into()));
+    let mut response = Fetch::Request(Request::new_with_init(&upload_url, &req_init)?)
+        .send()
+        .await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Gemini file upload failed: Status {}, Error: {}",
+            response.status_code(),
+            response.text().await?
+        )));
+    }
+    let mut file = response.json::<serde_json::Value>().await?["file"].take();
+
+    // Large PDFs are processed before they can be referenced
+    for _ in 0..FILE_PROCESSING_POLLS {
+        match file["state"].as_str() {
+            Some("PROCESSING") => {}
+            Some("FAILED") => {
+                return Err(Error::from("Gemini failed to process the uploaded file"))
+            }
+            _ => {
+                return file["uri"]
+                    .as_str()
+                    .map(|uri| uri.to_string())
+                    .ok_or_else(|| Error::from("Gemini file upload returned no file URI"))
+            }
+        }
+        Delay::from(std::time::Duration::from_secs(1)).await;
+        let name = file["name"]
+            .as_str()
+            .ok_or_else(|| Error::from("Gemini file upload returned no file name"))?;
+        let api_url = format!(
+            "https://generativelanguage.googleapis.com/v1beta/{}?key={}",
+            name, api_key
+        );
+        let mut response = Fetch::Url(Url::parse(&api_url)?).send().await?;
+        file = response.json::<serde_json::Value>().await?;
+    }
+    Err(Error::from(
+        "Gemini is still processing the uploaded file, try again later",
+    ))
+}
+
 /// Base function to make a request to Gemini API
 async fn gemini_api_request(
     env: &Env,
+    config: &Config,
     prompt: &str,
-    inline_content: (&str, &[u8]),
+    attachments: &[Attachment<'_>],
     response_schema: Option<serde_json::Value>,
-    previous_response: Option<&str>,
+    follow_up: Option<(&str, &str)>,
 ) -> Result<(String, bool)> {
-    let api_key = env.secret("GEMINI_API_KEY")?.to_string();
+    let timer = crate::metrics::Timer::start();
     let api_url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent?key={}",
-        api_key
+        "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
+        config.gemini_model, config.gemini_api_key
     );
 
     // Build parts array for the request
@@ -60,15 +516,11 @@ async fn gemini_api_request(
     })];
 
     // Add binary content if provided
-    parts.push(serde_json::json!({
-        "inline_data": {
-            "mime_type": inline_content.0,
-            "data": STANDARD.encode(inline_content.1)
-        }
-    }));
+    parts.extend(attachments.iter().map(Attachment::part));
 
     // Create the request payload with conversation structure if there's a previous response
-    let mut payload = if let Some(prev_response) = previous_response {
+    // `follow_up` holds the model's previous output and the instruction answering it
+    let mut payload = if let Some((prev_response, instruction)) = follow_up {
         serde_json::json!({
             "contents": [
                 {
@@ -81,7 +533,7 @@ async fn gemini_api_request(
                 },
                 {
                     "role": "user",
-                    "parts": [{"text": "Continue"}]
+                    "parts": [{"text": instruction}]
                 }
             ],
         })
@@ -94,41 +546,35 @@ async fn gemini_api_request(
         })
     };
 
+    let mut generation_config = serde_json::Map::new();
+    if let Some(temperature) = config.gemini_temperature {
+        generation_config.insert("temperature".to_string(), temperature.into());
+    }
+    if let Some(max_output_tokens) = config.gemini_max_output_tokens {
+        generation_config.insert("maxOutputTokens".to_string(), max_output_tokens.into());
+    }
     if let Some(response_schema) = response_schema {
-        payload["generationConfig"] = serde_json::json!({
-            "responseMimeType": "application/json",
-            "responseSchema": response_schema
-        });
+        generation_config.insert("responseMimeType".to_string(), "application/json".into());
+        generation_config.insert("responseSchema".to_string(), response_schema);
     }
-
-    // Make the request
-    let mut headers = Headers::new();
-    headers.set("Content-Type", "application/json")?;
-
-    let mut req_init = RequestInit::new();
-    req_init
-        .with_method(Method::Post)
-        .with_headers(headers)
-        .with_body(Some(wasm_bindgen::JsValue::from_str(&payload.to_string())));
-
-    let request = Request::new_with_init(&api_url, &req_init)?;
-    let mut response = Fetch::Request(request).send().await?;
-
-    // Handle errors
-    if response.status_code() != 200 {
-        let error_text = response.text().await?;
-        return Err(Error::from(format!(
-            "Gemini API failed: Status {}, Error: {}",
-            response.status_code(),
-            error_text
-        )));
+    if !generation_config.is_empty() {
+        payload["generationConfig"] = generation_config.into();
     }
 
+    let mut response = send_gemini_request(config, &api_url, &payload.to_string()).await?;
+
     // Parse the response
     let result = response.json::<serde_json::Value>().await?;
 
     console_log!("Gemini API response: {}", result);
 
+    let total_tokens = result
+        .get("usageMetadata")
+        .and_then(|u| u.get("totalTokenCount"))
+        .and_then(|t| t.as_f64())
+        .unwrap_or(0.0);
+    crate::metrics::record(env, "gemini", timer.elapsed_ms(), total_tokens);
+
     // Check if the response was truncated due to token limits
     let was_truncated = result
         .get("candidates")
@@ -153,20 +599,222 @@ async fn gemini_api_request(
     Ok((text, was_truncated))
 }
 
+/// POST a request to Gemini, retrying rate limits and server errors with backoff
+/// Failures that aren't transient, e.g. a 400 for a bad request, are returned right away
+async fn send_gemini_request(config: &Config, api_url: &str, body: &str) -> Result<Response> {
+    let send = || async {
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        let mut req_init = RequestInit::new();
+        req_init
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(wasm_bindgen::JsValue::from_str(body)));
+        let request = Request::new_with_init(api_url, &req_init)?;
+        Fetch::Request(request).send().await
+    };
+    crate::vector::retry_with_backoff(
+        crate::vector::RetryPolicy::from_config(config),
+        send,
+        is_transient_gemini_error,
+    )
+    .await
+    .map_err(|e| Error::from(format!("Gemini API failed: {}", e)))
+}
+
+/// Whether a failed Gemini request is worth another try: rate limits, overload and internal errors,
+/// by status code or by the status in the error body, which tells them apart from a bad request
+fn is_transient_gemini_error(status: u16, body: &str) -> bool {
+    if matches!(status, 429 | 500 | 503) {
+        return true;
+    }
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|error| error["error"]["status"].as_str().map(str::to_string))
+        .is_some_and(|status| {
+            matches!(
+                status.as_str(),
+                "RESOURCE_EXHAUSTED" | "UNAVAILABLE" | "INTERNAL"
+            )
+        })
+}
+
+/// Pages of a PDF to process, 1-based and inclusive, e.g. `1-20` or `5`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl PageRange {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (first, last) = spec.split_once('-').unwrap_or((spec, spec));
+        let page = |value: &str| value.trim().parse::<u32>().ok().filter(|page| *page > 0);
+        match (page(first), page(last)) {
+            (Some(first), Some(last)) if first <= last => Ok(PageRange { first, last }),
+            _ => Err(Error::from(format!(
+                "Invalid page range '{}', expected e.g. 1-20",
+                spec
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for PageRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// How the summary of a saved link is written, chosen per chat with `/style`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SummaryStyle {
+    #[default]
+    Dense,
+    Bullets,
+    Eli5,
+    Technical,
+}
+
+impl SummaryStyle {
+    pub const ALL: [SummaryStyle; 4] = [
+        SummaryStyle::Dense,
+        SummaryStyle::Bullets,
+        SummaryStyle::Eli5,
+        SummaryStyle::Technical,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SummaryStyle::Dense => "dense",
+            SummaryStyle::Bullets => "bullets",
+            SummaryStyle::Eli5 => "eli5",
+            SummaryStyle::Technical => "technical",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|style| style.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The prompt fragment asking for this kind of summary
+    fn prompt(&self) -> &'static str {
+        match self {
+            SummaryStyle::Dense => "You should generate a two sentence summary of the document, dense and concise brief",
+            SummaryStyle::Bullets => "You should summarize the document as three to five short bullet points, each starting with \"- \" on its own line",
+            SummaryStyle::Eli5 => "You should generate a short summary of the document that a curious twelve year old could follow, avoiding jargon",
+            SummaryStyle::Technical => "You should generate a three sentence technical summary of the document for an expert, covering the methods, key results and limitations",
+        }
+    }
+}
+
+/// Whether Gemini can turn content of this type into text, audio, video and binaries it can't
+pub fn is_summarizable(content_type: &str) -> bool {
+    let base = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let (kind, subtype) = base.split_once('/').unwrap_or((base.as_str(), ""));
+    !matches!(kind, "audio" | "video" | "font")
+        && !matches!(
+            subtype,
+            "octet-stream"
+                | "zip"
+                | "gzip"
+                | "x-gzip"
+                | "x-tar"
+                | "x-7z-compressed"
+                | "x-rar-compressed"
+                | "vnd.rar"
+                | "x-bzip2"
+                | "x-xz"
+                | "zstd"
+                | "wasm"
+                | "x-msdownload"
+                | "x-executable"
+                | "x-sharedlib"
+                | "x-apple-diskimage"
+                | "vnd.android.package-archive"
+        )
+}
+
+/// How to turn content of this type into Markdown, the rest of the prompt is shared
+fn content_prompt(content_type: &str) -> &'static str {
+    let base = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    match base.as_str() {
+        "text/plain" | "text/markdown" | "text/x-markdown" => {
+            "Rewrite the following text as clean Markdown, keeping the wording and adding headings and lists where the structure calls for them."
+        }
+        "application/json" => {
+            "The following is JSON data. Describe in Markdown what the data or API represents, its structure, \
+            and its notable fields and values, quoting short representative excerpts."
+        }
+        "text/javascript" | "application/javascript" | "application/x-javascript" | "text/css" => {
+            "The following is a source file. Summarize in Markdown what it does, its main functions, \
+            classes or rules, and how they fit together, quoting short representative snippets."
+        }
+        _ => {
+            "OCR the following content into Markdown without any html tags. Tables should be formatted as markdown tables. \
+            Figures should be described in the text, text in the figures should be extracted."
+        }
+    }
+}
+
+/// The prompt asking Gemini for the title, summary, language and chunks of content
+fn processing_prompt(
+    config: &Config,
+    content_type: &str,
+    style: SummaryStyle,
+    pages: Option<PageRange>,
+) -> String {
+    // Gemini reads PDFs natively and follows page numbers, so the range goes into the prompt
+    let pages = match pages {
+        Some(pages) if content_type.starts_with("application/pdf") => format!(
+            "Only use pages {} to {} of the document, ignore all other pages: the title, summary and chunks must come from those pages only.\n\n",
+            pages.first, pages.last
+        ),
+        Some(pages) => {
+            console_log!("Ignoring page range {} for {} content", pages, content_type);
+            String::new()
+        }
+        None => String::new(),
+    };
+    format!(
+        "{}{} \
+        Do not surround your output with triple backticks. \
+        Chunk the markdown content into sections of roughly {} tokens, each chunk should have roughly the same semantic (suitable for embedding). \
+        These chunks will be embedded and used in a RAG pipeline. Output in the chunks field, as array.\n\n\
+        {}, output in the summary field.\n\n\
+        You should extract the original title of the document, and if not present, you should generate one based on the content. output in the title field.\n\n\
+        You should detect the main language of the document, output its ISO 639-1 code (e.g. en, de, zh) in the language field.\n\n",
+        pages,
+        content_prompt(content_type),
+        config.chunk_token_size,
+        style.prompt()
+    )
+}
+
 /// Process a link with Gemini API and return structured data
+/// `pages` limits a PDF to a page range, which keeps the output of long documents within the
+/// follow-up requests; it's ignored for other content
 pub async fn chunk_and_summary_link(
     env: &Env,
+    config: &Config,
     content: &[u8],
     content_type: &str,
+    style: SummaryStyle,
+    pages: Option<PageRange>,
 ) -> Result<ProcessedLinkData> {
-    let initial_prompt =
-        "OCR the following content into Markdown without any html tags. Tables should be formatted as markdown tables. \
-        Figures should be described in the text, text in the figures should be extracted. \
-        Do not surround your output with triple backticks. \
-        Chunk the markdown content into sections of roughly 2000 tokens, each chunk should have roughly the same semantic (suitable for embedding). \
-        These chunks will be embedded and used in a RAG pipeline. Output in the chunks field, as array.\n\n\
-        You should generate a two sentence summary of the document, dense and concise brief, output in the summary field.\n\n\
-        You should extract the original title of the document, and if not present, you should generate one based on the content. output in the title field.\n\n";
+    let initial_prompt = processing_prompt(config, content_type, style, pages);
 
     let schema = serde_json::json!({
         "type": "object",
@@ -182,90 +830,670 @@ pub async fn chunk_and_summary_link(
             },
             "title": {
                 "type": "string"
+            },
+            "language": {
+                "type": "string"
             }
         },
         "required": [
             "summary",
             "chunks",
             "title"
-        ]
+        ],
+        // Chunks come last so a truncated response still carries the title and summary
+        "propertyOrdering": ["title", "language", "summary", "chunks"]
     });
 
     // Make the initial request
-    let (mut response_text, mut was_truncated) = gemini_api_request(
+    let attachments = attach(config, &[(content_type, content)]).await?;
+    let (response_text, mut was_truncated) = gemini_api_request(
         env,
-        initial_prompt,
-        (content_type, content),
+        config,
+        &initial_prompt,
+        &attachments,
         Some(schema.clone()),
         None,
     )
     .await?;
+    let mut data = ProcessedLinkData::default();
+    if !merge_response(&mut data, &response_text) {
+        return Err(Error::from(format!(
+            "Failed to parse Gemini response into structured data, response: {}",
+            response_text
+        )));
+    }
 
-    // If the response was truncated, keep sending follow-up requests until we get the complete output
-    if was_truncated {
-        let mut attempt = 1;
-        let max_attempts = 10; // Prevent infinite loops
-
-        while was_truncated && attempt < max_attempts {
-            console_log!(
-                "Gemini API response was truncated, making follow-up request (attempt {})",
-                attempt
-            );
-
-            // Make a follow-up request using the conversation approach
-            let (continued_text, still_truncated) = gemini_api_request(
-                env,
-                initial_prompt,
-                (content_type, content),
-                Some(schema.clone()),
-                Some(&response_text),
-            )
-            .await?;
-
-            // Append the new text to our accumulated response
-            // Note: We're just keeping everything as a string until we're done
-            response_text.push_str(&continued_text);
-            was_truncated = still_truncated;
+    // Each response is an independent JSON object, so follow-ups ask for the chunks after
+    // the last complete one and the results are merged structurally
+    let mut last_response = response_text;
+    let mut attempt = 1;
+    let max_attempts = 10; // Prevent infinite loops
+    while was_truncated && attempt < max_attempts {
+        console_log!(
+            "Gemini API response was truncated, making follow-up request (attempt {})",
+            attempt
+        );
 
-            attempt += 1;
-        }
+        let instruction = continuation_prompt(&data);
+        let (continued_text, still_truncated) = gemini_api_request(
+            env,
+            config,
+            &initial_prompt,
+            &attachments,
+            Some(schema.clone()),
+            Some((&last_response, &instruction)),
+        )
+        .await?;
 
-        if attempt >= max_attempts && was_truncated {
-            console_log!("Warning: Reached maximum follow-up attempts, document may be incomplete");
+        if !merge_response(&mut data, &continued_text) {
+            console_error!(
+                "Failed to parse follow-up Gemini response, stopping: {}",
+                continued_text
+            );
+            break;
         }
+        last_response = continued_text;
+        was_truncated = still_truncated;
+        attempt += 1;
     }
 
-    // Now that we have the complete (or as complete as possible) response, try to parse it
-    let data = serde_json::from_str::<ProcessedLinkData>(&response_text).map_err(|e| {
-        Error::from(format!(
-            "Failed to parse Gemini response into structured data: {}, response: {}",
-            e, response_text
-        ))
-    })?;
+    if was_truncated {
+        console_log!("Warning: Gemini output is still truncated, document may be incomplete");
+    }
+    if data.chunks.is_empty() {
+        return Err(Error::from("Gemini returned no chunks for the document"));
+    }
 
     Ok(data)
 }
 
-/// Fetch content from a URL
-/// Returns the content and the content type
-pub async fn fetch_content(link: &str) -> Result<(Vec<u8>, String)> {
-    let mut headers = Headers::new();
-    headers.set("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36")?;
-    headers.set(
-        "Accept",
-        "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8",
-    )?;
-    headers.set("Accept-Language", "en-US,en;q=0.5")?;
+/// Compare two documents with Gemini, each given as its content type and content
+pub async fn compare_documents(
+    env: &Env,
+    config: &Config,
+    first: (&str, &[u8]),
+    second: (&str, &[u8]),
+) -> Result<Comparison> {
+    let prompt = "You are given two documents, the first and the second attached file. Compare them. \
+        List the main points both documents share in the shared field, at most five short sentences. \
+        List the most important differences in the differences field, at most five short sentences, \
+        saying which document each side comes from. \
+        In the assessment field, say in one or two sentences which document looks more recent or more authoritative, and why.";
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "shared": {
+                "type": "array",
+                "items": {
+                    "type": "string"
+                }
+            },
+            "differences": {
+                "type": "array",
+                "items": {
+                    "type": "string"
+                }
+            },
+            "assessment": {
+                "type": "string"
+            }
+        },
+        "required": [
+            "shared",
+            "differences",
+            "assessment"
+        ]
+    });
 
-    let mut req_init = RequestInit::new();
-    req_init.with_method(Method::Get).with_headers(headers);
+    let attachments = attach(config, &[first, second]).await?;
+    let (response_text, _) =
+        gemini_api_request(env, config, prompt, &attachments, Some(schema), None).await?;
+    serde_json::from_str(&response_text)
+        .ok()
+        .or_else(|| serde_json::from_str(extract_json_object(&response_text)?).ok())
+        .ok_or_else(|| {
+            Error::from(format!(
+                "Failed to parse Gemini comparison, response: {}",
+                response_text
+            ))
+        })
+}
 
-    let request = Request::new_with_init(link, &req_init)?;
-    let mut response = Fetch::Request(request).send().await?;
+/// Prompt answering `question` from numbered sources, each given as its title and text
+/// Sources are numbered from 1 in the given order, which is how the answer cites them
+fn ask_prompt(question: &str, sources: &[(&str, &str)]) -> String {
+    let mut prompt = String::from(
+        "Answer the question using only the numbered sources below. \
+        After each sentence, cite the sources it relies on by their number in square brackets, e.g. [1] or [1][3]. \
+        If the sources don't answer the question, say so in one sentence instead of guessing. \
+        Answer in plain text without Markdown, in at most two short paragraphs, in the language of the question.\n\n",
+    );
+    for (i, (title, text)) in sources.iter().enumerate() {
+        prompt.push_str(&format!("Source [{}]: {}\n{}\n\n", i + 1, title, text));
+    }
+    prompt.push_str(&format!("Question: {}", question));
+    prompt
+}
 
-    if response.status_code() != 200 {
-        return Err(Error::from(format!(
-            "Failed to fetch link: Status {}",
+/// Answer a question with Gemini from the sources assembled by `ask_prompt`
+pub async fn answer_question(
+    env: &Env,
+    config: &Config,
+    question: &str,
+    sources: &[(&str, &str)],
+) -> Result<String> {
+    let (answer, _) =
+        gemini_api_request(env, config, &ask_prompt(question, sources), &[], None, None).await?;
+    Ok(answer.trim().to_string())
+}
+
+/// Ask for the rest of the chunks, quoting the start of the last complete one as the anchor
+fn continuation_prompt(data: &ProcessedLinkData) -> String {
+    match data.chunks.last() {
+        Some(last_chunk) => {
+            let anchor: String = last_chunk.chars().take(200).collect();
+            format!(
+                "Your output was cut off. Output the same JSON again, but only include the chunks that come \
+                after the chunk starting with:\n\n{}",
+                anchor
+            )
+        }
+        None => "Your output was cut off. Output the same JSON again, keeping each chunk shorter."
+            .to_string(),
+    }
+}
+
+/// Merge one response into the accumulated data
+/// Returns false when nothing could be recovered from the response
+fn merge_response(data: &mut ProcessedLinkData, text: &str) -> bool {
+    let Some(partial) = parse_processed_data(text) else {
+        return false;
+    };
+    if data.title.is_empty() {
+        data.title = partial.title;
+    }
+    if data.summary.is_empty() {
+        data.summary = partial.summary;
+    }
+    if data.language.is_empty() {
+        data.language = partial.language;
+    }
+    // A follow-up may repeat chunks it was told to skip
+    for chunk in partial.chunks {
+        if !data.chunks.contains(&chunk) {
+            data.chunks.push(chunk);
+        }
+    }
+    true
+}
+
+/// Parse Gemini output, tolerating prose or code fences around the JSON object
+/// A response cut off by the token limit is closed after its last complete value
+fn parse_processed_data(text: &str) -> Option<ProcessedLinkData> {
+    serde_json::from_str(text)
+        .ok()
+        .or_else(|| serde_json::from_str(extract_json_object(text)?).ok())
+        .or_else(|| serde_json::from_str(&close_truncated_json(text)?).ok())
+}
+
+/// Cut a truncated JSON object after its last complete value and close the open brackets
+fn close_truncated_json(text: &str) -> Option<String> {
+    let start = text.find('{')?;
+    let text = &text[start..];
+
+    let mut stack = vec![];
+    let mut cut = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut string_is_value = false;
+    let mut after_colon = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    in_string = false;
+                    if string_is_value {
+                        cut = Some((i + 1, stack.clone()));
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                string_is_value = after_colon || stack.last() == Some(&'[');
+                after_colon = false;
+            }
+            ':' => after_colon = true,
+            '{' | '[' => {
+                stack.push(c);
+                after_colon = false;
+            }
+            '}' | ']' => {
+                stack.pop();
+                if stack.is_empty() {
+                    return Some(text[..=i].to_string());
+                }
+                cut = Some((i + 1, stack.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    let (end, open) = cut?;
+    let mut closed = text[..end].to_string();
+    for bracket in open.iter().rev() {
+        closed.push(if *bracket == '{' { '}' } else { ']' });
+    }
+    Some(closed)
+}
+
+/// Locate the first balanced top-level JSON object in `text`
+/// Braces inside strings are skipped, so the object may contain arbitrary markdown
+fn extract_json_object(text: &str) -> Option<&str> {
+    let start = text.find('{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[start..start + i + 1]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// An excerpt of at most `max_len` graphemes around the sentence that best matches the query
+/// Whole sentences are kept where they fit, a sentence that doesn't is cut between words or,
+/// for text without spaces like Chinese, between graphemes
+pub fn snippet(text: &str, query: &str, max_len: usize) -> String {
+    let text = text.trim();
+    if text.graphemes(true).count() <= max_len {
+        return text.to_string();
+    }
+
+    let sentences = text.split_sentence_bounds().collect::<Vec<_>>();
+    let terms = query
+        .split_whitespace()
+        .map(|term| term.to_lowercase())
+        .collect::<Vec<_>>();
+    let score = |sentence: &str| {
+        let sentence = sentence.to_lowercase();
+        terms
+            .iter()
+            .filter(|term| sentence.contains(term.as_str()))
+            .count()
+    };
+    // The first of the best sentences, so an unmatched query shows the start of the text
+    let best = (0..sentences.len())
+        .rev()
+        .max_by_key(|&i| score(sentences[i]))
+        .unwrap_or(0);
+
+    let len = |sentence: &str| sentence.graphemes(true).count();
+    // Leave room for the ellipses
+    let budget = max_len.saturating_sub(2);
+    let (mut start, mut end) = (best, best + 1);
+    let mut used = len(sentences[best]);
+    if used > budget {
+        return format!(
+            "{}{}…",
+            if best > 0 { "…" } else { "" },
+            truncate_graphemes(sentences[best].trim(), budget)
+        );
+    }
+    // Grow the window after the match first, where its context usually continues
+    loop {
+        if end < sentences.len() && used + len(sentences[end]) <= budget {
+            used += len(sentences[end]);
+            end += 1;
+        } else if start > 0 && used + len(sentences[start - 1]) <= budget {
+            start -= 1;
+            used += len(sentences[start]);
+        } else {
+            break;
+        }
+    }
+
+    format!(
+        "{}{}{}",
+        if start > 0 { "…" } else { "" },
+        sentences[start..end].concat().trim(),
+        if end < sentences.len() { "…" } else { "" }
+    )
+}
+
+/// The first `max_len` graphemes of the text, cut at the last word boundary when there is one
+/// in the second half, so neither a word nor an emoji sequence is split
+fn truncate_graphemes(text: &str, max_len: usize) -> &str {
+    let cut = text
+        .grapheme_indices(true)
+        .nth(max_len)
+        .map_or(text.len(), |(i, _)| i);
+    let head = &text[..cut];
+    if cut == text.len() {
+        return head;
+    }
+    match head.rfind(char::is_whitespace) {
+        Some(space) if head[..space].graphemes(true).count() >= max_len / 2 => {
+            head[..space].trim_end()
+        }
+        _ => head,
+    }
+}
+
+/// Turn a Markdown chunk into plain prose for embedding, dropping syntax that only adds noise
+/// Formatting, link targets and rules are stripped, table rows become `header: value` sentences
+/// and fenced code blocks are kept, reduced to their comments or removed
+pub fn markdown_to_prose(text: &str, code_blocks: CodeBlocks) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut fence: Option<&str> = None;
+    let mut table_header: Option<Vec<String>> = None;
+    let mut table_rows = 0;
+    // A table without data rows still says what it is about
+    let end_table = |lines: &mut Vec<String>, header: Option<Vec<String>>, rows: usize| {
+        if let Some(header) = header.filter(|_| rows == 0) {
+            lines.push(header.join(", "));
+        }
+    };
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some(marker) = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+            match fence {
+                None => fence = Some(marker),
+                Some(open) if open == marker => fence = None,
+                Some(_) => {}
+            }
+            continue;
+        }
+        if fence.is_some() {
+            match code_blocks {
+                CodeBlocks::Keep => lines.push(line.to_string()),
+                CodeBlocks::Comments => lines.extend(code_comment(trimmed)),
+                CodeBlocks::Remove => {}
+            }
+            continue;
+        }
+
+        if trimmed.starts_with('|') {
+            let cells = trimmed
+                .trim_matches('|')
+                .split('|')
+                .map(|cell| strip_inline_markdown(cell.trim()))
+                .collect::<Vec<_>>();
+            let is_separator = cells
+                .iter()
+                .all(|cell| !cell.is_empty() && cell.chars().all(|c| matches!(c, '-' | ':')));
+            if is_separator {
+                continue;
+            }
+            match &table_header {
+                None => {
+                    table_header = Some(cells);
+                    table_rows = 0;
+                }
+                Some(header) => {
+                    table_rows += 1;
+                    let row = cells
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, value)| !value.is_empty())
+                        .map(|(i, value)| match header.get(i).filter(|h| !h.is_empty()) {
+                            Some(name) => format!("{}: {}", name, value),
+                            None => value.clone(),
+                        })
+                        .collect::<Vec<_>>();
+                    if !row.is_empty() {
+                        lines.push(format!("{}.", row.join("; ")));
+                    }
+                }
+            }
+            continue;
+        }
+        end_table(&mut lines, table_header.take(), table_rows);
+
+        let is_rule = trimmed.len() >= 3
+            && ['-', '*', '_']
+                .iter()
+                .any(|c| trimmed.chars().all(|ch| ch == *c || ch == ' '));
+        if is_rule {
+            continue;
+        }
+        let heading = trimmed.trim_start_matches('#');
+        let mut rest =
+            if heading.len() < trimmed.len() && (heading.is_empty() || heading.starts_with(' ')) {
+                heading.trim_start()
+            } else {
+                trimmed
+            };
+        while let Some(quoted) = rest.strip_prefix('>') {
+            rest = quoted.trim_start();
+        }
+        rest = strip_list_marker(rest);
+        let prose = strip_inline_markdown(rest);
+        if !prose.is_empty() || lines.last().is_some_and(|l| !l.is_empty()) {
+            lines.push(prose);
+        }
+    }
+    end_table(&mut lines, table_header, table_rows);
+    lines.join("\n").trim().to_string()
+}
+
+/// The text of a comment line of code, whatever the language's comment marker
+fn code_comment(line: &str) -> Option<String> {
+    let comment = ["///", "//!", "//", "#", "--", ";", "/*", "*/", "*"]
+        .into_iter()
+        .find_map(|marker| line.strip_prefix(marker))
+        .or_else(|| line.split_once(" // ").map(|(_, comment)| comment))?;
+    let comment = comment.trim().trim_end_matches("*/").trim();
+    (!comment.is_empty()).then(|| comment.to_string())
+}
+
+/// Remove a bullet, number or task box at the start of a list item
+fn strip_list_marker(line: &str) -> &str {
+    let rest = ["- ", "* ", "+ "]
+        .into_iter()
+        .find_map(|marker| line.strip_prefix(marker))
+        .or_else(|| {
+            let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+            (digits > 0)
+                .then(|| {
+                    line[digits..]
+                        .strip_prefix(". ")
+                        .or(line[digits..].strip_prefix(") "))
+                })
+                .flatten()
+        });
+    match rest {
+        Some(rest) => ["[ ] ", "[x] ", "[X] "]
+            .into_iter()
+            .find_map(|task| rest.strip_prefix(task))
+            .unwrap_or(rest),
+        None => line,
+    }
+}
+
+/// Replace links and images by their text and drop emphasis, strikethrough and code markers
+fn strip_inline_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let link = rest.strip_prefix("![").or_else(|| rest.strip_prefix('['));
+        if let Some((label, after)) = link.and_then(|link| link.split_once("](")) {
+            if let Some((_, tail)) = after.split_once(')') {
+                if !label.contains('[') {
+                    out.push_str(label);
+                    rest = tail;
+                    continue;
+                }
+            }
+        }
+        if let Some(tail) = ["**", "__", "~~"]
+            .into_iter()
+            .find_map(|marker| rest.strip_prefix(marker))
+        {
+            rest = tail;
+            continue;
+        }
+        if !matches!(c, '`' | '*') {
+            out.push(c);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    out.trim().to_string()
+}
+
+/// Keep at most `max` chunks, picked evenly across the document so every part stays searchable
+pub fn sample_chunks(chunks: Vec<String>, max: usize) -> Vec<String> {
+    let total = chunks.len();
+    if total <= max {
+        return chunks;
+    }
+    let mut keep = (0..max).map(|i| i * total / max).peekable();
+    chunks
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, chunk)| {
+            if keep.peek() == Some(&i) {
+                keep.next();
+                Some(chunk)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// A download body capped at `max_bytes`, rejected up front when `Content-Length` is over the cap
+/// Servers may omit or misreport `Content-Length`, so the cap is also enforced as chunks arrive
+struct CappedBody {
+    content: Vec<u8>,
+    max_bytes: usize,
+}
+
+impl CappedBody {
+    fn new(content_length: Option<&str>, max_bytes: usize) -> Result<Self> {
+        let content_length = content_length.and_then(|len| len.trim().parse::<usize>().ok());
+        if let Some(len) = content_length.filter(|len| *len > max_bytes) {
+            return Err(file_too_large(&format_size(len), max_bytes));
+        }
+        Ok(Self {
+            content: Vec::with_capacity(content_length.unwrap_or(0)),
+            max_bytes,
+        })
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Result<()> {
+        if self.content.len() + chunk.len() > self.max_bytes {
+            return Err(file_too_large(
+                &format!("more than {}", format_size(self.max_bytes)),
+                self.max_bytes,
+            ));
+        }
+        self.content.extend_from_slice(chunk);
+        Ok(())
+    }
+}
+
+fn file_too_large(size: &str, max_bytes: usize) -> Error {
+    Error::from(format!(
+        "File too large: {} exceeds the download limit of {}",
+        size,
+        format_size(max_bytes)
+    ))
+}
+
+/// Validators from a previous fetch, sent back as `If-None-Match` and `If-Modified-Since`
+#[derive(Debug, Default, Clone)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    /// The conditional request headers, none when nothing was recorded
+    pub fn request_headers(&self) -> Vec<(&'static str, &str)> {
+        let mut headers = vec![];
+        if let Some(etag) = &self.etag {
+            headers.push(("If-None-Match", etag.as_str()));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.push(("If-Modified-Since", last_modified.as_str()));
+        }
+        headers
+    }
+}
+
+/// Content downloaded from a link
+pub struct FetchedContent {
+    pub content: Vec<u8>,
+    pub content_type: String,
+    pub validators: CacheValidators,
+    /// Known for sources with structured metadata, e.g. threads on X
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub published_at: Option<String>,
+}
+
+pub enum FetchOutcome {
+    /// The server answered 304, the content is the same as when the validators were recorded
+    Unchanged,
+    Fetched(FetchedContent),
+}
+
+/// Fetch content from a URL
+/// Returns the content and the content type, or `Unchanged` when `validators` holds values from
+/// an earlier fetch and the server answers 304
+/// Downloads larger than `max_bytes` are rejected, using `Content-Length` when the server sends it
+pub async fn fetch_content(
+    link: &str,
+    max_bytes: usize,
+    validators: &CacheValidators,
+) -> Result<FetchOutcome> {
+    let mut headers = Headers::new();
+    headers.set("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.124 Safari/537.36")?;
+    headers.set(
+        "Accept",
+        "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8",
+    )?;
+    headers.set("Accept-Language", "en-US,en;q=0.5")?;
+    for (name, value) in validators.request_headers() {
+        headers.set(name, value)?;
+    }
+
+    let mut req_init = RequestInit::new();
+    req_init.with_method(Method::Get).with_headers(headers);
+
+    let request = Request::new_with_init(link, &req_init)?;
+    let mut response = Fetch::Request(request).send().await?;
+
+    if response.status_code() == 304 {
+        return Ok(FetchOutcome::Unchanged);
+    }
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Failed to fetch link: Status {}",
             response.status_code()
         )));
     }
@@ -275,8 +1503,980 @@ pub async fn fetch_content(link: &str) -> Result<(Vec<u8>, String)> {
         .get("Content-Type")
         .unwrap_or_else(|_| Some("application/octet-stream".to_string()))
         .unwrap_or_else(|| "application/octet-stream".to_string());
+    let validators = CacheValidators {
+        etag: response.headers().get("ETag")?,
+        last_modified: response.headers().get("Last-Modified")?,
+    };
+
+    let content_length = response.headers().get("Content-Length")?;
+    let mut body = CappedBody::new(content_length.as_deref(), max_bytes)?;
+    let mut stream = response.stream()?;
+    while let Some(chunk) = stream.next().await {
+        body.push(&chunk?)?;
+    }
+
+    Ok(FetchOutcome::Fetched(FetchedContent {
+        content: body.content,
+        content_type,
+        validators,
+        title: None,
+        author: None,
+        published_at: None,
+    }))
+}
+
+/// Most posts of a thread to unroll, counting back from the linked one
+const MAX_THREAD_POSTS: usize = 25;
+
+/// The post id of an X or Twitter status URL, e.g. `https://x.com/user/status/123`
+pub fn tweet_id(link: &str) -> Option<String> {
+    let url = Url::parse(link).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("mobile."))
+        .unwrap_or(&host);
+    if host != "x.com" && host != "twitter.com" {
+        return None;
+    }
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    let id = match segments.as_slice() {
+        [_, "status" | "statuses", id, ..] => *id,
+        ["i", "web", "status", id, ..] => *id,
+        _ => return None,
+    };
+    (!id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())).then(|| id.to_string())
+}
+
+/// The token the syndication endpoint expects, `((id / 1e15) * Math.PI).toString(36)` in
+/// JavaScript without zeros and the dot
+fn syndication_token(id: &str) -> String {
+    const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let value = id.parse::<f64>().unwrap_or(0.0) / 1e15 * std::f64::consts::PI;
+    let mut integer = value.trunc();
+    let mut fraction = value - integer;
+
+    // Shortest base 36 digits that still identify the double, like V8's DoubleToRadixCString
+    let mut fraction_digits = vec![];
+    let next = f64::from_bits(value.to_bits() + 1);
+    let mut delta = (0.5 * (next - value)).max(f64::from_bits(1));
+    if fraction >= delta {
+        loop {
+            fraction *= 36.0;
+            delta *= 36.0;
+            let digit = fraction as usize;
+            fraction_digits.push(digit);
+            fraction -= digit as f64;
+            if (fraction > 0.5 || (fraction == 0.5 && digit % 2 == 1)) && fraction + delta > 1.0 {
+                // Round up, carrying into the integer part when every digit overflows
+                loop {
+                    match fraction_digits.pop() {
+                        Some(digit) if digit + 1 < 36 => {
+                            fraction_digits.push(digit + 1);
+                            break;
+                        }
+                        Some(_) => {}
+                        None => {
+                            integer += 1.0;
+                            break;
+                        }
+                    }
+                }
+                break;
+            }
+            if fraction < delta {
+                break;
+            }
+        }
+    }
+
+    let mut integer = integer as u64;
+    let mut integer_digits = vec![];
+    loop {
+        integer_digits.push((integer % 36) as usize);
+        integer /= 36;
+        if integer == 0 {
+            break;
+        }
+    }
+    integer_digits
+        .iter()
+        .rev()
+        .chain(&fraction_digits)
+        .map(|digit| DIGITS[*digit] as char)
+        .filter(|c| *c != '0')
+        .collect()
+}
+
+/// A post from the syndication endpoint behind embedded posts on X
+async fn fetch_tweet(id: &str) -> Result<serde_json::Value> {
+    let api_url = format!(
+        "https://cdn.syndication.twimg.com/tweet-result?id={}&token={}",
+        id,
+        syndication_token(id)
+    );
+    let mut response = Fetch::Url(Url::parse(&api_url)?).send().await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Fetching post {} failed: Status {}",
+            id,
+            response.status_code()
+        )));
+    }
+    let tweet = response.json::<serde_json::Value>().await?;
+    if tweet["text"].as_str().is_none() {
+        return Err(Error::from(format!("Post {} is unavailable", id)));
+    }
+    Ok(tweet)
+}
+
+/// Fetch a post on X as Markdown, unrolled with the earlier posts of its author it replies to
+pub async fn fetch_thread(link: &str, id: &str) -> Result<FetchedContent> {
+    let mut thread = vec![fetch_tweet(id).await?];
+    while thread.len() < MAX_THREAD_POSTS {
+        let first = &thread[0];
+        let author = first["user"]["screen_name"].as_str();
+        let Some(parent) = first["in_reply_to_status_id_str"].as_str() else {
+            break;
+        };
+        if author.is_none() || first["in_reply_to_screen_name"].as_str() != author {
+            break;
+        }
+        match fetch_tweet(parent).await {
+            Ok(tweet) => thread.insert(0, tweet),
+            Err(e) => {
+                console_error!("Stopped unrolling the thread of {}: {}", link, e);
+                break;
+            }
+        }
+    }
+
+    let user = &thread[0]["user"];
+    let author = match (user["name"].as_str(), user["screen_name"].as_str()) {
+        (Some(name), Some(handle)) => Some(format!("{} (@{})", name, handle)),
+        (None, Some(handle)) => Some(format!("@{}", handle)),
+        (Some(name), None) => Some(name.to_string()),
+        (None, None) => None,
+    };
+    let published_at = thread[0]["created_at"].as_str().map(str::to_string);
+
+    let mut text = format!(
+        "# {} by {}\n\n",
+        if thread.len() > 1 { "Thread" } else { "Post" },
+        author.as_deref().unwrap_or("unknown author")
+    );
+    if let Some(date) = &published_at {
+        text.push_str(&format!("Posted {}\n\n", date));
+    }
+    for tweet in &thread {
+        text.push_str(tweet["text"].as_str().unwrap_or_default());
+        text.push_str("\n\n");
+    }
+    text.push_str(&format!("Source: {}\n", link));
+    console_log!("Unrolled {} posts from {}", thread.len(), link);
+
+    Ok(FetchedContent {
+        content: text.into_bytes(),
+        content_type: "text/markdown".to_string(),
+        validators: CacheValidators::default(),
+        title: None,
+        author,
+        published_at,
+    })
+}
+
+/// The video id of a YouTube URL, e.g. `https://www.youtube.com/watch?v=...` or `https://youtu.be/...`
+pub fn youtube_video_id(link: &str) -> Option<String> {
+    let url = Url::parse(link).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .unwrap_or(&host);
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    let id = match (host, segments.as_slice()) {
+        ("youtu.be", [id, ..]) => id.to_string(),
+        ("youtube.com", ["watch"]) => url
+            .query_pairs()
+            .find(|(key, _)| key == "v")?
+            .1
+            .into_owned(),
+        ("youtube.com", ["shorts" | "live" | "embed", id, ..]) => id.to_string(),
+        _ => return None,
+    };
+    // Video ids are 11 characters of URL-safe base64
+    (id.len() == 11
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'))
+    .then_some(id)
+}
+
+/// Fetch the transcript of a YouTube video as Markdown, preferring English and manual captions
+/// Fails when the video has no captions, so the caller can fall back to the page
+pub async fn fetch_youtube_transcript(
+    link: &str,
+    id: &str,
+    max_bytes: usize,
+) -> Result<FetchedContent> {
+    let page_url = format!("https://www.youtube.com/watch?v={}&hl=en", id);
+    let mut headers = Headers::new();
+    headers.set("Accept-Language", "en")?;
+    let request = Request::new_with_init(&page_url, RequestInit::new().with_headers(headers))?;
+    let mut response = Fetch::Request(request).send().await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Fetching video {} failed: Status {}",
+            id,
+            response.status_code()
+        )));
+    }
+    let page = response.text().await?;
+
+    // The player configuration is a JSON object assigned in an inline script
+    const MARKER: &str = "ytInitialPlayerResponse = ";
+    let start = page
+        .find(MARKER)
+        .ok_or_else(|| Error::from(format!("Video {} has no player data", id)))?
+        + MARKER.len();
+    let player = serde_json::Deserializer::from_str(&page[start..])
+        .into_iter::<serde_json::Value>()
+        .next()
+        .ok_or_else(|| Error::from(format!("Video {} has no player data", id)))??;
+
+    let tracks = player["captions"]["playerCaptionsTracklistRenderer"]["captionTracks"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let track = tracks
+        .iter()
+        .filter(|track| track["baseUrl"].is_string())
+        .min_by_key(|track| {
+            let generated = track["kind"].as_str() == Some("asr");
+            let english = track["languageCode"]
+                .as_str()
+                .is_some_and(|code| code.starts_with("en"));
+            (!english, generated)
+        })
+        .ok_or_else(|| Error::from(format!("Video {} has no captions", id)))?;
+    let track_url = format!(
+        "{}&fmt=json3",
+        track["baseUrl"].as_str().unwrap_or_default()
+    );
+    let mut response = Fetch::Url(Url::parse(&track_url)?).send().await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Fetching captions of video {} failed: Status {}",
+            id,
+            response.status_code()
+        )));
+    }
+    let captions = response.json::<serde_json::Value>().await?;
+    let lines = captions["events"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|event| event["segs"].as_array())
+        .map(|segs| {
+            segs.iter()
+                .filter_map(|seg| seg["utf8"].as_str())
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        return Err(Error::from(format!("Captions of video {} are empty", id)));
+    }
+
+    let details = &player["videoDetails"];
+    let title = details["title"].as_str().map(str::to_string);
+    let author = details["author"].as_str().map(str::to_string);
+    let published_at = player["microformat"]["playerMicroformatRenderer"]["publishDate"]
+        .as_str()
+        .map(str::to_string);
 
-    let content = response.bytes().await?;
+    let mut text = format!("# {}\n\n", title.as_deref().unwrap_or("YouTube video"));
+    if let Some(author) = &author {
+        text.push_str(&format!("Video by {}\n\n", author));
+    }
+    if let Some(date) = &published_at {
+        text.push_str(&format!("Published {}\n\n", date));
+    }
+    if let Some(description) = details["shortDescription"]
+        .as_str()
+        .filter(|d| !d.trim().is_empty())
+    {
+        text.push_str(&format!("## Description\n\n{}\n\n", description.trim()));
+    }
+    text.push_str("## Transcript\n\n");
+    text.push_str(&lines.join("\n"));
+    text.push_str(&format!("\n\nSource: {}\n", link));
+    if text.len() > max_bytes {
+        return Err(file_too_large(&format_size(text.len()), max_bytes));
+    }
+    console_log!("Fetched a transcript of {} lines for {}", lines.len(), link);
+
+    Ok(FetchedContent {
+        content: text.into_bytes(),
+        content_type: "text/markdown".to_string(),
+        validators: CacheValidators::default(),
+        title,
+        author,
+        published_at,
+    })
+}
+
+/// Round constants of SHA-256, the first 32 bits of the fractional parts of the cube roots of
+/// the first 64 primes
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
 
-    Ok((content, content_type))
+/// SHA-256 of `data` as lowercase hex, e.g. to recognize chunk text that was embedded before
+/// Runs synchronously in the worker, unlike `crypto.subtle.digest`
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+    // Pad with a 1 bit, zeros and the bit length so the message fills whole 64-byte blocks
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    state.iter().map(|s| format!("{:08x}", s)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_magic_bytes() {
+        assert_eq!(sniff_magic_bytes(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(
+            sniff_magic_bytes(b"\x89PNG\r\n\x1a\n\0\0"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_magic_bytes(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_magic_bytes(b"GIF89a"), Some("image/gif"));
+        assert_eq!(
+            sniff_magic_bytes(b"\xEF\xBB\xBF \n<!DOCTYPE HTML>"),
+            Some("text/html")
+        );
+        assert_eq!(sniff_magic_bytes(b"<html lang=\"en\">"), Some("text/html"));
+        assert_eq!(sniff_magic_bytes(b"plain text"), None);
+        assert_eq!(sniff_magic_bytes(b""), None);
+    }
+
+    #[test]
+    fn corrects_content_types() {
+        // Binary signatures win over any reported type
+        assert_eq!(
+            correct_content_type(b"%PDF-1.4", "text/html; charset=utf-8"),
+            "application/pdf"
+        );
+        // HTML only replaces generic types
+        assert_eq!(
+            correct_content_type(b"<!doctype html>", "application/octet-stream"),
+            "text/html"
+        );
+        assert_eq!(
+            correct_content_type(b"<html>", "application/xhtml+xml"),
+            "application/xhtml+xml"
+        );
+        // Matching types keep their parameters
+        assert_eq!(
+            correct_content_type(b"%PDF-1.4", "application/PDF; name=a.pdf"),
+            "application/PDF; name=a.pdf"
+        );
+        assert_eq!(
+            correct_content_type(b"{}", "application/json"),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn normalizes_urls() {
+        assert_eq!(
+            normalize_url(" HTTPS://Example.COM:443/Path/?utm_source=x&id=1 "),
+            "https://example.com/Path?id=1"
+        );
+        assert_eq!(
+            normalize_url("http://example.com:80"),
+            "http://example.com/"
+        );
+        assert_eq!(
+            normalize_url("https://example.com:8443/"),
+            "https://example.com:8443/"
+        );
+        assert_eq!(normalize_url("not a url "), "not a url");
+    }
+
+    #[test]
+    fn lists_url_variants() {
+        let variants = url_variants("https://www.example.com/a/");
+        assert_eq!(
+            variants,
+            vec![
+                "https://www.example.com/a",
+                "https://example.com/a",
+                "http://example.com/a",
+                "http://www.example.com/a",
+            ]
+        );
+        assert_eq!(url_variants("not a url"), vec!["not a url"]);
+    }
+
+    #[test]
+    fn normalizing_drops_fragments_and_sorts_parameters() {
+        assert_eq!(
+            normalize_url("https://example.com/post?b=2&gclid=x&a=1&fbclid=y#comments"),
+            "https://example.com/post?a=1&b=2"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/?UTM_Campaign=x#top"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/?q=a+b&b"),
+            normalize_url("https://example.com/?b=&q=a%20b")
+        );
+    }
+
+    #[test]
+    fn extracts_json_objects_from_prose() {
+        assert_eq!(
+            extract_json_object("Here you go:\n```json\n{\"a\": {\"b\": 1}}\n```"),
+            Some("{\"a\": {\"b\": 1}}")
+        );
+        // Braces and escaped quotes inside strings don't count
+        assert_eq!(
+            extract_json_object(r#"x {"code": "fn f() { \"}\" }"} y {"b": 2}"#),
+            Some(r#"{"code": "fn f() { \"}\" }"}"#)
+        );
+        assert_eq!(extract_json_object("no json here"), None);
+        assert_eq!(extract_json_object("{\"open\": 1"), None);
+    }
+
+    #[test]
+    fn closes_truncated_json() {
+        // Cut inside the third chunk, which is dropped
+        let truncated = r#"{"title": "T", "chunks": ["one", "two \"}\"", "thr"#;
+        assert_eq!(
+            close_truncated_json(truncated).as_deref(),
+            Some(r#"{"title": "T", "chunks": ["one", "two \"}\""]}"#)
+        );
+        // Cut inside a key, the last complete value is the title
+        assert_eq!(
+            close_truncated_json(r#"{"title": "T", "summ"#).as_deref(),
+            Some(r#"{"title": "T"}"#)
+        );
+        // A complete object is returned as is, trailing text dropped
+        assert_eq!(
+            close_truncated_json(r#"prose {"a": [1]} more"#).as_deref(),
+            Some(r#"{"a": [1]}"#)
+        );
+        assert_eq!(close_truncated_json(r#"{"tit"#), None);
+    }
+
+    #[test]
+    fn merges_truncated_responses() {
+        let mut data = ProcessedLinkData::default();
+        assert!(merge_response(
+            &mut data,
+            r#"{"title": "T", "summary": "S", "language": "en", "chunks": ["a", "b", "c"#
+        ));
+        assert_eq!(data.chunks, vec!["a", "b"]);
+        // A follow-up repeating a chunk doesn't duplicate it or replace the title
+        assert!(merge_response(
+            &mut data,
+            r#"{"title": "Other", "chunks": ["b", "c"]}"#
+        ));
+        assert_eq!(data.title, "T");
+        assert_eq!(data.chunks, vec!["a", "b", "c"]);
+        assert!(!merge_response(&mut data, "not json"));
+    }
+
+    #[test]
+    fn samples_chunks_evenly() {
+        let chunks = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(sample_chunks(chunks.clone(), 20), chunks);
+        assert_eq!(sample_chunks(chunks.clone(), 10), chunks);
+        assert_eq!(sample_chunks(chunks.clone(), 4), vec!["0", "2", "5", "7"]);
+        assert_eq!(sample_chunks(chunks.clone(), 1), vec!["0"]);
+        assert!(sample_chunks(chunks, 0).is_empty());
+    }
+
+    #[test]
+    fn sha256_matches_fips_180_vectors() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256_hex(&vec![b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn sha256_pads_at_block_boundaries() {
+        // 55 bytes still fit the length in the last block, 56 need another one
+        for (len, digest) in [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                63,
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+        ] {
+            assert_eq!(sha256_hex(&vec![b'a'; len]), digest, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn sends_recorded_validators_as_conditional_headers() {
+        assert!(CacheValidators::default().request_headers().is_empty());
+
+        let validators = CacheValidators {
+            etag: Some("\"33a64df5\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        assert_eq!(
+            validators.request_headers(),
+            vec![
+                ("If-None-Match", "\"33a64df5\""),
+                ("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ]
+        );
+
+        let validators = CacheValidators {
+            etag: None,
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        assert_eq!(
+            validators.request_headers(),
+            vec![("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT")]
+        );
+    }
+
+    #[test]
+    fn rejects_downloads_announced_over_the_cap() {
+        let err = CappedBody::new(Some("2048"), 1024).err().unwrap();
+        assert!(err.to_string().contains("File too large"), "{}", err);
+        // Exactly at the cap is allowed, a header that doesn't parse is checked while streaming
+        assert!(CappedBody::new(Some(" 1024 "), 1024).is_ok());
+        assert!(CappedBody::new(Some("lots"), 1024).is_ok());
+        assert!(CappedBody::new(None, 1024).is_ok());
+    }
+
+    #[test]
+    fn caps_streamed_downloads() {
+        // No Content-Length, e.g. a chunked response
+        let mut body = CappedBody::new(None, 10).unwrap();
+        body.push(b"hello").unwrap();
+        body.push(b"world").unwrap();
+        assert_eq!(body.content, b"helloworld");
+        assert!(body.push(b"!").is_err());
+
+        // A Content-Length under the real size doesn't lift the cap
+        let mut body = CappedBody::new(Some("4"), 10).unwrap();
+        body.push(b"0123456").unwrap();
+        let err = body.push(b"789A").err().unwrap();
+        assert!(err.to_string().contains("more than"), "{}", err);
+    }
+
+    #[test]
+    fn snippets_keep_the_matching_sentence() {
+        let text = "Rust is a language. Cloudflare runs workers at the edge. \
+                    Vectors power the search. The end.";
+        assert_eq!(snippet(text, "rust", 200), text);
+        let excerpt = snippet(text, "Workers", 40);
+        assert_eq!(excerpt, "…Cloudflare runs workers at the edge.…");
+        assert!(excerpt.graphemes(true).count() <= 40);
+        // Without a match the excerpt starts at the beginning
+        assert!(snippet(text, "python", 40).starts_with("Rust is a language."));
+    }
+
+    #[test]
+    fn snippets_cut_cjk_between_graphemes() {
+        let text = "第一句话在这里。向量搜索很快。最后一句。";
+        assert_eq!(snippet(text, "向量", 12), "…向量搜索很快。…");
+        let text = "这是一个非常非常长的句子没有任何标点符号";
+        assert_eq!(snippet(text, "", 10), "这是一个非常非常…");
+    }
+
+    #[test]
+    fn snippets_never_split_emoji_sequences() {
+        let family = "👨‍👩‍👧";
+        let text = family.repeat(10);
+        assert_eq!(snippet(&text, "", 5), format!("{}…", family.repeat(3)));
+        let text = format!("Trip {family}{family} to the {family} beach was fun {family}");
+        for max_len in 1..text.graphemes(true).count() {
+            let excerpt = snippet(&text, "beach", max_len);
+            assert!(excerpt.graphemes(true).count() <= max_len.max(1));
+            for grapheme in excerpt.graphemes(true) {
+                assert!(!grapheme.contains('\u{200d}') || grapheme == family);
+            }
+        }
+    }
+
+    #[test]
+    fn extracts_titles_in_windows_1252() {
+        let page = b"<html><head><title>Caf\xe9 \x93menu\x94</title></head></html>";
+        assert_eq!(
+            extract_title(page, "text/html; charset=windows-1252").as_deref(),
+            Some("Café “menu”")
+        );
+        // Latin-1 labels are read as windows-1252, as browsers do
+        assert_eq!(
+            extract_title(page, "text/html; charset=ISO-8859-1").as_deref(),
+            Some("Café “menu”")
+        );
+    }
+
+    #[test]
+    fn extracts_titles_in_utf16_with_a_bom() {
+        let mut page = vec![0xFF, 0xFE];
+        for unit in "<title>Caf\u{e9} 日本</title>".encode_utf16() {
+            page.extend_from_slice(&unit.to_le_bytes());
+        }
+        // The byte order mark wins over the content type
+        assert_eq!(
+            extract_title(&page, "text/html; charset=utf-8").as_deref(),
+            Some("Café 日本")
+        );
+    }
+
+    #[test]
+    fn extracts_titles_with_only_a_meta_charset() {
+        let page = b"<html><head><meta charset=\"windows-1252\">\
+                     <title>R\xe9sum\xe9 &amp; CV</title></head></html>";
+        assert_eq!(
+            extract_title(page, "text/html").as_deref(),
+            Some("Résumé & CV")
+        );
+        let page = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\">\
+                     <title>Na\xefve</title>";
+        assert_eq!(extract_title(page, "text/html").as_deref(), Some("Naïve"));
+        assert_eq!(extract_title(b"<p>No title</p>", "text/html"), None);
+    }
+
+    #[test]
+    fn parses_post_ids_from_status_urls() {
+        for link in [
+            "https://x.com/rustlang/status/1628832338187636740",
+            "https://twitter.com/rustlang/status/1628832338187636740?s=20",
+            "https://www.twitter.com/rustlang/statuses/1628832338187636740",
+            "https://mobile.twitter.com/rustlang/status/1628832338187636740/photo/1",
+            "https://X.com/i/web/status/1628832338187636740",
+        ] {
+            assert_eq!(
+                tweet_id(link).as_deref(),
+                Some("1628832338187636740"),
+                "{}",
+                link
+            );
+        }
+        for link in [
+            "https://x.com/rustlang",
+            "https://x.com/rustlang/status/",
+            "https://x.com/rustlang/status/abc",
+            "https://x.com/rustlang/likes/1628832338187636740",
+            "https://example.com/rustlang/status/1628832338187636740",
+            "https://notx.com/rustlang/status/1628832338187636740",
+            "not a url",
+        ] {
+            assert_eq!(tweet_id(link), None, "{}", link);
+        }
+    }
+
+    #[test]
+    fn syndication_tokens_match_javascript() {
+        // `((id / 1e15) * Math.PI).toString(36).replace(/(0+|\.)/g, '')` in Node
+        assert_eq!(syndication_token("20"), "6dq1a2xwd93");
+        assert_eq!(syndication_token("1445078208190291968"), "3i3uhthofho");
+        assert_eq!(syndication_token("1628832338187636740"), "3y54libozsy");
+    }
+
+    #[test]
+    fn markdown_becomes_prose() {
+        let chunk = "\
+## Getting *started*
+
+> **Note:** read the [install guide](https://example.com/install) first.
+
+- [x] Install `cargo`
+1. Run ![the badge](badge.svg) ~~twice~~ once
+
+---
+
+| Command | Purpose |
+|:--------|--------:|
+| `build` | Compile **everything** |
+| `test`  |  |
+";
+        assert_eq!(
+            markdown_to_prose(chunk, CodeBlocks::Keep),
+            "Getting started\n\n\
+             Note: read the install guide first.\n\n\
+             Install cargo\n\
+             Run the badge twice once\n\n\
+             Command: build; Purpose: Compile everything.\n\
+             Command: test."
+        );
+    }
+
+    #[test]
+    fn code_blocks_are_kept_reduced_or_removed() {
+        let chunk = "\
+Set up the client:
+
+```rust
+// Connect with retries
+let client = Client::new(); // reused across requests
+/* Timeouts are in seconds */
+```
+Done.";
+        assert_eq!(
+            markdown_to_prose(chunk, CodeBlocks::Keep),
+            "Set up the client:\n\n\
+             // Connect with retries\n\
+             let client = Client::new(); // reused across requests\n\
+             /* Timeouts are in seconds */\n\
+             Done."
+        );
+        assert_eq!(
+            markdown_to_prose(chunk, CodeBlocks::Comments),
+            "Set up the client:\n\n\
+             Connect with retries\n\
+             reused across requests\n\
+             Timeouts are in seconds\n\
+             Done."
+        );
+        assert_eq!(
+            markdown_to_prose(chunk, CodeBlocks::Remove),
+            "Set up the client:\n\nDone."
+        );
+    }
+
+    #[test]
+    fn header_only_tables_keep_their_header() {
+        let chunk = "| Name | Size |\n|---|---|\n\nAfter the table";
+        assert_eq!(
+            markdown_to_prose(chunk, CodeBlocks::Comments),
+            "Name, Size\n\nAfter the table"
+        );
+    }
+
+    #[test]
+    fn summary_styles_change_the_prompt() {
+        let config = crate::config::tests::parse(&[]).unwrap();
+        let prompt = |style| processing_prompt(&config, "text/html", style, None);
+        // The default keeps the two sentence summary
+        assert_eq!(SummaryStyle::default(), SummaryStyle::Dense);
+        assert!(prompt(SummaryStyle::Dense).contains("two sentence summary"));
+        assert!(prompt(SummaryStyle::Bullets).contains("bullet points"));
+        assert!(prompt(SummaryStyle::Eli5).contains("twelve year old"));
+        assert!(prompt(SummaryStyle::Technical).contains("technical summary"));
+        for style in SummaryStyle::ALL {
+            let prompt = prompt(style);
+            assert!(prompt.contains(&format!("{}, output in the summary field", style.prompt())));
+            for other in SummaryStyle::ALL
+                .into_iter()
+                .filter(|other| *other != style)
+            {
+                assert!(!prompt.contains(other.prompt()), "{:?}", other);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_summary_style_names() {
+        assert_eq!(SummaryStyle::parse(" ELI5 "), Some(SummaryStyle::Eli5));
+        assert_eq!(SummaryStyle::parse("bullets"), Some(SummaryStyle::Bullets));
+        assert_eq!(SummaryStyle::parse("haiku"), None);
+        for style in SummaryStyle::ALL {
+            assert_eq!(SummaryStyle::parse(style.name()), Some(style));
+        }
+    }
+
+    #[test]
+    fn prompts_follow_the_content_type() {
+        let config = crate::config::tests::parse(&[]).unwrap();
+        let prompt =
+            |content_type| processing_prompt(&config, content_type, SummaryStyle::Dense, None);
+        for content_type in ["text/html", "application/pdf", "image/png"] {
+            assert!(
+                prompt(content_type).starts_with("OCR the following"),
+                "{}",
+                content_type
+            );
+        }
+        for content_type in [
+            "text/plain",
+            "text/markdown; charset=utf-8",
+            "TEXT/X-MARKDOWN",
+        ] {
+            let prompt = prompt(content_type);
+            assert!(
+                prompt.starts_with("Rewrite the following text"),
+                "{}",
+                content_type
+            );
+            assert!(!prompt.contains("OCR"), "{}", content_type);
+        }
+        assert!(prompt("application/json").starts_with("The following is JSON data"));
+        for content_type in ["text/javascript", "application/javascript", "text/css"] {
+            assert!(
+                prompt(content_type).starts_with("The following is a source file"),
+                "{}",
+                content_type
+            );
+        }
+        // Every prompt asks for the same fields
+        for content_type in ["text/html", "text/plain", "application/json", "text/css"] {
+            let prompt = prompt(content_type);
+            for field in [
+                "chunks field",
+                "summary field",
+                "title field",
+                "language field",
+            ] {
+                assert!(prompt.contains(field), "{} {}", content_type, field);
+            }
+        }
+    }
+
+    #[test]
+    fn large_content_is_uploaded_instead_of_inlined() {
+        let at_limit = vec![0; INLINE_DATA_MAX_BYTES];
+        let over_limit = vec![0; INLINE_DATA_MAX_BYTES + 1];
+        assert!(!needs_upload(&[("application/pdf", b"%PDF-1.7")]));
+        assert!(!needs_upload(&[("application/pdf", &at_limit)]));
+        assert!(needs_upload(&[("application/pdf", &over_limit)]));
+        // Documents compared together share the request, so their sizes add up
+        let half = vec![0; INLINE_DATA_MAX_BYTES / 2 + 1];
+        assert!(!needs_upload(&[("application/pdf", &half)]));
+        assert!(needs_upload(&[
+            ("application/pdf", &half),
+            ("application/pdf", &half)
+        ]));
+    }
+
+    #[test]
+    fn attachments_become_request_parts() {
+        let inline = Attachment::Inline {
+            mime_type: "text/plain",
+            data: b"hi",
+        };
+        assert_eq!(
+            inline.part(),
+            serde_json::json!({ "inline_data": { "mime_type": "text/plain", "data": "aGk=" } })
+        );
+        let file = Attachment::File {
+            mime_type: "application/pdf".to_string(),
+            uri: "https://generativelanguage.googleapis.com/v1beta/files/abc".to_string(),
+        };
+        assert_eq!(
+            file.part(),
+            serde_json::json!({ "file_data": {
+                "mime_type": "application/pdf",
+                "file_uri": "https://generativelanguage.googleapis.com/v1beta/files/abc"
+            } })
+        );
+    }
+
+    #[test]
+    fn ask_prompts_number_the_sources() {
+        let prompt = ask_prompt(
+            "How fast is it?",
+            &[
+                ("First paper", "It runs in 2 ms."),
+                ("Blog", "Faster than C."),
+            ],
+        );
+        assert!(prompt.starts_with("Answer the question using only the numbered sources below."));
+        assert!(prompt.contains("cite the sources it relies on by their number in square brackets"));
+        let first = prompt
+            .find("Source [1]: First paper\nIt runs in 2 ms.\n\n")
+            .unwrap();
+        let second = prompt.find("Source [2]: Blog\nFaster than C.\n\n").unwrap();
+        assert!(first < second);
+        assert!(prompt.ends_with("Question: How fast is it?"));
+    }
 }
@@ -1,18 +1,143 @@
+use crate::config::{Config, DEFAULT_EMBEDDING_MODEL, DEFAULT_MAX_LEAF_SIZE, DEFAULT_NUM_TREES};
+use crate::metrics::{timed, SearchTimings, Timer};
 use crate::models::{
-    EmbeddingRequest, EmbeddingResponse, VectorGetResponse, VectorQueryRequest, VectorQueryResponse,
+    EmbeddingRequest, EmbeddingResponse, VectorGetResponse, VectorIndexResponse,
+    VectorQueryRequest, VectorQueryResponse,
 };
+use crate::utils::markdown_to_prose;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use vector_lite::{ANNIndexOwned, Vector};
+use std::ops::Deref;
+use std::sync::OnceLock;
+use vector_lite::{ANNIndexOwned, Vector, VectorLite};
 use worker::*;
 
 // Constants for Workers AI
-const CF_ACCOUNT_ID: &str = "CF_ACCOUNT_ID";
-const CF_API_TOKEN: &str = "CF_API_TOKEN";
 const VECTORIZE_INDEX_NAME: &str = "seen-index";
 const WORKERS_AI_API_URL: &str =
-    "https://api.cloudflare.com/client/v4/accounts/{account_id}/ai/run/@cf/baai/bge-base-en-v1.5";
+    "https://api.cloudflare.com/client/v4/accounts/{account_id}/ai/run/{model}";
 
-async fn post_request(url: &str, api_token: &str, body: &str) -> Result<Response> {
+/// Metric of the Vectorize index, looked up once per isolate
+static VECTORIZE_METRIC: OnceLock<String> = OnceLock::new();
+
+/// Most texts Workers AI embedding models take in one request
+const MAX_EMBEDDING_BATCH: usize = 100;
+
+/// Dimension of the embeddings, fixed at compile time by the vector_lite index
+/// Build with the `embedding-1024` feature for 1024-dimension models
+#[cfg(not(feature = "embedding-1024"))]
+pub const EMBEDDING_DIM: usize = 768;
+#[cfg(feature = "embedding-1024")]
+pub const EMBEDDING_DIM: usize = 1024;
+/// Dimension of indexes saved before it was recorded
+const LEGACY_EMBEDDING_DIM: usize = 768;
+
+// Constants for the vector_lite index
+pub const VECTOR_LITE_KEY: &str = "vector_lite.bin";
+const MULTILINGUAL_VECTOR_LITE_KEY: &str = "vector_lite_multilingual.bin";
+const INDEX_MAGIC: &[u8] = b"SEENIDX1";
+
+/// Longest wait between retries of Workers AI, Vectorize and Gemini calls
+const MAX_RETRY_DELAY_MS: u64 = 10_000;
+
+/// How often and how patiently transient API failures are retried
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// Total attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every further retry
+    base_ms: u64,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            attempts: config.ai_retry_attempts,
+            base_ms: config.ai_retry_base_ms,
+        }
+    }
+
+    /// Wait before retry number `retry` (0-based), preferring the server's `Retry-After` seconds
+    pub fn delay_ms(&self, retry: u32, retry_after: Option<&str>) -> u64 {
+        retry_after
+            .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+            .map(|seconds| seconds.saturating_mul(1000))
+            .unwrap_or_else(|| self.base_ms.saturating_mul(1 << retry.min(16)))
+            .min(MAX_RETRY_DELAY_MS)
+    }
+}
+
+/// Rate limits and server errors are worth another try, other failures are not
+fn is_transient(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+/// Send a request with `send` until it answers 200, retrying network errors and the failures
+/// `is_transient` accepts, given the status and body, with backoff
+/// Other failures are returned right away, errors tell the status, body and attempts made
+pub(crate) async fn retry_with_backoff<F, Fut>(
+    policy: RetryPolicy,
+    mut send: F,
+    is_transient: impl Fn(u16, &str) -> bool,
+) -> Result<Response>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<Response>>,
+{
+    let mut attempt = 1;
+    loop {
+        let (retry_after, error, transient) = match send().await {
+            Ok(response) if response.status_code() == 200 => return Ok(response),
+            Ok(mut response) => {
+                let status = response.status_code();
+                let retry_after = response.headers().get("Retry-After")?;
+                let body = response.text().await?;
+                let transient = is_transient(status, &body);
+                (
+                    retry_after,
+                    format!("status: {}, error: {}", status, body),
+                    transient,
+                )
+            }
+            Err(e) => (None, e.to_string(), true),
+        };
+
+        if !transient || attempt >= policy.attempts {
+            return Err(Error::from(format!(
+                "{} (attempt {} of {})",
+                error, attempt, policy.attempts
+            )));
+        }
+        let delay_ms = policy.delay_ms(attempt - 1, retry_after.as_deref());
+        console_warn!(
+            "Attempt {} of {} failed: {}, retrying in {}ms",
+            attempt,
+            policy.attempts,
+            error,
+            delay_ms
+        );
+        Delay::from(std::time::Duration::from_millis(delay_ms)).await;
+        attempt += 1;
+    }
+}
+
+/// POST to the Cloudflare API, retrying network errors and transient statuses with backoff
+async fn post_request(config: &Config, url: &str, body: &str) -> Result<Response> {
+    retry_with_backoff(
+        RetryPolicy::from_config(config),
+        || post_request_attempt(url, &config.cf_api_token, body),
+        |status, _| is_transient(status),
+    )
+    .await
+    .map_err(|e| {
+        Error::from(format!(
+            "Failed to send vector request, url: {}, {}",
+            url, e
+        ))
+    })
+}
+
+async fn post_request_attempt(url: &str, api_token: &str, body: &str) -> Result<Response> {
     let mut headers = Headers::new();
     headers.set("Authorization", &format!("Bearer {}", api_token))?;
     headers.set("Content-Type", "application/json")?;
@@ -23,79 +148,196 @@ async fn post_request(url: &str, api_token: &str, body: &str) -> Result<Response
         .with_body(Some(wasm_bindgen::JsValue::from_str(body)));
 
     let request = Request::new_with_init(url, &init)?;
-    let mut response = Fetch::Request(request).send().await?;
+    Fetch::Request(request).send().await
+}
 
-    if response.status_code() != 200 {
-        let error_text = response.text().await?;
-        return Err(Error::from(format!(
-            "Failed to send vector request, url: {}, error: {}",
-            url, error_text
-        )));
+/// Embedding model family of a vector, each space has its own model and vector_lite index
+/// Scores are only comparable within a space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingSpace {
+    /// `EMBEDDING_MODEL`, used for English and whenever no multilingual model is configured
+    Primary,
+    /// `MULTILINGUAL_EMBEDDING_MODEL`, used for documents in other languages
+    Multilingual,
+}
+
+impl EmbeddingSpace {
+    /// The spaces to search, the multilingual one only when its model is configured
+    pub fn active(config: &Config) -> Vec<Self> {
+        let mut spaces = vec![EmbeddingSpace::Primary];
+        if config.multilingual_embedding_model.is_some() {
+            spaces.push(EmbeddingSpace::Multilingual);
+        }
+        spaces
     }
 
-    Ok(response)
-}
+    pub fn name(self) -> &'static str {
+        match self {
+            EmbeddingSpace::Primary => "primary",
+            EmbeddingSpace::Multilingual => "multilingual",
+        }
+    }
+
+    /// Where the vector_lite index of this space is stored
+    pub fn bucket_key(self) -> &'static str {
+        match self {
+            EmbeddingSpace::Primary => VECTOR_LITE_KEY,
+            EmbeddingSpace::Multilingual => MULTILINGUAL_VECTOR_LITE_KEY,
+        }
+    }
 
-/// Generates embeddings for text using Workers AI
-pub async fn generate_embeddings(env: &Env, text: &str) -> Result<Vec<f32>> {
-    let account_id = env.secret(CF_ACCOUNT_ID)?.to_string();
-    let api_token = env.secret(CF_API_TOKEN)?.to_string();
+    /// The Workers AI model producing this space, falling back to the primary model
+    pub fn model(self, config: &Config) -> String {
+        match (self, &config.multilingual_embedding_model) {
+            (EmbeddingSpace::Multilingual, Some(model)) => model.clone(),
+            _ => config.embedding_model.clone(),
+        }
+    }
 
-    let url = WORKERS_AI_API_URL.replace("{account_id}", &account_id);
+    /// Space for a document in `language` (an ISO 639-1 code), English and unknown stay primary
+    pub fn for_language(config: &Config, language: &str) -> Self {
+        let code = language.split(['-', '_']).next().unwrap_or("").trim();
+        if code.is_empty()
+            || code.eq_ignore_ascii_case("en")
+            || config.multilingual_embedding_model.is_none()
+        {
+            EmbeddingSpace::Primary
+        } else {
+            EmbeddingSpace::Multilingual
+        }
+    }
 
-    let embedding_req = EmbeddingRequest {
-        text: vec![text.to_string()],
-    };
+    /// Space of stored vectors from the model recorded with them
+    pub fn for_model(config: &Config, model: Option<&str>) -> Self {
+        match (model, &config.multilingual_embedding_model) {
+            (Some(model), Some(multilingual)) if model == multilingual => {
+                EmbeddingSpace::Multilingual
+            }
+            _ => EmbeddingSpace::Primary,
+        }
+    }
+}
 
-    // First attempt
-    match generate_embedding_attempt(&url, &api_token, &embedding_req).await {
-        Ok(embeddings) => Ok(embeddings),
-        Err(e) => {
-            console_error!("First embedding attempt failed: {}, retrying once...", e);
-
-            // Retry once
-            match generate_embedding_attempt(&url, &api_token, &embedding_req).await {
-                Ok(embeddings) => Ok(embeddings),
-                Err(e) => {
-                    console_error!("Retry embedding attempt also failed: {}", e);
-                    Err(e)
+/// Generates a unit-length embedding for text using a Workers AI model
+pub async fn generate_embeddings(config: &Config, model: &str, text: &str) -> Result<Vec<f32>> {
+    let mut embeddings = generate_embeddings_batch(config, model, &[text.to_string()]).await?;
+    Ok(embeddings.remove(0))
+}
+
+/// Generates unit-length embeddings for many texts, in order, with one Workers AI call per
+/// `MAX_EMBEDDING_BATCH` texts instead of one per text
+pub async fn generate_embeddings_batch(
+    config: &Config,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let url = WORKERS_AI_API_URL
+        .replace("{account_id}", &config.cf_account_id)
+        .replace("{model}", model);
+
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(MAX_EMBEDDING_BATCH) {
+        // Chunks and queries go through the same preprocessing, so they stay comparable
+        let text = batch
+            .iter()
+            .map(|text| {
+                if config.embedding_preprocess {
+                    Some(markdown_to_prose(text, config.embedding_code_blocks))
+                        .filter(|prose| !prose.is_empty())
+                        .unwrap_or_else(|| text.to_string())
+                } else {
+                    text.to_string()
                 }
-            }
+            })
+            .collect();
+        let embedding_req = EmbeddingRequest { text };
+
+        // Transient failures are retried by post_request
+        let batch_embeddings = request_embeddings(config, &url, &embedding_req)
+            .await
+            .inspect_err(|e| console_error!("Embedding generation failed: {}", e))?;
+        if batch_embeddings.len() != batch.len() {
+            return Err(Error::from(format!(
+                "Failed to generate embeddings: got {} for {} texts",
+                batch_embeddings.len(),
+                batch.len()
+            )));
         }
+
+        for embedding in batch_embeddings {
+            check_embedding_dim(model, &embedding)?;
+            // Stored and query vectors are unit length, so cosine and dot product agree whatever the model returns
+            embeddings.push(normalize(embedding));
+        }
+    }
+    Ok(embeddings)
+}
+
+/// Scale a vector to unit L2 norm, all-zero vectors are returned unchanged
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
     }
+    vector
 }
 
-/// Helper function for a single embedding generation attempt
-async fn generate_embedding_attempt(
+/// Reject embeddings that don't fit the index, e.g. from a 1024-dimension model like bge-large
+fn check_embedding_dim(model: &str, embedding: &[f32]) -> Result<()> {
+    if embedding.len() != EMBEDDING_DIM {
+        return Err(Error::from(format!(
+            "Embedding model {} returned {} dimensions, but the index holds {}-dimension vectors. \
+            Set EMBEDDING_MODEL to a {}-dimension model such as {}, or build with{} the \
+            embedding-1024 feature",
+            model,
+            embedding.len(),
+            EMBEDDING_DIM,
+            EMBEDDING_DIM,
+            DEFAULT_EMBEDDING_MODEL,
+            if EMBEDDING_DIM == 1024 { "out" } else { "" }
+        )));
+    }
+    Ok(())
+}
+
+/// Request the embeddings of the texts of one batch
+async fn request_embeddings(
+    config: &Config,
     url: &str,
-    api_token: &str,
     embedding_req: &EmbeddingRequest,
-) -> Result<Vec<f32>> {
-    let mut response = post_request(url, api_token, &serde_json::to_string(embedding_req)?).await?;
+) -> Result<Vec<Vec<f32>>> {
+    let mut response = post_request(config, url, &serde_json::to_string(embedding_req)?).await?;
     let embedding_response: EmbeddingResponse = response.json().await?;
 
     if !embedding_response.success || embedding_response.result.data.is_empty() {
         return Err(Error::from("Failed to generate embeddings: empty response"));
     }
 
-    Ok(embedding_response.result.data[0].clone())
+    Ok(embedding_response.result.data)
 }
 
 /// Queries the Vectorize index for similar vectors and returns IDs, scores, and metadata
 pub async fn query_vectors_with_scores(
-    env: &Env,
+    config: &Config,
     query_text: &str,
     top_k: usize,
+    timings: &mut SearchTimings,
 ) -> Result<Vec<(String, f32)>> {
-    let account_id = env.secret(CF_ACCOUNT_ID)?.to_string();
-    let api_token = env.secret(CF_API_TOKEN)?.to_string();
-
     // Generate embedding for the query text
-    let query_vector = generate_embeddings(env, query_text).await?;
+    let (query_vector, embed_ms) = timed(generate_embeddings(
+        config,
+        &config.embedding_model,
+        query_text,
+    ))
+    .await;
+    timings.embed_ms = embed_ms;
+    let query_vector = query_vector?;
+    let timer = Timer::start();
 
     let url = format!(
         "https://api.cloudflare.com/client/v4/accounts/{}/vectorize/v2/indexes/{}/query",
-        account_id, VECTORIZE_INDEX_NAME
+        config.cf_account_id, VECTORIZE_INDEX_NAME
     );
 
     let query_req = VectorQueryRequest {
@@ -103,8 +345,9 @@ pub async fn query_vectors_with_scores(
         top_k,
         return_metadata: "all".to_string(),
     };
-    let mut response = post_request(&url, &api_token, &serde_json::to_string(&query_req)?).await?;
+    let mut response = post_request(config, &url, &serde_json::to_string(&query_req)?).await?;
     let query_response: VectorQueryResponse = response.json().await?;
+    timings.search_ms = timer.elapsed_ms();
 
     if !query_response.success {
         return Err(Error::from(
@@ -112,71 +355,613 @@ pub async fn query_vectors_with_scores(
         ));
     }
 
-    // Return vector IDs with scores and metadata
+    let metric = vectorize_metric(config).await;
     Ok(query_response
         .result
         .matches
         .into_iter()
-        .map(|m| (m.id, m.score))
+        .map(|m| (m.id, similarity(&metric, m.score)))
         .collect())
 }
 
-pub async fn get_vector_lite(env: &Env) -> Result<vector_lite::VectorLite<768>> {
+/// Vector ids of a document's chunks, `{document_id}-{chunk_index}`
+pub fn chunk_vector_ids(document_id: &str, chunk_count: usize) -> Vec<String> {
+    (0..chunk_count)
+        .map(|i| format!("{}-{}", document_id, i))
+        .collect()
+}
+
+/// Pair each chunk embedding with its vector id
+pub fn chunk_vectors(document_id: &str, embeddings: &[Vec<f32>]) -> Vec<(String, Vec<f32>)> {
+    chunk_vector_ids(document_id, embeddings.len())
+        .into_iter()
+        .zip(embeddings.iter().cloned())
+        .collect()
+}
+
+/// Parameters a vector_lite index is built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexParams {
+    pub num_trees: usize,
+    pub max_leaf_size: usize,
+    /// Store the vectors as int8 and rebuild the trees on load, see `StoredIndex::encode`
+    #[serde(default)]
+    pub quantized: bool,
+    /// Dimension of the vectors, an index of another dimension has to be rebuilt with `/reindex`
+    #[serde(default = "legacy_embedding_dim")]
+    pub dimensions: usize,
+}
+
+fn legacy_embedding_dim() -> usize {
+    LEGACY_EMBEDDING_DIM
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        Self {
+            num_trees: DEFAULT_NUM_TREES,
+            max_leaf_size: DEFAULT_MAX_LEAF_SIZE,
+            quantized: false,
+            dimensions: EMBEDDING_DIM,
+        }
+    }
+}
+
+impl IndexParams {
+    /// The configured parameters, `VECTOR_LITE_NUM_TREES`, `VECTOR_LITE_MAX_LEAF_SIZE` and `VECTOR_LITE_QUANTIZE`
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            num_trees: config.vector_lite_num_trees,
+            max_leaf_size: config.vector_lite_max_leaf_size,
+            quantized: config.vector_lite_quantize,
+            dimensions: EMBEDDING_DIM,
+        }
+    }
+}
+
+/// A vector scaled into int8, `value ≈ scale * q`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedVector {
+    pub scale: f32,
+    pub values: Vec<i8>,
+}
+
+impl QuantizedVector {
+    /// Symmetric scalar quantization with one scale per vector, the largest component maps to ±127
+    pub fn quantize(vector: &[f32]) -> Self {
+        let max = vector.iter().fold(0f32, |max, x| max.max(x.abs()));
+        let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+        Self {
+            scale,
+            values: vector
+                .iter()
+                .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+                .collect(),
+        }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|q| *q as f32 * self.scale).collect()
+    }
+}
+
+/// A vector_lite index together with the parameters it was built with
+/// Derefs to the index for searching, changes go through `insert` and `delete_by_id` so the
+/// int8 copy of a quantized index can't fall out of sync
+pub struct StoredIndex {
+    pub params: IndexParams,
+    index: VectorLite<EMBEDDING_DIM>,
+    /// The vectors of a quantized index by id, vector_lite doesn't expose its own for encoding
+    quantized: std::collections::HashMap<String, QuantizedVector>,
+}
+
+impl Deref for StoredIndex {
+    type Target = VectorLite<EMBEDDING_DIM>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.index
+    }
+}
+
+impl StoredIndex {
+    pub fn new(params: IndexParams) -> Self {
+        Self {
+            params,
+            index: VectorLite::<EMBEDDING_DIM>::new(params.num_trees, params.max_leaf_size),
+            quantized: Default::default(),
+        }
+    }
+
+    /// Insert a vector, keeping the int8 copy of a quantized index in sync
+    pub fn insert(&mut self, vector: Vector<EMBEDDING_DIM>, id: String) {
+        if self.params.quantized {
+            self.quantized
+                .insert(id.clone(), QuantizedVector::quantize(vector.as_slice()));
+        }
+        self.index.insert(vector, id);
+    }
+
+    pub fn delete_by_id(&mut self, id: &str) {
+        self.quantized.remove(id);
+        self.index.delete_by_id(id);
+    }
+
+    /// Serialize as `INDEX_MAGIC`, a little-endian u32 header length, the JSON params, then the index
+    /// A quantized index stores its vectors instead of the trees, as a little-endian u16 id length,
+    /// the id, the f32 scale and the int8 components each; that is about a quarter of the floats
+    /// of vector_lite's own format, at the cost of rebuilding the trees on every load
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let header = serde_json::to_vec(&self.params)?;
+        let body = if self.params.quantized {
+            let mut body = Vec::with_capacity(self.quantized.len() * (EMBEDDING_DIM + 48));
+            for (id, vector) in &self.quantized {
+                body.extend_from_slice(&(id.len() as u16).to_le_bytes());
+                body.extend_from_slice(id.as_bytes());
+                body.extend_from_slice(&vector.scale.to_le_bytes());
+                body.extend(vector.values.iter().map(|q| *q as u8));
+            }
+            body
+        } else {
+            self.index.to_bytes()
+        };
+
+        let mut bytes = Vec::with_capacity(INDEX_MAGIC.len() + 4 + header.len() + body.len());
+        bytes.extend_from_slice(INDEX_MAGIC);
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let Some(rest) = bytes.strip_prefix(INDEX_MAGIC) else {
+            // Blobs written before the parameters were persisted used the defaults
+            check_index_dim(LEGACY_EMBEDDING_DIM)?;
+            return Ok(Self {
+                params: IndexParams::default(),
+                index: VectorLite::<EMBEDDING_DIM>::from_bytes(bytes),
+                quantized: Default::default(),
+            });
+        };
+
+        let header_len = rest
+            .get(..4)
+            .map(|len| u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize)
+            .ok_or(Error::from("Corrupted vector lite header"))?;
+        let header = rest
+            .get(4..4 + header_len)
+            .ok_or(Error::from("Corrupted vector lite header"))?;
+        let params = serde_json::from_slice::<IndexParams>(header)?;
+        check_index_dim(params.dimensions)?;
+        let body = &rest[4 + header_len..];
+        if !params.quantized {
+            return Ok(Self {
+                params,
+                index: VectorLite::<EMBEDDING_DIM>::from_bytes(body),
+                quantized: Default::default(),
+            });
+        }
+
+        let mut index = Self::new(params);
+        let mut rest = body;
+        while !rest.is_empty() {
+            let corrupted = || Error::from("Corrupted quantized vector lite index");
+            let id_len = rest
+                .get(..2)
+                .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+                .ok_or_else(corrupted)?;
+            let entry = rest
+                .get(..2 + id_len + 4 + EMBEDDING_DIM)
+                .ok_or_else(corrupted)?;
+            let id = String::from_utf8(entry[2..2 + id_len].to_vec()).map_err(|_| corrupted())?;
+            let scale = &entry[2 + id_len..2 + id_len + 4];
+            let vector = QuantizedVector {
+                scale: f32::from_le_bytes([scale[0], scale[1], scale[2], scale[3]]),
+                values: entry[2 + id_len + 4..].iter().map(|q| *q as i8).collect(),
+            };
+            let dequantized = Vector::try_from(vector.dequantize()).map_err(|_| corrupted())?;
+            index.index.insert(dequantized, id.clone());
+            index.quantized.insert(id, vector);
+            rest = &rest[entry.len()..];
+        }
+        Ok(index)
+    }
+}
+
+/// Reject a stored index of another dimension than this build, which vector_lite would misread
+fn check_index_dim(dimensions: usize) -> Result<()> {
+    if dimensions != EMBEDDING_DIM {
+        return Err(Error::from(format!(
+            "The vector index holds {}-dimension vectors, but this build uses {}. \
+            Run /reindex to rebuild it",
+            dimensions, EMBEDDING_DIM
+        )));
+    }
+    Ok(())
+}
+
+async fn get_vector_lite_bytes(env: &Env, space: EmbeddingSpace) -> Result<Vec<u8>> {
     let bucket = env.bucket("SEEN_BUCKET")?;
     let bytes = bucket
-        .get("vector_lite.bin")
+        .get(space.bucket_key())
         .execute()
         .await?
         .ok_or(Error::from("Failed to get vector lite"))?;
-    let bytes = bytes
+    bytes
+        .body()
+        .ok_or(Error::from("Failed to get vector lite body"))?
+        .bytes()
+        .await
+}
+
+/// Size of the stored index of a space without downloading it, `None` if it has never been saved
+pub async fn get_vector_lite_size(env: &Env, space: EmbeddingSpace) -> Result<Option<u64>> {
+    let bucket = env.bucket("SEEN_BUCKET")?;
+    Ok(bucket
+        .head(space.bucket_key())
+        .await?
+        .map(|object| object.size()))
+}
+
+/// Load the stored index of a space, or `None` if it has never been saved
+pub async fn load_vector_lite(env: &Env, space: EmbeddingSpace) -> Result<Option<StoredIndex>> {
+    let bucket = env.bucket("SEEN_BUCKET")?;
+    let Some(object) = bucket.get(space.bucket_key()).execute().await? else {
+        return Ok(None);
+    };
+    let bytes = object
         .body()
         .ok_or(Error::from("Failed to get vector lite body"))?
         .bytes()
         .await?;
-    Ok(vector_lite::VectorLite::<768>::from_bytes(&bytes))
+    Ok(Some(StoredIndex::decode(&bytes)?))
 }
 
-pub async fn save_vector_lite(env: &Env, vector_lite: &vector_lite::VectorLite<768>) -> Result<()> {
+pub async fn save_vector_lite(
+    env: &Env,
+    space: EmbeddingSpace,
+    vector_lite: &StoredIndex,
+) -> Result<()> {
     let bucket = env.bucket("SEEN_BUCKET")?;
     bucket
-        .put("vector_lite.bin", vector_lite.to_bytes())
+        .put(space.bucket_key(), vector_lite.encode()?)
         .execute()
         .await?;
     Ok(())
 }
 
+/// Summary of the stored vector_lite index
+pub struct IndexInfo {
+    pub vector_count: usize,
+    pub params: IndexParams,
+    pub configured_params: IndexParams,
+    pub size_bytes: usize,
+}
+
+pub async fn get_index_info(
+    env: &Env,
+    config: &Config,
+    space: EmbeddingSpace,
+) -> Result<IndexInfo> {
+    let bytes = get_vector_lite_bytes(env, space).await?;
+    let index = StoredIndex::decode(&bytes)?;
+    Ok(IndexInfo {
+        vector_count: index.len(),
+        params: index.params,
+        configured_params: IndexParams::from_config(config),
+        size_bytes: bytes.len(),
+    })
+}
+
+/// Rebuild the vector_lite index of every space from the embeddings table with the configured parameters
+/// The embeddings table is the source of truth, the indexes in R2 are derived from it
+/// Each index is rebuilt inside its Durable Object, so updates arriving meanwhile aren't lost
+/// Returns the number of vectors in the new indexes
+pub async fn rebuild_vector_lite(env: &Env, config: &Config) -> Result<usize> {
+    for space in EmbeddingSpace::active(config) {
+        let model = space.model(config);
+        match crate::config::embedding_dimensions(&model) {
+            Some(dimensions) if dimensions != EMBEDDING_DIM => {
+                return Err(Error::from(format!(
+                    "Embedding model {} outputs {} dimensions, but this build indexes {}. \
+                    Change the model or build with{} the embedding-1024 feature before reindexing",
+                    model,
+                    dimensions,
+                    EMBEDDING_DIM,
+                    if EMBEDDING_DIM == 1024 { "out" } else { "" }
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    // Links embedded at another dimension, e.g. before switching models, need new embeddings
+    let mut stale_links = std::collections::BTreeSet::new();
+    let mut total = 0;
+    for space in EmbeddingSpace::active(config) {
+        let rebuilt = crate::vector_index::rebuild(env, space).await?;
+        total += rebuilt.vectors;
+        stale_links.extend(rebuilt.stale_links);
+    }
+
+    if !stale_links.is_empty() {
+        console_warn!(
+            "{} links have embeddings of another dimension, queued for /repair",
+            stale_links.len()
+        );
+    }
+    for link_id in &stale_links {
+        crate::d1::set_link_status(
+            env,
+            link_id,
+            crate::d1::STATUS_NEEDS_REEMBED,
+            &format!("Embedded at another dimension than {}", EMBEDDING_DIM),
+        )
+        .await?;
+    }
+    Ok(total)
+}
+
+/// Build the vector_lite index of a space from the embeddings table with the configured parameters
+/// Embeddings saved before they were normalized are scaled to unit length on the way
+/// Also returns the links whose embeddings have another dimension and were left out
+pub async fn build_vector_lite(
+    env: &Env,
+    config: &Config,
+    space: EmbeddingSpace,
+) -> Result<(StoredIndex, Vec<String>)> {
+    let embeddings = crate::d1::get_all_embeddings(env).await?;
+    let mut stale_links = vec![];
+    let mut index = StoredIndex::new(IndexParams::from_config(config));
+    for embedding in embeddings
+        .iter()
+        .filter(|e| EmbeddingSpace::for_model(config, e.model.as_deref()) == space)
+    {
+        if embedding.vector.len() != EMBEDDING_DIM {
+            if let Some((link_id, _)) = embedding.vector_id.rsplit_once('-') {
+                if !stale_links.iter().any(|stale| stale == link_id) {
+                    stale_links.push(link_id.to_string());
+                }
+            }
+            continue;
+        }
+        match Vector::try_from(normalize(embedding.vector.clone())) {
+            Ok(vector) => index.insert(vector, embedding.vector_id.clone()),
+            Err(_) => console_error!("Skipping malformed embedding: {}", embedding.vector_id),
+        }
+    }
+    Ok((index, stale_links))
+}
+
+/// How a space's vector_lite index compares to the embeddings table
+pub struct Consistency {
+    pub space: EmbeddingSpace,
+    pub stored: usize,
+    pub indexed: usize,
+    /// Stored vectors the index doesn't return for their own embedding
+    pub missing: Vec<String>,
+    /// Vectors the index returned that are no longer stored
+    pub stale: Vec<String>,
+}
+
+/// Compare every index to the embeddings table, which is the source of truth
+/// Each stored vector is looked up by its own embedding, so an ANN miss can show up as missing
+pub async fn check_consistency(env: &Env, config: &Config) -> Result<Vec<Consistency>> {
+    const LOOKUP_K: usize = 3;
+    let embeddings = crate::d1::get_all_embeddings(env).await?;
+    let stored_ids = embeddings
+        .iter()
+        .map(|e| e.vector_id.as_str())
+        .collect::<std::collections::HashSet<_>>();
+
+    let mut report = vec![];
+    for space in EmbeddingSpace::active(config) {
+        let index = load_vector_lite(env, space)
+            .await?
+            .unwrap_or_else(|| StoredIndex::new(IndexParams::from_config(config)));
+        let mut consistency = Consistency {
+            space,
+            stored: 0,
+            indexed: index.len(),
+            missing: vec![],
+            stale: vec![],
+        };
+        for embedding in embeddings
+            .iter()
+            .filter(|e| EmbeddingSpace::for_model(config, e.model.as_deref()) == space)
+        {
+            consistency.stored += 1;
+            let Ok(vector) = Vector::try_from(normalize(embedding.vector.clone())) else {
+                continue;
+            };
+            let found = index.search(&vector, LOOKUP_K);
+            if !found.iter().any(|(id, _)| *id == embedding.vector_id) {
+                consistency.missing.push(embedding.vector_id.clone());
+            }
+            for (id, _) in found {
+                if !stored_ids.contains(id.as_str()) && !consistency.stale.contains(&id) {
+                    consistency.stale.push(id);
+                }
+            }
+        }
+        report.push(consistency);
+    }
+    Ok(report)
+}
+
+/// Whether to scan every vector of an index holding `len` of them instead of querying its trees
+/// `SEARCH_EXACT` forces either mode, otherwise exact search is used below `EXACT_SEARCH_THRESHOLD` vectors
+pub fn use_exact_search(forced: Option<bool>, len: usize, threshold: usize) -> bool {
+    forced.unwrap_or(len < threshold)
+}
+
+/// The embeddings of a space by vector id, for exact search over the same vectors as its index
+pub async fn load_exact_vectors(
+    env: &Env,
+    config: &Config,
+    space: EmbeddingSpace,
+) -> Result<Vec<(String, Vec<f32>)>> {
+    Ok(crate::d1::get_all_embeddings(env)
+        .await?
+        .into_iter()
+        .filter(|e| EmbeddingSpace::for_model(config, e.model.as_deref()) == space)
+        .filter(|e| e.vector.len() == EMBEDDING_DIM)
+        .map(|e| (e.vector_id, e.vector))
+        .collect())
+}
+
+/// Cosine similarity of two vectors, 0 if either is all zeros
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let mut dot = 0.0;
+    let mut norm_a = 0.0;
+    let mut norm_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+/// Cosine similarity in [0, 1] from a score in `metric`, so both search paths show the same number
+/// Embeddings are unit vectors, so a dot product is already the cosine and a euclidean distance d
+/// is 1 - d^2 / 2; opposite vectors score 0 rather than a negative number
+/// Vectorize reports the plain L2 distance for euclidean indexes, not its square, and vector_lite's
+/// `ScoreMetric::Cosine` is already the similarity, see the tests
+pub fn similarity(metric: &str, score: f32) -> f32 {
+    let cosine = match metric {
+        "euclidean" => 1.0 - score * score / 2.0,
+        _ => score,
+    };
+    cosine.clamp(0.0, 1.0)
+}
+
+/// Metric the Vectorize index was created with, `cosine` if it can't be looked up
+async fn vectorize_metric(config: &Config) -> String {
+    if let Some(metric) = VECTORIZE_METRIC.get() {
+        return metric.clone();
+    }
+    match get_vectorize_metric(config).await {
+        Ok(metric) => VECTORIZE_METRIC.get_or_init(|| metric).clone(),
+        Err(e) => {
+            console_warn!(
+                "Failed to look up the Vectorize metric, assuming cosine: {}",
+                e
+            );
+            "cosine".to_string()
+        }
+    }
+}
+
+async fn get_vectorize_metric(config: &Config) -> Result<String> {
+    let url = format!(
+        "https://api.cloudflare.com/client/v4/accounts/{}/vectorize/v2/indexes/{}",
+        config.cf_account_id, VECTORIZE_INDEX_NAME
+    );
+    let mut headers = Headers::new();
+    headers.set("Authorization", &format!("Bearer {}", config.cf_api_token))?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Get).with_headers(headers);
+    let mut response = Fetch::Request(Request::new_with_init(&url, &init)?)
+        .send()
+        .await?;
+    if response.status_code() != 200 {
+        return Err(Error::from(format!(
+            "Failed to get Vectorize index, status: {}",
+            response.status_code()
+        )));
+    }
+    let index: VectorIndexResponse = response.json().await?;
+    if !index.success {
+        return Err(Error::from(
+            "Failed to get Vectorize index: unsuccessful response",
+        ));
+    }
+    Ok(index.result.config.metric)
+}
+
+/// Exact nearest neighbors by cosine similarity, best first
+pub fn exact_search(
+    query: &[f32],
+    embeddings: &[(String, Vec<f32>)],
+    top_k: usize,
+) -> Vec<(String, f32)> {
+    let mut scored = embeddings
+        .iter()
+        .map(|(id, embedding)| {
+            let score = similarity("cosine", cosine_similarity(query, embedding));
+            (id.clone(), score)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scored.truncate(top_k);
+    scored
+}
+
+/// Query the vector_lite index of every active space, merging the results by score
+/// Each space is embedded with its own model, timings add up across spaces
+/// Small indexes are scanned exactly by their Durable Object, see `use_exact_search`
 pub(crate) async fn query_vectors_with_scores_vector_lite(
     env: &Env,
+    config: &Config,
     query_text: &str,
     top_k: usize,
+    timings: &mut SearchTimings,
 ) -> Result<Vec<(String, f32)>> {
-    let (query_vector, vector_lite) =
-        futures_util::join!(generate_embeddings(env, query_text), get_vector_lite(env));
+    let mut vectors = vec![];
+    for space in EmbeddingSpace::active(config) {
+        let (query_vector, embed_ms) = timed(embed_query(config, space, query_text)).await;
+        timings.embed_ms += embed_ms;
+        let (_, query_vector) = query_vector?;
 
-    let query_vector = query_vector?;
-    let vector_lite = vector_lite?;
-    let vector = Vector::try_from(query_vector).unwrap();
-    let vectors = vector_lite
-        .search_with_metric(&vector, top_k, vector_lite::ScoreMetric::Cosine)
-        .into_iter()
-        .collect();
+        // The index is cached in a Durable Object, so loading only shows up on its first search
+        // and is told apart from the search by the object
+        let (matches, round_trip_ms) = timed(crate::vector_index::search(
+            env,
+            config,
+            space,
+            query_vector,
+            top_k,
+        ))
+        .await;
+        let matches = matches?;
+        timings.add_index_search(round_trip_ms, matches.load_index_ms);
+        vectors.extend(
+            matches
+                .results
+                .into_iter()
+                .map(|(id, score)| (id, similarity("cosine", score))),
+        );
+    }
 
+    vectors.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    vectors.truncate(top_k);
     Ok(vectors)
 }
 
-pub async fn get_vector_by_id(env: &Env, ids: &[&str]) -> Result<Vec<Vector<768>>> {
-    let account_id = env.secret(CF_ACCOUNT_ID)?.to_string();
-    let api_token = env.secret(CF_API_TOKEN)?.to_string();
+async fn embed_query(
+    config: &Config,
+    space: EmbeddingSpace,
+    query_text: &str,
+) -> Result<(EmbeddingSpace, Vec<f32>)> {
+    let embedding = generate_embeddings(config, &space.model(config), query_text).await?;
+    Ok((space, embedding))
+}
+
+pub async fn get_vector_by_id(config: &Config, ids: &[&str]) -> Result<Vec<Vector<EMBEDDING_DIM>>> {
     let url = format!(
         "https://api.cloudflare.com/client/v4/accounts/{}/vectorize/v2/indexes/{}/get_by_ids",
-        account_id, VECTORIZE_INDEX_NAME
+        config.cf_account_id, VECTORIZE_INDEX_NAME
     );
 
     let get_req = json!({
         "ids": ids.to_owned()
     });
 
-    let mut response = post_request(&url, &api_token, &serde_json::to_string(&get_req)?).await?;
+    let mut response = post_request(config, &url, &serde_json::to_string(&get_req)?).await?;
     let get_response: VectorGetResponse = response.json().await?;
 
     if !get_response.success {
@@ -193,16 +978,13 @@ pub async fn get_vector_by_id(env: &Env, ids: &[&str]) -> Result<Vec<Vector<768>
 
 /// Deletes vectors from the Vectorize index with IDs matching the document ID
 pub async fn delete_vectors_by_prefix(
-    env: &Env,
+    config: &Config,
     id_prefix: &str,
     chunk_count: usize,
 ) -> Result<()> {
-    let account_id = env.secret(CF_ACCOUNT_ID)?.to_string();
-    let api_token = env.secret(CF_API_TOKEN)?.to_string();
-
     let url = format!(
         "https://api.cloudflare.com/client/v4/accounts/{}/vectorize/v2/indexes/{}/delete_by_ids",
-        account_id, VECTORIZE_INDEX_NAME
+        config.cf_account_id, VECTORIZE_INDEX_NAME
     );
 
     // Construct vector IDs based on the document ID and chunk count
@@ -215,8 +997,7 @@ pub async fn delete_vectors_by_prefix(
         "ids": vector_ids
     });
 
-    let mut response =
-        post_request(&url, &api_token, &serde_json::to_string(&delete_payload)?).await?;
+    let mut response = post_request(config, &url, &serde_json::to_string(&delete_payload)?).await?;
 
     // Parse the response to check if success is true
     let response_data: serde_json::Value = response.json().await?;
@@ -238,3 +1019,356 @@ pub async fn delete_vectors_by_prefix(
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vector_lite::ScoreMetric;
+
+    /// Deterministic unit vectors, a small LCG keeps the tests free of a seeded RNG
+    fn random_vectors(count: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as f32 / (1u64 << 31) as f32 - 0.5
+        };
+        (0..count)
+            .map(|_| normalize((0..EMBEDDING_DIM).map(|_| next()).collect()))
+            .collect()
+    }
+
+    fn build_index(params: IndexParams, vectors: &[Vec<f32>]) -> StoredIndex {
+        let mut index = StoredIndex::new(params);
+        for (i, vector) in vectors.iter().enumerate() {
+            index.insert(
+                Vector::try_from(vector.clone()).unwrap(),
+                format!("doc-{}", i),
+            );
+        }
+        index
+    }
+
+    #[test]
+    fn stored_index_round_trips() {
+        let params = IndexParams {
+            num_trees: 2,
+            max_leaf_size: 8,
+            ..Default::default()
+        };
+        let vectors = random_vectors(30, 1);
+        let index = build_index(params, &vectors);
+
+        let decoded = StoredIndex::decode(&index.encode().unwrap()).unwrap();
+        assert_eq!(decoded.params, params);
+        assert_eq!(decoded.len(), 30);
+        let query = Vector::try_from(vectors[7].clone()).unwrap();
+        assert_eq!(
+            decoded.search_with_metric(&query, 3, ScoreMetric::Cosine),
+            index.search_with_metric(&query, 3, ScoreMetric::Cosine)
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_indexes() {
+        let index = build_index(IndexParams::default(), &random_vectors(2, 2));
+        let bytes = index.encode().unwrap();
+        assert!(StoredIndex::decode(&bytes[..INDEX_MAGIC.len() + 2]).is_err());
+        assert!(StoredIndex::decode(&bytes[..INDEX_MAGIC.len() + 6]).is_err());
+
+        let other_dim = IndexParams {
+            dimensions: EMBEDDING_DIM + 1,
+            ..Default::default()
+        };
+        let bytes = StoredIndex::new(other_dim).encode().unwrap();
+        assert!(StoredIndex::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn exact_search_matches_ann_on_a_small_corpus() {
+        // Fewer vectors than a leaf holds, so the ANN search compares them all
+        let vectors = random_vectors(12, 3);
+        let index = build_index(IndexParams::default(), &vectors);
+        let embeddings = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, vector)| (format!("doc-{}", i), vector.clone()))
+            .collect::<Vec<_>>();
+
+        for query in vectors.iter().take(4) {
+            let exact = exact_search(query, &embeddings, 5);
+            let ann = index.search_with_metric(
+                &Vector::try_from(query.clone()).unwrap(),
+                5,
+                ScoreMetric::Cosine,
+            );
+            let ids = |results: &[(String, f32)]| {
+                results.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>()
+            };
+            assert_eq!(ids(&exact), ids(&ann));
+            assert!((exact[0].1 - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn small_indexes_are_searched_exactly() {
+        assert!(use_exact_search(None, 0, 2000));
+        assert!(use_exact_search(None, 1999, 2000));
+        assert!(!use_exact_search(None, 2000, 2000));
+        // SEARCH_EXACT wins over the size either way
+        assert!(use_exact_search(Some(true), 5000, 2000));
+        assert!(!use_exact_search(Some(false), 10, 2000));
+    }
+
+    #[test]
+    fn exact_search_ranks_by_cosine() {
+        let embeddings = vec![
+            ("far".to_string(), vec![0.0, 1.0]),
+            ("near".to_string(), vec![1.0, 0.1]),
+            ("opposite".to_string(), vec![-1.0, 0.0]),
+        ];
+        let results = exact_search(&[1.0, 0.0], &embeddings, 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "near");
+        assert_eq!(results[1], ("far".to_string(), 0.0));
+    }
+
+    #[test]
+    fn retry_delays_back_off_and_honor_retry_after() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_ms: 500,
+        };
+        assert_eq!(policy.delay_ms(0, None), 500);
+        assert_eq!(policy.delay_ms(1, None), 1000);
+        assert_eq!(policy.delay_ms(2, None), 2000);
+        assert_eq!(policy.delay_ms(10, None), MAX_RETRY_DELAY_MS);
+        assert_eq!(policy.delay_ms(40, None), MAX_RETRY_DELAY_MS);
+        assert_eq!(policy.delay_ms(0, Some(" 3 ")), 3000);
+        assert_eq!(policy.delay_ms(0, Some("3600")), MAX_RETRY_DELAY_MS);
+        // HTTP dates aren't parsed, the backoff applies
+        assert_eq!(
+            policy.delay_ms(1, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            1000
+        );
+    }
+
+    #[test]
+    fn only_rate_limits_and_server_errors_are_transient() {
+        assert!(is_transient(429));
+        assert!(is_transient(503));
+        assert!(!is_transient(400));
+        assert!(!is_transient(404));
+    }
+
+    #[test]
+    fn normalizes_to_unit_length() {
+        let normalized = normalize(vec![3.0, 4.0]);
+        assert_eq!(normalized, vec![0.6, 0.8]);
+        for vector in random_vectors(5, 4) {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-5, "{}", norm);
+        }
+        assert_eq!(normalize(vec![0.0, 0.0]), vec![0.0, 0.0]);
+        // Scaling doesn't change the direction, so cosine similarity is preserved
+        let a = vec![1.0, 2.0, -3.0];
+        let b = vec![-2.0, 0.5, 1.0];
+        assert!(
+            (cosine_similarity(&a, &b) - cosine_similarity(&normalize(a), &normalize(b))).abs()
+                < 1e-6
+        );
+    }
+
+    /// Unit vectors around `clusters` random centers, like embeddings of related documents
+    fn clustered_vectors(clusters: usize, per_cluster: usize, seed: u64) -> Vec<Vec<f32>> {
+        let centers = random_vectors(clusters, seed);
+        let noise = random_vectors(clusters * per_cluster, seed + 1);
+        noise
+            .iter()
+            .enumerate()
+            .map(|(i, noise)| {
+                let center = &centers[i / per_cluster];
+                normalize(center.iter().zip(noise).map(|(c, n)| c + 0.5 * n).collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quantized_vectors_round_trip_closely() {
+        for vector in random_vectors(20, 5) {
+            let quantized = QuantizedVector::quantize(&vector);
+            let restored = quantized.dequantize();
+            let max = vector.iter().fold(0f32, |max, x| max.max(x.abs()));
+            for (x, y) in vector.iter().zip(&restored) {
+                // Rounding moves each component by at most half a step
+                assert!((x - y).abs() <= max / 127.0 / 2.0 + 1e-7);
+            }
+            assert!(cosine_similarity(&vector, &restored) > 0.9999);
+        }
+        let zero = QuantizedVector::quantize(&[0.0, 0.0]);
+        assert_eq!(zero.dequantize(), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn quantized_index_round_trips() {
+        let params = IndexParams {
+            quantized: true,
+            ..Default::default()
+        };
+        let vectors = random_vectors(25, 6);
+        let mut index = build_index(params, &vectors);
+        index.delete_by_id("doc-3");
+
+        let bytes = index.encode().unwrap();
+        // The int8 body is about a quarter of the float one
+        let float_bytes = build_index(IndexParams::default(), &vectors)
+            .encode()
+            .unwrap();
+        assert!(bytes.len() * 3 < float_bytes.len());
+
+        let decoded = StoredIndex::decode(&bytes).unwrap();
+        assert_eq!(decoded.params, params);
+        assert_eq!(decoded.len(), 24);
+        assert_eq!(decoded.quantized, index.quantized);
+        let query = Vector::try_from(vectors[3].clone()).unwrap();
+        let results = decoded.search_with_metric(&query, 30, ScoreMetric::Cosine);
+        assert!(results.iter().all(|(id, _)| id != "doc-3"));
+    }
+
+    #[test]
+    fn quantization_keeps_the_top_results() {
+        // The figures `VECTOR_LITE_QUANTIZE` quotes in the README
+        const TOP_K: usize = 10;
+        let vectors = clustered_vectors(20, 50, 7);
+        let queries = clustered_vectors(20, 2, 7);
+        let embeddings = |quantize: bool| {
+            vectors
+                .iter()
+                .enumerate()
+                .map(|(i, vector)| {
+                    let vector = if quantize {
+                        QuantizedVector::quantize(vector).dequantize()
+                    } else {
+                        vector.clone()
+                    };
+                    (format!("doc-{}", i), vector)
+                })
+                .collect::<Vec<_>>()
+        };
+        let (float, quantized) = (embeddings(false), embeddings(true));
+
+        let mut kept = 0;
+        let mut max_score_change = 0f32;
+        for query in &queries {
+            let expected = exact_search(query, &float, TOP_K);
+            let actual = exact_search(query, &quantized, TOP_K);
+            kept += actual
+                .iter()
+                .filter(|(id, _)| expected.iter().any(|(expected, _)| expected == id))
+                .count();
+            for (id, score) in &actual {
+                let float_score =
+                    cosine_similarity(query, &float[id[4..].parse::<usize>().unwrap()].1);
+                max_score_change = max_score_change.max((score - float_score).abs());
+            }
+        }
+        let recall = kept as f32 / (queries.len() * TOP_K) as f32;
+        assert!(recall >= 0.98, "recall {}", recall);
+        assert!(
+            max_score_change < 0.002,
+            "score change {}",
+            max_score_change
+        );
+    }
+
+    #[test]
+    fn scores_agree_across_metrics() {
+        let vectors = random_vectors(6, 8);
+        let (query, target) = (&vectors[0], &vectors[1]);
+        let cosine = cosine_similarity(query, target);
+        let dot = query.iter().zip(target).map(|(x, y)| x * y).sum::<f32>();
+        let distance = query
+            .iter()
+            .zip(target)
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt();
+        let expected = cosine.clamp(0.0, 1.0);
+        for (metric, score) in [
+            ("cosine", cosine),
+            ("dot-product", dot),
+            ("euclidean", distance),
+        ] {
+            assert!(
+                (similarity(metric, score) - expected).abs() < 1e-5,
+                "{} {}",
+                metric,
+                score
+            );
+        }
+        assert_eq!(similarity("euclidean", 0.0), 1.0);
+        assert_eq!(similarity("euclidean", 2.0), 0.0);
+        assert_eq!(similarity("cosine", -0.5), 0.0);
+    }
+
+    #[test]
+    fn vector_lite_and_exact_search_score_alike() {
+        // The vector_lite path treats `ScoreMetric::Cosine` as the similarity itself
+        let vectors = random_vectors(10, 9);
+        let index = build_index(IndexParams::default(), &vectors);
+        let embeddings = vectors
+            .iter()
+            .enumerate()
+            .map(|(i, vector)| (format!("doc-{}", i), vector.clone()))
+            .collect::<Vec<_>>();
+        let query = &vectors[2];
+        let exact = exact_search(query, &embeddings, 10);
+        let ann = index.search_with_metric(
+            &Vector::try_from(query.clone()).unwrap(),
+            10,
+            ScoreMetric::Cosine,
+        );
+        for (id, score) in ann {
+            let (_, expected) = exact.iter().find(|(exact_id, _)| *exact_id == id).unwrap();
+            assert!(
+                (similarity("cosine", score) - expected).abs() < 1e-4,
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_embeddings_of_another_dimension() {
+        assert!(check_embedding_dim(DEFAULT_EMBEDDING_MODEL, &vec![0.1; EMBEDDING_DIM]).is_ok());
+        let other = if EMBEDDING_DIM == 768 { 1024 } else { 768 };
+        let error = check_embedding_dim("@cf/baai/bge-m3", &vec![0.1; other])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("@cf/baai/bge-m3"), "{}", error);
+        assert!(
+            error.contains(&format!("returned {} dimensions", other)),
+            "{}",
+            error
+        );
+        assert!(error.contains("EMBEDDING_MODEL"), "{}", error);
+        assert!(check_embedding_dim(DEFAULT_EMBEDDING_MODEL, &[]).is_err());
+    }
+
+    #[test]
+    fn knows_the_dimensions_of_workers_ai_models() {
+        use crate::config::embedding_dimensions;
+        assert_eq!(
+            embedding_dimensions(DEFAULT_EMBEDDING_MODEL),
+            Some(EMBEDDING_DIM)
+        );
+        assert_eq!(embedding_dimensions("@cf/baai/bge-base-en-v1.5"), Some(768));
+        assert_eq!(
+            embedding_dimensions("@cf/baai/bge-large-en-v1.5"),
+            Some(1024)
+        );
+        assert_eq!(embedding_dimensions("@cf/baai/bge-m3"), Some(1024));
+        assert_eq!(embedding_dimensions("@cf/unknown/model"), None);
+    }
+}
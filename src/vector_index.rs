@@ -0,0 +1,293 @@
+use crate::config::Config;
+use crate::metrics::timed;
+use crate::vector::{self, EmbeddingSpace, IndexParams, StoredIndex};
+use serde::{Deserialize, Serialize};
+use vector_lite::{ANNIndexOwned, ScoreMetric, Vector};
+use worker::*;
+
+const VECTOR_INDEX_BINDING: &str = "VECTOR_INDEX";
+const VECTOR_INDEX_NAME: &str = "seen";
+const MULTILINGUAL_VECTOR_INDEX_NAME: &str = "seen-multilingual";
+
+#[derive(Serialize, Deserialize)]
+struct SearchRequest {
+    vector: Vec<f32>,
+    top_k: usize,
+    /// `SEARCH_EXACT` and `EXACT_SEARCH_THRESHOLD`, the index knows its own size to compare
+    exact: Option<bool>,
+    exact_threshold: usize,
+}
+
+/// Matches of a search, best first, with the time the object spent loading what it searched
+#[derive(Serialize, Deserialize)]
+pub struct IndexSearch {
+    pub results: Vec<(String, f32)>,
+    /// Loading the index or its copy for exact search, 0 once the object has them in memory
+    pub load_index_ms: f64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UpdateRequest {
+    delete: Vec<String>,
+    insert: Vec<(String, Vec<f32>)>,
+}
+
+/// Outcome of rebuilding an index from the embeddings table
+#[derive(Serialize, Deserialize)]
+pub struct RebuildReport {
+    pub vectors: usize,
+    /// Links whose embeddings have another dimension than the index, left out of it
+    pub stale_links: Vec<String>,
+}
+
+/// Durable Object keeping the vector_lite index in memory between requests
+/// R2 stays the persistent copy: the index is loaded once and written back after every update,
+/// and only this object writes it, so rebuilds happen here too rather than behind its back
+/// There is one instance per embedding space, each request names the space it is for
+#[durable_object]
+pub struct VectorIndex {
+    env: Env,
+    space: EmbeddingSpace,
+    index: Option<StoredIndex>,
+    /// The vectors of the index for exact search, which vector_lite doesn't expose; read from the
+    /// embeddings table by the first exact search and kept in step with updates from then on
+    exact: Option<Vec<(String, Vec<f32>)>>,
+}
+
+#[durable_object]
+impl DurableObject for VectorIndex {
+    fn new(_state: State, env: Env) -> Self {
+        Self {
+            env,
+            space: EmbeddingSpace::Primary,
+            index: None,
+            exact: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let multilingual = req
+            .url()?
+            .query_pairs()
+            .any(|(key, value)| key == "space" && value == EmbeddingSpace::Multilingual.name());
+        self.space = if multilingual {
+            EmbeddingSpace::Multilingual
+        } else {
+            EmbeddingSpace::Primary
+        };
+
+        let result = match req.path().as_str() {
+            "/search" => {
+                let body = req.json::<SearchRequest>().await?;
+                match self.search(body).await {
+                    Ok(results) => Response::from_json(&results),
+                    Err(e) => Err(e),
+                }
+            }
+            "/update" => {
+                let body = req.json::<UpdateRequest>().await?;
+                match self.update(body).await {
+                    Ok(len) => Response::from_json(&len),
+                    Err(e) => Err(e),
+                }
+            }
+            "/warm" => match self.loaded_index().await {
+                Ok(index) => Response::from_json(&index.len()),
+                Err(e) => Err(e),
+            },
+            "/rebuild" => match self.rebuild().await {
+                Ok(report) => Response::from_json(&report),
+                Err(e) => Err(e),
+            },
+            _ => Response::error("Not Found", 404),
+        };
+        result.or_else(|e| {
+            console_error!("Vector index request failed: {}", e);
+            Response::error(e.to_string(), 500)
+        })
+    }
+}
+
+impl VectorIndex {
+    async fn loaded_index(&mut self) -> Result<&mut StoredIndex> {
+        if self.index.is_none() {
+            let index = match vector::load_vector_lite(&self.env, self.space).await? {
+                Some(index) => index,
+                None => StoredIndex::new(IndexParams::from_config(&Config::from_env(&self.env)?)),
+            };
+            console_log!(
+                "Loaded {} vector index with {} vectors",
+                self.space.name(),
+                index.len()
+            );
+            self.index = Some(index);
+        }
+        self.index
+            .as_mut()
+            .ok_or(Error::from("Vector index is not loaded"))
+    }
+
+    async fn search(&mut self, req: SearchRequest) -> Result<IndexSearch> {
+        let (index, mut load_index_ms) = timed(self.loaded_index()).await;
+        let len = index?.len();
+        if vector::use_exact_search(req.exact, len, req.exact_threshold) {
+            let (vectors, load_exact_ms) = timed(self.exact_vectors()).await;
+            load_index_ms += load_exact_ms;
+            return Ok(IndexSearch {
+                results: vector::exact_search(&req.vector, vectors?, req.top_k),
+                load_index_ms,
+            });
+        }
+        let vector = Vector::try_from(req.vector)
+            .map_err(|_| Error::from("Query vector has the wrong dimension"))?;
+        let index = self.loaded_index().await?;
+        Ok(IndexSearch {
+            results: index.search_with_metric(&vector, req.top_k, ScoreMetric::Cosine),
+            load_index_ms,
+        })
+    }
+
+    async fn exact_vectors(&mut self) -> Result<&[(String, Vec<f32>)]> {
+        if self.exact.is_none() {
+            let config = Config::from_env(&self.env)?;
+            let vectors = vector::load_exact_vectors(&self.env, &config, self.space).await?;
+            console_log!(
+                "Loaded {} {} vectors for exact search",
+                vectors.len(),
+                self.space.name()
+            );
+            self.exact = Some(vectors);
+        }
+        Ok(self.exact.as_deref().unwrap_or_default())
+    }
+
+    /// The index is taken out while the update is saved and only put back once R2 has it, so a
+    /// failed write leaves the next request to reload the last saved index instead of serving
+    /// changes that were never persisted
+    async fn update(&mut self, req: UpdateRequest) -> Result<usize> {
+        let mut insert = Vec::with_capacity(req.insert.len());
+        for (id, embedding) in &req.insert {
+            let vector = Vector::try_from(embedding.clone())
+                .map_err(|_| Error::from(format!("Vector {} has the wrong dimension", id)))?;
+            insert.push((id.clone(), vector));
+        }
+
+        self.loaded_index().await?;
+        let mut index = self
+            .index
+            .take()
+            .ok_or(Error::from("Vector index is not loaded"))?;
+        // Dropped along with the index on failure, the next exact search reads it again
+        let exact = self.exact.take();
+        for id in req.delete.iter() {
+            index.delete_by_id(id);
+        }
+        for (id, vector) in insert {
+            index.insert(vector, id);
+        }
+        vector::save_vector_lite(&self.env, self.space, &index).await?;
+        let len = index.len();
+        self.index = Some(index);
+        self.exact = exact.map(|mut exact| {
+            exact.retain(|(id, _)| {
+                !req.delete.contains(id) && !req.insert.iter().any(|(new, _)| new == id)
+            });
+            exact.extend(req.insert);
+            exact
+        });
+        Ok(len)
+    }
+
+    /// Replace the index with one built from the embeddings table, swapped in once it's saved
+    async fn rebuild(&mut self) -> Result<RebuildReport> {
+        let config = Config::from_env(&self.env)?;
+        let (index, stale_links) =
+            vector::build_vector_lite(&self.env, &config, self.space).await?;
+        vector::save_vector_lite(&self.env, self.space, &index).await?;
+        let report = RebuildReport {
+            vectors: index.len(),
+            stale_links,
+        };
+        self.index = Some(index);
+        self.exact = None;
+        Ok(report)
+    }
+}
+
+async fn call(
+    env: &Env,
+    space: EmbeddingSpace,
+    op: &str,
+    body: Option<String>,
+) -> Result<Response> {
+    let name = match space {
+        EmbeddingSpace::Primary => VECTOR_INDEX_NAME,
+        EmbeddingSpace::Multilingual => MULTILINGUAL_VECTOR_INDEX_NAME,
+    };
+    let stub = env
+        .durable_object(VECTOR_INDEX_BINDING)?
+        .id_from_name(name)?
+        .get_stub()?;
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(body.map(|b| wasm_bindgen::JsValue::from_str(&b)));
+
+    let request = Request::new_with_init(
+        &format!("https://vector-index/{}?space={}", op, space.name()),
+        &init,
+    )?;
+    let mut response = stub.fetch_with_request(request).await?;
+    if response.status_code() != 200 {
+        let error_text = response.text().await?;
+        return Err(Error::from(format!(
+            "Vector index {} failed: {}",
+            op, error_text
+        )));
+    }
+    Ok(response)
+}
+
+/// Search the cached index of a space, returning vector ids and cosine scores
+/// Below `EXACT_SEARCH_THRESHOLD` vectors every one is compared, from the copy the object keeps
+pub async fn search(
+    env: &Env,
+    config: &Config,
+    space: EmbeddingSpace,
+    vector: Vec<f32>,
+    top_k: usize,
+) -> Result<IndexSearch> {
+    let body = serde_json::to_string(&SearchRequest {
+        vector,
+        top_k,
+        exact: config.search_exact,
+        exact_threshold: config.exact_search_threshold,
+    })?;
+    call(env, space, "search", Some(body)).await?.json().await
+}
+
+/// Delete and insert vectors in one step, persisting the index once
+/// Returns the number of vectors in the index afterwards
+pub async fn update(
+    env: &Env,
+    space: EmbeddingSpace,
+    delete: Vec<String>,
+    insert: Vec<(String, Vec<f32>)>,
+) -> Result<usize> {
+    let body = serde_json::to_string(&UpdateRequest { delete, insert })?;
+    call(env, space, "update", Some(body)).await?.json().await
+}
+
+/// Load the index into the Durable Object ahead of an update, returning its vector count
+pub async fn warm(env: &Env, space: EmbeddingSpace) -> Result<usize> {
+    call(env, space, "warm", None).await?.json().await
+}
+
+/// Rebuild the index of a space from the embeddings table with the configured parameters
+pub async fn rebuild(env: &Env, space: EmbeddingSpace) -> Result<RebuildReport> {
+    call(env, space, "rebuild", None).await?.json().await
+}